@@ -0,0 +1,1467 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::app::library::{find_books, home_menu, HomeItem, LibraryView};
+use crate::app::reader_screen::{ReaderAction, ReaderScreen};
+use crate::config::settings::Settings;
+use crate::imaging::source::{is_image_collection, open_image_source};
+use crate::imaging::viewer::{ImageViewer, ViewerAction};
+use crate::net::calibre::{discover, CalibreEvent, CalibreLibrary, CalibreSession};
+use crate::net::kosync::SyncClient;
+use crate::net::opds::OpdsClient;
+use crate::net::wifi::{KnownNetworks, NetworkInfo, WifiCredentials};
+use crate::reader::location::{load_location, save_location, ContentLoc};
+use crate::ui::canvas::Canvas;
+use crate::ui::confirm::{ConfirmAction, ConfirmDialog};
+use crate::ui::list_view::ListAction;
+use crate::ui::menu::{Menu, MenuAction};
+use crate::ui::opds_browser::{OpdsAction, OpdsBrowser};
+use crate::ui::popup::{Popup, PopupAction};
+use crate::ui::progress::ProgressBar;
+use crate::ui::touch::{TouchEvent, TouchEventKind};
+use crate::ui::wifi_setup::{WifiSetupAction, WifiSetupView};
+use crate::update::remote::{update_prompt, Manifest};
+use anyhow::{anyhow, Result};
+use embedded_graphics::{
+    mono_font::{iso_8859_1::FONT_7X13, MonoTextStyle},
+    pixelcolor::Gray8,
+    prelude::*,
+    primitives::{PrimitiveStyle, Rectangle},
+    text::{Baseline, Text},
+};
+use log::*;
+use std::{
+    net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpStream},
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+// part of the display an error popup covers
+const POPUP_PERCENT: u32 = 40;
+// the folder of the books folder that books from catalogs and calibre go to
+const DOWNLOADS_DIR: &str = "books";
+// how long calibre has to answer a broadcast
+const CALIBRE_WAIT: Duration = Duration::from_secs(3);
+// the calibre session parses metadata and writes books
+const CALIBRE_STACK_SIZE: usize = 32768;
+
+/// What the app needs the board to do
+///
+/// The app doesn't own the hardware, requests are taken after each
+/// event with `take_requests`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BoardRequest {
+    /// turn the display to a rotation in degrees, then `resize` the app
+    Rotate(u16),
+    /// store the settings, they changed
+    SaveSettings,
+    /// scan for wifi networks, then `networks_found`
+    WifiScan,
+    /// connect to a wifi network, then `wifi_connected`
+    WifiConnect(WifiCredentials),
+    /// forget a saved wifi network, then `networks_changed`
+    WifiForget(String),
+    /// bring the network up, then `online` with its broadcast address
+    Network,
+    /// start the file server, then `file_server_started`
+    StartFileServer,
+    /// stop the file server
+    StopFileServer,
+    /// fetch the update manifest, then `update_checked`
+    CheckUpdate,
+    /// install a firmware release and reboot, `update_failed` if it can't
+    Update(Manifest),
+    /// go back to the firmware before the update and reboot,
+    /// `update_failed` if it can't
+    RollBack,
+    /// erase the settings and reboot, `reset_failed` if it can't
+    FactoryReset,
+}
+
+/// What the board does while the app works on the network
+///
+/// Downloads run on the app thread, the board shows their progress
+/// drawn on the canvas and says when they should stop.
+pub trait ShowProgress {
+    /// refresh the display from the canvas
+    fn show(&mut self, canvas: &mut Canvas);
+    /// true if the screen was tapped to stop
+    fn stopped(&mut self) -> bool;
+}
+
+// work waiting for the network
+#[derive(Debug)]
+enum NetJob {
+    // the reading progress of a book opened, from the sync server
+    Pull(PathBuf),
+    // the progress of a book closed, its stored location and percentage
+    Push(PathBuf, ContentLoc, f64),
+    // a feed, search or download of the catalog browser
+    Opds(OpdsAction),
+    // look for calibre and connect to it
+    Calibre,
+}
+
+// the screen shown
+enum Screen {
+    Library,
+    Reader(Box<ReaderScreen>),
+    // the path of the comic or folder, and its viewer
+    Viewer(PathBuf, Box<ImageViewer>),
+    Wifi(Box<WifiSetupView>),
+    // the file server runs while its address is shown over the library
+    Transfer(Popup),
+    Opds(Box<OpdsBrowser>),
+    // a calibre session runs on its thread, its events come on the channel,
+    // shutting the stream down ends it
+    Calibre(TcpStream, mpsc::Receiver<CalibreEvent>, Box<ProgressBar>),
+    // a question over the library, the request is made on yes
+    Confirm(ConfirmDialog, Box<BoardRequest>),
+}
+
+/// The app, its screens and the moves between them
+///
+/// Runs on the app thread, which hands it the events. Screens draw on
+/// the canvas, the board refreshes the display from it after each
+/// event. Errors are shown in a popup rather than stopping the app.
+pub struct AppController {
+    books_dir: PathBuf,
+    ereader_dir: PathBuf,
+    settings: Settings,
+    size: Size,
+    books: Vec<PathBuf>,
+    library: LibraryView,
+    screen: Screen,
+    popup: Option<Popup>,
+    // the home menu over the library
+    menu: Option<Menu<HomeItem>>,
+    requests: Vec<BoardRequest>,
+    // the saved wifi networks, from the board
+    known_networks: KnownNetworks,
+    // the wifi state, drawn in the corner
+    wifi_status: String,
+    // the progress sync server, when set up
+    sync: Option<SyncClient>,
+    net_jobs: Vec<NetJob>,
+    // free and total bytes on the sdcard, for calibre
+    card_space: (u64, u64),
+}
+
+impl AppController {
+    /// create the app on the library, for a display size
+    pub fn new(
+        books_dir: &Path,
+        ereader_dir: &Path,
+        settings: Settings,
+        width: u32,
+        height: u32,
+    ) -> Self {
+        let books = find_books(books_dir, ereader_dir);
+        info!("{} books in {:?}", books.len(), books_dir);
+        Self {
+            books_dir: books_dir.to_path_buf(),
+            ereader_dir: ereader_dir.to_path_buf(),
+            settings,
+            size: Size::new(width, height),
+            books,
+            library: LibraryView::new(width, height),
+            screen: Screen::Library,
+            popup: None,
+            menu: None,
+            requests: Vec::new(),
+            known_networks: KnownNetworks::default(),
+            wifi_status: String::new(),
+            sync: None,
+            net_jobs: Vec::new(),
+            card_space: (0, 0),
+        }
+    }
+
+    /// the settings, as changed in the app
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// the requests for the board made since the last call
+    pub fn take_requests(&mut self) -> Vec<BoardRequest> {
+        std::mem::take(&mut self.requests)
+    }
+
+    /// lay the screens out for a new display size, after a rotation
+    pub fn resize(&mut self, width: u32, height: u32, canvas: &mut Canvas) {
+        self.size = Size::new(width, height);
+        self.library = LibraryView::new(width, height);
+        self.popup = None;
+        self.menu = None;
+        match &self.screen {
+            Screen::Wifi(_) | Screen::Opds(_) | Screen::Confirm(..) => {
+                self.screen = Screen::Library
+            }
+            Screen::Transfer(_) => self.stop_file_server(),
+            _ => (),
+        }
+        let two_page_spread = self.settings.display.two_page_spread;
+        let result = match &mut self.screen {
+            Screen::Reader(reader) => reader.resize(width, height, two_page_spread),
+            Screen::Viewer(_, viewer) => {
+                viewer.resize(width, height);
+                Ok(())
+            }
+            Screen::Library
+            | Screen::Wifi(_)
+            | Screen::Transfer(_)
+            | Screen::Opds(_)
+            | Screen::Calibre(..)
+            | Screen::Confirm(..) => Ok(()),
+        };
+        if let Err(e) = result {
+            self.error("Can't lay out the book", &e);
+        }
+        self.draw(canvas);
+    }
+
+    /// sync reading progress with a server, see `SyncClient::new`
+    pub fn set_sync(&mut self, sync: Option<SyncClient>) {
+        self.sync = sync;
+    }
+
+    // queue work for when the network is up
+    fn net(&mut self, job: NetJob) {
+        self.net_jobs.push(job);
+        if !self.requests.contains(&BoardRequest::Network) {
+            self.requests.push(BoardRequest::Network);
+        }
+    }
+
+    /// the network is up, at a broadcast address, or couldn't be brought
+    /// up, do the work waiting for it
+    ///
+    /// Progress pushes are tried without a network too, the sync client
+    /// keeps them to send later. Downloads show their progress with `board`.
+    pub fn online(
+        &mut self,
+        network: Result<Ipv4Addr>,
+        canvas: &mut Canvas,
+        board: &mut dyn ShowProgress,
+    ) {
+        if let Err(e) = &network {
+            warn!("the network is down: {}", e);
+        }
+        for job in std::mem::take(&mut self.net_jobs) {
+            match job {
+                NetJob::Pull(path) => {
+                    if network.is_ok() {
+                        self.pull_progress(&path);
+                    }
+                }
+                NetJob::Push(path, loc, percentage) => {
+                    if let Some(sync) = &mut self.sync {
+                        if let Err(e) = sync.on_close(&path, &loc, percentage) {
+                            warn!("progress of {:?} kept to send later: {}", path, e);
+                        }
+                    }
+                }
+                NetJob::Opds(action) => {
+                    let result = match &network {
+                        Ok(_) => self.opds(action, canvas, board),
+                        Err(e) => Err(anyhow!("{:#}", e)),
+                    };
+                    if let Err(e) = result {
+                        self.error("Catalog error", &e);
+                    }
+                }
+                NetJob::Calibre => {
+                    let result = match &network {
+                        Ok(broadcast) => self.find_calibre(*broadcast),
+                        Err(e) => Err(anyhow!("{:#}", e)),
+                    };
+                    if let Err(e) = result {
+                        self.error("Calibre error", &e);
+                    }
+                }
+            }
+        }
+        self.draw(canvas);
+    }
+
+    // offer a jump when another device is further in the book opened
+    fn pull_progress(&mut self, path: &Path) {
+        let (Some(sync), Screen::Reader(reader)) = (&self.sync, &mut self.screen) else {
+            return;
+        };
+        if reader.path() != path {
+            return;
+        }
+        match sync.on_open(path, reader.percentage()) {
+            Ok(Some(remote)) => reader.offer_jump(&remote),
+            Ok(None) => (),
+            Err(e) => warn!("can't get the progress of {:?}: {}", path, e),
+        }
+    }
+
+    /// the free and total bytes on the sdcard
+    pub fn set_card_space(&mut self, free: u64, total: u64) {
+        self.card_space = (free, total);
+    }
+
+    /// does work on the network run without a request, keep it up
+    pub fn needs_network(&self) -> bool {
+        matches!(self.screen, Screen::Calibre(..))
+    }
+
+    // look for calibre on the network and connect to the first found
+    fn find_calibre(&mut self, broadcast: Ipv4Addr) -> Result<()> {
+        let found = discover(IpAddr::V4(broadcast), CALIBRE_WAIT)?;
+        let (name, addr) = found
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("calibre wasn't found, start its wireless connection"))?;
+        self.connect_calibre(&name, addr)
+    }
+
+    // run a session with calibre on its thread
+    fn connect_calibre(&mut self, name: &str, addr: SocketAddr) -> Result<()> {
+        let library = CalibreLibrary::open(&self.ereader_dir, &self.books_dir.join(DOWNLOADS_DIR))?;
+        let (free, total) = self.card_space;
+        let mut session =
+            CalibreSession::connect(addr, &self.settings.calibre, library, free, total)?;
+        let stream = session.stream()?;
+        let (events_send, events) = mpsc::channel();
+        thread::Builder::new()
+            .name("calibre_thd".to_string())
+            .stack_size(CALIBRE_STACK_SIZE)
+            .spawn(move || {
+                let result = session.run(&mut |event| {
+                    let _ = events_send.send(event);
+                });
+                if let Err(e) = result {
+                    let _ = events_send.send(CalibreEvent::Message(format!("{:#}", e)));
+                }
+            })?;
+        let title = format!("Connected to calibre {}", name);
+        let progress = ProgressBar::new(&title, self.size.width, self.size.height);
+        self.screen = Screen::Calibre(stream, events, Box::new(progress));
+        Ok(())
+    }
+
+    // take the events of the calibre session, true to redraw
+    fn calibre_events(&mut self) -> bool {
+        let Screen::Calibre(_, events, progress) = &mut self.screen else {
+            return false;
+        };
+        let (mut redraw, mut changed, mut ended) = (false, false, false);
+        let mut messages = Vec::new();
+        loop {
+            match events.try_recv() {
+                Ok(CalibreEvent::Receiving { done, total, .. }) => {
+                    redraw |= progress.update(done, Some(total));
+                }
+                Ok(CalibreEvent::BookReceived(_) | CalibreEvent::BookDeleted(_)) => changed = true,
+                Ok(CalibreEvent::Message(message)) => messages.push(message),
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    ended = true;
+                    break;
+                }
+            }
+        }
+        if changed {
+            self.rescan();
+        }
+        if ended {
+            info!("calibre session over");
+            self.screen = Screen::Library;
+        }
+        if !messages.is_empty() {
+            self.popup = Some(Popup::new(
+                "Calibre",
+                &messages.join("\n"),
+                self.size.width,
+                self.size.height,
+                POPUP_PERCENT,
+            ));
+        }
+        redraw || ended || !messages.is_empty()
+    }
+
+    // fetch a feed for the catalog browser, or download a book from it
+    fn opds(
+        &mut self,
+        action: OpdsAction,
+        canvas: &mut Canvas,
+        board: &mut dyn ShowProgress,
+    ) -> Result<()> {
+        let Screen::Opds(browser) = &mut self.screen else {
+            return Ok(());
+        };
+        let client = match browser.catalog() {
+            Some(catalog) => OpdsClient::new(&catalog.username, &catalog.password),
+            None => return Ok(()),
+        };
+        match action {
+            OpdsAction::Open(_, url) => browser.push_feed(client.feed(&url)?),
+            OpdsAction::Search(terms) => {
+                let feed = match browser.feed() {
+                    Some(feed) => client.search(feed, &terms)?,
+                    None => return Ok(()),
+                };
+                browser.push_feed(feed);
+            }
+            OpdsAction::Download(entry) => {
+                let dir = self.books_dir.join(DOWNLOADS_DIR);
+                std::fs::create_dir_all(&dir)?;
+                // the bar is drawn over the catalog as the book comes
+                let title = format!("{}, tap to stop", entry.title);
+                let mut bar = ProgressBar::new(&title, self.size.width, self.size.height);
+                let mut stopped = false;
+                let mut progress = |done, total| {
+                    if bar.update(done, total) {
+                        let _ = bar.draw(canvas);
+                        board.show(canvas);
+                    }
+                    stopped = board.stopped();
+                    !stopped
+                };
+                let path = match client.download(&entry, &dir, &mut progress) {
+                    Ok(path) => path,
+                    Err(e) if stopped => {
+                        info!("{}", e);
+                        let text = format!("{} carries on from here next time.", entry.title);
+                        self.popup = Some(Popup::new(
+                            "Download stopped",
+                            &text,
+                            self.size.width,
+                            self.size.height,
+                            POPUP_PERCENT,
+                        ));
+                        return Ok(());
+                    }
+                    Err(e) => return Err(e),
+                };
+                self.rescan();
+                let text = format!("{} is in the library.", entry.title);
+                self.popup = Some(Popup::new(
+                    "Downloaded",
+                    &text,
+                    self.size.width,
+                    self.size.height,
+                    POPUP_PERCENT,
+                ));
+                info!("downloaded {:?}", path);
+            }
+            OpdsAction::None | OpdsAction::Redraw | OpdsAction::Close => (),
+        }
+        Ok(())
+    }
+
+    /// the device is going to sleep, push the progress of the open book
+    pub fn sleep(&mut self) {
+        if let Screen::Reader(reader) = &self.screen {
+            let (path, percentage) = (reader.path().to_path_buf(), reader.percentage());
+            self.push_progress(path, percentage);
+        }
+    }
+
+    // push the stored location of a book to the sync server
+    fn push_progress(&mut self, path: PathBuf, percentage: f64) {
+        if self.sync.is_some() {
+            let loc = load_location(&self.ereader_dir, &path);
+            self.net(NetJob::Push(path, loc, percentage));
+        }
+    }
+
+    /// the books found, in the order listed
+    pub fn books(&self) -> &[PathBuf] {
+        &self.books
+    }
+
+    /// the open book, if any
+    pub fn reader(&mut self) -> Option<&mut ReaderScreen> {
+        match &mut self.screen {
+            Screen::Reader(reader) => Some(reader),
+            Screen::Library
+            | Screen::Viewer(..)
+            | Screen::Wifi(_)
+            | Screen::Transfer(_)
+            | Screen::Opds(_)
+            | Screen::Calibre(..)
+            | Screen::Confirm(..) => None,
+        }
+    }
+
+    /// look for books again
+    pub fn rescan(&mut self) {
+        self.books = find_books(&self.books_dir, &self.ereader_dir);
+    }
+
+    /// draw the screen shown
+    pub fn draw(&mut self, canvas: &mut Canvas) {
+        let result = match &mut self.screen {
+            Screen::Library => self
+                .library
+                .draw(canvas, &self.books)
+                .map_err(anyhow::Error::from),
+            Screen::Reader(reader) => reader.draw(canvas),
+            Screen::Viewer(_, viewer) => viewer.draw(canvas),
+            Screen::Wifi(view) => view.draw(canvas).map_err(anyhow::Error::from),
+            Screen::Opds(browser) => browser.draw(canvas).map_err(anyhow::Error::from),
+            Screen::Calibre(_, _, progress) => self
+                .library
+                .draw(canvas, &self.books)
+                .and_then(|_| progress.draw(canvas))
+                .map_err(anyhow::Error::from),
+            Screen::Transfer(popup) => self
+                .library
+                .draw(canvas, &self.books)
+                .and_then(|_| popup.draw(canvas))
+                .map_err(anyhow::Error::from),
+            Screen::Confirm(dialog, _) => self
+                .library
+                .draw(canvas, &self.books)
+                .and_then(|_| dialog.draw(canvas))
+                .map_err(anyhow::Error::from),
+        };
+        if let Err(e) = result {
+            self.error("Can't show the page", &e);
+        }
+        if let Some(menu) = &self.menu {
+            let _ = menu.draw(canvas);
+        }
+        if !self.wifi_status.is_empty() {
+            let _ = draw_status(canvas, &self.wifi_status);
+        }
+        if let Some(popup) = &self.popup {
+            let _ = popup.draw(canvas);
+        }
+    }
+
+    /// handle a touch event
+    pub fn touch(&mut self, evt: &TouchEvent, canvas: &mut Canvas) {
+        if let Some(popup) = &mut self.popup {
+            match popup.touch(evt) {
+                PopupAction::None => return,
+                PopupAction::Redraw => (),
+                PopupAction::Close => self.popup = None,
+            }
+            self.draw(canvas);
+            return;
+        }
+        if let Some(menu) = &self.menu {
+            match menu.touch(evt) {
+                MenuAction::None => return,
+                MenuAction::Select(item) => {
+                    self.menu = None;
+                    self.home_item(item);
+                }
+                MenuAction::Close => self.menu = None,
+            }
+            self.draw(canvas);
+            return;
+        }
+        match self.screen_touch(evt) {
+            Ok(true) => self.draw(canvas),
+            Ok(false) => (),
+            Err(e) => {
+                self.error("Error", &e);
+                self.draw(canvas);
+            }
+        }
+    }
+
+    // a touch on the screen shown, true to redraw
+    fn screen_touch(&mut self, evt: &TouchEvent) -> Result<bool> {
+        match &mut self.screen {
+            Screen::Library => match self.library.touch(evt, &self.books) {
+                ListAction::Select(i) => {
+                    let path = self.books[i].clone();
+                    self.open(&path)?;
+                    Ok(true)
+                }
+                ListAction::Redraw => Ok(true),
+                // the title opens the home menu
+                ListAction::Close => {
+                    self.menu = Some(home_menu(self.size.width));
+                    Ok(true)
+                }
+                ListAction::None => Ok(false),
+            },
+            Screen::Reader(reader) => match reader.touch(evt)? {
+                ReaderAction::None => Ok(false),
+                ReaderAction::Redraw => Ok(true),
+                ReaderAction::Close => {
+                    self.close_book();
+                    Ok(true)
+                }
+                ReaderAction::Rotate => {
+                    let degrees = self.settings.display.next_rotation();
+                    self.requests.push(BoardRequest::Rotate(degrees));
+                    self.requests.push(BoardRequest::SaveSettings);
+                    Ok(false)
+                }
+            },
+            Screen::Viewer(path, viewer) => match viewer.touch(evt) {
+                ViewerAction::None => Ok(false),
+                ViewerAction::Redraw => {
+                    save_location(&self.ereader_dir, path, &viewer.location())?;
+                    Ok(true)
+                }
+                ViewerAction::Close => {
+                    self.close_book();
+                    Ok(true)
+                }
+            },
+            Screen::Wifi(view) => match view.touch(evt, &self.known_networks) {
+                WifiSetupAction::None => Ok(false),
+                WifiSetupAction::Redraw => Ok(true),
+                WifiSetupAction::Connect(creds) => {
+                    self.requests.push(BoardRequest::WifiConnect(creds));
+                    Ok(false)
+                }
+                WifiSetupAction::Forget(ssid) => {
+                    self.requests.push(BoardRequest::WifiForget(ssid));
+                    Ok(false)
+                }
+                WifiSetupAction::Close => {
+                    self.screen = Screen::Library;
+                    Ok(true)
+                }
+            },
+            Screen::Opds(browser) => match browser.touch(evt) {
+                OpdsAction::None => Ok(false),
+                OpdsAction::Redraw => Ok(true),
+                OpdsAction::Close => {
+                    self.screen = Screen::Library;
+                    Ok(true)
+                }
+                action => {
+                    self.net(NetJob::Opds(action));
+                    Ok(false)
+                }
+            },
+            // a tap disconnects, the session ends on its thread
+            Screen::Calibre(stream, ..) => {
+                if evt.kind() == TouchEventKind::Tap {
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
+                Ok(false)
+            }
+            Screen::Transfer(popup) => match popup.touch(evt) {
+                PopupAction::None => Ok(false),
+                PopupAction::Redraw => Ok(true),
+                PopupAction::Close => {
+                    self.stop_file_server();
+                    Ok(true)
+                }
+            },
+            Screen::Confirm(dialog, request) => match dialog.touch(evt) {
+                ConfirmAction::None => Ok(false),
+                ConfirmAction::Yes => {
+                    self.requests.push(*request.clone());
+                    self.screen = Screen::Library;
+                    Ok(true)
+                }
+                ConfirmAction::No => {
+                    self.screen = Screen::Library;
+                    Ok(true)
+                }
+            },
+        }
+    }
+
+    // an item of the home menu
+    fn home_item(&mut self, item: HomeItem) {
+        match item {
+            HomeItem::Wifi => self.requests.push(BoardRequest::WifiScan),
+            HomeItem::Transfer => self.requests.push(BoardRequest::StartFileServer),
+            HomeItem::Catalogs => {
+                let catalogs = &self.settings.opds.catalogs;
+                let browser = OpdsBrowser::new(catalogs, self.size.width, self.size.height);
+                self.screen = Screen::Opds(Box::new(browser));
+            }
+            HomeItem::Calibre => self.net(NetJob::Calibre),
+            HomeItem::Update => {
+                if self.settings.update.manifest_url.is_empty() {
+                    self.popup = Some(Popup::new(
+                        "Update",
+                        "Set the update manifest url in the settings",
+                        self.size.width,
+                        self.size.height,
+                        POPUP_PERCENT,
+                    ));
+                } else {
+                    self.requests.push(BoardRequest::CheckUpdate);
+                }
+            }
+            HomeItem::Reset => {
+                let question = "Erase the settings and go back to the defaults?";
+                let dialog = ConfirmDialog::new(question, self.size.width, self.size.height)
+                    .labels("Reset", "Cancel");
+                self.screen = Screen::Confirm(dialog, Box::new(BoardRequest::FactoryReset));
+            }
+        }
+    }
+
+    /// the update manifest was fetched, a newer release is offered
+    ///
+    /// Without one, going back to the firmware before the last update is
+    /// offered if the other slot still has it.
+    pub fn update_checked(
+        &mut self,
+        checked: Result<Option<Manifest>>,
+        running_version: &str,
+        can_roll_back: bool,
+        canvas: &mut Canvas,
+    ) {
+        match checked {
+            Ok(Some(manifest)) => {
+                let dialog = update_prompt(
+                    &manifest,
+                    running_version,
+                    self.size.width,
+                    self.size.height,
+                );
+                let request = BoardRequest::Update(manifest);
+                self.screen = Screen::Confirm(dialog, Box::new(request));
+            }
+            Ok(None) if can_roll_back => {
+                let question = format!(
+                    "Firmware {} is the latest. Roll back to the firmware before it?",
+                    running_version
+                );
+                let dialog = ConfirmDialog::new(&question, self.size.width, self.size.height)
+                    .labels("Roll back", "Keep");
+                self.screen = Screen::Confirm(dialog, Box::new(BoardRequest::RollBack));
+            }
+            Ok(None) => {
+                self.popup = Some(Popup::new(
+                    "Update",
+                    &format!("Firmware {} is the latest", running_version),
+                    self.size.width,
+                    self.size.height,
+                    POPUP_PERCENT,
+                ))
+            }
+            Err(e) => self.error("Update error", &e),
+        }
+        self.draw(canvas);
+    }
+
+    /// the settings couldn't be erased
+    pub fn reset_failed(&mut self, e: &anyhow::Error, canvas: &mut Canvas) {
+        self.error("Reset error", e);
+        self.draw(canvas);
+    }
+
+    /// the update couldn't be installed, the running firmware is kept
+    pub fn update_failed(&mut self, e: &anyhow::Error, canvas: &mut Canvas) {
+        self.error("Update error", e);
+        self.draw(canvas);
+    }
+
+    /// the file server started, at a url with a pin to log in with
+    pub fn file_server_started(&mut self, started: Result<(String, String)>, canvas: &mut Canvas) {
+        match started {
+            Ok((url, pin)) => {
+                let text = format!(
+                    "Open {} in a browser and log in with the pin {}. Tap to stop.",
+                    url, pin
+                );
+                let popup = Popup::new(
+                    "File transfer",
+                    &text,
+                    self.size.width,
+                    self.size.height,
+                    POPUP_PERCENT,
+                );
+                self.screen = Screen::Transfer(popup);
+            }
+            Err(e) => self.error("Can't start the file server", &e),
+        }
+        self.draw(canvas);
+    }
+
+    fn stop_file_server(&mut self) {
+        self.requests.push(BoardRequest::StopFileServer);
+        self.screen = Screen::Library;
+    }
+
+    /// books were added or removed, list them again
+    pub fn library_changed(&mut self, canvas: &mut Canvas) {
+        self.rescan();
+        if matches!(self.screen, Screen::Library | Screen::Transfer(_)) {
+            self.draw(canvas);
+        }
+    }
+
+    /// the networks of a wifi scan, shown to pick one to connect to
+    pub fn networks_found(
+        &mut self,
+        found: Result<Vec<NetworkInfo>>,
+        known: &KnownNetworks,
+        canvas: &mut Canvas,
+    ) {
+        self.known_networks = known.clone();
+        match found {
+            Ok(networks) => {
+                let view = WifiSetupView::new(self.size.width, self.size.height, networks, known);
+                self.screen = Screen::Wifi(Box::new(view));
+            }
+            Err(e) => self.error("Can't scan for WiFi", &e),
+        }
+        self.draw(canvas);
+    }
+
+    /// the result of a `WifiConnect`, a failure asks for the password again
+    pub fn wifi_connected(
+        &mut self,
+        ssid: &str,
+        result: Result<()>,
+        known: &KnownNetworks,
+        canvas: &mut Canvas,
+    ) {
+        self.networks_changed(known, canvas);
+        let Screen::Wifi(view) = &mut self.screen else {
+            return;
+        };
+        match result {
+            Ok(()) => self.screen = Screen::Library,
+            Err(e) => {
+                view.connect_failed(ssid);
+                if !view.asking_password() {
+                    self.error("Can't connect to WiFi", &e);
+                }
+            }
+        }
+        self.draw(canvas);
+    }
+
+    /// the saved wifi networks changed
+    pub fn networks_changed(&mut self, known: &KnownNetworks, canvas: &mut Canvas) {
+        self.known_networks = known.clone();
+        if let Screen::Wifi(view) = &mut self.screen {
+            view.set_known(known);
+            self.draw(canvas);
+        }
+    }
+
+    /// the wifi state for the corner of the screen, empty when off
+    pub fn set_wifi_status(&mut self, status: String, canvas: &mut Canvas) {
+        if status != self.wifi_status {
+            self.wifi_status = status;
+            self.draw(canvas);
+        }
+    }
+
+    /// is work running that should be ticked without waiting
+    pub fn busy(&self) -> bool {
+        match &self.screen {
+            Screen::Reader(reader) => reader.busy(),
+            Screen::Library
+            | Screen::Viewer(..)
+            | Screen::Wifi(_)
+            | Screen::Transfer(_)
+            | Screen::Opds(_)
+            | Screen::Calibre(..)
+            | Screen::Confirm(..) => false,
+        }
+    }
+
+    /// handle the tick, work that isn't driven by touches
+    pub fn tick(&mut self, canvas: &mut Canvas) {
+        if self.calibre_events() {
+            self.draw(canvas);
+        }
+        let result = match &mut self.screen {
+            Screen::Reader(reader) => reader.poll(),
+            Screen::Library
+            | Screen::Viewer(..)
+            | Screen::Wifi(_)
+            | Screen::Transfer(_)
+            | Screen::Opds(_)
+            | Screen::Calibre(..)
+            | Screen::Confirm(..) => Ok(false),
+        };
+        match result {
+            Ok(true) => self.draw(canvas),
+            Ok(false) => (),
+            Err(e) => {
+                self.error("Error", &e);
+                self.draw(canvas);
+            }
+        }
+    }
+
+    /// open a book, closing the one open
+    ///
+    /// Comics and folders of images open in the image viewer.
+    pub fn open(&mut self, path: &Path) -> Result<()> {
+        self.close_book();
+        if is_image_collection(path) {
+            let viewer = ImageViewer::new(
+                open_image_source(path)?,
+                self.size.width,
+                self.size.height,
+                &self.settings.viewer,
+                load_location(&self.ereader_dir, path),
+            );
+            self.screen = Screen::Viewer(path.to_path_buf(), Box::new(viewer));
+            return Ok(());
+        }
+        let reader = ReaderScreen::open(
+            &self.ereader_dir,
+            path,
+            self.size.width,
+            self.size.height,
+            self.settings.display.two_page_spread,
+        )?;
+        self.screen = Screen::Reader(Box::new(reader));
+        if self.sync.is_some() {
+            self.net(NetJob::Pull(path.to_path_buf()));
+        }
+        Ok(())
+    }
+
+    /// close the open book, back to the library
+    pub fn close_book(&mut self) {
+        match &mut self.screen {
+            Screen::Reader(reader) => {
+                reader.close();
+                let (path, percentage) = (reader.path().to_path_buf(), reader.percentage());
+                if reader.finished() {
+                    self.mark_read(&path);
+                }
+                self.push_progress(path, percentage);
+            }
+            Screen::Viewer(path, viewer) => {
+                if let Err(e) = save_location(&self.ereader_dir, path, &viewer.location()) {
+                    warn!("can't save the place in {:?}: {}", path, e);
+                }
+            }
+            Screen::Library
+            | Screen::Wifi(_)
+            | Screen::Transfer(_)
+            | Screen::Opds(_)
+            | Screen::Calibre(..)
+            | Screen::Confirm(..) => (),
+        }
+        self.screen = Screen::Library;
+    }
+
+    // a book read to the end is marked read, for calibre to pick up
+    fn mark_read(&self, path: &Path) {
+        let books_dir = self.books_dir.join(DOWNLOADS_DIR);
+        if !path.starts_with(&books_dir) {
+            return;
+        }
+        let result = CalibreLibrary::open(&self.ereader_dir, &books_dir).and_then(|mut library| {
+            library.set_read(path, true);
+            library.save()
+        });
+        if let Err(e) = result {
+            warn!("can't mark {:?} read: {}", path, e);
+        }
+    }
+
+    // show an error in a popup
+    fn error(&mut self, title: &str, e: &anyhow::Error) {
+        error!("{}: {:#}", title, e);
+        self.popup = Some(Popup::new(
+            title,
+            &format!("{:#}", e),
+            self.size.width,
+            self.size.height,
+            POPUP_PERCENT,
+        ));
+    }
+}
+
+// draw a status in the bottom right corner
+fn draw_status<D>(target: &mut D, text: &str) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = Gray8>,
+{
+    let area = target.bounding_box();
+    let font = &FONT_7X13;
+    let width = font.character_size.width * text.chars().count() as u32 + 4;
+    let height = font.character_size.height + 2;
+    let corner = Point::new(
+        area.size.width as i32 - width as i32,
+        area.size.height as i32 - height as i32,
+    );
+    Rectangle::new(corner, Size::new(width, height))
+        .into_styled(PrimitiveStyle::with_fill(Gray8::WHITE))
+        .draw(target)?;
+    let style = MonoTextStyle::new(font, Gray8::BLACK);
+    Text::with_baseline(text, corner + Point::new(2, 1), style, Baseline::Top).draw(target)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::settings::{OpdsCatalog, SyncSettings};
+    use crate::net::calibre::{opcode, read_message, write_message};
+    use crate::net::kosync::{document_hash, jump_prompt, Progress};
+    use crate::test_util::{bmp, epub, response, temp_dir, zip_file, TestServer};
+    use serde_json::json;
+    use std::{
+        fs,
+        io::{BufReader, Write},
+        net::TcpListener,
+        time::Instant,
+    };
+
+    fn tap(x: u32, y: u32) -> TouchEvent {
+        TouchEvent::with_position(TouchEventKind::Tap, x, y)
+    }
+
+    // three chapters of 200 words
+    fn chapters() -> Vec<(String, String)> {
+        (0..3)
+            .map(|c| {
+                let words: Vec<String> = (0..200).map(|n| format!("c{}w{}", c, n)).collect();
+                (
+                    format!("Chapter {}", c),
+                    format!("<html><body><p>{}</p></body></html>", words.join(" ")),
+                )
+            })
+            .collect()
+    }
+
+    // counts the refreshes, stops downloads when told to
+    #[derive(Default)]
+    struct Board {
+        shown: usize,
+        stop: bool,
+    }
+
+    impl ShowProgress for Board {
+        fn show(&mut self, _canvas: &mut Canvas) {
+            self.shown += 1;
+        }
+
+        fn stopped(&mut self) -> bool {
+            self.stop
+        }
+    }
+
+    #[test]
+    fn library_opens_books() {
+        let root = temp_dir("controller");
+        let chapter = (
+            "One".to_string(),
+            "<html><body><p>hello</p></body></html>".to_string(),
+        );
+        epub(&root.join("a.epub"), &[chapter]);
+        fs::write(root.join("b.epub"), b"not a zip").unwrap();
+        let mut canvas = Canvas::new(300, 400);
+        let mut app =
+            AppController::new(&root, &root.join("ereader"), Settings::default(), 300, 400);
+        app.draw(&mut canvas);
+        assert_eq!(app.books().len(), 2);
+
+        // the first row opens the book
+        app.touch(&tap(50, 60), &mut canvas);
+        assert_eq!(
+            app.reader().map(|r| r.path().to_path_buf()),
+            Some(root.join("a.epub"))
+        );
+        app.tick(&mut canvas);
+        app.close_book();
+        assert!(app.reader().is_none());
+
+        // a bad book shows an error, a tap closes it
+        app.touch(&tap(50, 100), &mut canvas);
+        assert!(app.reader().is_none());
+        assert!(app.popup.is_some());
+        app.touch(&tap(50, 10), &mut canvas);
+        assert!(app.popup.is_none());
+    }
+
+    #[test]
+    fn rotate_asks_the_board() {
+        let root = temp_dir("controller-rotate");
+        let chapter = (
+            "One".to_string(),
+            "<html><body><p>hello</p></body></html>".to_string(),
+        );
+        epub(&root.join("a.epub"), &[chapter]);
+        let mut canvas = Canvas::new(300, 400);
+        let mut app =
+            AppController::new(&root, &root.join("ereader"), Settings::default(), 300, 400);
+        app.touch(&tap(50, 60), &mut canvas);
+        // the menu, then its fifth button
+        app.touch(&tap(150, 10), &mut canvas);
+        app.touch(&tap(190, 10), &mut canvas);
+        assert_eq!(
+            app.take_requests(),
+            vec![BoardRequest::Rotate(0), BoardRequest::SaveSettings]
+        );
+        assert_eq!(app.settings().display.rotation, 0);
+        assert!(app.take_requests().is_empty());
+
+        // the board turns the display and the book is laid out again
+        canvas.resize(400, 300);
+        app.resize(400, 300, &mut canvas);
+        assert_eq!(app.reader().unwrap().location().item, 0);
+    }
+
+    #[test]
+    fn wifi_from_the_home_menu() {
+        let root = temp_dir("controller-wifi");
+        let mut canvas = Canvas::new(300, 400);
+        let mut app =
+            AppController::new(&root, &root.join("ereader"), Settings::default(), 300, 400);
+        // the library title opens the home menu, its first button scans
+        app.touch(&tap(150, 10), &mut canvas);
+        assert!(app.menu.is_some());
+        app.touch(&tap(10, 10), &mut canvas);
+        assert_eq!(app.take_requests(), vec![BoardRequest::WifiScan]);
+
+        let home = WifiCredentials {
+            ssid: "home".to_string(),
+            password: "old".to_string(),
+        };
+        let mut known = KnownNetworks::default();
+        known.remember(home.clone());
+        let network = |ssid: &str| NetworkInfo {
+            ssid: ssid.to_string(),
+            rssi: -50,
+            secured: true,
+        };
+        let found = vec![network("home"), network("cafe")];
+        app.networks_found(Ok(found), &known, &mut canvas);
+        assert!(matches!(app.screen, Screen::Wifi(_)));
+
+        // a saved network connects with its password, when that fails
+        // the password is asked for again
+        app.touch(&tap(50, 60), &mut canvas);
+        assert_eq!(app.take_requests(), vec![BoardRequest::WifiConnect(home)]);
+        let failed = Err(anyhow::anyhow!("auth failed"));
+        app.wifi_connected("home", failed, &known, &mut canvas);
+        let Screen::Wifi(view) = &app.screen else {
+            panic!("the wifi screen closed");
+        };
+        assert!(view.asking_password());
+        assert!(app.popup.is_none());
+
+        // forgetting it unmarks it
+        app.screen = Screen::Wifi(Box::new(WifiSetupView::new(
+            300,
+            400,
+            vec![network("home")],
+            &known,
+        )));
+        let hold = TouchEvent::with_position(TouchEventKind::Hold, 50, 60);
+        app.touch(&hold, &mut canvas);
+        assert_eq!(
+            app.take_requests(),
+            vec![BoardRequest::WifiForget("home".to_string())]
+        );
+        known.forget("home");
+        app.networks_changed(&known, &mut canvas);
+        app.touch(&hold, &mut canvas);
+        assert!(app.take_requests().is_empty());
+
+        app.set_wifi_status("WiFi".to_string(), &mut canvas);
+        assert_eq!(app.wifi_status, "WiFi");
+    }
+
+    #[test]
+    fn progress_is_synced_on_open_and_close() {
+        let root = temp_dir("controller-sync");
+        epub(&root.join("a.epub"), &chapters());
+        let hash = document_hash(&root.join("a.epub")).unwrap();
+        let remote = Progress {
+            document: hash.clone(),
+            progress: "/body/DocFragment[3]/body".to_string(),
+            percentage: 0.9,
+            device: "phone".to_string(),
+            device_id: "B".to_string(),
+            timestamp: None,
+        };
+        let body = serde_json::to_vec(&remote).unwrap();
+        let server = TestServer::start(2, move |_| response("200 OK", &[], &body));
+        let ereader_dir = root.join("ereader");
+        let settings = SyncSettings {
+            server: server.url.clone(),
+            username: "reader".to_string(),
+            ..Default::default()
+        };
+        let mut canvas = Canvas::new(300, 400);
+        let mut app = AppController::new(&root, &ereader_dir, Settings::default(), 300, 400);
+        app.set_sync(SyncClient::new(&settings, &ereader_dir, &[1, 2, 3, 4, 5, 6]).unwrap());
+
+        // opening pulls once the network is up, a further device offers a jump
+        app.touch(&tap(50, 60), &mut canvas);
+        assert_eq!(app.take_requests(), vec![BoardRequest::Network]);
+        app.online(Ok(Ipv4Addr::LOCALHOST), &mut canvas, &mut Board::default());
+        let area = jump_prompt(&remote, 300, 400).area();
+        let yes = tap(60, (area.top_left.y + area.size.height as i32 - 40) as u32);
+        app.touch(&yes, &mut canvas);
+        assert_eq!(app.reader().unwrap().location().item, 2);
+
+        // closing pushes the stored location
+        app.close_book();
+        assert_eq!(app.take_requests(), vec![BoardRequest::Network]);
+        app.online(
+            Err(anyhow::anyhow!("no wifi")),
+            &mut canvas,
+            &mut Board::default(),
+        );
+        let requests = server.requests();
+        assert!(requests[0].starts_with(&format!("GET /syncs/progress/{} ", hash)));
+        assert!(requests[1].starts_with("PUT /syncs/progress "));
+        assert!(requests[1].contains("DocFragment[3]"));
+    }
+
+    #[test]
+    fn file_transfer_from_the_home_menu() {
+        let root = temp_dir("controller-transfer");
+        let mut canvas = Canvas::new(300, 400);
+        let mut app =
+            AppController::new(&root, &root.join("ereader"), Settings::default(), 300, 400);
+        app.touch(&tap(150, 10), &mut canvas);
+        app.touch(&tap(70, 10), &mut canvas);
+        assert_eq!(app.take_requests(), vec![BoardRequest::StartFileServer]);
+        let started = Ok(("http://10.0.0.5/".to_string(), "123456".to_string()));
+        app.file_server_started(started, &mut canvas);
+        assert!(matches!(app.screen, Screen::Transfer(_)));
+
+        // an upload shows up in the library
+        let chapter = (
+            "One".to_string(),
+            "<html><body><p>hello</p></body></html>".to_string(),
+        );
+        epub(&root.join("new.epub"), &[chapter]);
+        app.library_changed(&mut canvas);
+        assert_eq!(app.books().len(), 1);
+
+        // a tap outside the address stops the server
+        app.touch(&tap(150, 10), &mut canvas);
+        assert_eq!(app.take_requests(), vec![BoardRequest::StopFileServer]);
+        assert!(matches!(app.screen, Screen::Library));
+    }
+
+    #[test]
+    fn catalogs_download_into_the_library() {
+        let root = temp_dir("controller-opds");
+        let book = root.join("served.epub");
+        epub(&book, &chapters());
+        let epub_bytes = fs::read(&book).unwrap();
+        fs::remove_file(&book).unwrap();
+        let feed = r#"<feed xmlns="http://www.w3.org/2005/Atom"><title>New</title>
+            <entry><title>Moby</title>
+            <link rel="http://opds-spec.org/acquisition" type="application/epub+zip" href="/b/1.epub"/>
+            </entry></feed>"#;
+        let server = TestServer::start(3, move |request| {
+            if request.starts_with("GET /b/1.epub") {
+                response("200 OK", &[], &epub_bytes)
+            } else {
+                response("200 OK", &[], feed.as_bytes())
+            }
+        });
+        let mut settings = Settings::default();
+        settings.opds.catalogs.push(OpdsCatalog {
+            name: "Test".to_string(),
+            url: format!("{}/opds", server.url),
+            ..Default::default()
+        });
+        let mut canvas = Canvas::new(300, 400);
+        let mut app = AppController::new(&root, &root.join("ereader"), settings, 300, 400);
+        app.touch(&tap(150, 10), &mut canvas);
+        app.touch(&tap(130, 10), &mut canvas);
+        assert!(matches!(app.screen, Screen::Opds(_)));
+
+        // the catalog feed, then its book, each once the network is up
+        app.touch(&tap(50, 60), &mut canvas);
+        assert_eq!(app.take_requests(), vec![BoardRequest::Network]);
+        app.online(Ok(Ipv4Addr::LOCALHOST), &mut canvas, &mut Board::default());
+
+        // a tap stops the download, its part is kept
+        app.touch(&tap(50, 60), &mut canvas);
+        assert_eq!(app.take_requests(), vec![BoardRequest::Network]);
+        let mut board = Board {
+            stop: true,
+            ..Default::default()
+        };
+        app.online(Ok(Ipv4Addr::LOCALHOST), &mut canvas, &mut board);
+        assert_eq!(board.shown, 1);
+        assert!(app.popup.take().is_some());
+        assert!(app.books().is_empty());
+        assert!(root.join("books/Moby.epub.part").exists());
+
+        // the bar is shown as the book comes
+        app.touch(&tap(50, 60), &mut canvas);
+        assert_eq!(app.take_requests(), vec![BoardRequest::Network]);
+        let mut board = Board::default();
+        app.online(Ok(Ipv4Addr::LOCALHOST), &mut canvas, &mut board);
+        assert!(board.shown >= 2);
+        assert!(app.popup.is_some());
+        assert_eq!(app.books(), [root.join("books/Moby.epub")]);
+        assert!(server.requests()[2].starts_with("GET /b/1.epub"));
+    }
+
+    #[test]
+    fn calibre_sends_books_into_the_library() {
+        let root = temp_dir("controller-calibre");
+        let book = root.join("sent.epub");
+        epub(&book, &chapters());
+        let epub_bytes = fs::read(&book).unwrap();
+        fs::remove_file(&book).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut canvas = Canvas::new(300, 400);
+        let mut app =
+            AppController::new(&root, &root.join("ereader"), Settings::default(), 300, 400);
+        app.set_card_space(1000, 2000);
+        app.connect_calibre("desk", addr).unwrap();
+        assert!(app.needs_network());
+
+        // calibre sends a book then ejects the device
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let args = json!({
+            "lpath": "Author/Sent.epub",
+            "length": epub_bytes.len(),
+            "wantsSendOkToSendbook": true,
+            "metadata": { "title": "Sent" },
+        });
+        write_message(&mut stream, opcode::SEND_BOOK, &args).unwrap();
+        assert_eq!(read_message(&mut reader).unwrap().0, opcode::OK);
+        stream.write_all(&epub_bytes).unwrap();
+        write_message(&mut stream, opcode::NOOP, &json!({ "ejecting": true })).unwrap();
+        assert_eq!(read_message(&mut reader).unwrap().0, opcode::OK);
+
+        let start = Instant::now();
+        while app.needs_network() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            app.tick(&mut canvas);
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(matches!(app.screen, Screen::Library));
+        assert_eq!(app.books(), [root.join("books/Author/Sent.epub")]);
+
+        // read to the end it's marked read for calibre
+        app.touch(&tap(50, 60), &mut canvas);
+        let reader = app.reader().unwrap();
+        let last = loop {
+            // past the end the error tells the page count, once counted
+            let e = reader.goto_page(u32::MAX).unwrap_err().to_string();
+            if let Some(pages) = e.strip_prefix("the book has ") {
+                break pages.trim_end_matches(" pages").parse().unwrap();
+            }
+            reader.poll().unwrap();
+            thread::sleep(Duration::from_millis(10));
+        };
+        reader.goto_page(last).unwrap();
+        app.close_book();
+        let library = CalibreLibrary::open(&root.join("ereader"), &root.join("books")).unwrap();
+        assert_eq!(library.books()["Author/Sent.epub"]["_is_read_"], true);
+    }
+
+    #[test]
+    fn updates_are_offered_from_the_home_menu() {
+        let root = temp_dir("controller-update");
+        let mut canvas = Canvas::new(300, 400);
+        let mut settings = Settings::default();
+        let mut app = AppController::new(&root, &root.join("ereader"), settings.clone(), 300, 400);
+        // without a manifest url there's nothing to check
+        app.touch(&tap(150, 10), &mut canvas);
+        app.touch(&tap(245, 10), &mut canvas);
+        assert!(app.take_requests().is_empty());
+        assert!(app.popup.take().is_some());
+
+        settings.update.manifest_url = "http://example.com/manifest.json".to_string();
+        let mut app = AppController::new(&root, &root.join("ereader"), settings, 300, 400);
+        app.touch(&tap(150, 10), &mut canvas);
+        app.touch(&tap(245, 10), &mut canvas);
+        assert_eq!(app.take_requests(), vec![BoardRequest::CheckUpdate]);
+        app.update_checked(Ok(None), "0.2.0", false, &mut canvas);
+        assert!(app.popup.take().is_some());
+
+        // with the earlier firmware still there, going back is offered
+        app.update_checked(Ok(None), "0.2.0", true, &mut canvas);
+        assert!(app.popup.is_none());
+        let Screen::Confirm(dialog, _) = &app.screen else {
+            panic!("no roll back dialog");
+        };
+        let area = dialog.area();
+        let yes = tap(60, (area.top_left.y + area.size.height as i32 - 40) as u32);
+        app.touch(&yes, &mut canvas);
+        assert_eq!(app.take_requests(), vec![BoardRequest::RollBack]);
+        assert!(matches!(app.screen, Screen::Library));
+
+        // a newer release asks first, yes installs it
+        let manifest = Manifest {
+            version: "0.3.0".to_string(),
+            url: "http://example.com/0.3.0.bin".to_string(),
+            sha256: "00".repeat(32),
+            min_battery: 0.0,
+            notes: String::new(),
+        };
+        app.update_checked(Ok(Some(manifest.clone())), "0.2.0", true, &mut canvas);
+        let area = update_prompt(&manifest, "0.2.0", 300, 400).area();
+        let yes = tap(60, (area.top_left.y + area.size.height as i32 - 40) as u32);
+        app.touch(&yes, &mut canvas);
+        assert_eq!(app.take_requests(), vec![BoardRequest::Update(manifest)]);
+        assert!(matches!(app.screen, Screen::Library));
+        app.update_failed(&anyhow!("battery is low"), &mut canvas);
+        assert!(app.popup.is_some());
+    }
+
+    #[test]
+    fn factory_reset_asks_first() {
+        let root = temp_dir("controller-reset");
+        let mut canvas = Canvas::new(300, 400);
+        let mut app =
+            AppController::new(&root, &root.join("ereader"), Settings::default(), 300, 400);
+        app.touch(&tap(150, 10), &mut canvas);
+        app.touch(&tap(280, 10), &mut canvas);
+        assert!(matches!(app.screen, Screen::Confirm(..)));
+        // a tap outside the dialog cancels
+        app.touch(&tap(150, 10), &mut canvas);
+        assert!(matches!(app.screen, Screen::Library));
+        assert!(app.take_requests().is_empty());
+
+        app.touch(&tap(150, 10), &mut canvas);
+        app.touch(&tap(280, 10), &mut canvas);
+        let Screen::Confirm(dialog, _) = &app.screen else {
+            panic!("no question asked");
+        };
+        let area = dialog.area();
+        let yes = tap(60, (area.top_left.y + area.size.height as i32 - 40) as u32);
+        app.touch(&yes, &mut canvas);
+        assert_eq!(app.take_requests(), vec![BoardRequest::FactoryReset]);
+    }
+
+    #[test]
+    fn comics_open_at_the_last_image() {
+        let root = temp_dir("controller-comic");
+        let image = bmp(4, 4, &[[0; 3]; 16]);
+        zip_file(
+            &root.join("comic.cbz"),
+            &[("1.bmp", &image[..]), ("2.bmp", &image[..])],
+        );
+        let mut canvas = Canvas::new(300, 400);
+        let mut app =
+            AppController::new(&root, &root.join("ereader"), Settings::default(), 300, 400);
+        app.touch(&tap(50, 60), &mut canvas);
+        let viewer_index = |app: &AppController| match &app.screen {
+            Screen::Viewer(_, viewer) => Some(viewer.index()),
+            _ => None,
+        };
+        assert_eq!(viewer_index(&app), Some(0));
+
+        // a tap on the right goes to the next image, the top bar closes
+        app.touch(&tap(290, 200), &mut canvas);
+        assert_eq!(viewer_index(&app), Some(1));
+        app.touch(&tap(150, 10), &mut canvas);
+        assert_eq!(viewer_index(&app), None);
+
+        app.touch(&tap(50, 60), &mut canvas);
+        assert_eq!(viewer_index(&app), Some(1));
+    }
+}
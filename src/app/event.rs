@@ -0,0 +1,19 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::console::command::ConsoleRequest;
+use crate::ui::touch::TouchEvent;
+
+/// Something for the app thread to handle
+///
+/// The touch thread and the serial console send on one channel, the app
+/// thread sleeps on it until there is work. A ticker sends `Tick` for
+/// work that isn't driven by touches, the file server `LibraryChanged`
+/// when books were added or removed.
+#[derive(Debug)]
+pub enum AppEvent {
+    Touch(TouchEvent),
+    Console(ConsoleRequest),
+    Tick,
+    LibraryChanged,
+}
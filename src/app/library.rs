@@ -0,0 +1,223 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::imaging::source::{is_image_collection, natural_cmp};
+use crate::reader::content::BookContent;
+use crate::reader::epub::{is_epub, EpubBook};
+use crate::reader::fb2::{is_fb2, Fb2Book};
+use crate::reader::text_book::{text_format, TextBook};
+use crate::ui::list_view::{ListAction, ListRow, ListView};
+use crate::ui::menu::Menu;
+use crate::ui::touch::TouchEvent;
+use anyhow::{anyhow, Result};
+use embedded_graphics::{pixelcolor::GrayColor, prelude::*};
+use log::*;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// folder searched for books, the sdcard
+pub const BOOKS_DIR: &str = "/sdcard";
+
+// folders deeper than this are not searched for books
+const MAX_DEPTH: usize = 4;
+
+/// is the path a book the reader opens
+pub fn is_book(path: &Path) -> bool {
+    is_epub(path) || is_fb2(path) || text_format(path).is_some()
+}
+
+/// open the content of a book
+pub fn open_book(ereader_dir: &Path, path: &Path) -> Result<Box<dyn BookContent>> {
+    if is_epub(path) {
+        Ok(Box::new(EpubBook::open(path)?))
+    } else if is_fb2(path) {
+        Ok(Box::new(Fb2Book::open(ereader_dir, path)?))
+    } else if text_format(path).is_some() {
+        Ok(Box::new(TextBook::open(ereader_dir, path)?))
+    } else {
+        Err(anyhow!("not a book {}", path.display()))
+    }
+}
+
+/// the name a book is listed under, its file name without the extension
+///
+/// Zipped FB2 books lose both extensions, folders keep their whole name.
+pub fn book_name(path: &Path) -> String {
+    if path.is_dir() {
+        return path
+            .file_name()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+    }
+    let stem = |p: &Path| p.file_stem().map(|s| s.to_string_lossy().into_owned());
+    let name = stem(path).unwrap_or_default();
+    if is_fb2(path) && is_fb2(Path::new(&name)) {
+        stem(Path::new(&name)).unwrap_or(name)
+    } else {
+        name
+    }
+}
+
+/// the books under a folder, in name order
+///
+/// Comics and folders of images are listed with the books, the ereader's
+/// own folder and hidden entries are skipped.
+pub fn find_books(root: &Path, ereader_dir: &Path) -> Vec<PathBuf> {
+    let mut books = Vec::new();
+    add_books(root, ereader_dir, 0, &mut books);
+    books.sort_by(|a, b| natural_cmp(&book_name(a), &book_name(b)));
+    books
+}
+
+fn add_books(dir: &Path, ereader_dir: &Path, depth: usize, books: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("can't list {:?}: {}", dir, e);
+            return;
+        }
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let hidden = entry.file_name().to_string_lossy().starts_with('.');
+        if hidden || path == ereader_dir {
+            continue;
+        }
+        if is_image_collection(&path) {
+            books.push(path);
+        } else if path.is_dir() {
+            if depth < MAX_DEPTH {
+                add_books(&path, ereader_dir, depth + 1, books);
+            }
+        } else if is_book(&path) {
+            books.push(path);
+        }
+    }
+}
+
+/// The screens reachable from the home menu
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum HomeItem {
+    Wifi,
+    Transfer,
+    Catalogs,
+    Calibre,
+    Update,
+    Reset,
+}
+
+/// the menu shown over the library when its title is tapped
+pub fn home_menu(width: u32) -> Menu<HomeItem> {
+    let items = [
+        ("WiFi", HomeItem::Wifi),
+        ("Transfer", HomeItem::Transfer),
+        ("OPDS", HomeItem::Catalogs),
+        ("Calibre", HomeItem::Calibre),
+        ("Update", HomeItem::Update),
+        ("Reset", HomeItem::Reset),
+    ];
+    Menu::new(&items, width)
+}
+
+/// The list of books, the home screen
+#[derive(Debug)]
+pub struct LibraryView {
+    list: ListView,
+}
+
+impl LibraryView {
+    /// create the view for a display size
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            list: ListView::new("Books", width, height),
+        }
+    }
+
+    /// draw the current page of books
+    pub fn draw<D>(&self, display: &mut D, books: &[PathBuf]) -> Result<(), D::Error>
+    where
+        D: DrawTarget,
+        D::Color: GrayColor,
+    {
+        let names: Vec<String> = books.iter().map(|b| book_name(b)).collect();
+        let rows: Vec<ListRow> = names.iter().map(|n| ListRow::new(n)).collect();
+        self.list.draw(display, &rows)
+    }
+
+    /// handle a touch event, a selection is the index of a book
+    pub fn touch(&mut self, evt: &TouchEvent, books: &[PathBuf]) -> ListAction {
+        self.list.touch(evt, books.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    #[test]
+    fn books_on_the_card() {
+        let root = temp_dir("library");
+        let ereader_dir = root.join("ereader");
+        for dir in ["ereader/books", "fiction/old", ".trash", "comics/vol.1"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in [
+            "b 10.epub",
+            "b 9.EPUB",
+            "notes.txt",
+            "readme.md",
+            "cover.jpg",
+            "fiction/c.fb2.zip",
+            "comics/vol.1/001.jpg",
+            "comics/z.cbz",
+            "fiction/old/a.epub",
+            "ereader/books/cached.epub",
+            ".trash/gone.epub",
+            ".hidden.epub",
+        ] {
+            fs::write(root.join(file), b"").unwrap();
+        }
+        let books = find_books(&root, &ereader_dir);
+        assert_eq!(
+            books,
+            vec![
+                root.join("fiction/old/a.epub"),
+                root.join("b 9.EPUB"),
+                root.join("b 10.epub"),
+                root.join("fiction/c.fb2.zip"),
+                root.join("notes.txt"),
+                root.join("readme.md"),
+                root.join("comics/vol.1"),
+                root.join("comics/z.cbz"),
+            ]
+        );
+        assert_eq!(book_name(&books[1]), "b 9");
+        assert!(open_book(&ereader_dir, &root.join("cover.jpg")).is_err());
+
+        // text books open with their headings as contents
+        fs::write(
+            root.join("readme.md"),
+            "# One\n\nsome text\n\n# Two\n\nmore\n",
+        )
+        .unwrap();
+        let mut book = open_book(&ereader_dir, &root.join("readme.md")).unwrap();
+        let toc = book.toc().unwrap();
+        assert_eq!(toc.len(), 2);
+        assert_eq!(toc[1].title, "Two");
+
+        // so do fb2 books, with their sections
+        fs::write(
+            root.join("d.fb2"),
+            "<FictionBook><body><section><title><p>Start</p></title><p>text</p></section>\
+             </body></FictionBook>",
+        )
+        .unwrap();
+        assert_eq!(book_name(&books[3]), "c");
+        assert_eq!(book_name(&books[6]), "vol.1");
+        let mut book = open_book(&ereader_dir, &root.join("d.fb2")).unwrap();
+        assert_eq!(book.toc().unwrap()[0].title, "Start");
+    }
+}
@@ -0,0 +1,1414 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::app::library::{book_name, open_book};
+use crate::dict::stardict::Dictionaries;
+use crate::imaging::decode::decode_image_bytes;
+use crate::imaging::dither::{draw_image, Dither};
+use crate::net::kosync::{jump_prompt, remote_location, Progress};
+use crate::reader::bookmarks::{draw_dog_ear, is_bookmark_gesture, BookmarkDb, BookmarkListView};
+use crate::reader::content::BookContent;
+use crate::reader::footnote::{tap_link, LinkTap};
+use crate::reader::highlights::{
+    draw_highlights, export_markdown, Highlight, HighlightDb, Selection,
+};
+use crate::reader::history::{follow_link, is_back_gesture, NavHistory};
+use crate::reader::layout::{BitmapFonts, Page, PageLayout, FOOTER_HEIGHT};
+use crate::reader::location::{load_location, save_location, ContentLoc, PageRange};
+use crate::reader::menu::{reader_menu, ReaderMenuItem};
+use crate::reader::page_map::PageMap;
+use crate::reader::pagination::{PageIndex, Repaginator};
+use crate::reader::search::{draw_match, SearchHit, SearchResultsView, Searcher};
+use crate::reader::spread::SpreadLayout;
+use crate::reader::stats::{ReadingStats, StatsView};
+use crate::reader::toc::{Toc, TocAction, TocView};
+use crate::reader::typography::{
+    Typography, TypographyAction, TypographyPanel, TypographyStore, PINCH_STEP,
+};
+use crate::ui::canvas::Canvas;
+use crate::ui::confirm::{ConfirmAction, ConfirmDialog};
+use crate::ui::keyboard::{Keyboard, KeyboardAction};
+use crate::ui::list_view::ListAction;
+use crate::ui::menu::{Menu, MenuAction};
+use crate::ui::popup::{Popup, PopupAction};
+use crate::ui::progress::ProgressBar;
+use crate::ui::touch::{PinchSteps, TouchEvent, TouchEventKind};
+use anyhow::{anyhow, Result};
+use embedded_graphics::{
+    mono_font::{iso_8859_1::FONT_7X13, MonoTextStyle},
+    pixelcolor::{Gray8, GrayColor},
+    prelude::*,
+    primitives::Rectangle,
+    text::{Alignment, Baseline, Text, TextStyleBuilder},
+};
+use log::*;
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+// space between the pages of a spread
+const GUTTER: u32 = 20;
+// a tap this close to the top opens the menu
+const MENU_ZONE: u32 = 60;
+// the folder of the ereader directory holding the dictionaries
+const DICT_DIR: &str = "dict";
+// part of the display a definition covers
+const DEFINITION_PERCENT: u32 = 50;
+// how long a search runs before touches are looked at
+const SEARCH_SLICE: Duration = Duration::from_millis(100);
+
+/// What the reader wants done after an event
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ReaderAction {
+    None,
+    Redraw,
+    /// close the book, back to the library
+    Close,
+    /// turn the display to its next rotation
+    Rotate,
+}
+
+// what the keyboard is typing
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Input {
+    Search,
+    // the note of a highlight, by its index
+    Note(usize),
+}
+
+// what is shown over the pages
+enum Overlay {
+    None,
+    Menu(Menu<ReaderMenuItem>),
+    Toc(Toc, TocView),
+    Keyboard(Keyboard, Input),
+    // a search running, a tap stops it
+    Searching(Searcher, ProgressBar),
+    SearchResults(Vec<SearchHit>, SearchResultsView),
+    // the panel, and the typography when it was opened
+    Typography(TypographyPanel, Typography),
+    Bookmarks(BookmarkListView),
+    Stats(StatsView),
+    // a footnote or the definitions of a word, a tap closes it
+    Popup(Popup),
+    // another device is further on, and where that is here
+    Jump(ConfirmDialog, ContentLoc),
+}
+
+/// The open book
+///
+/// Pages are laid out when they are shown, the page index giving the
+/// page numbers is made in the background. The location is saved on
+/// every page turn, so the book opens where it was left.
+pub struct ReaderScreen {
+    ereader_dir: PathBuf,
+    path: PathBuf,
+    book: Box<dyn BookContent>,
+    size: Size,
+    spread: SpreadLayout,
+    layout: PageLayout,
+    index: Option<PageIndex>,
+    repaginator: Repaginator,
+    // the page starts of one item, while there is no index
+    item_starts: Option<(u32, Vec<ContentLoc>)>,
+    // start of the first page shown
+    loc: ContentLoc,
+    map: PageMap,
+    overlay: Overlay,
+    // the last phrase searched for
+    phrase: String,
+    // the search hit jumped to, boxed on its page
+    hit: Option<SearchHit>,
+    pinch: PinchSteps,
+    bookmarks: BookmarkDb,
+    highlights: HighlightDb,
+    selection: Selection,
+    history: NavHistory,
+    stats: ReadingStats,
+    // opened on the first lookup
+    dictionaries: Option<Dictionaries>,
+    // the content on the pages last drawn
+    shown: PageRange,
+}
+
+impl ReaderScreen {
+    /// open a book where it was left, for a display size
+    pub fn open(
+        ereader_dir: &Path,
+        path: &Path,
+        width: u32,
+        height: u32,
+        two_page_spread: bool,
+    ) -> Result<Self> {
+        let book = open_book(ereader_dir, path)?;
+        let typography = TypographyStore::new(ereader_dir, path).load();
+        let spread = SpreadLayout::new(width, height, two_page_spread, GUTTER);
+        let mut screen = Self {
+            ereader_dir: ereader_dir.to_path_buf(),
+            path: path.to_path_buf(),
+            book,
+            size: Size::new(width, height),
+            spread,
+            layout: PageLayout::new(&typography, spread.page_size()),
+            index: None,
+            repaginator: Repaginator::new(),
+            item_starts: None,
+            loc: ContentLoc::default(),
+            map: PageMap::new(),
+            overlay: Overlay::None,
+            phrase: String::new(),
+            hit: None,
+            pinch: PinchSteps::new(PINCH_STEP),
+            bookmarks: BookmarkDb::open(ereader_dir, path),
+            highlights: HighlightDb::open(ereader_dir, path),
+            selection: Selection::new(),
+            history: NavHistory::open(ereader_dir, path),
+            stats: ReadingStats::open(ereader_dir),
+            dictionaries: None,
+            shown: PageRange::new(ContentLoc::default(), ContentLoc::default()),
+        };
+        screen.repaginate()?;
+        screen.goto(load_location(ereader_dir, path))?;
+        screen.stats.start_session(path, &book_name(path), now());
+        info!("opened {:?} at {:?}", path, screen.loc);
+        Ok(screen)
+    }
+
+    /// the path of the book
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// the start of the first page shown
+    pub fn location(&self) -> ContentLoc {
+        self.loc
+    }
+
+    /// lay the book out for a new display size, after a rotation
+    pub fn resize(&mut self, width: u32, height: u32, two_page_spread: bool) -> Result<()> {
+        self.size = Size::new(width, height);
+        self.spread = SpreadLayout::new(width, height, two_page_spread, GUTTER);
+        self.layout = PageLayout::new(self.layout.typography(), self.spread.page_size());
+        self.overlay = Overlay::None;
+        self.repaginate()?;
+        self.goto(self.loc)
+    }
+
+    /// save where the book was left and the reading time, and stop the
+    /// background layout
+    pub fn close(&mut self) {
+        self.repaginator.cancel();
+        self.save();
+        if let Err(e) = self.stats.end_session(now()) {
+            warn!("can't save the reading stats: {}", e);
+        }
+    }
+
+    fn save(&self) {
+        if let Err(e) = save_location(&self.ereader_dir, &self.path, &self.loc) {
+            warn!("can't save the location of {:?}: {}", self.path, e);
+        }
+    }
+
+    // lay the pages out with other typography, for a preview until the
+    // book is paginated again
+    fn set_typography(&mut self, typography: &Typography) -> Result<()> {
+        self.layout = PageLayout::new(typography, self.spread.page_size());
+        self.repaginator.cancel();
+        self.index = None;
+        self.item_starts = None;
+        self.goto(self.loc)
+    }
+
+    // save the typography for the book or all books, and paginate with it
+    fn save_typography(&mut self, per_book: bool) -> Result<()> {
+        let store = TypographyStore::new(&self.ereader_dir, &self.path);
+        let typography = self.layout.typography();
+        if per_book {
+            store.save_book(typography)?;
+        } else {
+            store.clear_book()?;
+            store.save_global(typography)?;
+        }
+        self.repaginate()
+    }
+
+    // lay the whole book out again in the background
+    fn repaginate(&mut self) -> Result<()> {
+        self.index = None;
+        self.item_starts = None;
+        let ereader_dir = self.ereader_dir.clone();
+        let path = self.path.clone();
+        let size = self.layout.size();
+        self.repaginator.start(
+            self.layout.typography().clone(),
+            move |typography, cancel| {
+                let mut book = open_book(&ereader_dir, &path)?;
+                PageLayout::new(typography, size).paginate(&mut *book, cancel)
+            },
+        )
+    }
+
+    /// is work running that wants polling without waiting for a tick
+    pub fn busy(&self) -> bool {
+        matches!(self.overlay, Overlay::Searching(..))
+    }
+
+    /// take a finished page index and run a search, true to redraw
+    pub fn poll(&mut self) -> Result<bool> {
+        let searched = self.search()?;
+        Ok(self.poll_index()? || searched)
+    }
+
+    // take a finished page index, true when the page numbers changed
+    fn poll_index(&mut self) -> Result<bool> {
+        match self.repaginator.poll() {
+            Some(Ok(index)) => {
+                info!("{} pages in {:?}", index.page_count(), self.path);
+                self.index = Some(index);
+                self.item_starts = None;
+                // spreads start on even pages once they are known
+                self.goto(self.loc)?;
+                Ok(true)
+            }
+            Some(Err(e)) => {
+                warn!("pagination of {:?} failed: {}", self.path, e);
+                Ok(false)
+            }
+            None => Ok(false),
+        }
+    }
+
+    // the page starts of an item
+    fn starts(&mut self, item: u32) -> Result<Vec<ContentLoc>> {
+        if let Some(index) = &self.index {
+            let from = index.starts.partition_point(|s| s.item < item);
+            let to = index.starts.partition_point(|s| s.item <= item);
+            return Ok(index.starts[from..to].to_vec());
+        }
+        if let Some((i, starts)) = &self.item_starts {
+            if *i == item {
+                return Ok(starts.clone());
+            }
+        }
+        let mut starts = Vec::new();
+        self.layout.layout_item(&mut *self.book, item, |page| {
+            starts.push(page.start());
+            true
+        })?;
+        self.item_starts = Some((item, starts.clone()));
+        Ok(starts)
+    }
+
+    // the start of the page after the one starting at `loc`
+    fn following(&mut self, loc: ContentLoc) -> Result<Option<ContentLoc>> {
+        if let Some(next) = self.starts(loc.item)?.into_iter().find(|s| *s > loc) {
+            return Ok(Some(next));
+        }
+        for item in loc.item + 1..self.book.item_count() {
+            if let Some(first) = self.starts(item)?.first() {
+                return Ok(Some(*first));
+            }
+        }
+        Ok(None)
+    }
+
+    // the start of the page before the one starting at `loc`
+    fn preceding(&mut self, loc: ContentLoc) -> Result<Option<ContentLoc>> {
+        if let Some(prev) = self.starts(loc.item)?.into_iter().rev().find(|s| *s < loc) {
+            return Ok(Some(prev));
+        }
+        for item in (0..loc.item.min(self.book.item_count())).rev() {
+            if let Some(last) = self.starts(item)?.last() {
+                return Ok(Some(*last));
+            }
+        }
+        Ok(None)
+    }
+
+    /// show the page holding a location
+    pub fn goto(&mut self, loc: ContentLoc) -> Result<()> {
+        // a location saved before the book changed may be past its end
+        let loc = if loc.item < self.book.item_count() {
+            loc
+        } else {
+            ContentLoc::default()
+        };
+        let start = match self.layout.page_at(&mut *self.book, loc)? {
+            Some(page) => Some(page.start()),
+            // an item with nothing to show, such as a cover that won't decode
+            None => match self.following(ContentLoc::new(loc.item, 0))? {
+                Some(next) => Some(next),
+                None => self.preceding(loc)?,
+            },
+        };
+        let mut start = start.unwrap_or(loc);
+        if let Some(index) = &self.index {
+            if let Some(page) = index.page_of(&start) {
+                let first = self.spread.view_start(page);
+                start = index.page_start(first).unwrap_or(start);
+            }
+        }
+        self.loc = start;
+        self.hit = None;
+        self.save();
+        Ok(())
+    }
+
+    /// how far into the book the page shown is, 0.0 to 1.0, by pages once
+    /// the page index is made and by items before
+    pub fn percentage(&self) -> f64 {
+        if let Some(index) = &self.index {
+            if let Some(page) = index.page_of(&self.loc) {
+                return page as f64 / index.page_count().max(1) as f64;
+            }
+        }
+        self.loc.item as f64 / self.book.item_count().max(1) as f64
+    }
+
+    // the location a percentage of the way into the book
+    fn location_at(&self, percentage: f64) -> ContentLoc {
+        if let Some(index) = &self.index {
+            let page = (percentage * index.page_count() as f64) as u32;
+            let page = page.min(index.page_count().saturating_sub(1));
+            if let Some(loc) = index.page_start(page) {
+                return loc;
+            }
+        }
+        let item = (percentage * self.book.item_count() as f64) as u32;
+        ContentLoc::new(item.min(self.book.item_count().saturating_sub(1)), 0)
+    }
+
+    /// ask to jump to where another device is in the book
+    pub fn offer_jump(&mut self, remote: &Progress) {
+        let target = remote_location(remote, |p| self.location_at(p));
+        let dialog = jump_prompt(remote, self.size.width, self.size.height);
+        self.overlay = Overlay::Jump(dialog, target);
+    }
+
+    /// show a 1 based page, once the page index is made
+    pub fn goto_page(&mut self, page: u32) -> Result<()> {
+        let index = self
+            .index
+            .as_ref()
+            .ok_or_else(|| anyhow!("the pages are still being counted"))?;
+        let loc = index
+            .page_start(page.saturating_sub(1))
+            .ok_or_else(|| anyhow!("the book has {} pages", index.page_count()))?;
+        self.overlay = Overlay::None;
+        self.goto(loc)
+    }
+
+    // the starts of the pages shown
+    fn view(&mut self) -> Result<Vec<ContentLoc>> {
+        let mut starts = vec![self.loc];
+        while starts.len() < self.spread.pages_per_view() as usize {
+            match self.following(*starts.last().unwrap())? {
+                Some(next) => starts.push(next),
+                None => break,
+            }
+        }
+        Ok(starts)
+    }
+
+    // turn to the next or previous view, false at the ends of the book
+    fn turn(&mut self, forward: bool) -> Result<bool> {
+        let mut loc = self.loc;
+        for _ in 0..self.spread.pages_per_view() {
+            let next = if forward {
+                self.following(loc)?
+            } else {
+                self.preceding(loc)?
+            };
+            match next {
+                Some(next) => loc = next,
+                // there is no next view without all its pages before it
+                None if forward => return Ok(false),
+                None => break,
+            }
+        }
+        if loc == self.loc {
+            return Ok(false);
+        }
+        self.loc = loc;
+        self.hit = None;
+        self.save();
+        self.stats.page_turn(now(), self.spread.pages_per_view());
+        Ok(true)
+    }
+
+    /// draw the pages shown, and what is over them
+    pub fn draw(&mut self, canvas: &mut Canvas) -> Result<()> {
+        match &self.overlay {
+            Overlay::Toc(toc, view) => return Ok(view.draw(canvas, toc)?),
+            Overlay::Stats(view) => return Ok(view.draw(canvas)?),
+            Overlay::Bookmarks(view) => {
+                return Ok(view.draw(canvas, &self.bookmarks, &self.shown)?);
+            }
+            Overlay::SearchResults(hits, view) => {
+                let index = &self.index;
+                let page_of = |loc: &ContentLoc| index.as_ref().and_then(|i| i.page_of(loc));
+                return Ok(view.draw(canvas, hits, page_of)?);
+            }
+            _ => (),
+        }
+        let mut pages = Vec::new();
+        for start in self.view()? {
+            if let Some(page) = self.layout.page_at(&mut *self.book, start)? {
+                pages.push(page);
+            }
+        }
+        let end = pages.last().map(|p| p.end()).unwrap_or(self.loc);
+        self.shown = PageRange::new(self.loc, end);
+        let footers: Vec<String> = pages.iter().map(|p| self.footer(p)).collect();
+        let areas = self.spread.page_areas();
+        let map = &mut self.map;
+        map.clear();
+        let book = &mut *self.book;
+        self.spread
+            .draw(canvas, 0, pages.len() as u32, |target, i| {
+                let i = i as usize;
+                pages[i].draw(target, areas[i].top_left, map)?;
+                for (path, area) in pages[i].images() {
+                    draw_picture(target, book, path, &area)?;
+                }
+                draw_footer(target, &footers[i])
+            })?;
+        draw_highlights(canvas, &self.map, &self.highlights, &self.shown)?;
+        if let Some(hit) = &self.hit {
+            draw_match(canvas, &self.map, hit)?;
+        }
+        if self.bookmarks.on_page(&self.shown).is_some() {
+            draw_dog_ear(canvas, self.size.width)?;
+        }
+        match &mut self.overlay {
+            Overlay::Menu(menu) => menu.draw(canvas)?,
+            Overlay::Keyboard(keyboard, _) => keyboard.draw(canvas)?,
+            Overlay::Searching(_, progress) => progress.draw(canvas)?,
+            Overlay::Typography(panel, _) => panel.draw(canvas, self.layout.typography())?,
+            Overlay::Popup(popup) => popup.draw(canvas)?,
+            Overlay::Jump(dialog, _) => dialog.draw(canvas)?,
+            _ => (),
+        }
+        Ok(())
+    }
+
+    // the page number under a page, once the index is made
+    fn footer(&self, page: &Page) -> String {
+        match &self.index {
+            Some(index) => match index.page_of(&page.start()) {
+                Some(n) => format!("{} / {}", n + 1, index.page_count()),
+                None => String::new(),
+            },
+            None => String::new(),
+        }
+    }
+
+    /// handle a touch event
+    pub fn touch(&mut self, evt: &TouchEvent) -> Result<ReaderAction> {
+        match &mut self.overlay {
+            Overlay::Menu(menu) => match menu.touch(evt) {
+                MenuAction::None => Ok(ReaderAction::None),
+                MenuAction::Select(item) => self.menu_item(item),
+                MenuAction::Close => {
+                    self.overlay = Overlay::None;
+                    Ok(ReaderAction::Redraw)
+                }
+            },
+            Overlay::Toc(toc, view) => match view.touch(evt, toc) {
+                TocAction::None => Ok(ReaderAction::None),
+                TocAction::Redraw => Ok(ReaderAction::Redraw),
+                TocAction::Jump(loc) => {
+                    self.overlay = Overlay::None;
+                    self.goto(loc)?;
+                    Ok(ReaderAction::Redraw)
+                }
+                TocAction::Close => {
+                    self.overlay = Overlay::None;
+                    Ok(ReaderAction::Redraw)
+                }
+            },
+            Overlay::Keyboard(keyboard, input) => match keyboard.touch(evt) {
+                KeyboardAction::None => Ok(ReaderAction::None),
+                KeyboardAction::Redraw => Ok(ReaderAction::Redraw),
+                KeyboardAction::Done(text) => {
+                    let input = *input;
+                    self.overlay = Overlay::None;
+                    self.input(input, &text)
+                }
+                KeyboardAction::Cancel => {
+                    self.overlay = Overlay::None;
+                    Ok(ReaderAction::Redraw)
+                }
+            },
+            Overlay::Searching(searcher, _) => {
+                if searcher.touch(evt) {
+                    self.finish_search();
+                    return Ok(ReaderAction::Redraw);
+                }
+                Ok(ReaderAction::None)
+            }
+            Overlay::SearchResults(hits, view) => match view.touch(evt, hits) {
+                ListAction::None => Ok(ReaderAction::None),
+                ListAction::Redraw => Ok(ReaderAction::Redraw),
+                ListAction::Select(i) => {
+                    let hit = hits[i].clone();
+                    self.overlay = Overlay::None;
+                    self.goto(hit.start)?;
+                    self.hit = Some(hit);
+                    Ok(ReaderAction::Redraw)
+                }
+                ListAction::Close => {
+                    self.overlay = Overlay::None;
+                    Ok(ReaderAction::Redraw)
+                }
+            },
+            Overlay::Typography(panel, original) => {
+                let mut typography = self.layout.typography().clone();
+                match panel.touch(evt, &mut typography) {
+                    TypographyAction::None => Ok(ReaderAction::None),
+                    TypographyAction::Changed => {
+                        self.set_typography(&typography)?;
+                        Ok(ReaderAction::Redraw)
+                    }
+                    TypographyAction::Redraw => Ok(ReaderAction::Redraw),
+                    TypographyAction::Close => {
+                        let per_book = panel.per_book;
+                        let store = TypographyStore::new(&self.ereader_dir, &self.path);
+                        let changed =
+                            *original != typography || per_book != store.has_book_settings();
+                        self.overlay = Overlay::None;
+                        if changed {
+                            self.save_typography(per_book)?;
+                        }
+                        Ok(ReaderAction::Redraw)
+                    }
+                }
+            }
+            Overlay::Popup(popup) => match popup.touch(evt) {
+                PopupAction::None => Ok(ReaderAction::None),
+                PopupAction::Redraw => Ok(ReaderAction::Redraw),
+                PopupAction::Close => {
+                    self.overlay = Overlay::None;
+                    Ok(ReaderAction::Redraw)
+                }
+            },
+            Overlay::Bookmarks(view) => match view.touch(evt, &self.bookmarks) {
+                ListAction::None => Ok(ReaderAction::None),
+                ListAction::Redraw => Ok(ReaderAction::Redraw),
+                ListAction::Select(i) => {
+                    let loc = self.bookmarks.bookmarks()[i].loc;
+                    self.overlay = Overlay::None;
+                    self.goto(loc)?;
+                    Ok(ReaderAction::Redraw)
+                }
+                ListAction::Close => {
+                    self.overlay = Overlay::None;
+                    Ok(ReaderAction::Redraw)
+                }
+            },
+            Overlay::Jump(dialog, target) => match dialog.touch(evt) {
+                ConfirmAction::None => Ok(ReaderAction::None),
+                ConfirmAction::Yes => {
+                    let target = *target;
+                    self.overlay = Overlay::None;
+                    self.goto(target)?;
+                    Ok(ReaderAction::Redraw)
+                }
+                ConfirmAction::No => {
+                    self.overlay = Overlay::None;
+                    Ok(ReaderAction::Redraw)
+                }
+            },
+            Overlay::Stats(view) => match view.touch(evt) {
+                ListAction::Close => {
+                    self.overlay = Overlay::None;
+                    Ok(ReaderAction::Redraw)
+                }
+                ListAction::Redraw => Ok(ReaderAction::Redraw),
+                _ => Ok(ReaderAction::None),
+            },
+            Overlay::None => self.page_touch(evt),
+        }
+    }
+
+    // back to where a link was followed from
+    fn back(&mut self) -> Result<ReaderAction> {
+        match self.history.pop()? {
+            Some(loc) => {
+                self.goto(loc)?;
+                Ok(ReaderAction::Redraw)
+            }
+            None => Ok(ReaderAction::None),
+        }
+    }
+
+    // add a bookmark to the pages shown, or remove theirs
+    fn toggle_bookmark(&mut self) -> Result<()> {
+        let mut name = self
+            .book
+            .item_title(self.loc.item)
+            .unwrap_or_else(|| "Bookmark".to_string());
+        if let Some(page) = self.index.as_ref().and_then(|i| i.page_of(&self.loc)) {
+            name = format!("{}, p. {}", name, page + 1);
+        }
+        if self.bookmarks.toggle(&self.shown, &name, now()) {
+            info!("bookmark '{}' added", name);
+        }
+        self.bookmarks.save()
+    }
+
+    // text typed on the keyboard
+    fn input(&mut self, input: Input, text: &str) -> Result<ReaderAction> {
+        match input {
+            Input::Search => self.start_search(text),
+            Input::Note(index) => {
+                self.highlights.set_note(index, Some(text));
+                self.highlights.save()?;
+            }
+        }
+        Ok(ReaderAction::Redraw)
+    }
+
+    // keep a highlight and ask for its note, a selection starting in a
+    // highlight removes it instead, and one word is looked up
+    fn highlight(&mut self, highlight: Highlight) -> Result<ReaderAction> {
+        if let Some(index) = self.highlights.at(&highlight.start) {
+            self.highlights.remove(index);
+        } else if !highlight.text.contains(' ') {
+            return self.define(&highlight.text);
+        } else {
+            let index = self.highlights.add(highlight);
+            let keyboard = Keyboard::new("Note", self.size.width, self.size.height);
+            self.overlay = Overlay::Keyboard(keyboard, Input::Note(index));
+        }
+        self.highlights.save()?;
+        Ok(ReaderAction::Redraw)
+    }
+
+    // show the definitions of a word
+    fn define(&mut self, word: &str) -> Result<ReaderAction> {
+        let dictionaries = match &self.dictionaries {
+            Some(dictionaries) => dictionaries,
+            None => {
+                let dir = self.ereader_dir.join(DICT_DIR);
+                self.dictionaries.insert(Dictionaries::open_dir(&dir)?)
+            }
+        };
+        let definitions = dictionaries.lookup(word)?;
+        let text = if definitions.is_empty() {
+            format!("No definition of '{}'", word)
+        } else {
+            definitions
+                .iter()
+                .map(|d| format!("{}: {}", d.dictionary, d.text))
+                .collect::<Vec<_>>()
+                .join("\n\n")
+        };
+        let title = definitions.first().map(|d| d.word.as_str()).unwrap_or(word);
+        self.overlay = Overlay::Popup(Popup::new(
+            title,
+            &text,
+            self.size.width,
+            self.size.height,
+            DEFINITION_PERCENT,
+        ));
+        Ok(ReaderAction::Redraw)
+    }
+
+    /// write the reading stats as json and csv, the paths written
+    pub fn export_stats(&self) -> Result<Vec<PathBuf>> {
+        let mut paths = vec![self.stats.export_json(&self.ereader_dir)?];
+        paths.extend(self.stats.export_csv(&self.ereader_dir)?);
+        Ok(paths)
+    }
+
+    // the pages after the view to the end of the chapter and of the book,
+    // none until the index is made
+    fn pages_left(&self) -> (u32, u32) {
+        let Some(index) = &self.index else {
+            return (0, 0);
+        };
+        let Some(page) = index.page_of(&self.loc) else {
+            return (0, 0);
+        };
+        let after = page + self.spread.pages_per_view();
+        let chapter_end = index.starts.partition_point(|s| s.item <= self.loc.item) as u32;
+        (
+            chapter_end.saturating_sub(after),
+            index.page_count().saturating_sub(after),
+        )
+    }
+
+    /// is the last page of the book shown, false until the index is made
+    pub fn finished(&self) -> bool {
+        let Some(index) = &self.index else {
+            return false;
+        };
+        index
+            .page_of(&self.loc)
+            .is_some_and(|page| page + self.spread.pages_per_view() >= index.page_count())
+    }
+
+    /// write the highlights to the exports folder, returns the file written
+    pub fn export_highlights(&self) -> Result<PathBuf> {
+        export_markdown(&self.ereader_dir, &book_name(&self.path), &self.highlights)
+    }
+
+    // start searching the book, it runs as the screen is polled
+    fn start_search(&mut self, phrase: &str) {
+        self.phrase = phrase.trim().to_string();
+        let progress = ProgressBar::new("Searching", self.size.width, self.size.height);
+        self.overlay = Overlay::Searching(Searcher::new(&self.phrase), progress);
+    }
+
+    // search for a while, true to redraw
+    fn search(&mut self) -> Result<bool> {
+        let Overlay::Searching(searcher, progress) = &mut self.overlay else {
+            return Ok(false);
+        };
+        let until = Instant::now() + SEARCH_SLICE;
+        while Instant::now() < until {
+            if !searcher.step(&mut *self.book)? {
+                self.finish_search();
+                return Ok(true);
+            }
+        }
+        let items = self.book.item_count() as u64;
+        Ok(progress.update(searcher.item() as u64, Some(items)))
+    }
+
+    // show the hits of a finished or stopped search
+    fn finish_search(&mut self) {
+        let overlay = std::mem::replace(&mut self.overlay, Overlay::None);
+        if let Overlay::Searching(searcher, _) = overlay {
+            let hits = searcher.hits().to_vec();
+            info!("search for '{}': {} hits", self.phrase, hits.len());
+            let view = SearchResultsView::new(&self.phrase, self.size.width, self.size.height);
+            self.overlay = Overlay::SearchResults(hits, view);
+        }
+    }
+
+    // a touch on the pages
+    fn page_touch(&mut self, evt: &TouchEvent) -> Result<ReaderAction> {
+        // footnotes show over the page, which stays put, other links jump
+        // away, a link in the bookmark corner is tapped, the corner still
+        // bookmarks with a hold
+        let (width, height) = (self.size.width, self.size.height);
+        match tap_link(&mut *self.book, &self.map, evt, width, height) {
+            LinkTap::None => (),
+            LinkTap::Note(popup) => {
+                self.overlay = Overlay::Popup(popup);
+                return Ok(ReaderAction::Redraw);
+            }
+            LinkTap::Follow(href) => {
+                let target = follow_link(&mut *self.book, &mut self.history, &href, self.loc)?;
+                return match target {
+                    Some(target) => {
+                        self.goto(target)?;
+                        Ok(ReaderAction::Redraw)
+                    }
+                    None => Ok(ReaderAction::None),
+                };
+            }
+        }
+        if is_back_gesture(evt) && self.history.can_go_back() {
+            return self.back();
+        }
+        if is_bookmark_gesture(evt, self.size.width) {
+            self.toggle_bookmark()?;
+            return Ok(ReaderAction::Redraw);
+        }
+        // a hold and drag selects words to highlight
+        if let Some(highlight) = self.selection.touch(evt, &self.map, now()) {
+            return self.highlight(highlight);
+        }
+        let forward = match evt.kind() {
+            TouchEventKind::Tap if evt.y() < MENU_ZONE => {
+                let menu = reader_menu(self.size.width, self.history.can_go_back());
+                self.overlay = Overlay::Menu(menu);
+                return Ok(ReaderAction::Redraw);
+            }
+            TouchEventKind::Tap => evt.x() >= self.size.width / 3,
+            TouchEventKind::SwipeLeft => true,
+            TouchEventKind::SwipeRight => false,
+            TouchEventKind::PinchEnlarge | TouchEventKind::PinchReduce => {
+                let mut typography = self.layout.typography().clone();
+                if !typography.pinch(&mut self.pinch, evt) {
+                    return Ok(ReaderAction::None);
+                }
+                self.set_typography(&typography)?;
+                let per_book =
+                    TypographyStore::new(&self.ereader_dir, &self.path).has_book_settings();
+                self.save_typography(per_book)?;
+                return Ok(ReaderAction::Redraw);
+            }
+            TouchEventKind::Release => {
+                self.pinch.steps(evt);
+                return Ok(ReaderAction::None);
+            }
+            TouchEventKind::Hold | TouchEventKind::Drag => return Ok(ReaderAction::None),
+            _ => return Ok(ReaderAction::None),
+        };
+        Ok(if self.turn(forward)? {
+            ReaderAction::Redraw
+        } else {
+            ReaderAction::None
+        })
+    }
+
+    fn menu_item(&mut self, item: ReaderMenuItem) -> Result<ReaderAction> {
+        self.overlay = Overlay::None;
+        match item {
+            ReaderMenuItem::Contents => {
+                let toc = Toc::new(self.book.toc()?);
+                let view = TocView::new(&toc, &self.loc, self.size.width, self.size.height);
+                self.overlay = Overlay::Toc(toc, view);
+            }
+            ReaderMenuItem::Search => {
+                let keyboard = Keyboard::new("Search", self.size.width, self.size.height)
+                    .with_text(&self.phrase);
+                self.overlay = Overlay::Keyboard(keyboard, Input::Search);
+            }
+            ReaderMenuItem::Typography => {
+                let per_book =
+                    TypographyStore::new(&self.ereader_dir, &self.path).has_book_settings();
+                let panel =
+                    TypographyPanel::new(&BitmapFonts, per_book, self.size.width, self.size.height);
+                self.overlay = Overlay::Typography(panel, self.layout.typography().clone());
+            }
+            ReaderMenuItem::Bookmarks => {
+                let view = BookmarkListView::new(self.size.width, self.size.height);
+                self.overlay = Overlay::Bookmarks(view);
+            }
+            ReaderMenuItem::Stats => {
+                let view = StatsView::new(
+                    self.size.width,
+                    self.size.height,
+                    &self.stats,
+                    &self.path,
+                    self.pages_left(),
+                    now(),
+                );
+                self.overlay = Overlay::Stats(view);
+            }
+            ReaderMenuItem::Rotate => return Ok(ReaderAction::Rotate),
+            ReaderMenuItem::Back => return self.back(),
+            ReaderMenuItem::Library => return Ok(ReaderAction::Close),
+        }
+        Ok(ReaderAction::Redraw)
+    }
+}
+
+// the unix time now, the system clock is set from the rtc at start
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+// the page number, centered in the footer of a page
+// draw an image of the book in its place on a page, one that can't be
+// decoded is left out
+fn draw_picture<D>(
+    target: &mut D,
+    book: &mut dyn BookContent,
+    path: &str,
+    area: &Rectangle,
+) -> Result<(), D::Error>
+where
+    D: DrawTarget,
+    D::Color: GrayColor + From<Gray8>,
+{
+    let image = book
+        .read_resource(path)
+        .and_then(|bytes| decode_image_bytes(&bytes, area.size.width, area.size.height));
+    match image {
+        // book pictures are mostly photos and engravings
+        Ok(image) => draw_image(target, image, area, Dither::ErrorDiffusion, false),
+        Err(e) => {
+            warn!("can't show the image {}: {}", path, e);
+            Ok(())
+        }
+    }
+}
+
+fn draw_footer<D>(target: &mut D, text: &str) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = Gray8>,
+{
+    let area = target.bounding_box();
+    let position = Point::new(
+        (area.size.width / 2) as i32,
+        area.size.height as i32 - (FOOTER_HEIGHT / 2) as i32,
+    );
+    let centered = TextStyleBuilder::new()
+        .alignment(Alignment::Center)
+        .baseline(Baseline::Middle)
+        .build();
+    let style = MonoTextStyle::new(&FONT_7X13, Gray8::BLACK);
+    Text::with_text_style(text, position, style, centered).draw(target)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{epub, temp_dir};
+    use std::{thread, time::Duration};
+
+    fn tap(x: u32, y: u32) -> TouchEvent {
+        TouchEvent::with_position(TouchEventKind::Tap, x, y)
+    }
+
+    // wait for the page index
+    fn wait_for_index(screen: &mut ReaderScreen) {
+        for _ in 0..500 {
+            if screen.poll().unwrap() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("no page index");
+    }
+
+    fn chapters() -> Vec<(String, String)> {
+        (1..=3)
+            .map(|c| {
+                let text: Vec<String> = (0..200).map(|w| format!("c{}w{}", c, w)).collect();
+                (
+                    format!("Chapter {}", c),
+                    format!("<html><body><p>{}</p></body></html>", text.join(" ")),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn pages_turn_and_the_place_is_kept() {
+        let dir = temp_dir("reader-screen");
+        let path = dir.join("book.epub");
+        epub(&path, &chapters());
+        let ereader_dir = dir.join("ereader");
+        let mut canvas = Canvas::new(300, 400);
+        let mut screen = ReaderScreen::open(&ereader_dir, &path, 300, 400, false).unwrap();
+        assert_eq!(screen.location(), ContentLoc::new(0, 15));
+        screen.draw(&mut canvas).unwrap();
+        assert_eq!(screen.map.words()[0].text, "c1w0");
+
+        // forward across the end of the first chapter and back
+        let mut turns = 0;
+        while screen.location().item == 0 {
+            assert_eq!(screen.touch(&tap(250, 200)).unwrap(), ReaderAction::Redraw);
+            turns += 1;
+        }
+        assert_eq!(screen.location(), ContentLoc::new(1, 15));
+        assert_eq!(
+            screen
+                .touch(&TouchEvent::new(TouchEventKind::SwipeRight))
+                .unwrap(),
+            ReaderAction::Redraw
+        );
+        assert_eq!(screen.location().item, 0);
+        // going back from the start does nothing
+        screen.goto(ContentLoc::new(0, 0)).unwrap();
+        assert_eq!(screen.touch(&tap(10, 200)).unwrap(), ReaderAction::None);
+
+        // the page numbers come with the index, and agree with the turns
+        wait_for_index(&mut screen);
+        let index = screen.index.clone().unwrap();
+        assert_eq!(index.page_of(&ContentLoc::new(1, 15)), Some(turns));
+        screen.goto(ContentLoc::new(1, 15)).unwrap();
+        let loc = screen.loc;
+        let page = screen.layout.page_at(&mut *screen.book, loc).unwrap();
+        assert_eq!(
+            screen.footer(&page.unwrap()),
+            format!("{} / {}", turns + 1, index.page_count())
+        );
+
+        // the place is kept for the next time
+        screen.close();
+        let screen = ReaderScreen::open(&ereader_dir, &path, 300, 400, false).unwrap();
+        assert_eq!(screen.location(), ContentLoc::new(1, 15));
+    }
+
+    #[test]
+    fn contents_jump_to_a_chapter() {
+        let dir = temp_dir("reader-toc");
+        let path = dir.join("book.epub");
+        epub(&path, &chapters());
+        let mut canvas = Canvas::new(300, 400);
+        let mut screen = ReaderScreen::open(&dir.join("ereader"), &path, 300, 400, false).unwrap();
+        // the top opens the menu, the first button is Contents
+        assert_eq!(screen.touch(&tap(150, 10)).unwrap(), ReaderAction::Redraw);
+        assert_eq!(screen.touch(&tap(5, 10)).unwrap(), ReaderAction::Redraw);
+        screen.draw(&mut canvas).unwrap();
+        assert!(matches!(screen.overlay, Overlay::Toc(..)));
+        // rows are 40 high under the title bar, the third is chapter 3
+        assert_eq!(screen.touch(&tap(50, 140)).unwrap(), ReaderAction::Redraw);
+        assert_eq!(screen.location(), ContentLoc::new(2, 15));
+        screen.draw(&mut canvas).unwrap();
+        assert_eq!(screen.map.words()[0].text, "c3w0");
+
+        // the last button closes the book
+        screen.touch(&tap(150, 10)).unwrap();
+        assert_eq!(screen.touch(&tap(290, 10)).unwrap(), ReaderAction::Close);
+    }
+
+    #[test]
+    fn search_jumps_to_a_hit() {
+        let dir = temp_dir("reader-search");
+        let path = dir.join("book.epub");
+        epub(&path, &chapters());
+        let mut canvas = Canvas::new(300, 400);
+        let mut screen = ReaderScreen::open(&dir.join("ereader"), &path, 300, 400, false).unwrap();
+        // the third menu button asks for the phrase
+        screen.touch(&tap(150, 10)).unwrap();
+        screen.touch(&tap(100, 10)).unwrap();
+        assert!(matches!(
+            screen.overlay,
+            Overlay::Keyboard(_, Input::Search)
+        ));
+        screen.input(Input::Search, "C2W150").unwrap();
+        assert!(screen.busy());
+        screen.draw(&mut canvas).unwrap();
+        while screen.busy() {
+            screen.poll().unwrap();
+        }
+        screen.draw(&mut canvas).unwrap();
+        let Overlay::SearchResults(hits, _) = &screen.overlay else {
+            panic!("no results");
+        };
+        assert_eq!(hits.len(), 1);
+
+        // the hit is boxed on its page until the page turns
+        screen.touch(&tap(50, 60)).unwrap();
+        assert_eq!(screen.location().item, 1);
+        screen.draw(&mut canvas).unwrap();
+        let hit = screen.hit.clone().unwrap();
+        assert!(screen.map.words_overlapping(hit.start, hit.end).count() == 1);
+        screen.touch(&tap(250, 200)).unwrap();
+        assert!(screen.hit.is_none());
+
+        // a tap stops a search, the hits so far are shown
+        screen.start_search("w1");
+        screen.touch(&tap(150, 200)).unwrap();
+        assert!(matches!(screen.overlay, Overlay::SearchResults(..)));
+    }
+
+    #[test]
+    fn typography_is_previewed_then_saved() {
+        let dir = temp_dir("reader-typography");
+        let path = dir.join("book.epub");
+        epub(&path, &chapters());
+        let ereader_dir = dir.join("ereader");
+        let mut canvas = Canvas::new(300, 600);
+        let mut screen = ReaderScreen::open(&ereader_dir, &path, 300, 600, false).unwrap();
+        wait_for_index(&mut screen);
+        let pages = screen.index.as_ref().unwrap().page_count();
+
+        // the fourth menu button opens the panel, the second row is the size
+        screen.touch(&tap(150, 10)).unwrap();
+        screen.touch(&tap(140, 10)).unwrap();
+        assert!(matches!(screen.overlay, Overlay::Typography(..)));
+        for _ in 0..4 {
+            assert_eq!(screen.touch(&tap(250, 260)).unwrap(), ReaderAction::Redraw);
+        }
+        assert_eq!(screen.layout.typography().font_size, 16);
+        assert!(screen.index.is_none());
+        screen.draw(&mut canvas).unwrap();
+        // the last row saves for this book only, a tap outside closes
+        screen.touch(&tap(250, 560)).unwrap();
+        screen.touch(&tap(150, 100)).unwrap();
+        let store = TypographyStore::new(&ereader_dir, &path);
+        assert!(store.has_book_settings());
+        assert_eq!(store.load().font_size, 16);
+        wait_for_index(&mut screen);
+        assert!(screen.index.as_ref().unwrap().page_count() > pages);
+
+        // a pinch makes the text smaller again
+        let reduce = TouchEvent::pinch(TouchEventKind::PinchReduce, PINCH_STEP * 2.0);
+        assert_eq!(screen.touch(&reduce).unwrap(), ReaderAction::Redraw);
+        assert_eq!(store.load().font_size, 14);
+    }
+
+    #[test]
+    fn bookmarks_from_the_corner() {
+        let dir = temp_dir("reader-bookmarks");
+        let path = dir.join("book.epub");
+        epub(&path, &chapters());
+        let mut canvas = Canvas::new(300, 400);
+        let mut screen = ReaderScreen::open(&dir.join("ereader"), &path, 300, 400, false).unwrap();
+        screen.goto(ContentLoc::new(1, 15)).unwrap();
+        screen.draw(&mut canvas).unwrap();
+        // a tap in the top right corner adds a bookmark, shown as a dog-ear
+        assert_eq!(screen.touch(&tap(290, 10)).unwrap(), ReaderAction::Redraw);
+        screen.draw(&mut canvas).unwrap();
+        assert_eq!(canvas.pixel(Point::new(298, 1)), Gray8::BLACK);
+        assert_eq!(screen.bookmarks.bookmarks()[0].name, "Chapter 2");
+        assert_eq!(screen.bookmarks.bookmarks()[0].loc, ContentLoc::new(1, 15));
+
+        // the list jumps back to it
+        screen.goto(ContentLoc::new(0, 0)).unwrap();
+        screen.draw(&mut canvas).unwrap();
+        assert_ne!(canvas.pixel(Point::new(298, 1)), Gray8::BLACK);
+        screen.touch(&tap(150, 10)).unwrap();
+        screen.touch(&tap(60, 10)).unwrap();
+        assert!(matches!(screen.overlay, Overlay::Bookmarks(_)));
+        screen.draw(&mut canvas).unwrap();
+        screen.touch(&tap(50, 60)).unwrap();
+        assert_eq!(screen.location(), ContentLoc::new(1, 15));
+
+        // they are kept with the book, a second tap removes it
+        let bookmarks = BookmarkDb::open(&dir.join("ereader"), &path);
+        assert_eq!(bookmarks.bookmarks().len(), 1);
+        screen.draw(&mut canvas).unwrap();
+        screen.touch(&tap(290, 10)).unwrap();
+        assert!(screen.bookmarks.bookmarks().is_empty());
+    }
+
+    #[test]
+    fn highlights_with_notes_are_exported() {
+        let dir = temp_dir("reader-highlights");
+        let path = dir.join("book.epub");
+        epub(&path, &chapters());
+        let ereader_dir = dir.join("ereader");
+        let mut canvas = Canvas::new(300, 400);
+        let mut screen = ReaderScreen::open(&ereader_dir, &path, 300, 400, false).unwrap();
+        screen.draw(&mut canvas).unwrap();
+        let at = |kind, screen: &ReaderScreen, i: usize| {
+            let center = screen.map.words()[i].rect.center();
+            TouchEvent::with_position(kind, center.x as u32, center.y as u32)
+        };
+
+        // hold on a word, drag to another, the release asks for a note,
+        // below the top where taps open the menu and bookmark
+        let k = screen
+            .map
+            .words()
+            .iter()
+            .position(|w| w.rect.top_left.y >= 60)
+            .unwrap();
+        screen.touch(&at(TouchEventKind::Hold, &screen, k)).unwrap();
+        screen
+            .touch(&at(TouchEventKind::Drag, &screen, k + 2))
+            .unwrap();
+        let release = at(TouchEventKind::Release, &screen, k + 2);
+        assert_eq!(screen.touch(&release).unwrap(), ReaderAction::Redraw);
+        assert!(matches!(
+            screen.overlay,
+            Overlay::Keyboard(_, Input::Note(0))
+        ));
+        screen.overlay = Overlay::None;
+        screen.input(Input::Note(0), "three words").unwrap();
+        screen.draw(&mut canvas).unwrap();
+        let bar = screen.map.words()[k + 1].rect;
+        let under = Point::new(bar.center().x, bar.top_left.y + bar.size.height as i32);
+        assert_eq!(canvas.pixel(under), Gray8::BLACK);
+
+        let db = HighlightDb::open(&ereader_dir, &path);
+        let words: Vec<String> = (k..k + 3).map(|w| format!("c1w{}", w)).collect();
+        assert_eq!(db.highlights()[0].text, words.join(" "));
+        let export = std::fs::read_to_string(screen.export_highlights().unwrap()).unwrap();
+        assert!(export.starts_with("# book\n"));
+        assert!(export.contains("**Note:** three words"));
+
+        // selecting in a highlight removes it
+        screen
+            .touch(&at(TouchEventKind::Hold, &screen, k + 1))
+            .unwrap();
+        screen
+            .touch(&at(TouchEventKind::Release, &screen, k + 1))
+            .unwrap();
+        assert!(screen.highlights.highlights().is_empty());
+    }
+
+    #[test]
+    fn holding_a_word_defines_it() {
+        let dir = temp_dir("reader-define");
+        let path = dir.join("book.epub");
+        epub(&path, &chapters());
+        let ereader_dir = dir.join("ereader");
+        let mut canvas = Canvas::new(300, 400);
+        let mut screen = ReaderScreen::open(&ereader_dir, &path, 300, 400, false).unwrap();
+        screen.draw(&mut canvas).unwrap();
+        let k = screen
+            .map
+            .words()
+            .iter()
+            .position(|w| w.rect.top_left.y >= 60)
+            .unwrap();
+        let word = format!("c1w{}", k);
+
+        // a dictionary with the word, an index entry is the word, offset and size
+        let dict_dir = ereader_dir.join(DICT_DIR);
+        std::fs::create_dir_all(&dict_dir).unwrap();
+        std::fs::write(
+            dict_dir.join("test.ifo"),
+            "StarDict's dict ifo file\nversion=2.4.2\nbookname=Test\nsametypesequence=m\n",
+        )
+        .unwrap();
+        let mut idx = word.as_bytes().to_vec();
+        idx.push(0);
+        idx.extend_from_slice(&0u32.to_be_bytes());
+        idx.extend_from_slice(&6u32.to_be_bytes());
+        std::fs::write(dict_dir.join("test.idx"), idx).unwrap();
+        std::fs::write(dict_dir.join("test.dict"), "a word").unwrap();
+
+        let center = screen.map.words()[k].rect.center();
+        let (x, y) = (center.x as u32, center.y as u32);
+        screen
+            .touch(&TouchEvent::with_position(TouchEventKind::Hold, x, y))
+            .unwrap();
+        screen
+            .touch(&TouchEvent::with_position(TouchEventKind::Release, x, y))
+            .unwrap();
+        assert!(matches!(screen.overlay, Overlay::Popup(_)));
+        assert!(screen.highlights.highlights().is_empty());
+        screen.draw(&mut canvas).unwrap();
+        assert_eq!(screen.touch(&tap(150, 10)).unwrap(), ReaderAction::Redraw);
+        assert!(matches!(screen.overlay, Overlay::None));
+    }
+
+    #[test]
+    fn footnotes_show_over_the_page() {
+        let dir = temp_dir("reader-footnote");
+        let path = dir.join("book.epub");
+        let mut book = chapters();
+        book[0].1 = "<html><body><p>see the note<a epub:type=\"noteref\" \
+                     href=\"c2.xhtml#n1\">1</a> here</p></body></html>"
+            .to_string();
+        book[2].1 = "<html><body><p id=\"n1\">1. the note text</p></body></html>".to_string();
+        epub(&path, &book);
+        let mut canvas = Canvas::new(300, 400);
+        let mut screen = ReaderScreen::open(&dir.join("ereader"), &path, 300, 400, false).unwrap();
+        screen.draw(&mut canvas).unwrap();
+        let link = screen.map.links()[0].rect.center();
+        assert_eq!(
+            screen.touch(&tap(link.x as u32, link.y as u32)).unwrap(),
+            ReaderAction::Redraw
+        );
+        let Overlay::Popup(popup) = &screen.overlay else {
+            panic!("no footnote");
+        };
+        assert!(format!("{:?}", popup).contains("the note text"));
+        assert_eq!(screen.location(), ContentLoc::new(0, 15));
+        screen.draw(&mut canvas).unwrap();
+        screen.touch(&tap(150, 10)).unwrap();
+        assert!(matches!(screen.overlay, Overlay::None));
+    }
+
+    #[test]
+    fn links_jump_and_back_returns() {
+        let dir = temp_dir("reader-links");
+        let path = dir.join("book.epub");
+        let ereader_dir = dir.join("ereader");
+        let mut book = chapters();
+        book[0].1 = "<html><body><p>see <a href=\"c2.xhtml#end\">the end</a> \
+                     of it</p></body></html>"
+            .to_string();
+        book[2].1 = "<html><body><p>x</p><p id=\"end\">the end</p></body></html>".to_string();
+        epub(&path, &book);
+        let mut canvas = Canvas::new(300, 400);
+        let mut screen = ReaderScreen::open(&ereader_dir, &path, 300, 400, false).unwrap();
+        screen.draw(&mut canvas).unwrap();
+        let link = screen.map.links()[0].rect.center();
+        assert_eq!(
+            screen.touch(&tap(link.x as u32, link.y as u32)).unwrap(),
+            ReaderAction::Redraw
+        );
+        assert_eq!(screen.location().item, 2);
+
+        // the history survives closing the book, the menu offers Back first
+        screen.close();
+        let mut screen = ReaderScreen::open(&ereader_dir, &path, 300, 400, false).unwrap();
+        screen.draw(&mut canvas).unwrap();
+        screen.touch(&tap(150, 10)).unwrap();
+        assert_eq!(screen.touch(&tap(5, 10)).unwrap(), ReaderAction::Redraw);
+        assert_eq!(screen.location(), ContentLoc::new(0, 15));
+
+        // the top left corner goes back too
+        screen.draw(&mut canvas).unwrap();
+        screen.touch(&tap(link.x as u32, link.y as u32)).unwrap();
+        screen.draw(&mut canvas).unwrap();
+        assert_eq!(screen.touch(&tap(10, 10)).unwrap(), ReaderAction::Redraw);
+        assert_eq!(screen.location(), ContentLoc::new(0, 15));
+        assert!(!screen.history.can_go_back());
+    }
+
+    #[test]
+    fn reading_time_is_kept_and_shown() {
+        let dir = temp_dir("reader-stats");
+        let path = dir.join("book.epub");
+        let ereader_dir = dir.join("ereader");
+        epub(&path, &chapters());
+        let mut canvas = Canvas::new(300, 400);
+        let mut screen = ReaderScreen::open(&ereader_dir, &path, 300, 400, false).unwrap();
+        screen.draw(&mut canvas).unwrap();
+        screen.touch(&tap(250, 200)).unwrap();
+        screen.touch(&tap(250, 200)).unwrap();
+        screen.close();
+
+        let stats = ReadingStats::open(&ereader_dir);
+        let book = stats.book(&path).unwrap();
+        assert_eq!(book.pages, 2);
+        assert_eq!(book.sessions, 1);
+        assert_eq!(book.title, "book");
+
+        // the menu shows them, the title bar closes them
+        let mut screen = ReaderScreen::open(&ereader_dir, &path, 300, 400, false).unwrap();
+        wait_for_index(&mut screen);
+        screen.draw(&mut canvas).unwrap();
+        screen.touch(&tap(150, 10)).unwrap();
+        screen.touch(&tap(5 * 42 + 5, 10)).unwrap();
+        assert!(matches!(screen.overlay, Overlay::Stats(_)));
+        let (chapter, book) = screen.pages_left();
+        assert!(chapter > 0 && book > chapter);
+        screen.draw(&mut canvas).unwrap();
+        assert_eq!(screen.touch(&tap(150, 10)).unwrap(), ReaderAction::Redraw);
+        assert!(matches!(screen.overlay, Overlay::None));
+
+        let paths = screen.export_stats().unwrap();
+        assert_eq!(paths.len(), 3);
+        assert!(std::fs::read_to_string(&paths[1])
+            .unwrap()
+            .contains("book,"));
+    }
+
+    #[test]
+    fn goto_page_once_the_pages_are_counted() {
+        let dir = temp_dir("reader-goto-page");
+        let path = dir.join("book.epub");
+        let ereader_dir = dir.join("ereader");
+        epub(&path, &chapters());
+        let mut screen = ReaderScreen::open(&ereader_dir, &path, 300, 400, false).unwrap();
+        if screen.index.is_none() {
+            assert!(screen.goto_page(2).is_err());
+        }
+        wait_for_index(&mut screen);
+        let index = screen.index.as_ref().unwrap();
+        let (count, third) = (index.page_count(), index.page_start(2).unwrap());
+        screen.goto_page(3).unwrap();
+        assert_eq!(screen.location(), third);
+        assert_eq!(load_location(&ereader_dir, &path), third);
+        assert!(screen.goto_page(count + 1).is_err());
+    }
+
+    #[test]
+    fn spreads_start_on_even_pages() {
+        let dir = temp_dir("reader-spread");
+        let path = dir.join("book.epub");
+        epub(&path, &chapters());
+        let mut canvas = Canvas::new(800, 300);
+        let mut screen = ReaderScreen::open(&dir.join("ereader"), &path, 800, 300, true).unwrap();
+        wait_for_index(&mut screen);
+        let index = screen.index.clone().unwrap();
+        screen.goto(index.page_start(3).unwrap()).unwrap();
+        assert_eq!(screen.location(), index.page_start(2).unwrap());
+        screen.draw(&mut canvas).unwrap();
+        screen.touch(&tap(700, 150)).unwrap();
+        assert_eq!(screen.location(), index.page_start(4).unwrap());
+    }
+}
@@ -0,0 +1,482 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::net::http::is_web_url;
+use anyhow::{anyhow, Result};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// version of the settings schema, bump it and add a migration when it changes
+pub const SETTINGS_VERSION: u32 = 1;
+
+/// Touch gesture tuning
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TouchSettings {
+    /// distance in pixels where a tap or hold becomes a swipe
+    pub distance_threshold: f32,
+    /// distance in pixels a held finger moves to send a drag
+    pub drag_threshold: f32,
+    /// time in ms a finger is down before a tap becomes a hold
+    pub hold_ms: u32,
+    /// time in ms without movement before a hold is released
+    pub hold_release_ms: u32,
+    /// time in ms to wait for touches when idle
+    pub idle_ms: u32,
+}
+
+impl Default for TouchSettings {
+    fn default() -> Self {
+        Self {
+            distance_threshold: 30.0,
+            drag_threshold: 5.0,
+            hold_ms: 500,
+            hold_release_ms: 1000,
+            idle_ms: 100_000,
+        }
+    }
+}
+
+/// Display settings
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DisplaySettings {
+    /// rotation in degrees, 0, 90, 180 or 270
+    pub rotation: u16,
+    /// show two facing pages in landscape
+    pub two_page_spread: bool,
+}
+
+impl Default for DisplaySettings {
+    fn default() -> Self {
+        Self {
+            rotation: 270,
+            // the pages of a spread are small on a 6 inch display, so it is opt in
+            two_page_spread: false,
+        }
+    }
+}
+
+impl DisplaySettings {
+    /// is the display in landscape, the panel is natively landscape
+    pub fn landscape(&self) -> bool {
+        self.rotation == 0 || self.rotation == 180
+    }
+
+    /// step to the next rotation, portrait, landscape, then their flipped variants
+    pub fn next_rotation(&mut self) -> u16 {
+        self.rotation = match self.rotation {
+            270 => 0,
+            0 => 90,
+            90 => 180,
+            _ => 270,
+        };
+        self.rotation
+    }
+}
+
+/// Log levels
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogSettings {
+    /// level for targets not listed
+    pub default: String,
+    /// level for each log target
+    pub targets: BTreeMap<String, String>,
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        Self {
+            default: "debug".to_string(),
+            targets: BTreeMap::new(),
+        }
+    }
+}
+
+impl LogSettings {
+    /// parse a level name
+    pub fn level(name: &str) -> Option<log::LevelFilter> {
+        name.parse().ok()
+    }
+}
+
+/// The sdcard SPI pins
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SdCardSettings {
+    pub miso: i32,
+    pub mosi: i32,
+    pub clk: i32,
+    pub cs: i32,
+}
+
+impl Default for SdCardSettings {
+    fn default() -> Self {
+        Self {
+            miso: 12,
+            mosi: 13,
+            clk: 14,
+            cs: 15,
+        }
+    }
+}
+
+/// Image viewer settings
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ViewerSettings {
+    /// manga order, swipe right and tap left for the next image
+    pub right_to_left: bool,
+    /// error diffusion dither instead of ordered
+    pub smooth_dither: bool,
+}
+
+/// Wifi settings
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WifiSettings {
+    /// minutes without network use before the radio is shut down
+    pub idle_minutes: u32,
+}
+
+impl Default for WifiSettings {
+    fn default() -> Self {
+        Self { idle_minutes: 5 }
+    }
+}
+
+/// An OPDS catalog to browse
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OpdsCatalog {
+    pub name: String,
+    pub url: String,
+    /// for catalogs behind basic authentication, empty if none
+    pub username: String,
+    pub password: String,
+}
+
+/// OPDS catalog settings
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OpdsSettings {
+    pub catalogs: Vec<OpdsCatalog>,
+}
+
+/// KOReader progress sync settings, sync is off without a server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SyncSettings {
+    pub server: String,
+    pub username: String,
+    pub password: String,
+    /// the name other devices show for this one
+    pub device_name: String,
+    /// filled in from the wifi mac address when empty
+    pub device_id: String,
+}
+
+impl Default for SyncSettings {
+    fn default() -> Self {
+        Self {
+            server: String::new(),
+            username: String::new(),
+            password: String::new(),
+            device_name: "InkPlate".to_string(),
+            device_id: String::new(),
+        }
+    }
+}
+
+/// Calibre wireless device settings
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CalibreSettings {
+    /// the name calibre shows for the device
+    pub device_name: String,
+    /// the password set in calibre's wireless device connection, empty if none
+    pub password: String,
+    /// the id calibre keeps the device's books under, from the name when empty
+    pub device_uuid: String,
+}
+
+impl Default for CalibreSettings {
+    fn default() -> Self {
+        Self {
+            device_name: "InkPlate".to_string(),
+            password: String::new(),
+            device_uuid: String::new(),
+        }
+    }
+}
+
+/// Firmware updates over wifi, off without a manifest url
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UpdateSettings {
+    /// http or https url of the release manifest
+    pub manifest_url: String,
+    /// lowest battery voltage to start an update at
+    pub min_battery: f64,
+}
+
+impl Default for UpdateSettings {
+    fn default() -> Self {
+        Self {
+            manifest_url: String::new(),
+            min_battery: 3.6,
+        }
+    }
+}
+
+/// The device settings
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub version: u32,
+    pub touch: TouchSettings,
+    pub display: DisplaySettings,
+    pub log: LogSettings,
+    pub sdcard: SdCardSettings,
+    pub viewer: ViewerSettings,
+    pub wifi: WifiSettings,
+    pub opds: OpdsSettings,
+    pub sync: SyncSettings,
+    pub calibre: CalibreSettings,
+    pub update: UpdateSettings,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            version: SETTINGS_VERSION,
+            touch: TouchSettings::default(),
+            display: DisplaySettings::default(),
+            log: LogSettings::default(),
+            sdcard: SdCardSettings::default(),
+            viewer: ViewerSettings::default(),
+            wifi: WifiSettings::default(),
+            opds: OpdsSettings::default(),
+            sync: SyncSettings::default(),
+            calibre: CalibreSettings::default(),
+            update: UpdateSettings::default(),
+        }
+    }
+}
+
+// a migration from one version to the next
+type Migration = fn(&mut toml::Table);
+
+// the migrations from each version to the next, the first is from version 1,
+// the first that shipped, there are none yet
+const MIGRATIONS: &[Migration] = &[];
+
+// run the migrations from `version` to the last one
+fn migrate(table: &mut toml::Table, version: u32, migrations: &[Migration]) {
+    for (i, migration) in migrations.iter().enumerate().skip(version as usize - 1) {
+        info!("migrating settings from version {}", i + 1);
+        migration(table);
+    }
+}
+
+impl Settings {
+    /// parse settings, migrating older versions and fixing bad values
+    pub fn from_toml(text: &str) -> Result<Self> {
+        let mut table: toml::Table = text.parse()?;
+        let version = match table.get("version") {
+            Some(v) => v
+                .as_integer()
+                .ok_or_else(|| anyhow!("settings version isn't a number"))?
+                as u32,
+            // a hand written file may leave it out
+            None => 1,
+        };
+        if version == 0 {
+            return Err(anyhow!("settings version 0 isn't valid"));
+        }
+        if version > SETTINGS_VERSION {
+            return Err(anyhow!(
+                "settings version {} is newer than {}",
+                version,
+                SETTINGS_VERSION
+            ));
+        }
+        migrate(&mut table, version, MIGRATIONS);
+        table.insert(
+            "version".to_string(),
+            toml::Value::Integer(SETTINGS_VERSION as i64),
+        );
+        let mut settings: Settings = toml::Value::Table(table).try_into()?;
+        for problem in settings.validate() {
+            warn!("settings: {}", problem);
+        }
+        Ok(settings)
+    }
+
+    /// the settings as toml
+    pub fn to_toml(&self) -> Result<String> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+
+    /// fix out of range values, returns what was fixed
+    pub fn validate(&mut self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let defaults = Settings::default();
+        let t = &mut self.touch;
+        if !(5.0..=200.0).contains(&t.distance_threshold) {
+            problems.push(format!(
+                "bad touch distance_threshold {}",
+                t.distance_threshold
+            ));
+            t.distance_threshold = defaults.touch.distance_threshold;
+        }
+        if !(1.0..=50.0).contains(&t.drag_threshold) {
+            problems.push(format!("bad touch drag_threshold {}", t.drag_threshold));
+            t.drag_threshold = defaults.touch.drag_threshold;
+        }
+        if !(100..=5000).contains(&t.hold_ms) {
+            problems.push(format!("bad touch hold_ms {}", t.hold_ms));
+            t.hold_ms = defaults.touch.hold_ms;
+        }
+        if !(100..=10_000).contains(&t.hold_release_ms) {
+            problems.push(format!("bad touch hold_release_ms {}", t.hold_release_ms));
+            t.hold_release_ms = defaults.touch.hold_release_ms;
+        }
+        if t.idle_ms < 1000 {
+            problems.push(format!("bad touch idle_ms {}", t.idle_ms));
+            t.idle_ms = defaults.touch.idle_ms;
+        }
+        if ![0, 90, 180, 270].contains(&self.display.rotation) {
+            problems.push(format!("bad display rotation {}", self.display.rotation));
+            self.display.rotation = defaults.display.rotation;
+        }
+        if LogSettings::level(&self.log.default).is_none() {
+            problems.push(format!("bad default log level '{}'", self.log.default));
+            self.log.default = defaults.log.default.clone();
+        }
+        self.log.targets.retain(|target, level| {
+            let good = LogSettings::level(level).is_some();
+            if !good {
+                problems.push(format!("bad log level '{}' for {}", level, target));
+            }
+            good
+        });
+        let sd = &self.sdcard;
+        let pins = [sd.miso, sd.mosi, sd.clk, sd.cs];
+        let distinct = pins
+            .iter()
+            .all(|p| pins.iter().filter(|q| *q == p).count() == 1);
+        // gpio 34 to 39 are inputs only, good for miso and nothing else
+        let outputs_ok = [sd.mosi, sd.clk, sd.cs].iter().all(|p| (0..34).contains(p));
+        if !(0..40).contains(&sd.miso) || !outputs_ok || !distinct {
+            problems.push(format!("bad sdcard pins {:?}", pins));
+            self.sdcard = defaults.sdcard;
+        }
+        if !(1..=120).contains(&self.wifi.idle_minutes) {
+            problems.push(format!("bad wifi idle_minutes {}", self.wifi.idle_minutes));
+            self.wifi.idle_minutes = defaults.wifi.idle_minutes;
+        }
+        self.opds.catalogs.retain(|c| {
+            let good = is_web_url(&c.url);
+            if !good {
+                problems.push(format!("bad opds catalog url '{}'", c.url));
+            }
+            good
+        });
+        if !self.sync.server.is_empty() && !is_web_url(&self.sync.server) {
+            problems.push(format!("bad sync server '{}'", self.sync.server));
+            self.sync.server.clear();
+        }
+        let u = &mut self.update;
+        if !u.manifest_url.is_empty() && !is_web_url(&u.manifest_url) {
+            problems.push(format!("bad update manifest_url '{}'", u.manifest_url));
+            u.manifest_url.clear();
+        }
+        if !(3.0..=4.2).contains(&u.min_battery) {
+            problems.push(format!("bad update min_battery {}", u.min_battery));
+            u.min_battery = defaults.update.min_battery;
+        }
+        problems
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input_only_pins_are_for_miso() {
+        let mut s = Settings::default();
+        s.sdcard.miso = 35;
+        assert!(s.validate().is_empty());
+        assert_eq!(s.sdcard.miso, 35);
+        s.sdcard.cs = 36;
+        assert_eq!(s.validate().len(), 1);
+        assert_eq!(s.sdcard, SdCardSettings::default());
+        s.sdcard.mosi = s.sdcard.clk;
+        assert_eq!(s.validate().len(), 1);
+    }
+
+    #[test]
+    fn toml_round_trip() {
+        let mut s = Settings::default();
+        s.display.rotation = 90;
+        s.log.targets.insert("wifi".to_string(), "info".to_string());
+        s.opds.catalogs.push(OpdsCatalog {
+            name: "Gutenberg".to_string(),
+            url: "https://m.gutenberg.org/ebooks.opds/".to_string(),
+            ..Default::default()
+        });
+        s.sync.server = "https://sync.example.com".to_string();
+        let text = s.to_toml().unwrap();
+        assert_eq!(Settings::from_toml(&text).unwrap(), s);
+    }
+
+    #[test]
+    fn missing_keys_and_version_get_defaults() {
+        let s = Settings::from_toml("[display]\nrotation = 0\n").unwrap();
+        assert_eq!(s.version, SETTINGS_VERSION);
+        assert_eq!(s.display.rotation, 0);
+        assert_eq!(s.touch, TouchSettings::default());
+    }
+
+    #[test]
+    fn newer_versions_are_refused() {
+        let text = format!("version = {}\n", SETTINGS_VERSION + 1);
+        assert!(Settings::from_toml(&text).is_err());
+        assert!(Settings::from_toml("version = 0\n").is_err());
+        assert!(Settings::from_toml("version = \"one\"\n").is_err());
+    }
+
+    #[test]
+    fn migrations_run_in_order_from_the_stored_version() {
+        assert_eq!(MIGRATIONS.len() as u32 + 1, SETTINGS_VERSION);
+        // a made up chain, 1 renames a key, 2 moves it into a section
+        fn one_to_two(t: &mut toml::Table) {
+            if let Some(v) = t.remove("idle") {
+                t.insert("idle_minutes".to_string(), v);
+            }
+        }
+        fn two_to_three(t: &mut toml::Table) {
+            if let Some(v) = t.remove("idle_minutes") {
+                let mut wifi = toml::Table::new();
+                wifi.insert("idle_minutes".to_string(), v);
+                t.insert("wifi".to_string(), toml::Value::Table(wifi));
+            }
+        }
+        let chain: &[Migration] = &[one_to_two, two_to_three];
+        let mut t: toml::Table = "idle = 9".parse().unwrap();
+        migrate(&mut t, 1, chain);
+        assert_eq!(t.to_string(), "[wifi]\nidle_minutes = 9\n");
+        // from version 2 the rename has been done already
+        let mut t: toml::Table = "idle = 9\nidle_minutes = 7".parse().unwrap();
+        migrate(&mut t, 2, chain);
+        assert_eq!(t["idle"].as_integer(), Some(9));
+        assert_eq!(t["wifi"]["idle_minutes"].as_integer(), Some(7));
+        let mut t: toml::Table = "idle = 9".parse().unwrap();
+        migrate(&mut t, 3, chain);
+        assert_eq!(t.to_string(), "idle = 9\n");
+    }
+}
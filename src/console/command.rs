@@ -0,0 +1,448 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::config::settings::LogSettings;
+use crate::ui::touch::TouchEventKind;
+use anyhow::{anyhow, Result};
+use log::LevelFilter;
+use std::{fmt::Write, fs, path::Path, sync::mpsc};
+
+/// highest front light level
+pub const MAX_LIGHT: u8 = 63;
+/// where a screenshot goes without a path
+pub const SCREENSHOT_PATH: &str = "/sdcard/screenshot.pgm";
+
+/// i2c address of the PCF85063A rtc
+pub const RTC_ADDRESS: u8 = 0x51;
+/// first of the rtc time registers, seconds to years
+pub const RTC_TIME_REGISTER: u8 = 0x04;
+/// i2c address of the MCP4018 front light potentiometer
+pub const LIGHT_ADDRESS: u8 = 0x2e;
+
+/// the help text
+pub const HELP: &str = "\
+help                          this text
+heap                          free heap
+tasks                         cpu use of each task
+battery                       battery voltage
+rtc get                       the rtc time
+rtc set YYYY-MM-DD HH:MM:SS   set the rtc, utc
+light N                       front light level, 0 to 63
+ls [DIR]                      list a directory, /sdcard by default
+screenshot [FILE]             save the screen as a pgm
+touch inject tap|hold X Y     send a touch event
+touch inject swipe left|right
+goto page N                   go to a page of the open book
+export highlights             write the highlights of the open book as markdown
+export stats                  write the reading stats as json and csv
+loglevel TARGET LEVEL         off, error, warn, info, debug or trace";
+
+/// A time to set the rtc to
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RtcTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl RtcTime {
+    /// parse `YYYY-MM-DD HH:MM:SS`, a `T` may join the date and time
+    pub fn parse(text: &str) -> Result<Self> {
+        let bad = || anyhow!("bad time '{}', use YYYY-MM-DD HH:MM:SS", text);
+        let (date, time) = text.trim().split_once(['T', ' ']).ok_or_else(bad)?;
+        let date: Vec<&str> = date.split('-').collect();
+        let time: Vec<&str> = time.trim().split(':').collect();
+        if date.len() != 3 || time.len() != 3 {
+            return Err(bad());
+        }
+        let t = Self {
+            year: date[0].parse().map_err(|_| bad())?,
+            month: date[1].parse().map_err(|_| bad())?,
+            day: date[2].parse().map_err(|_| bad())?,
+            hour: time[0].parse().map_err(|_| bad())?,
+            minute: time[1].parse().map_err(|_| bad())?,
+            second: time[2].parse().map_err(|_| bad())?,
+        };
+        if !t.valid() {
+            return Err(bad());
+        }
+        Ok(t)
+    }
+
+    // is this a time the rtc can keep, it has a two digit year
+    fn valid(&self) -> bool {
+        (2000..2100).contains(&self.year)
+            && (1..=12).contains(&self.month)
+            && (1..=days_in_month(self.year, self.month)).contains(&self.day)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+
+    /// the rtc time registers, seconds to years, in bcd
+    pub fn registers(&self) -> [u8; 7] {
+        [
+            bcd(self.second),
+            bcd(self.minute),
+            bcd(self.hour),
+            bcd(self.day),
+            self.weekday(),
+            bcd(self.month),
+            bcd((self.year % 100) as u8),
+        ]
+    }
+
+    /// the time in the rtc time registers, none if they don't hold one
+    pub fn from_registers(regs: &[u8; 7]) -> Option<Self> {
+        let t = Self {
+            // bit 7 of the seconds is the oscillator stopped flag
+            second: from_bcd(regs[0] & 0x7f)?,
+            minute: from_bcd(regs[1] & 0x7f)?,
+            hour: from_bcd(regs[2] & 0x3f)?,
+            day: from_bcd(regs[3] & 0x3f)?,
+            month: from_bcd(regs[5] & 0x1f)?,
+            year: 2000 + from_bcd(regs[6])? as u16,
+        };
+        t.valid().then_some(t)
+    }
+
+    /// days since the epoch
+    pub fn days(&self) -> i64 {
+        // days from civil, Howard Hinnant's algorithm
+        let (m, d) = (self.month as i64, self.day as i64);
+        let y = self.year as i64 - i64::from(m <= 2);
+        let era = y.div_euclid(400);
+        let yoe = y - era * 400;
+        let mp = if m > 2 { m - 3 } else { m + 9 };
+        let doy = (153 * mp + 2) / 5 + d - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        era * 146_097 + doe - 719_468
+    }
+
+    /// seconds since the epoch
+    pub fn unix_time(&self) -> i64 {
+        self.days() * 86_400
+            + self.hour as i64 * 3600
+            + self.minute as i64 * 60
+            + self.second as i64
+    }
+
+    /// day of the week, 0 is sunday
+    pub fn weekday(&self) -> u8 {
+        // the epoch was a thursday
+        (self.days() + 4).rem_euclid(7) as u8
+    }
+}
+
+fn bcd(v: u8) -> u8 {
+    ((v / 10) << 4) | (v % 10)
+}
+
+fn from_bcd(v: u8) -> Option<u8> {
+    let (tens, ones) = (v >> 4, v & 0x0f);
+    (tens < 10 && ones < 10).then_some(tens * 10 + ones)
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        // leap years, except centuries not divisible by 400
+        2 => match (year % 4, year % 100, year % 400) {
+            (0, 0, 0) => 29,
+            (0, 0, _) => 28,
+            (0, _, _) => 29,
+            _ => 28,
+        },
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// A console command
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Help,
+    Heap,
+    Tasks,
+    Battery,
+    RtcGet,
+    RtcSet(RtcTime),
+    Light(u8),
+    Ls(String),
+    Screenshot(String),
+    /// a touch event at a position
+    Touch(TouchEventKind, u32, u32),
+    /// a 1 based page
+    GotoPage(u32),
+    /// write the highlights of the open book to the exports folder
+    ExportHighlights,
+    /// write the reading stats to the exports folder
+    ExportStats,
+    LogLevel(String, LevelFilter),
+}
+
+impl Command {
+    /// does the command need the devices the app thread owns
+    pub fn needs_app(&self) -> bool {
+        matches!(
+            self,
+            Command::Battery
+                | Command::RtcGet
+                | Command::RtcSet(_)
+                | Command::Light(_)
+                | Command::Screenshot(_)
+                | Command::GotoPage(_)
+                | Command::ExportHighlights
+                | Command::ExportStats
+        )
+    }
+}
+
+/// A command for the app thread, which owns the devices
+///
+/// The app answers with the text to print, see `Command::needs_app`.
+#[derive(Debug)]
+pub struct ConsoleRequest {
+    pub command: Command,
+    pub reply: mpsc::Sender<String>,
+}
+
+// parse a number argument
+fn number<T: std::str::FromStr>(arg: Option<&&str>, usage: &str) -> Result<T> {
+    arg.and_then(|a| a.parse().ok())
+        .ok_or_else(|| anyhow!("usage: {}", usage))
+}
+
+/// parse a console line, none for an empty line
+pub fn parse(line: &str) -> Result<Option<Command>> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let Some(name) = words.first() else {
+        return Ok(None);
+    };
+    let args = &words[1..];
+    let cmd = match (name.to_ascii_lowercase().as_str(), args) {
+        ("help" | "?", _) => Command::Help,
+        ("heap", []) => Command::Heap,
+        ("tasks", []) => Command::Tasks,
+        ("battery", []) => Command::Battery,
+        ("rtc", ["get"]) => Command::RtcGet,
+        ("rtc", ["set", time @ ..]) => Command::RtcSet(RtcTime::parse(&time.join(" "))?),
+        ("rtc", _) => return Err(anyhow!("usage: rtc get | rtc set YYYY-MM-DD HH:MM:SS")),
+        ("light", [_]) => {
+            let level: u8 = number(args.first(), "light 0-63")?;
+            if level > MAX_LIGHT {
+                return Err(anyhow!("usage: light 0-63"));
+            }
+            Command::Light(level)
+        }
+        ("ls", []) => Command::Ls("/sdcard".to_string()),
+        ("ls", [dir]) if dir.starts_with('/') => Command::Ls(dir.to_string()),
+        ("screenshot", []) => Command::Screenshot(SCREENSHOT_PATH.to_string()),
+        ("screenshot", [file]) if file.starts_with('/') => Command::Screenshot(file.to_string()),
+        ("touch", ["inject", "swipe", dir]) => match *dir {
+            "left" => Command::Touch(TouchEventKind::SwipeLeft, 0, 0),
+            "right" => Command::Touch(TouchEventKind::SwipeRight, 0, 0),
+            _ => return Err(anyhow!("usage: touch inject swipe left|right")),
+        },
+        ("touch", ["inject", kind, _, _]) => {
+            let usage = "touch inject tap|hold X Y";
+            let kind = match *kind {
+                "tap" => TouchEventKind::Tap,
+                "hold" => TouchEventKind::Hold,
+                _ => return Err(anyhow!("usage: {}", usage)),
+            };
+            Command::Touch(
+                kind,
+                number(args.get(2), usage)?,
+                number(args.get(3), usage)?,
+            )
+        }
+        ("goto", ["page", _]) => {
+            let page: u32 = number(args.get(1), "goto page N")?;
+            if page == 0 {
+                return Err(anyhow!("pages start at 1"));
+            }
+            Command::GotoPage(page)
+        }
+        ("export", ["highlights"]) => Command::ExportHighlights,
+        ("export", ["stats"]) => Command::ExportStats,
+        ("loglevel", [target, level]) => match LogSettings::level(level) {
+            Some(level) => Command::LogLevel(target.to_string(), level),
+            None => return Err(anyhow!("bad log level '{}'", level)),
+        },
+        (
+            "heap" | "tasks" | "battery" | "light" | "ls" | "screenshot" | "touch" | "goto"
+            | "export" | "loglevel",
+            _,
+        ) => return Err(anyhow!("bad arguments for {}, see help", name)),
+        _ => return Err(anyhow!("unknown command '{}', see help", name)),
+    };
+    Ok(Some(cmd))
+}
+
+/// list a directory, directories first, with file sizes
+pub fn list_dir(dir: &Path) -> Result<String> {
+    let mut entries: Vec<(bool, String, u64)> = fs::read_dir(dir)?
+        .filter_map(|e| e.ok())
+        .map(|e| {
+            let meta = e.metadata().ok();
+            let is_dir = meta.as_ref().map(|m| m.is_dir()).unwrap_or(false);
+            let size = meta.map(|m| m.len()).unwrap_or(0);
+            (is_dir, e.file_name().to_string_lossy().to_string(), size)
+        })
+        .collect();
+    entries.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+    let mut out = String::new();
+    for (is_dir, name, size) in entries {
+        if is_dir {
+            let _ = writeln!(out, "{:>10}  {}/", "", name);
+        } else {
+            let _ = writeln!(out, "{:>10}  {}", size, name);
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cmd(line: &str) -> Command {
+        parse(line).unwrap().unwrap()
+    }
+
+    fn error(line: &str) -> String {
+        parse(line).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn simple_commands() {
+        assert_eq!(parse("  ").unwrap(), None);
+        assert_eq!(cmd("HELP"), Command::Help);
+        assert_eq!(cmd("heap"), Command::Heap);
+        assert_eq!(cmd("rtc get"), Command::RtcGet);
+        assert_eq!(cmd("ls"), Command::Ls("/sdcard".to_string()));
+        assert_eq!(
+            cmd("ls /sdcard/books"),
+            Command::Ls("/sdcard/books".to_string())
+        );
+        assert_eq!(
+            cmd("screenshot"),
+            Command::Screenshot(SCREENSHOT_PATH.to_string())
+        );
+        assert_eq!(cmd("export highlights"), Command::ExportHighlights);
+        assert_eq!(cmd("export stats"), Command::ExportStats);
+        assert_eq!(error("heap now"), "bad arguments for heap, see help");
+        assert_eq!(error("reboot"), "unknown command 'reboot', see help");
+        assert!(cmd("battery").needs_app() && !cmd("ls").needs_app());
+    }
+
+    #[test]
+    fn rtc_set() {
+        let t = RtcTime {
+            year: 2024,
+            month: 3,
+            day: 9,
+            hour: 17,
+            minute: 5,
+            second: 42,
+        };
+        assert_eq!(cmd("rtc set 2024-03-09 17:05:42"), Command::RtcSet(t));
+        assert_eq!(cmd("rtc set 2024-03-09T17:05:42"), Command::RtcSet(t));
+        assert!(error("rtc set 2024-03-09").starts_with("bad time"));
+        assert!(error("rtc set 1999-12-31 23:59:59").starts_with("bad time"));
+        assert!(error("rtc set 2024-13-01 00:00:00").starts_with("bad time"));
+        assert!(error("rtc set 2024-04-31 00:00:00").starts_with("bad time"));
+        assert!(error("rtc set 2024-03-09 24:00:00").starts_with("bad time"));
+        assert!(error("rtc now").starts_with("usage: rtc get"));
+    }
+
+    #[test]
+    fn leap_days() {
+        assert!(parse("rtc set 2024-02-29 12:00:00").is_ok());
+        assert!(parse("rtc set 2000-02-29 12:00:00").is_ok());
+        assert!(parse("rtc set 2023-02-29 12:00:00").is_err());
+        assert!(parse("rtc set 2100-02-29 12:00:00").is_err());
+        assert_eq!(days_in_month(2100, 2), 28);
+        assert_eq!(days_in_month(2400, 2), 29);
+    }
+
+    #[test]
+    fn rtc_registers() {
+        let t = RtcTime::parse("2024-03-09 17:05:42").unwrap();
+        // a saturday
+        assert_eq!(t.registers(), [0x42, 0x05, 0x17, 0x09, 6, 0x03, 0x24]);
+        assert_eq!(RtcTime::from_registers(&t.registers()), Some(t));
+        // the oscillator stopped flag isn't part of the seconds
+        let mut regs = t.registers();
+        regs[0] |= 0x80;
+        assert_eq!(RtcTime::from_registers(&regs), Some(t));
+        assert_eq!(RtcTime::from_registers(&[0x5a, 0, 0, 1, 0, 1, 0]), None);
+        assert_eq!(t.unix_time(), 1_710_003_942);
+        assert_eq!(RtcTime::parse("2000-01-01 00:00:00").unwrap().weekday(), 6);
+    }
+
+    #[test]
+    fn light() {
+        assert_eq!(cmd("light 0"), Command::Light(0));
+        assert_eq!(cmd("light 63"), Command::Light(MAX_LIGHT));
+        assert_eq!(error("light 64"), "usage: light 0-63");
+        assert_eq!(error("light dim"), "usage: light 0-63");
+        assert_eq!(error("light"), "bad arguments for light, see help");
+    }
+
+    #[test]
+    fn touch_inject() {
+        assert_eq!(
+            cmd("touch inject tap 10 20"),
+            Command::Touch(TouchEventKind::Tap, 10, 20)
+        );
+        assert_eq!(
+            cmd("touch inject hold 700 5"),
+            Command::Touch(TouchEventKind::Hold, 700, 5)
+        );
+        assert_eq!(
+            cmd("touch inject swipe left"),
+            Command::Touch(TouchEventKind::SwipeLeft, 0, 0)
+        );
+        assert_eq!(
+            error("touch inject swipe up"),
+            "usage: touch inject swipe left|right"
+        );
+        assert_eq!(
+            error("touch inject poke 1 2"),
+            "usage: touch inject tap|hold X Y"
+        );
+        assert_eq!(
+            error("touch inject tap -1 2"),
+            "usage: touch inject tap|hold X Y"
+        );
+        assert_eq!(error("touch tap"), "bad arguments for touch, see help");
+    }
+
+    #[test]
+    fn goto_page() {
+        assert_eq!(cmd("goto page 12"), Command::GotoPage(12));
+        assert_eq!(error("goto page 0"), "pages start at 1");
+        assert_eq!(error("goto page x"), "usage: goto page N");
+    }
+
+    #[test]
+    fn loglevel() {
+        assert_eq!(
+            cmd("loglevel wifi debug"),
+            Command::LogLevel("wifi".to_string(), LevelFilter::Debug)
+        );
+        assert_eq!(
+            cmd("loglevel * off"),
+            Command::LogLevel("*".to_string(), LevelFilter::Off)
+        );
+        assert_eq!(error("loglevel wifi loud"), "bad log level 'loud'");
+        assert_eq!(
+            error("loglevel wifi"),
+            "bad arguments for loglevel, see help"
+        );
+    }
+}
@@ -0,0 +1,123 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+/// the word as it should be looked up, without surrounding punctuation
+pub fn clean_word(text: &str) -> &str {
+    text.trim_matches(|c: char| !c.is_alphanumeric())
+}
+
+/// Forms of a word to try in the dictionary, in order
+///
+/// The word itself comes first, then lowercased, then base forms guessed
+/// by removing english plural, verb and comparative endings
+pub fn candidates(word: &str) -> Vec<String> {
+    let mut forms = vec![word.to_string()];
+    let lower = word.to_lowercase();
+    add(&mut forms, &lower);
+    let w = lower.strip_suffix("'s").unwrap_or(&lower);
+    add(&mut forms, w);
+
+    // (suffix, replacement) pairs, longest suffixes first
+    const RULES: &[(&str, &str)] = &[
+        ("ies", "y"),
+        ("ied", "y"),
+        ("ier", "y"),
+        ("iest", "y"),
+        ("ves", "f"),
+        ("ves", "fe"),
+        ("sses", "ss"),
+        ("xes", "x"),
+        ("ches", "ch"),
+        ("shes", "sh"),
+        ("oes", "o"),
+        ("ing", ""),
+        ("ing", "e"),
+        ("ed", ""),
+        ("ed", "e"),
+        ("est", ""),
+        ("est", "e"),
+        ("er", ""),
+        ("er", "e"),
+        ("es", ""),
+        ("s", ""),
+    ];
+    for (suffix, replacement) in RULES {
+        if let Some(stem) = w.strip_suffix(suffix) {
+            // don't strip a word down to nothing
+            if stem.chars().count() < 2 {
+                continue;
+            }
+            add(&mut forms, &format!("{}{}", stem, replacement));
+            // stopped -> stop, running -> run, bigger -> big
+            if replacement.is_empty() && matches!(*suffix, "ing" | "ed" | "er" | "est") {
+                let mut chars = stem.chars().rev();
+                if let (Some(a), Some(b)) = (chars.next(), chars.next()) {
+                    if a == b && !"aeiouls".contains(a) {
+                        add(&mut forms, &stem[..stem.len() - a.len_utf8()]);
+                    }
+                }
+            }
+        }
+    }
+    forms
+}
+
+// add a form if it is new
+fn add(forms: &mut Vec<String>, form: &str) {
+    if !form.is_empty() && !forms.iter().any(|f| f == form) {
+        forms.push(form.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // is `base` among the forms of `word`
+    fn finds(word: &str, base: &str) -> bool {
+        candidates(word).iter().any(|f| f == base)
+    }
+
+    #[test]
+    fn words_are_cleaned() {
+        assert_eq!(clean_word("“Whale!”"), "Whale");
+        assert_eq!(clean_word("(don't)"), "don't");
+        assert_eq!(clean_word("—"), "");
+    }
+
+    #[test]
+    fn the_word_comes_first() {
+        let forms = candidates("Ahab's");
+        assert_eq!(forms[..3], ["Ahab's", "ahab's", "ahab"]);
+        assert_eq!(candidates("whale"), ["whale"]);
+    }
+
+    #[test]
+    fn endings_are_removed() {
+        for (word, base) in [
+            ("ponies", "pony"),
+            ("carried", "carry"),
+            ("wolves", "wolf"),
+            ("knives", "knife"),
+            ("glasses", "glass"),
+            ("boxes", "box"),
+            ("watches", "watch"),
+            ("potatoes", "potato"),
+            ("harpooned", "harpoon"),
+            ("hoped", "hope"),
+            ("sailing", "sail"),
+            ("making", "make"),
+            ("stopped", "stop"),
+            ("running", "run"),
+            ("bigger", "big"),
+            ("happiest", "happy"),
+            ("whales", "whale"),
+        ] {
+            assert!(finds(word, base), "{} -> {}", word, base);
+        }
+        // doubled l and s stay, a word isn't stripped to one letter
+        assert!(!finds("killing", "kil"));
+        assert!(!finds("is", "i"));
+        assert!(!finds("bed", "b"));
+    }
+}
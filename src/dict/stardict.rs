@@ -0,0 +1,552 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::dict::lemma;
+use anyhow::{anyhow, Result};
+use flate2::read::DeflateDecoder;
+use log::*;
+use std::{
+    cmp::Ordering,
+    fs::{self, File},
+    io::{ErrorKind, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+// every BLOCK-th index entry offset is kept in memory
+const BLOCK: usize = 32;
+// longest word allowed by the StarDict format, plus the nul
+const MAX_WORD: usize = 256;
+
+/// A definition found in a dictionary
+#[derive(Debug, Clone)]
+pub struct Definition {
+    pub dictionary: String,
+    pub word: String,
+    pub text: String,
+}
+
+// the chunk table of a dictzip file, for random access
+#[derive(Debug)]
+struct DictZip {
+    chunk_len: u32,
+    // file offsets of the compressed chunks, plus the end of the last
+    chunks: Vec<u64>,
+}
+
+impl DictZip {
+    // read the gzip header and the dictzip 'RA' extra field
+    fn open(file: &mut File) -> Result<Self> {
+        let mut hdr = [0u8; 10];
+        file.read_exact(&mut hdr)?;
+        if hdr[0] != 0x1f || hdr[1] != 0x8b || hdr[2] != 8 {
+            return Err(anyhow!("not a gzip file"));
+        }
+        let flags = hdr[3];
+        if flags & 0x04 == 0 {
+            return Err(anyhow!("gzip file has no dictzip chunk table"));
+        }
+        let mut len = [0u8; 2];
+        file.read_exact(&mut len)?;
+        let mut extra = vec![0u8; u16::from_le_bytes(len) as usize];
+        file.read_exact(&mut extra)?;
+        let mut pos = 10 + 2 + extra.len() as u64;
+        // skip the file name and comment, and the header crc
+        for flag in [0x08, 0x10] {
+            if flags & flag != 0 {
+                let mut b = [0u8; 1];
+                loop {
+                    file.read_exact(&mut b)?;
+                    pos += 1;
+                    if b[0] == 0 {
+                        break;
+                    }
+                }
+            }
+        }
+        if flags & 0x02 != 0 {
+            pos += 2;
+        }
+        // find the RA subfield
+        let mut i = 0;
+        while i + 4 <= extra.len() {
+            let sublen = u16::from_le_bytes([extra[i + 2], extra[i + 3]]) as usize;
+            let data = extra
+                .get(i + 4..i + 4 + sublen)
+                .ok_or_else(|| anyhow!("bad gzip extra field"))?;
+            if &extra[i..i + 2] == b"RA" && data.len() >= 6 {
+                let chunk_len = u16::from_le_bytes([data[2], data[3]]) as u32;
+                if chunk_len == 0 {
+                    return Err(anyhow!("dictzip chunk length is 0"));
+                }
+                let count = u16::from_le_bytes([data[4], data[5]]) as usize;
+                let mut chunks = Vec::with_capacity(count + 1);
+                chunks.push(pos);
+                for c in data[6..].chunks_exact(2).take(count) {
+                    pos += u16::from_le_bytes([c[0], c[1]]) as u64;
+                    chunks.push(pos);
+                }
+                return Ok(Self { chunk_len, chunks });
+            }
+            i += 4 + sublen;
+        }
+        Err(anyhow!("gzip file has no dictzip chunk table"))
+    }
+
+    // read uncompressed data, only inflating the chunks needed
+    fn read(&self, file: &mut File, offset: u64, size: usize) -> Result<Vec<u8>> {
+        let first = (offset / self.chunk_len as u64) as usize;
+        let last = ((offset + size as u64).saturating_sub(1) / self.chunk_len as u64) as usize;
+        if last + 1 >= self.chunks.len() {
+            return Err(anyhow!("dictzip offset out of range"));
+        }
+        let mut data = Vec::new();
+        for c in first..=last {
+            let mut comp = vec![0u8; (self.chunks[c + 1] - self.chunks[c]) as usize];
+            file.seek(SeekFrom::Start(self.chunks[c]))?;
+            file.read_exact(&mut comp)?;
+            // chunks are flushed but not finished, so the stream is always incomplete
+            match DeflateDecoder::new(&comp[..]).read_to_end(&mut data) {
+                Err(e) if e.kind() != ErrorKind::UnexpectedEof => return Err(e.into()),
+                _ => {}
+            }
+        }
+        let start = (offset - first as u64 * self.chunk_len as u64) as usize;
+        data.get(start..start + size)
+            .map(|d| d.to_vec())
+            .ok_or_else(|| anyhow!("dictzip chunk too short"))
+    }
+}
+
+/// A StarDict dictionary on the sdcard
+///
+/// Only every `BLOCK`-th index offset is kept in memory, a lookup binary
+/// searches those and then reads a single block of the index
+#[derive(Debug)]
+pub struct StarDict {
+    name: String,
+    idx_path: PathBuf,
+    dict_path: PathBuf,
+    dictzip: Option<DictZip>,
+    offset_64: bool,
+    same_type_sequence: Option<String>,
+    idx_size: u64,
+    samples: Vec<u32>,
+}
+
+impl StarDict {
+    /// open a dictionary from its `.ifo` file
+    pub fn open(ifo_path: &Path) -> Result<Self> {
+        let ifo = fs::read_to_string(ifo_path)?;
+        if !ifo.starts_with("StarDict's dict ifo file") {
+            return Err(anyhow!("{:?} is not a StarDict ifo file", ifo_path));
+        }
+        let mut name = String::new();
+        let mut offset_64 = false;
+        let mut same_type_sequence = None;
+        for line in ifo.lines() {
+            if let Some((key, value)) = line.split_once('=') {
+                match key.trim() {
+                    "bookname" => name = value.trim().to_string(),
+                    "idxoffsetbits" => offset_64 = value.trim() == "64",
+                    "sametypesequence" => same_type_sequence = Some(value.trim().to_string()),
+                    _ => {}
+                }
+            }
+        }
+        let idx_path = ifo_path.with_extension("idx");
+        let mut dict_path = ifo_path.with_extension("dict");
+        let mut dictzip = None;
+        if !dict_path.exists() {
+            dict_path = ifo_path.with_extension("dict.dz");
+            dictzip = Some(DictZip::open(&mut File::open(&dict_path)?)?);
+        }
+        let idx_size = fs::metadata(&idx_path)?.len();
+        let mut dict = Self {
+            name,
+            idx_path,
+            dict_path,
+            dictzip,
+            offset_64,
+            same_type_sequence,
+            idx_size,
+            samples: Vec::new(),
+        };
+        dict.load_samples()?;
+        info!(
+            "opened dictionary '{}', {} index blocks",
+            dict.name,
+            dict.samples.len()
+        );
+        Ok(dict)
+    }
+
+    /// the dictionary name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // size of an index entry after the word
+    fn entry_tail(&self) -> usize {
+        if self.offset_64 {
+            12
+        } else {
+            8
+        }
+    }
+
+    // load the sampled offsets from the cache file, or build it by scanning the index
+    fn load_samples(&mut self) -> Result<()> {
+        let cache_path = self.idx_path.with_extension("idx.cache");
+        if let Ok(cache) = fs::read(&cache_path) {
+            let mut words = cache
+                .chunks_exact(4)
+                .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]));
+            if words.next() == Some(self.idx_size as u32) {
+                self.samples = words.collect();
+                return Ok(());
+            }
+        }
+        debug!("building index samples for {:?}", self.idx_path);
+        let mut idx = std::io::BufReader::new(File::open(&self.idx_path)?);
+        let mut pos: u64 = 0;
+        let mut count = 0;
+        let mut word = Vec::with_capacity(MAX_WORD);
+        let mut tail = [0u8; 12];
+        while pos < self.idx_size {
+            if count % BLOCK == 0 {
+                self.samples.push(pos as u32);
+            }
+            word.clear();
+            let n = std::io::BufRead::read_until(&mut idx, 0, &mut word)?;
+            idx.read_exact(&mut tail[..self.entry_tail()])?;
+            pos += (n + self.entry_tail()) as u64;
+            count += 1;
+        }
+        let mut cache = Vec::with_capacity((self.samples.len() + 1) * 4);
+        cache.extend_from_slice(&(self.idx_size as u32).to_le_bytes());
+        for s in self.samples.iter() {
+            cache.extend_from_slice(&s.to_le_bytes());
+        }
+        if let Err(e) = fs::write(&cache_path, cache) {
+            warn!("unable to write index cache {:?}: {}", cache_path, e);
+        }
+        Ok(())
+    }
+
+    // read the entries of block `b` of the index
+    fn read_block(&self, idx: &mut File, b: usize) -> Result<Vec<(String, u64, u32)>> {
+        let start = self.samples[b] as u64;
+        let end = self
+            .samples
+            .get(b + 1)
+            .map(|s| *s as u64)
+            .unwrap_or(self.idx_size);
+        let len = end
+            .checked_sub(start)
+            .ok_or_else(|| anyhow!("corrupt index cache"))?;
+        let mut data = vec![0u8; len as usize];
+        idx.seek(SeekFrom::Start(start))?;
+        idx.read_exact(&mut data)?;
+        let mut entries = Vec::with_capacity(BLOCK);
+        let mut pos = 0;
+        while pos < data.len() {
+            let nul = data[pos..]
+                .iter()
+                .position(|b| *b == 0)
+                .ok_or_else(|| anyhow!("corrupt index"))?;
+            let word = String::from_utf8_lossy(&data[pos..pos + nul]).to_string();
+            // the offset and size after the word, cut short in a truncated index
+            let t = data[pos + nul + 1..]
+                .get(..self.entry_tail())
+                .ok_or_else(|| anyhow!("index entry for '{}' is cut short", word))?;
+            let (offset, size) = if self.offset_64 {
+                (
+                    u64::from_be_bytes(t[..8].try_into()?),
+                    u32::from_be_bytes(t[8..12].try_into()?),
+                )
+            } else {
+                (
+                    u32::from_be_bytes(t[..4].try_into()?) as u64,
+                    u32::from_be_bytes(t[4..8].try_into()?),
+                )
+            };
+            entries.push((word, offset, size));
+            pos += nul + 1 + self.entry_tail();
+        }
+        Ok(entries)
+    }
+
+    // the first word of block `b`
+    fn block_word(&self, idx: &mut File, b: usize) -> Result<String> {
+        let mut buf = [0u8; MAX_WORD];
+        idx.seek(SeekFrom::Start(self.samples[b] as u64))?;
+        let n = idx.read(&mut buf)?;
+        let nul = buf[..n].iter().position(|b| *b == 0).unwrap_or(n);
+        Ok(String::from_utf8_lossy(&buf[..nul]).to_string())
+    }
+
+    /// look up a word, ignoring ascii case
+    pub fn lookup(&self, word: &str) -> Result<Option<Definition>> {
+        if self.samples.is_empty() {
+            return Ok(None);
+        }
+        let mut idx = File::open(&self.idx_path)?;
+        // find the last block starting at or before the word
+        let (mut lo, mut hi) = (0, self.samples.len());
+        while hi - lo > 1 {
+            let mid = (lo + hi) / 2;
+            if ascii_casecmp(&self.block_word(&mut idx, mid)?, word) == Ordering::Less {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        // a match can start at the end of the previous block
+        for b in lo..(lo + 2).min(self.samples.len()) {
+            for (w, offset, size) in self.read_block(&mut idx, b)? {
+                match ascii_casecmp(&w, word) {
+                    Ordering::Less => continue,
+                    Ordering::Equal => {
+                        let text = self.read_definition(offset, size)?;
+                        return Ok(Some(Definition {
+                            dictionary: self.name.clone(),
+                            word: w,
+                            text,
+                        }));
+                    }
+                    Ordering::Greater => return Ok(None),
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    // read and decode a definition from the dict file
+    fn read_definition(&self, offset: u64, size: u32) -> Result<String> {
+        let mut file = File::open(&self.dict_path)?;
+        let data = match &self.dictzip {
+            Some(dz) => dz.read(&mut file, offset, size as usize)?,
+            None => {
+                let mut data = vec![0u8; size as usize];
+                file.seek(SeekFrom::Start(offset))?;
+                file.read_exact(&mut data)?;
+                data
+            }
+        };
+        Ok(decode_fields(&data, self.same_type_sequence.as_deref()))
+    }
+}
+
+// compare the way StarDict sorts its index
+fn ascii_casecmp(a: &str, b: &str) -> Ordering {
+    let la = a.bytes().map(|c| c.to_ascii_lowercase());
+    let lb = b.bytes().map(|c| c.to_ascii_lowercase());
+    la.cmp(lb)
+}
+
+// turn the data fields of an entry into text
+fn decode_fields(data: &[u8], same_type_sequence: Option<&str>) -> String {
+    let mut text = String::new();
+    let mut pos = 0;
+    let types: Vec<u8> = same_type_sequence
+        .map(|s| s.bytes().collect())
+        .unwrap_or_default();
+    let mut t = 0;
+    while pos < data.len() {
+        // the type comes from the sequence, or is the first byte of the field
+        let (kind, last) = if types.is_empty() {
+            pos += 1;
+            (data[pos - 1], false)
+        } else if t < types.len() {
+            t += 1;
+            (types[t - 1], t == types.len())
+        } else {
+            break;
+        };
+        if kind.is_ascii_lowercase() {
+            // text, nul terminated unless last in the sequence
+            let end = if last {
+                data.len()
+            } else {
+                data[pos..]
+                    .iter()
+                    .position(|b| *b == 0)
+                    .map(|n| pos + n)
+                    .unwrap_or(data.len())
+            };
+            let field = String::from_utf8_lossy(&data[pos..end]);
+            match kind {
+                b'm' | b'l' | b't' | b'y' => text.push_str(&field),
+                _ => text.push_str(&strip_markup(&field)),
+            }
+            text.push('\n');
+            pos = end + 1;
+        } else {
+            // binary data, skip it
+            let size = if last {
+                data.len() - pos
+            } else {
+                data.get(pos..pos + 4)
+                    .map(|s| u32::from_be_bytes([s[0], s[1], s[2], s[3]]) as usize + 4)
+                    .unwrap_or(data.len())
+            };
+            pos += size;
+        }
+    }
+    text.trim().to_string()
+}
+
+// remove html/xdxf/pango tags, line breaks become newlines
+fn strip_markup(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut tag = String::new();
+    let mut in_tag = false;
+    for ch in s.chars() {
+        match (in_tag, ch) {
+            (false, '<') => {
+                in_tag = true;
+                tag.clear();
+            }
+            (true, '>') => {
+                in_tag = false;
+                let name = tag.trim_start_matches('/').to_lowercase();
+                if name.starts_with("br") || name.starts_with("p") || name.starts_with("div") {
+                    out.push('\n');
+                }
+            }
+            (true, c) => tag.push(c),
+            (false, c) => out.push(c),
+        }
+    }
+    out.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+/// All the dictionaries found on the sdcard
+#[derive(Debug, Default)]
+pub struct Dictionaries {
+    dicts: Vec<StarDict>,
+}
+
+impl Dictionaries {
+    /// open all the `.ifo` dictionaries in a directory, in name order
+    pub fn open_dir(dir: &Path) -> Result<Self> {
+        let mut ifos: Vec<PathBuf> = match fs::read_dir(dir) {
+            Ok(entries) => entries
+                .filter_map(|e| e.ok().map(|e| e.path()))
+                .filter(|p| p.extension().map(|e| e == "ifo").unwrap_or(false))
+                .collect(),
+            Err(e) => {
+                info!("no dictionaries in {:?}: {}", dir, e);
+                Vec::new()
+            }
+        };
+        ifos.sort();
+        let mut dicts = Vec::new();
+        for ifo in ifos {
+            match StarDict::open(&ifo) {
+                Ok(d) => dicts.push(d),
+                Err(e) => warn!("unable to open dictionary {:?}: {}", ifo, e),
+            }
+        }
+        Ok(Self { dicts })
+    }
+
+    /// the dictionaries
+    pub fn dictionaries(&self) -> &[StarDict] {
+        &self.dicts
+    }
+
+    /// look up a word in every dictionary, trying base forms if the word isn't found
+    pub fn lookup(&self, word: &str) -> Result<Vec<Definition>> {
+        let word = lemma::clean_word(word);
+        let mut found = Vec::new();
+        if word.is_empty() {
+            return Ok(found);
+        }
+        let forms = lemma::candidates(word);
+        for dict in self.dicts.iter() {
+            for form in forms.iter() {
+                if let Some(def) = dict.lookup(form)? {
+                    found.push(def);
+                    break;
+                }
+            }
+        }
+        debug!("lookup '{}': {} definitions", word, found.len());
+        Ok(found)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    // an index entry with a 32 bit offset
+    fn entry(word: &str, offset: u32, size: u32) -> Vec<u8> {
+        let mut e = word.as_bytes().to_vec();
+        e.push(0);
+        e.extend_from_slice(&offset.to_be_bytes());
+        e.extend_from_slice(&size.to_be_bytes());
+        e
+    }
+
+    fn write_dict(dir: &Path, idx: &[u8]) -> PathBuf {
+        let ifo = dir.join("test.ifo");
+        fs::write(
+            &ifo,
+            "StarDict's dict ifo file\nversion=2.4.2\nbookname=Test\nsametypesequence=m\n",
+        )
+        .unwrap();
+        fs::write(dir.join("test.idx"), idx).unwrap();
+        fs::write(dir.join("test.dict"), "a fruitanother fruit").unwrap();
+        ifo
+    }
+
+    #[test]
+    fn lookup() {
+        let dir = temp_dir("stardict");
+        let mut idx = entry("apple", 0, 7);
+        idx.extend(entry("banana", 7, 13));
+        let dict = StarDict::open(&write_dict(&dir, &idx)).unwrap();
+        let def = dict.lookup("Banana").unwrap().unwrap();
+        assert_eq!(def.text, "another fruit");
+        assert!(dict.lookup("cherry").unwrap().is_none());
+    }
+
+    #[test]
+    fn truncated_index_is_an_error() {
+        let dir = temp_dir("stardict");
+        let mut idx = entry("apple", 0, 7);
+        idx.extend_from_slice(b"banana\0\0\0\0");
+        // a cache of the right size skips the scan that would notice
+        let mut cache = (idx.len() as u32).to_le_bytes().to_vec();
+        cache.extend_from_slice(&0u32.to_le_bytes());
+        fs::write(dir.join("test.idx.cache"), cache).unwrap();
+        let dict = StarDict::open(&write_dict(&dir, &idx)).unwrap();
+        assert!(dict.lookup("banana").is_err());
+        // without the cache the scan finds it
+        fs::remove_file(dir.join("test.idx.cache")).unwrap();
+        assert!(StarDict::open(&dir.join("test.ifo")).is_err());
+    }
+
+    #[test]
+    fn dictzip_chunk_length_zero() {
+        let dir = temp_dir("stardict");
+        let path = dir.join("test.dict.dz");
+        // gzip header with FEXTRA, an RA field of version 1, chunk length 0, 1 chunk
+        let mut gz = vec![0x1f, 0x8b, 8, 0x04, 0, 0, 0, 0, 0, 3];
+        let ra: [u8; 8] = [1, 0, 0, 0, 1, 0, 10, 0];
+        gz.extend_from_slice(&(4 + ra.len() as u16).to_le_bytes());
+        gz.extend_from_slice(b"RA");
+        gz.extend_from_slice(&(ra.len() as u16).to_le_bytes());
+        gz.extend_from_slice(&ra);
+        fs::write(&path, gz).unwrap();
+        let err = DictZip::open(&mut File::open(&path).unwrap()).unwrap_err();
+        assert!(err.to_string().contains("chunk length"));
+    }
+}
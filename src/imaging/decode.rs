@@ -0,0 +1,564 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use anyhow::{anyhow, Result};
+use log::*;
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Cursor, Read, Seek, SeekFrom},
+    num::NonZeroU64,
+    path::Path,
+};
+
+/// widest or tallest image header accepted, larger ones are refused before decoding
+pub const MAX_DIMENSION: u32 = 16384;
+/// most pixels in a grayscale image, the display is 1024x758
+pub const MAX_PIXELS: usize = 2 * 1024 * 1024;
+// most bytes a decoder may allocate for a frame, shrinking happens after it
+const MAX_DECODE_BYTES: usize = 8 * 1024 * 1024;
+
+/// the bytes of a `width` x `height` grayscale image, if it isn't too large
+pub fn pixel_count(width: u32, height: u32) -> Result<usize> {
+    (width as usize)
+        .checked_mul(height as usize)
+        .filter(|n| *n <= MAX_PIXELS)
+        .ok_or_else(|| anyhow!("image of {}x{} is too large", width, height))
+}
+
+// refuse an image header too large to decode
+fn check_dimensions(width: u32, height: u32) -> Result<()> {
+    if width == 0 || height == 0 || width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return Err(anyhow!("unsupported image size {}x{}", width, height));
+    }
+    Ok(())
+}
+
+/// An 8 bit grayscale image
+///
+/// Image buffers are large, with `CONFIG_SPIRAM_USE_MALLOC` they are
+/// allocated in SPIRAM
+#[derive(Debug, Clone, PartialEq)]
+pub struct GrayImage {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+impl GrayImage {
+    /// create a white image
+    pub fn new(width: u32, height: u32) -> Result<Self> {
+        Ok(Self {
+            width,
+            height,
+            data: vec![255; pixel_count(width, height)?],
+        })
+    }
+
+    /// scale to a size, nearest neighbour, used to enlarge small images
+    pub fn resized(&self, width: u32, height: u32) -> Result<Self> {
+        let mut data = Vec::with_capacity(pixel_count(width, height)?);
+        for y in 0..height {
+            let sy = (y as u64 * self.height as u64 / height as u64) as usize;
+            let row = &self.data[sy * self.width as usize..(sy + 1) * self.width as usize];
+            data.extend(
+                (0..width).map(|x| row[(x as u64 * self.width as u64 / width as u64) as usize]),
+            );
+        }
+        Ok(Self {
+            width,
+            height,
+            data,
+        })
+    }
+}
+
+/// The image formats that can be decoded
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+    Gif,
+    Bmp,
+}
+
+/// the image format from the first bytes of the file
+pub fn detect_format(magic: &[u8]) -> Option<ImageFormat> {
+    if magic.starts_with(b"\x89PNG") {
+        Some(ImageFormat::Png)
+    } else if magic.starts_with(&[0xff, 0xd8]) {
+        Some(ImageFormat::Jpeg)
+    } else if magic.starts_with(b"GIF8") {
+        Some(ImageFormat::Gif)
+    } else if magic.starts_with(b"BM") {
+        Some(ImageFormat::Bmp)
+    } else {
+        None
+    }
+}
+
+/// the width and height in an image header, without decoding the image
+pub fn image_size(bytes: &[u8]) -> Option<(u32, u32)> {
+    let be16 = |i: usize| Some(u16::from_be_bytes(bytes.get(i..i + 2)?.try_into().ok()?) as u32);
+    let le16 = |i: usize| Some(u16::from_le_bytes(bytes.get(i..i + 2)?.try_into().ok()?) as u32);
+    let be32 = |i: usize| Some(u32::from_be_bytes(bytes.get(i..i + 4)?.try_into().ok()?));
+    let le32 = |i: usize| Some(i32::from_le_bytes(bytes.get(i..i + 4)?.try_into().ok()?));
+    let (width, height) = match detect_format(bytes)? {
+        ImageFormat::Png => (be32(16)?, be32(20)?),
+        ImageFormat::Gif => (le16(6)?, le16(8)?),
+        // rows are stored bottom up when the height is positive
+        ImageFormat::Bmp => (le32(18)?.unsigned_abs(), le32(22)?.unsigned_abs()),
+        ImageFormat::Jpeg => {
+            // the segments up to the start of frame
+            let mut i = 2;
+            loop {
+                if *bytes.get(i)? != 0xff {
+                    return None;
+                }
+                let marker = *bytes.get(i + 1)?;
+                match marker {
+                    0xff => i += 1,
+                    0x01 | 0xd0..=0xd7 => i += 2,
+                    0xc0..=0xcf if !matches!(marker, 0xc4 | 0xc8 | 0xcc) => {
+                        break (be16(i + 7)?, be16(i + 5)?);
+                    }
+                    _ => i += 2 + be16(i + 2)? as usize,
+                }
+            }
+        }
+    };
+    Some((width, height)).filter(|(w, h)| *w > 0 && *h > 0)
+}
+
+/// the largest size with the same aspect that fits, never larger than the image
+pub fn fit_size(width: u32, height: u32, max_width: u32, max_height: u32) -> (u32, u32) {
+    if width <= max_width && height <= max_height {
+        return (width.max(1), height.max(1));
+    }
+    // compare the ratios without floats, w/h > max_w/max_h
+    if width as u64 * max_height as u64 > max_width as u64 * height as u64 {
+        (
+            max_width,
+            ((height as u64 * max_width as u64 / width as u64) as u32).max(1),
+        )
+    } else {
+        (
+            ((width as u64 * max_height as u64 / height as u64) as u32).max(1),
+            max_height,
+        )
+    }
+}
+
+// Shrinks an image a row at a time by averaging the pixels that fall on
+// each output pixel, so the full size image is never in memory
+struct RowScaler {
+    in_width: u32,
+    in_height: u32,
+    image: GrayImage,
+    sums: Vec<u32>,
+    counts: Vec<u16>,
+    in_row: u32,
+    out_row: u32,
+}
+
+impl RowScaler {
+    fn new(in_width: u32, in_height: u32, max_width: u32, max_height: u32) -> Result<Self> {
+        check_dimensions(in_width, in_height)?;
+        let (w, h) = fit_size(in_width, in_height, max_width, max_height);
+        Ok(Self {
+            in_width,
+            in_height,
+            image: GrayImage {
+                width: w,
+                height: h,
+                data: Vec::with_capacity(pixel_count(w, h)?),
+            },
+            sums: vec![0; w as usize],
+            counts: vec![0; w as usize],
+            in_row: 0,
+            out_row: 0,
+        })
+    }
+
+    // add the next row of gray pixels
+    fn push_row(&mut self, row: &[u8]) {
+        let out_row =
+            (self.in_row as u64 * self.image.height as u64 / self.in_height as u64) as u32;
+        if out_row != self.out_row {
+            self.flush();
+            self.out_row = out_row;
+        }
+        for (x, g) in row.iter().take(self.in_width as usize).enumerate() {
+            let ox = (x as u64 * self.image.width as u64 / self.in_width as u64) as usize;
+            self.sums[ox] += *g as u32;
+            self.counts[ox] += 1;
+        }
+        self.in_row += 1;
+    }
+
+    // write out the averaged row
+    fn flush(&mut self) {
+        for (s, c) in self.sums.iter_mut().zip(self.counts.iter_mut()) {
+            self.image
+                .data
+                .push(if *c > 0 { (*s / *c as u32) as u8 } else { 255 });
+            *s = 0;
+            *c = 0;
+        }
+    }
+
+    fn finish(mut self) -> GrayImage {
+        self.flush();
+        let len = self.image.width as usize * self.image.height as usize;
+        self.image.data.resize(len, 255);
+        self.image
+    }
+}
+
+// luma of a color, composited on white paper
+fn luma(r: u8, g: u8, b: u8, a: u8) -> u8 {
+    let l = (r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000;
+    ((l * a as u32 + 255 * (255 - a as u32)) / 255) as u8
+}
+
+// convert a row of pixels with `channels` bytes each to gray
+fn row_to_gray(row: &[u8], channels: usize, gray: &mut Vec<u8>) {
+    gray.clear();
+    gray.extend(row.chunks_exact(channels).map(|p| match channels {
+        1 => p[0],
+        2 => luma(p[0], p[0], p[0], p[1]),
+        3 => luma(p[0], p[1], p[2], 255),
+        _ => luma(p[0], p[1], p[2], p[3]),
+    }));
+}
+
+fn decode_png<R: Read>(r: R, max_width: u32, max_height: u32) -> Result<GrayImage> {
+    let limits = png::Limits {
+        bytes: MAX_DECODE_BYTES,
+    };
+    let mut decoder = png::Decoder::new_with_limits(r, limits);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info()?;
+    let (width, height) = (reader.info().width, reader.info().height);
+    let channels = reader.output_color_type().0.samples();
+    let mut scaler = RowScaler::new(width, height, max_width, max_height)?;
+    let mut gray = Vec::with_capacity(width as usize);
+    if reader.info().interlaced {
+        // the passes come out separately, so decode the whole frame
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let frame = reader.next_frame(&mut buf)?;
+        for row in buf.chunks_exact(frame.line_size).take(height as usize) {
+            row_to_gray(row, channels, &mut gray);
+            scaler.push_row(&gray);
+        }
+    } else {
+        while let Some(row) = reader.next_row()? {
+            row_to_gray(row.data(), channels, &mut gray);
+            scaler.push_row(&gray);
+        }
+    }
+    Ok(scaler.finish())
+}
+
+fn decode_jpeg<R: Read>(r: R, max_width: u32, max_height: u32) -> Result<GrayImage> {
+    let mut decoder = jpeg_decoder::Decoder::new(r);
+    decoder.set_max_decoding_buffer_size(MAX_DECODE_BYTES);
+    decoder.read_info()?;
+    // let the decoder shrink by up to 8 while decoding, to bound memory
+    let (max_w, max_h) = (
+        max_width.min(u16::MAX as u32),
+        max_height.min(u16::MAX as u32),
+    );
+    let (width, height) = decoder.scale(max_w as u16, max_h as u16)?;
+    let pixels = decoder.decode()?;
+    let info = decoder
+        .info()
+        .ok_or_else(|| anyhow!("jpeg has no image info"))?;
+    let channels = match info.pixel_format {
+        jpeg_decoder::PixelFormat::L8 => 1,
+        jpeg_decoder::PixelFormat::L16 => 2,
+        jpeg_decoder::PixelFormat::RGB24 => 3,
+        jpeg_decoder::PixelFormat::CMYK32 => 4,
+    };
+    let (width, height) = (width as u32, height as u32);
+    let mut scaler = RowScaler::new(width, height, max_width, max_height)?;
+    let mut gray = Vec::with_capacity(width as usize);
+    for row in pixels.chunks_exact(width as usize * channels) {
+        gray.clear();
+        gray.extend(row.chunks_exact(channels).map(|p| match info.pixel_format {
+            jpeg_decoder::PixelFormat::L8 => p[0],
+            // big endian, the high byte is enough
+            jpeg_decoder::PixelFormat::L16 => p[0],
+            jpeg_decoder::PixelFormat::RGB24 => luma(p[0], p[1], p[2], 255),
+            jpeg_decoder::PixelFormat::CMYK32 => {
+                // adobe jpegs store inverted cmyk
+                let k = p[3] as u32;
+                luma(
+                    (p[0] as u32 * k / 255) as u8,
+                    (p[1] as u32 * k / 255) as u8,
+                    (p[2] as u32 * k / 255) as u8,
+                    255,
+                )
+            }
+        }));
+        scaler.push_row(&gray);
+    }
+    Ok(scaler.finish())
+}
+
+fn decode_gif<R: Read>(r: R, max_width: u32, max_height: u32) -> Result<GrayImage> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    if let Some(limit) = NonZeroU64::new(MAX_DECODE_BYTES as u64) {
+        options.set_memory_limit(gif::MemoryLimit::Bytes(limit));
+    }
+    let mut decoder = options.read_info(r)?;
+    let (width, height) = (decoder.width() as u32, decoder.height() as u32);
+    let global = decoder.global_palette().map(|p| p.to_vec());
+    // only the first frame is shown
+    let frame = decoder
+        .read_next_frame()?
+        .ok_or_else(|| anyhow!("gif has no frames"))?;
+    let palette = frame
+        .palette
+        .as_deref()
+        .or(global.as_deref())
+        .ok_or_else(|| anyhow!("gif has no palette"))?;
+    let grays: Vec<u8> = palette
+        .chunks_exact(3)
+        .map(|c| luma(c[0], c[1], c[2], 255))
+        .collect();
+    let mut scaler = RowScaler::new(width, height, max_width, max_height)?;
+    let mut gray = vec![255; width as usize];
+    let (left, top) = (frame.left as usize, frame.top as usize);
+    let fw = frame.width as usize;
+    for y in 0..height as usize {
+        gray.fill(255);
+        if y >= top && y < top + frame.height as usize {
+            let row = &frame.buffer[(y - top) * fw..(y - top + 1) * fw];
+            for (x, idx) in row.iter().enumerate() {
+                if Some(*idx) != frame.transparent && left + x < width as usize {
+                    gray[left + x] = grays.get(*idx as usize).copied().unwrap_or(255);
+                }
+            }
+        }
+        scaler.push_row(&gray);
+    }
+    Ok(scaler.finish())
+}
+
+// little endian values from the bmp headers
+fn le_u16(b: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([b[at], b[at + 1]])
+}
+
+fn le_u32(b: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]])
+}
+
+fn decode_bmp<R: Read + Seek>(mut r: R, max_width: u32, max_height: u32) -> Result<GrayImage> {
+    let mut hdr = [0u8; 54];
+    r.read_exact(&mut hdr)?;
+    let data_offset = le_u32(&hdr, 10) as u64;
+    let dib_size = le_u32(&hdr, 14);
+    let width = le_u32(&hdr, 18) as i32;
+    let raw_height = le_u32(&hdr, 22) as i32;
+    let bpp = le_u16(&hdr, 28) as u32;
+    let compression = le_u32(&hdr, 30);
+    if dib_size < 40 || width <= 0 || raw_height == 0 {
+        return Err(anyhow!("unsupported bmp header"));
+    }
+    // uncompressed, or bitfields which we assume are the usual BGRA masks
+    if compression != 0 && !(compression == 3 && bpp == 32) {
+        return Err(anyhow!("compressed bmp not supported"));
+    }
+    if ![1, 4, 8, 24, 32].contains(&bpp) {
+        return Err(anyhow!("bmp with {} bits per pixel not supported", bpp));
+    }
+    let (width, height) = (width as u32, raw_height.unsigned_abs());
+    check_dimensions(width, height)?;
+    let bottom_up = raw_height > 0;
+
+    // the palette, as grays
+    let mut grays = Vec::new();
+    if bpp <= 8 {
+        let count = match le_u32(&hdr, 46) {
+            0 => 1 << bpp,
+            n => n.min(256),
+        };
+        let mut pal = vec![0u8; count as usize * 4];
+        r.seek(SeekFrom::Start(14 + dib_size as u64))?;
+        r.read_exact(&mut pal)?;
+        grays = pal
+            .chunks_exact(4)
+            .map(|c| luma(c[2], c[1], c[0], 255))
+            .collect();
+    }
+
+    // rows are padded to 4 bytes, the size is checked so this can't overflow
+    let stride = ((bpp as u64 * width as u64 + 31) / 32 * 4) as usize;
+    let mut row = vec![0u8; stride];
+    let mut gray = Vec::with_capacity(width as usize);
+    let mut scaler = RowScaler::new(width, height, max_width, max_height)?;
+    for y in 0..height as u64 {
+        // bottom up rows are stored last row first
+        let file_row = if bottom_up { height as u64 - 1 - y } else { y };
+        r.seek(SeekFrom::Start(data_offset + file_row * stride as u64))?;
+        r.read_exact(&mut row)?;
+        gray.clear();
+        for x in 0..width as usize {
+            let g = match bpp {
+                1 => grays.get(((row[x / 8] >> (7 - x % 8)) & 1) as usize),
+                4 => grays.get(((row[x / 2] >> (4 * (1 - x % 2))) & 0xf) as usize),
+                8 => grays.get(row[x] as usize),
+                _ => None,
+            };
+            gray.push(match bpp {
+                24 => luma(row[x * 3 + 2], row[x * 3 + 1], row[x * 3], 255),
+                32 => luma(row[x * 4 + 2], row[x * 4 + 1], row[x * 4], 255),
+                _ => g.copied().unwrap_or(255),
+            });
+        }
+        scaler.push_row(&gray);
+    }
+    Ok(scaler.finish())
+}
+
+/// decode an image, shrunk to fit in `max_width` x `max_height`
+pub fn decode_image<R>(mut r: R, max_width: u32, max_height: u32) -> Result<GrayImage>
+where
+    R: BufRead + Seek,
+{
+    let mut magic = [0u8; 8];
+    let n = r.read(&mut magic)?;
+    r.seek(SeekFrom::Start(0))?;
+    let format = detect_format(&magic[..n]).ok_or_else(|| anyhow!("unknown image format"))?;
+    debug!("decoding {:?} image", format);
+    match format {
+        ImageFormat::Png => decode_png(r, max_width, max_height),
+        ImageFormat::Jpeg => decode_jpeg(r, max_width, max_height),
+        ImageFormat::Gif => decode_gif(r, max_width, max_height),
+        ImageFormat::Bmp => decode_bmp(r, max_width, max_height),
+    }
+}
+
+/// decode an image file, shrunk to fit in `max_width` x `max_height`
+pub fn decode_image_file(path: &Path, max_width: u32, max_height: u32) -> Result<GrayImage> {
+    decode_image(BufReader::new(File::open(path)?), max_width, max_height)
+}
+
+/// decode an image held in memory, such as a book resource
+pub fn decode_image_bytes(bytes: &[u8], max_width: u32, max_height: u32) -> Result<GrayImage> {
+    decode_image(Cursor::new(bytes), max_width, max_height)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{bmp, gif, png};
+
+    // a 16x8 gray baseline jpeg, a black block then a white one: flat
+    // quantizing, one dc code for size 11 and only end of block for ac
+    fn jpeg_halves() -> Vec<u8> {
+        let mut b = vec![0xff, 0xd8, 0xff, 0xdb, 0, 67, 0];
+        b.extend([1; 64]);
+        b.extend([0xff, 0xc0, 0, 11, 8, 0, 8, 0, 16, 1, 1, 0x11, 0]);
+        for (class, symbol) in [(0x00, 11), (0x10, 0)] {
+            b.extend([0xff, 0xc4, 0, 20, class, 1]);
+            b.extend([0; 15]);
+            b.push(symbol);
+        }
+        b.extend([0xff, 0xda, 0, 8, 1, 1, 0, 0, 63, 0]);
+        // dc -1024 then +2040, each followed by end of block
+        b.extend([0x3f, 0xf3, 0xfc, 0x3f, 0xff, 0xd9]);
+        b
+    }
+
+    #[test]
+    fn sizes_from_headers() {
+        let white = [255, 255, 255];
+        assert_eq!(image_size(&bmp(3, 2, &[white; 6])), Some((3, 2)));
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        png.extend([0, 0, 1, 44, 0, 0, 0, 200]);
+        assert_eq!(image_size(&png), Some((300, 200)));
+        assert_eq!(image_size(b"GIF89a\x10\x00\x08\x00"), Some((16, 8)));
+        // an app segment, then the frame
+        let jpeg = [
+            0xff, 0xd8, 0xff, 0xe0, 0, 4, 0, 0, 0xff, 0xc0, 0, 11, 8, 0, 50, 0, 80, 1, 1, 0x11, 0,
+        ];
+        assert_eq!(image_size(&jpeg), Some((80, 50)));
+        assert_eq!(image_size(&jpeg[..10]), None);
+        assert_eq!(image_size(b"hello"), None);
+    }
+
+    #[test]
+    fn small_bmp() {
+        let white = [255, 255, 255];
+        let black = [0, 0, 0];
+        let data = bmp(2, 2, &[black, white, white, black]);
+        let image = decode_image_bytes(&data, 100, 100).unwrap();
+        assert_eq!((image.width, image.height), (2, 2));
+        assert_eq!(image.data, vec![0, 255, 255, 0]);
+    }
+
+    #[test]
+    fn small_png() {
+        let data = png(3, 1, png::ColorType::Grayscale, &[0, 128, 255]);
+        let image = decode_image_bytes(&data, 100, 100).unwrap();
+        assert_eq!((image.width, image.height), (3, 1));
+        assert_eq!(image.data, vec![0, 128, 255]);
+
+        // color goes through luma, transparent is white
+        let rgba = [255, 255, 255, 255, 0, 0, 0, 255, 0, 0, 0, 0];
+        let data = png(3, 1, png::ColorType::Rgba, &rgba);
+        let image = decode_image_bytes(&data, 100, 100).unwrap();
+        assert_eq!(image.data, vec![255, 0, 255]);
+    }
+
+    #[test]
+    fn small_jpeg() {
+        let image = decode_image_bytes(&jpeg_halves(), 100, 100).unwrap();
+        assert_eq!((image.width, image.height), (16, 8));
+        for row in image.data.chunks(16) {
+            assert!(row[..8].iter().all(|&p| p <= 2), "{:?}", row);
+            assert!(row[8..].iter().all(|&p| p >= 253), "{:?}", row);
+        }
+    }
+
+    #[test]
+    fn small_gif() {
+        let palette = [0, 0, 0, 255, 255, 255, 128, 128, 128];
+        let data = gif(3, 2, &palette, &[0, 1, 2, 2, 1, 0]);
+        let image = decode_image_bytes(&data, 100, 100).unwrap();
+        assert_eq!((image.width, image.height), (3, 2));
+        assert_eq!(image.data, vec![0, 255, 128, 128, 255, 0]);
+    }
+
+    #[test]
+    fn large_images_are_scaled_down() {
+        let data = png(8, 4, png::ColorType::Grayscale, &[200; 32]);
+        let image = decode_image_bytes(&data, 4, 4).unwrap();
+        assert_eq!((image.width, image.height), (4, 2));
+        assert!(image.data.iter().all(|&p| p == 200));
+        let image = decode_image_bytes(&jpeg_halves(), 4, 4).unwrap();
+        assert_eq!((image.width, image.height), (4, 2));
+        assert!(image.data[0] <= 2 && image.data[3] >= 253);
+    }
+
+    #[test]
+    fn huge_bmp_header_is_refused() {
+        // 2^31 - 1 pixels wide, the stride would overflow a u32
+        let data = bmp(i32::MAX, 1, &[]);
+        let err = decode_image_bytes(&data, 1024, 758).unwrap_err();
+        assert!(err.to_string().contains("image size"), "{}", err);
+    }
+
+    #[test]
+    fn sizes_are_checked() {
+        assert!(pixel_count(u32::MAX, u32::MAX).is_err());
+        assert!(GrayImage::new(100_000, 100_000).is_err());
+        let image = GrayImage::new(4, 2).unwrap();
+        assert_eq!(image.data.len(), 8);
+        assert!(image.resized(u32::MAX, 2).is_err());
+        assert_eq!(image.resized(8, 4).unwrap().data.len(), 32);
+    }
+}
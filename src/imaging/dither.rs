@@ -0,0 +1,251 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::imaging::decode::GrayImage;
+use embedded_graphics::{
+    pixelcolor::{Gray8, GrayColor},
+    prelude::*,
+    primitives::Rectangle,
+};
+
+/// Gray levels the display can show
+pub const GRAY_LEVELS: u8 = 8;
+
+/// How to reduce an image to the display gray levels
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Dither {
+    /// bayer matrix, fast and stable, good for line art
+    Ordered,
+    /// floyd-steinberg, smoother for photos and covers
+    ErrorDiffusion,
+}
+
+// 4x4 bayer matrix, thresholds 0..16
+const BAYER: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+// the luma of a gray level
+fn level_luma(level: u8) -> u8 {
+    (level as u32 * 255 / (GRAY_LEVELS as u32 - 1)) as u8
+}
+
+// the level nearest a luma
+fn nearest_level(luma: i32) -> u8 {
+    let max = GRAY_LEVELS as i32 - 1;
+    ((luma.clamp(0, 255) * max + 127) / 255) as u8
+}
+
+/// reduce the image in place to levels 0..GRAY_LEVELS, 0 is black
+pub fn dither(image: &mut GrayImage, method: Dither) {
+    let w = image.width as usize;
+    if w == 0 {
+        return;
+    }
+    match method {
+        Dither::Ordered => {
+            let max = GRAY_LEVELS as u32 - 1;
+            for (y, row) in image.data.chunks_exact_mut(w).enumerate() {
+                for (x, p) in row.iter_mut().enumerate() {
+                    // in steps of 1/255 of a level, so the levels' own
+                    // lumas come out flat, spread the threshold over a step
+                    let t = (BAYER[y % 4][x % 4] as u32 * 2 + 1) * 255 / 32;
+                    let v = *p as u32 * max;
+                    *p = (v / 255) as u8 + u8::from(v % 255 > t);
+                }
+            }
+        }
+        Dither::ErrorDiffusion => {
+            // only this row and the next need their errors, padded for the edges
+            let mut this_err = vec![0i32; w + 2];
+            let mut next_err = vec![0i32; w + 2];
+            for row in image.data.chunks_exact_mut(w) {
+                for (x, p) in row.iter_mut().enumerate() {
+                    let want = *p as i32 + this_err[x + 1] / 16;
+                    let level = nearest_level(want);
+                    let err = want - level_luma(level) as i32;
+                    this_err[x + 2] += err * 7;
+                    next_err[x] += err * 3;
+                    next_err[x + 1] += err * 5;
+                    next_err[x + 2] += err;
+                    *p = level;
+                }
+                std::mem::swap(&mut this_err, &mut next_err);
+                next_err.fill(0);
+            }
+        }
+    }
+}
+
+/// draw a dithered image with its top left corner at `origin`
+pub fn draw_dithered<D>(display: &mut D, image: &GrayImage, origin: Point) -> Result<(), D::Error>
+where
+    D: DrawTarget,
+    D::Color: GrayColor + From<Gray8>,
+{
+    let area = Rectangle::new(origin, Size::new(image.width, image.height));
+    display.fill_contiguous(
+        &area,
+        image
+            .data
+            .iter()
+            .map(|level| Gray8::new(level_luma(*level)).into()),
+    )
+}
+
+/// draw the `part` of a dithered image, with its top left corner at `origin`
+pub fn draw_dithered_part<D>(
+    display: &mut D,
+    image: &GrayImage,
+    part: &Rectangle,
+    origin: Point,
+) -> Result<(), D::Error>
+where
+    D: DrawTarget,
+    D::Color: GrayColor + From<Gray8>,
+{
+    let bounds = Rectangle::new(Point::zero(), Size::new(image.width, image.height));
+    let part = part.intersection(&bounds);
+    if part.is_zero_sized() {
+        return Ok(());
+    }
+    let (x, w) = (part.top_left.x as usize, part.size.width as usize);
+    let rows = part.rows().map(|y| y as usize);
+    display.fill_contiguous(
+        &Rectangle::new(origin, part.size),
+        rows.flat_map(|y| {
+            let start = y * image.width as usize + x;
+            image.data[start..start + w]
+                .iter()
+                .map(|level| Gray8::new(level_luma(*level)).into())
+        }),
+    )
+}
+
+/// fit an image in `area`, centered, then dither and draw it
+///
+/// Images are decoded to fit already, `enlarge` scales small images up to
+/// fill the area, used for covers and the image viewer.
+pub fn draw_image<D>(
+    display: &mut D,
+    mut image: GrayImage,
+    area: &Rectangle,
+    method: Dither,
+    enlarge: bool,
+) -> Result<(), D::Error>
+where
+    D: DrawTarget,
+    D::Color: GrayColor + From<Gray8>,
+{
+    if enlarge && image.width < area.size.width && image.height < area.size.height {
+        let (w, h) = fit_larger(image.width, image.height, area.size.width, area.size.height);
+        // too large to enlarge, shown at its own size
+        if let Ok(larger) = image.resized(w, h) {
+            image = larger;
+        }
+    }
+    dither(&mut image, method);
+    let x = (area.size.width.saturating_sub(image.width) / 2) as i32;
+    let y = (area.size.height.saturating_sub(image.height) / 2) as i32;
+    draw_dithered(display, &image, area.top_left + Point::new(x, y))
+}
+
+// the size a small image is enlarged to, keeping its aspect
+fn fit_larger(width: u32, height: u32, max_width: u32, max_height: u32) -> (u32, u32) {
+    if width as u64 * max_height as u64 > max_width as u64 * height as u64 {
+        (
+            max_width,
+            (height as u64 * max_width as u64 / width.max(1) as u64) as u32,
+        )
+    } else {
+        (
+            (width as u64 * max_height as u64 / height.max(1) as u64) as u32,
+            max_height,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a left to right gradient, black to white
+    fn gradient(height: u32) -> GrayImage {
+        let mut image = GrayImage::new(256, height).unwrap();
+        for row in image.data.chunks_exact_mut(256) {
+            for (x, p) in row.iter_mut().enumerate() {
+                *p = x as u8;
+            }
+        }
+        image
+    }
+
+    // the mean luma of the levels in columns `from..to`
+    fn mean_luma(image: &GrayImage, from: usize, to: usize) -> f32 {
+        let w = image.width as usize;
+        let lumas: Vec<u32> = image
+            .data
+            .chunks_exact(w)
+            .flat_map(|row| row[from..to].iter().map(|l| level_luma(*l) as u32))
+            .collect();
+        lumas.iter().sum::<u32>() as f32 / lumas.len() as f32
+    }
+
+    #[test]
+    fn gradient_keeps_its_tone() {
+        for method in [Dither::Ordered, Dither::ErrorDiffusion] {
+            let mut image = gradient(16);
+            dither(&mut image, method);
+            assert!(image.data.iter().all(|l| *l < GRAY_LEVELS), "{:?}", method);
+            for row in image.data.chunks_exact(256) {
+                assert_eq!((row[0], row[255]), (0, GRAY_LEVELS - 1), "{:?}", method);
+            }
+            // each band of 32 columns averages to the band's own luma
+            for band in 0..8 {
+                let want = band as f32 * 32.0 + 15.5;
+                let got = mean_luma(&image, band * 32, band * 32 + 32);
+                assert!((got - want).abs() < 8.0, "{:?} {} {}", method, band, got);
+            }
+        }
+    }
+
+    #[test]
+    fn levels_stay_put() {
+        for method in [Dither::Ordered, Dither::ErrorDiffusion] {
+            let mut image = GrayImage::new(GRAY_LEVELS as u32, 4).unwrap();
+            for row in image.data.chunks_exact_mut(GRAY_LEVELS as usize) {
+                for (l, p) in row.iter_mut().enumerate() {
+                    *p = level_luma(l as u8);
+                }
+            }
+            dither(&mut image, method);
+            for row in image.data.chunks_exact(GRAY_LEVELS as usize) {
+                assert_eq!(row, [0, 1, 2, 3, 4, 5, 6, 7], "{:?}", method);
+            }
+        }
+    }
+
+    #[test]
+    fn error_is_spread_to_neighbours() {
+        // between levels 2 and 3, a mix of both keeps the mean
+        let mut image = GrayImage::new(32, 32).unwrap();
+        image.data.fill(100);
+        dither(&mut image, Dither::ErrorDiffusion);
+        assert!(image.data.iter().all(|l| *l == 2 || *l == 3));
+        assert!(image.data.contains(&2) && image.data.contains(&3));
+        let mean = mean_luma(&image, 0, 32);
+        assert!((mean - 100.0).abs() < 1.5, "{}", mean);
+        // the error of a pixel moves right and down, not left or up
+        let mut image = GrayImage::new(3, 2).unwrap();
+        image.data.copy_from_slice(&[100, 255, 255, 0, 0, 0]);
+        dither(&mut image, Dither::ErrorDiffusion);
+        assert_eq!(image.data, vec![3, 7, 7, 0, 0, 0]);
+    }
+
+    #[test]
+    fn nearest_levels() {
+        assert_eq!(nearest_level(-40), 0);
+        assert_eq!(nearest_level(18), 0);
+        assert_eq!(nearest_level(19), 1);
+        assert_eq!(nearest_level(300), GRAY_LEVELS - 1);
+        assert_eq!(level_luma(GRAY_LEVELS - 1), 255);
+    }
+}
@@ -0,0 +1,184 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use anyhow::{anyhow, Result};
+use std::{
+    cmp::Ordering,
+    fs::{self, File},
+    io::{BufReader, Read},
+    path::{Path, PathBuf},
+};
+
+const IMAGE_EXTENSIONS: [&str; 6] = ["png", "jpg", "jpeg", "gif", "bmp", "jpe"];
+
+/// A sequence of images viewed page by page
+pub trait ImageSource {
+    /// number of images
+    fn len(&self) -> usize;
+
+    /// are there no images
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// name of an image, for the title bar
+    fn name(&self, index: usize) -> &str;
+
+    /// read the encoded bytes of an image
+    fn read(&mut self, index: usize) -> Result<Vec<u8>>;
+}
+
+/// does the file name look like an image
+pub fn is_image_name(name: &str) -> bool {
+    Path::new(name)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| IMAGE_EXTENSIONS.iter().any(|i| e.eq_ignore_ascii_case(i)))
+        .unwrap_or(false)
+}
+
+/// compare names so `page2` comes before `page10`
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+    loop {
+        match (a.first(), b.first()) {
+            (None, None) => return Ordering::Equal,
+            (None, _) => return Ordering::Less,
+            (_, None) => return Ordering::Greater,
+            (Some(ca), Some(cb)) if ca.is_ascii_digit() && cb.is_ascii_digit() => {
+                let na = a.iter().take_while(|c| c.is_ascii_digit()).count();
+                let nb = b.iter().take_while(|c| c.is_ascii_digit()).count();
+                // compare the numbers without leading zeros, longer is larger
+                let da = trim_zeros(&a[..na]);
+                let db = trim_zeros(&b[..nb]);
+                let ord = da.len().cmp(&db.len()).then_with(|| da.cmp(db));
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+                a = &a[na..];
+                b = &b[nb..];
+            }
+            (Some(ca), Some(cb)) => {
+                let ord = ca.to_ascii_lowercase().cmp(&cb.to_ascii_lowercase());
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+                a = &a[1..];
+                b = &b[1..];
+            }
+        }
+    }
+}
+
+fn trim_zeros(digits: &[u8]) -> &[u8] {
+    let n = digits.iter().take_while(|c| **c == b'0').count();
+    &digits[n.min(digits.len().saturating_sub(1))..]
+}
+
+/// can the path be opened in the image viewer, a `.cbz` or a folder with images
+pub fn is_image_collection(path: &Path) -> bool {
+    if path.is_dir() {
+        fs::read_dir(path)
+            .map(|mut entries| {
+                entries.any(|e| {
+                    e.map(|e| is_image_name(&e.file_name().to_string_lossy()))
+                        .unwrap_or(false)
+                })
+            })
+            .unwrap_or(false)
+    } else {
+        path.extension()
+            .map(|e| e.eq_ignore_ascii_case("cbz"))
+            .unwrap_or(false)
+    }
+}
+
+/// open a `.cbz` or a folder of images
+pub fn open_image_source(path: &Path) -> Result<Box<dyn ImageSource>> {
+    let source: Box<dyn ImageSource> = if path.is_dir() {
+        Box::new(FolderSource::open(path)?)
+    } else {
+        Box::new(CbzSource::open(path)?)
+    };
+    if source.is_empty() {
+        return Err(anyhow!("no images in {}", path.display()));
+    }
+    Ok(source)
+}
+
+/// Images in a comic book zip archive
+pub struct CbzSource {
+    archive: zip::ZipArchive<BufReader<File>>,
+    names: Vec<String>,
+}
+
+impl CbzSource {
+    /// open the archive and list its images in page order
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut archive = zip::ZipArchive::new(BufReader::new(File::open(path)?))?;
+        let mut names = Vec::new();
+        for i in 0..archive.len() {
+            let entry = archive.by_index(i)?;
+            // skip the macos resource forks
+            if entry.is_file() && is_image_name(entry.name()) && !entry.name().contains("__MACOSX")
+            {
+                names.push(entry.name().to_string());
+            }
+        }
+        names.sort_by(|a, b| natural_cmp(a, b));
+        Ok(Self { archive, names })
+    }
+}
+
+impl ImageSource for CbzSource {
+    fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    fn name(&self, index: usize) -> &str {
+        &self.names[index]
+    }
+
+    fn read(&mut self, index: usize) -> Result<Vec<u8>> {
+        let mut entry = self.archive.by_name(&self.names[index])?;
+        let mut bytes = Vec::with_capacity(entry.size() as usize);
+        entry.read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+/// Images in a folder
+pub struct FolderSource {
+    dir: PathBuf,
+    names: Vec<String>,
+}
+
+impl FolderSource {
+    /// list the images in the folder in page order
+    pub fn open(dir: &Path) -> Result<Self> {
+        let mut names: Vec<String> = fs::read_dir(dir)?
+            .filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .filter(|n| is_image_name(n))
+            .collect();
+        names.sort_by(|a, b| natural_cmp(a, b));
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            names,
+        })
+    }
+}
+
+impl ImageSource for FolderSource {
+    fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    fn name(&self, index: usize) -> &str {
+        &self.names[index]
+    }
+
+    fn read(&mut self, index: usize) -> Result<Vec<u8>> {
+        Ok(fs::read(self.dir.join(&self.names[index]))?)
+    }
+}
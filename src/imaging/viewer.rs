@@ -0,0 +1,429 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::config::settings::ViewerSettings;
+use crate::imaging::{
+    decode::{decode_image_bytes, GrayImage, MAX_DIMENSION},
+    dither::{dither, draw_dithered_part, Dither},
+    source::ImageSource,
+};
+use crate::reader::location::ContentLoc;
+use crate::ui::touch::{PinchSteps, TouchEvent, TouchEventKind};
+use anyhow::Result;
+use embedded_graphics::{
+    mono_font::{iso_8859_1::FONT_10X20, MonoTextStyle},
+    pixelcolor::{Gray8, GrayColor},
+    prelude::*,
+    primitives::{PrimitiveStyle, Rectangle},
+    text::{Baseline, Text},
+};
+use log::*;
+
+// taps in the top bar close the viewer
+const TOP_BAR_HEIGHT: u32 = 40;
+// zoom is a percent of the fit page size
+const ZOOM_RANGE: (u32, u32) = (100, 400);
+const ZOOM_STEP: u32 = 25;
+// pinch distance for one zoom step
+const PINCH_STEP: f32 = 20.0;
+// largest decoded image, the buffer is in SPIRAM
+const MAX_PIXELS: u32 = 2_000_000;
+
+/// How images are scaled to the display
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FitMode {
+    /// whole image on the display
+    Page,
+    /// image as wide as the display, scroll down
+    Width,
+    /// percent of the fit page size, pan with a drag
+    Zoom(u32),
+}
+
+/// What the caller does after a touch
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ViewerAction {
+    None,
+    Redraw,
+    Close,
+}
+
+/// Views the images of a `.cbz` or folder one at a time
+///
+/// The current image is decoded for the fit mode, dithered once and kept,
+/// so a pan only redraws. The location is a `ContentLoc` with the image as
+/// the item, saved with `save_location` like a book location.
+pub struct ImageViewer {
+    source: Box<dyn ImageSource>,
+    index: usize,
+    mode: FitMode,
+    view: Size,
+    right_to_left: bool,
+    dither: Dither,
+    image: Option<GrayImage>,
+    // top left of the visible part of the image
+    offset: Point,
+    // after a goto, the image starts at its beginning when going forward
+    // and at its end going back, set once the size is known
+    start_forward: Option<bool>,
+    // last touch point of a drag
+    drag_from: Option<Point>,
+    pinch: PinchSteps,
+}
+
+impl ImageViewer {
+    /// create a viewer for a display size, starting at a stored location
+    pub fn new(
+        source: Box<dyn ImageSource>,
+        width: u32,
+        height: u32,
+        settings: &ViewerSettings,
+        start: ContentLoc,
+    ) -> Self {
+        let index = (start.item as usize).min(source.len().saturating_sub(1));
+        Self {
+            source,
+            index,
+            mode: FitMode::Page,
+            view: Size::new(width, height),
+            right_to_left: settings.right_to_left,
+            dither: if settings.smooth_dither {
+                Dither::ErrorDiffusion
+            } else {
+                Dither::Ordered
+            },
+            image: None,
+            offset: Point::zero(),
+            start_forward: None,
+            drag_from: None,
+            pinch: PinchSteps::new(PINCH_STEP),
+        }
+    }
+
+    /// the location to store
+    pub fn location(&self) -> ContentLoc {
+        ContentLoc::new(self.index as u32, 0)
+    }
+
+    /// index of the current image
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// the fit mode
+    pub fn mode(&self) -> FitMode {
+        self.mode
+    }
+
+    /// change the fit mode
+    pub fn set_mode(&mut self, mode: FitMode) {
+        if mode != self.mode {
+            self.mode = mode;
+            self.image = None;
+        }
+    }
+
+    /// change the display size, after a rotation
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.view = Size::new(width, height);
+        self.image = None;
+    }
+
+    /// go to an image
+    pub fn goto(&mut self, index: usize) -> bool {
+        if index >= self.source.len() || index == self.index {
+            return false;
+        }
+        let forward = index > self.index;
+        self.index = index;
+        self.image = None;
+        self.offset = Point::zero();
+        self.start_forward = Some(forward);
+        true
+    }
+
+    // start a scrolled image where reading starts
+    fn start_offset(&self, forward: bool) -> Point {
+        let max = self.max_offset();
+        if forward {
+            Point::new(if self.right_to_left { max.x } else { 0 }, 0)
+        } else {
+            Point::new(if self.right_to_left { 0 } else { max.x }, max.y)
+        }
+    }
+
+    // next image, or scroll down a screen first when the image is taller
+    fn advance(&mut self, forward: bool) -> bool {
+        let max = self.max_offset();
+        let step = self.view.height.saturating_sub(TOP_BAR_HEIGHT).max(1) as i32;
+        if forward && self.offset.y < max.y {
+            self.offset.y = self.offset.y.saturating_add(step).min(max.y);
+            return true;
+        }
+        if !forward && self.offset.y > 0 {
+            self.offset.y = self.offset.y.saturating_sub(step).max(0);
+            return true;
+        }
+        if forward {
+            self.goto(self.index + 1)
+        } else {
+            self.index > 0 && self.goto(self.index - 1)
+        }
+    }
+
+    // the largest offset that keeps the display covered
+    fn max_offset(&self) -> Point {
+        match &self.image {
+            Some(image) => Point::new(
+                image.width.saturating_sub(self.view.width) as i32,
+                image.height.saturating_sub(self.view.height) as i32,
+            ),
+            None => Point::zero(),
+        }
+    }
+
+    fn clamp_offset(&mut self) {
+        let max = self.max_offset();
+        self.offset = Point::new(self.offset.x.clamp(0, max.x), self.offset.y.clamp(0, max.y));
+    }
+
+    // the decode bounds for the fit mode
+    fn decode_size(&self) -> (u32, u32) {
+        let (w, h) = (self.view.width, self.view.height);
+        let (w, h) = match self.mode {
+            FitMode::Page => (w, h),
+            // a very tall strip still fits the pixel limit below
+            FitMode::Width => (w, MAX_PIXELS / w.max(1)),
+            FitMode::Zoom(percent) => (w * percent / 100, h * percent / 100),
+        };
+        // keep the buffer in bounds, scaling both sides alike
+        let pixels = w as u64 * h as u64;
+        if pixels > MAX_PIXELS as u64 {
+            let scale = (MAX_PIXELS as f64 / pixels as f64).sqrt();
+            ((w as f64 * scale) as u32, (h as f64 * scale) as u32)
+        } else {
+            (w, h)
+        }
+    }
+
+    // decode and dither the current image if needed
+    fn load(&mut self) -> Result<()> {
+        if self.image.is_some() {
+            return Ok(());
+        }
+        let bytes = self.source.read(self.index)?;
+        let (max_w, max_h) = self.decode_size();
+        let mut image = decode_image_bytes(&bytes, max_w, max_h)?;
+        drop(bytes);
+        if self.mode == FitMode::Width && image.width < self.view.width {
+            let h = image.height as u64 * self.view.width as u64 / image.width.max(1) as u64;
+            image = image.resized(self.view.width, h.min(MAX_DIMENSION as u64) as u32)?;
+        }
+        debug!(
+            "image {} {}: {}x{}",
+            self.index,
+            self.source.name(self.index),
+            image.width,
+            image.height
+        );
+        dither(&mut image, self.dither);
+        self.image = Some(image);
+        match self.start_forward.take() {
+            Some(forward) => self.offset = self.start_offset(forward),
+            None => self.clamp_offset(),
+        }
+        Ok(())
+    }
+
+    fn zoom(&mut self, evt: &TouchEvent) -> bool {
+        let steps = self.pinch.steps(evt);
+        if steps == 0 {
+            return false;
+        }
+        let percent = match self.mode {
+            FitMode::Zoom(p) => p,
+            _ => ZOOM_RANGE.0,
+        };
+        let percent = (percent as i64 + steps as i64 * ZOOM_STEP as i64)
+            .clamp(ZOOM_RANGE.0 as i64, ZOOM_RANGE.1 as i64) as u32;
+        let mode = if percent == ZOOM_RANGE.0 {
+            FitMode::Page
+        } else {
+            FitMode::Zoom(percent)
+        };
+        if mode == self.mode {
+            return false;
+        }
+        // keep the center of the view in place
+        let old = self.image.as_ref().map(|i| i.width).unwrap_or(1).max(1) as i64;
+        let half = (self.view.width as i64 / 2, self.view.height as i64 / 2);
+        let center = (self.offset.x as i64 + half.0, self.offset.y as i64 + half.1);
+        self.set_mode(mode);
+        if self.load().is_ok() {
+            let new = self.image.as_ref().map(|i| i.width).unwrap_or(1) as i64;
+            // in i64 then clamped, the offsets are at most MAX_DIMENSION
+            let scale = |c: i64, h: i64| (c * new / old - h).clamp(0, i32::MAX as i64) as i32;
+            self.offset = Point::new(scale(center.0, half.0), scale(center.1, half.1));
+            self.clamp_offset();
+        }
+        true
+    }
+
+    /// handle a touch event
+    pub fn touch(&mut self, evt: &TouchEvent) -> ViewerAction {
+        let changed = match evt.kind() {
+            TouchEventKind::Tap if evt.y() < TOP_BAR_HEIGHT => return ViewerAction::Close,
+            TouchEventKind::Tap => {
+                let third = self.view.width / 3;
+                if evt.x() < third {
+                    self.advance(self.right_to_left)
+                } else if evt.x() >= self.view.width - third {
+                    self.advance(!self.right_to_left)
+                } else {
+                    // the middle cycles the fit modes
+                    self.set_mode(match self.mode {
+                        FitMode::Page => FitMode::Width,
+                        _ => FitMode::Page,
+                    });
+                    true
+                }
+            }
+            TouchEventKind::SwipeLeft => self.advance(!self.right_to_left),
+            TouchEventKind::SwipeRight => self.advance(self.right_to_left),
+            TouchEventKind::PinchEnlarge | TouchEventKind::PinchReduce => self.zoom(evt),
+            TouchEventKind::Hold => {
+                self.drag_from = Some(Point::new(evt.x() as i32, evt.y() as i32));
+                false
+            }
+            TouchEventKind::Drag => {
+                let to = Point::new(evt.x() as i32, evt.y() as i32);
+                match self.drag_from.replace(to) {
+                    Some(from) => {
+                        let before = self.offset;
+                        self.offset -= to - from;
+                        self.clamp_offset();
+                        self.offset != before
+                    }
+                    None => false,
+                }
+            }
+            TouchEventKind::Release => {
+                self.drag_from = None;
+                self.pinch.steps(evt);
+                false
+            }
+            _ => false,
+        };
+        if changed {
+            ViewerAction::Redraw
+        } else {
+            ViewerAction::None
+        }
+    }
+
+    /// draw the visible part of the current image and the image number
+    pub fn draw<D>(&mut self, display: &mut D) -> Result<()>
+    where
+        D: DrawTarget,
+        D::Color: GrayColor + From<Gray8>,
+    {
+        self.load()?;
+        let draw_err = |_| anyhow::anyhow!("draw failed");
+        display.clear(D::Color::WHITE).map_err(draw_err)?;
+        if let Some(image) = &self.image {
+            // center an image smaller than the display
+            let origin = Point::new(
+                (self.view.width.saturating_sub(image.width) / 2) as i32,
+                (self.view.height.saturating_sub(image.height) / 2) as i32,
+            );
+            let part = Rectangle::new(self.offset, self.view);
+            draw_dithered_part(display, image, &part, origin).map_err(draw_err)?;
+        }
+        let label = format!(" {} / {} ", self.index + 1, self.source.len());
+        let size = Size::new(label.len() as u32 * 10, 20);
+        let corner = Point::new(
+            self.view.width.saturating_sub(size.width) as i32,
+            self.view.height.saturating_sub(size.height) as i32,
+        );
+        Rectangle::new(corner, size)
+            .into_styled(PrimitiveStyle::with_fill(D::Color::WHITE))
+            .draw(display)
+            .map_err(draw_err)?;
+        Text::with_baseline(
+            &label,
+            corner,
+            MonoTextStyle::new(&FONT_10X20, D::Color::BLACK),
+            Baseline::Top,
+        )
+        .draw(display)
+        .map_err(draw_err)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::bmp;
+
+    // gray images of the given sizes
+    struct Sizes(Vec<(i32, i32)>);
+
+    impl ImageSource for Sizes {
+        fn len(&self) -> usize {
+            self.0.len()
+        }
+
+        fn name(&self, _index: usize) -> &str {
+            "image"
+        }
+
+        fn read(&mut self, index: usize) -> Result<Vec<u8>> {
+            let (w, h) = self.0[index];
+            Ok(bmp(w, h, &vec![[128; 3]; (w * h) as usize]))
+        }
+    }
+
+    fn viewer(sizes: Vec<(i32, i32)>) -> ImageViewer {
+        let settings = ViewerSettings::default();
+        ImageViewer::new(
+            Box::new(Sizes(sizes)),
+            100,
+            100,
+            &settings,
+            ContentLoc::new(0, 0),
+        )
+    }
+
+    #[test]
+    fn back_to_a_tall_image_starts_at_its_end() {
+        let mut v = viewer(vec![(50, 400), (100, 100)]);
+        v.set_mode(FitMode::Width);
+        v.goto(1);
+        v.load().unwrap();
+        assert_eq!(v.offset, Point::zero());
+        assert!(v.goto(0));
+        v.load().unwrap();
+        // 50x400 fit to 100 wide is 800 tall
+        assert_eq!(v.offset, Point::new(0, 700));
+        assert!(v.advance(false));
+        assert_eq!(v.offset, Point::new(0, 640));
+        assert!(v.goto(1));
+        v.load().unwrap();
+        assert_eq!(v.offset, Point::zero());
+    }
+
+    #[test]
+    fn zoom_keeps_offsets_in_the_image() {
+        let mut v = viewer(vec![(1000, 1000)]);
+        v.load().unwrap();
+        // a small move doesn't zoom, a long one zooms to the limit
+        assert!(!v.zoom(&TouchEvent::pinch(TouchEventKind::PinchEnlarge, 10.0)));
+        let enlarge = TouchEvent::pinch(TouchEventKind::PinchEnlarge, 1000.0);
+        assert!(v.zoom(&enlarge));
+        assert_eq!(v.mode(), FitMode::Zoom(ZOOM_RANGE.1));
+        let max = v.max_offset();
+        assert!((0..=max.x).contains(&v.offset.x) && (0..=max.y).contains(&v.offset.y));
+        assert_eq!(v.offset, Point::new(150, 150));
+    }
+}
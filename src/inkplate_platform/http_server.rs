@@ -0,0 +1,112 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::net::file_manager::{Credentials, FileManager, TOKEN_HEADER};
+use anyhow::Result;
+use embedded_svc::http::Method;
+use esp_idf_svc::http::server::{Configuration, EspHttpServer};
+use log::*;
+use std::{
+    fmt::Debug,
+    io,
+    path::Path,
+    sync::{mpsc, Arc, Mutex},
+};
+
+// the handlers copy files, so need more stack than the default
+const STACK_SIZE: usize = 10240;
+
+/// a new random pin and session token for the file manager
+pub fn new_credentials() -> Credentials {
+    // the hardware rng is random once the radio is on
+    let random = || unsafe { esp_idf_svc::sys::esp_random() };
+    Credentials {
+        pin: format!("{:06}", random() % 1_000_000),
+        token: (0..4).map(|_| format!("{:08x}", random())).collect(),
+    }
+}
+
+/// start the file manager web server on port 80
+///
+/// the wifi must be up, the pin in `credentials` should be on the display
+/// for the user to log in with. `library_ch` gets a message after files
+/// change so the library can be indexed again. The server stops when dropped.
+pub fn start_file_server(
+    root: &Path,
+    credentials: Credentials,
+    library_ch: mpsc::Sender<()>,
+) -> Result<EspHttpServer> {
+    let manager = Arc::new(FileManager::new(root, credentials));
+    let config = Configuration {
+        stack_size: STACK_SIZE,
+        uri_match_wildcard: true,
+        ..Default::default()
+    };
+    let mut server = EspHttpServer::new(&config)?;
+    let methods = [
+        (Method::Get, "GET"),
+        (Method::Put, "PUT"),
+        (Method::Post, "POST"),
+        (Method::Delete, "DELETE"),
+    ];
+    for (method, name) in methods {
+        let manager = manager.clone();
+        let library_ch = Mutex::new(library_ch.clone());
+        server.fn_handler("/*", method, move |mut req| {
+            let uri = req.uri().to_string();
+            debug!("http {} {}", name, uri);
+            let token = req.header(TOKEN_HEADER).map(|t| t.to_string());
+            let response = manager.handle(name, &uri, token.as_deref(), &mut IoReader(&mut req));
+            if response.library_changed {
+                let _ = library_ch.lock().unwrap().send(());
+            }
+            let disposition = response
+                .file_name
+                .as_ref()
+                .map(|n| format!("attachment; filename=\"{}\"", n.replace('"', "")));
+            let mut headers = vec![("Content-Type", response.content_type)];
+            if let Some(disposition) = &disposition {
+                headers.push(("Content-Disposition", disposition.as_str()));
+            }
+            let mut resp =
+                req.into_response(response.status, Some(response.status_message()), &headers)?;
+            response.write_body(&mut IoWriter(&mut resp))?;
+            Ok(())
+        })?;
+    }
+    info!("file server started");
+    Ok(server)
+}
+
+// std reads from an embedded-svc reader, for the request body
+struct IoReader<'a, R>(&'a mut R);
+
+impl<'a, R> io::Read for IoReader<'a, R>
+where
+    R: embedded_svc::io::Read,
+    R::Error: Debug,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        embedded_svc::io::Read::read(self.0, buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))
+    }
+}
+
+// std writes to an embedded-svc writer, for the response body
+struct IoWriter<'a, W>(&'a mut W);
+
+impl<'a, W> io::Write for IoWriter<'a, W>
+where
+    W: embedded_svc::io::Write,
+    W::Error: Debug,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        embedded_svc::io::Write::write(self.0, buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        embedded_svc::io::Write::flush(self.0)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))
+    }
+}
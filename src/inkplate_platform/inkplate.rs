@@ -1,10 +1,17 @@
 // Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
+use crate::config::settings::Settings;
+use crate::console::command::{RtcTime, LIGHT_ADDRESS, MAX_LIGHT, RTC_ADDRESS, RTC_TIME_REGISTER};
 use crate::inkplate_platform::battery::BatteryMonitor;
+use crate::inkplate_platform::settings_store::{SettingsStore, SETTINGS_MIRROR};
+use crate::ui::canvas::Canvas;
 use anyhow::{anyhow, Result};
 use core::num::NonZeroU32;
+use embedded_graphics::prelude::*;
+use embedded_hal::blocking::i2c::{Write, WriteRead};
 use esp_idf_svc::{
+    eventloop::EspSystemEventLoop,
     hal::{
         adc::config::Config,
         adc::*,
@@ -12,15 +19,17 @@ use esp_idf_svc::{
         gpio::{self, Input, InterruptType, PinDriver},
         i2c::{I2cConfig, I2cDriver},
         interrupt,
+        modem::Modem,
         peripherals::Peripherals,
         prelude::*,
         task,
     },
+    nvs::EspDefaultNvsPartition,
     sys,
 };
 use inkplate_drivers::{
     eink::{
-        config::Builder,
+        config::{Builder, Config as EinkConfig},
         display::{self, ColorDepth, Display},
         eink_reg_update::EinkGpioHelper,
         graphics::GraphicDisplayGray3Bit,
@@ -29,10 +38,10 @@ use inkplate_drivers::{
     },
     front_light::FrontLight,
     multiplexer::{Multiplexer, OutputPinProxy, PinName},
-    rtc::Rtc,
     touch_sensor::TouchSensor,
 };
 use log::*;
+use std::{fs, path::Path, sync::mpsc};
 
 //////////////////////////////////////////////////////////////////////////////////////
 // Define some types to shorten declarations
@@ -84,12 +93,22 @@ pub struct InkPlateDevices<'a> {
     pub adc1: Option<AdcDriver<'a, ADC1>>,
     pub bat_mon: Option<BatteryMonitor<MplexOutputPin<'a>>>,
     pub front_light: Option<FrontLight<'a, I2c0, MplexOutputPin<'a>>>,
-    pub rtc: Option<Rtc<'a, I2c0>>,
     pub graphics: Option<Graphics<'a>>,
+    pub nvs: Option<EspDefaultNvsPartition>,
+    pub settings_store: Option<SettingsStore>,
+    pub settings: Option<Settings>,
+    pub modem: Option<Modem>,
+    pub sysloop: Option<EspSystemEventLoop>,
+    /// did the sdcard mount
+    pub sdcard_mounted: bool,
 }
 
 /// static variable to hold touch sensor task id, for notifications
 pub static mut TOUCH_SENSOR_TASK_ID: Option<sys::TaskHandle_t> = None;
+/// notification bit of the touch sensor interrupt
+pub const TOUCH_NOTICE: u32 = 1;
+/// notification bit of a new display config for the touch task
+pub const DISPLAY_CONFIG_NOTICE: u32 = 2;
 
 /// main function to setup the InkPlate hardware
 pub fn inkplate_setup() -> Result<InkPlateDevices<'static>> {
@@ -99,6 +118,13 @@ pub fn inkplate_setup() -> Result<InkPlateDevices<'static>> {
 
     // grab some hardware
     let dp = Peripherals::take().unwrap();
+
+    // the settings in nvs, the sdcard mirror is checked once the card is mounted
+    let nvs = EspDefaultNvsPartition::take()?;
+    // the wifi radio is left off, it is started on demand
+    let sysloop = EspSystemEventLoop::take()?;
+    let mut settings_store = SettingsStore::new(nvs.clone(), Path::new(SETTINGS_MIRROR))?;
+    let sd_pins = settings_store.load_stored().unwrap_or_default().sdcard;
     let i2c0 = dp.i2c0;
     let sda = dp.pins.gpio21;
     let scl = dp.pins.gpio22;
@@ -120,7 +146,7 @@ pub fn inkplate_setup() -> Result<InkPlateDevices<'static>> {
                 if let Some(id) = TOUCH_SENSOR_TASK_ID {
                     // if we have a task id in the static variable, then thread is ready
                     // so notify it to wake up
-                    task::notify(id, NonZeroU32::new(1u32).unwrap());
+                    task::notify(id, NonZeroU32::new(TOUCH_NOTICE).unwrap());
                 }
             }
         })?;
@@ -147,17 +173,16 @@ pub fn inkplate_setup() -> Result<InkPlateDevices<'static>> {
     let mut front_light = FrontLight::new(i2c_bus0.acquire_i2c(), light_en);
     front_light.setup()?;
 
-    // rtc
-    let rtc = Rtc::new(i2c_bus0.acquire_i2c());
-
     // initialize the sdcard, which includes the dedicated SPI bus
-    unsafe {
-        let good = sys::sdcard_setup();
-        info!("sdcard setup: {}", good);
-    }
+    let sdcard_mounted =
+        unsafe { sys::sdcard_setup(sd_pins.miso, sd_pins.mosi, sd_pins.clk, sd_pins.cs) };
+    info!("sdcard setup: {}", sdcard_mounted);
     std::env::set_var("TMPDIR", "/sdcard/tmp");
     info!("temp_dir: {:?}", std::env::temp_dir());
 
+    // now the full settings
+    let settings = settings_store.load()?;
+
     // now the eink multiplexer pins
     let oe = unsafe { mplex.take_pin(PinName::EpdOe)?.into_output()? };
     let gmod = unsafe { mplex.take_pin(PinName::EpdGmode)?.into_output()? };
@@ -207,7 +232,7 @@ pub fn inkplate_setup() -> Result<InkPlateDevices<'static>> {
         .dimensions(display::Dimensions::R1024x758)
         .depth(ColorDepth::Gray3Bit)
         //.depth(ColorDepth::BW)
-        .rotation(display::Rotation::Rotate270)
+        .rotation(rotation_from_degrees(settings.display.rotation))
         .build()
         .map_err(|e| anyhow!("unable to create eink config: {:?}", e))?;
     // the 3bit graphics interface
@@ -227,10 +252,172 @@ pub fn inkplate_setup() -> Result<InkPlateDevices<'static>> {
         bat_mon: Some(bat_mon),
         front_light: Some(front_light),
         graphics: Some(graphics),
-        rtc: Some(rtc),
+        nvs: Some(nvs),
+        settings_store: Some(settings_store),
+        settings: Some(settings),
+        modem: Some(dp.modem),
+        sysloop: Some(sysloop),
+        sdcard_mounted,
     })
 }
 
+/// check the hardware a new firmware needs, for `ota::self_check`
+///
+/// the display was initialized by the setup, this checks the touch
+/// sensor answers and the sdcard is mounted and readable
+pub fn hardware_check(devices: &mut InkPlateDevices) -> bool {
+    let display = devices.graphics.is_some();
+    let touch = match devices.touch_sensor.as_mut().map(|t| t.resolution()) {
+        Some(Ok(_)) => true,
+        Some(Err(e)) => {
+            error!("touch sensor check failed: {:?}", e);
+            false
+        }
+        None => false,
+    };
+    let sdcard = devices.sdcard_mounted && fs::read_dir("/sdcard").is_ok();
+    info!(
+        "hardware check: display {}, touch {}, sdcard {}",
+        display, touch, sdcard
+    );
+    display && touch && sdcard
+}
+
+/// change the display rotation at runtime
+///
+/// the new config is sent to the touch thread so touches keep matching
+/// the display, the caller lays out and redraws the current page
+pub fn set_display_rotation(
+    graphics: &mut Graphics,
+    degrees: u16,
+    display_config_ch: &mpsc::Sender<EinkConfig>,
+) -> Result<EinkConfig> {
+    info!("display rotation {} degrees", degrees);
+    graphics.set_rotation(rotation_from_degrees(degrees));
+    let config = graphics.config();
+    display_config_ch.send(config)?;
+    // wake the touch task, it takes the config before the next touch
+    interrupt::free(|| unsafe {
+        if let Some(id) = TOUCH_SENSOR_TASK_ID {
+            task::notify(id, NonZeroU32::new(DISPLAY_CONFIG_NOTICE).unwrap());
+        }
+    });
+    Ok(config)
+}
+
+/// copy what changed on the canvas to the display, and refresh it
+pub fn refresh(graphics: &mut Graphics, canvas: &mut Canvas) -> Result<()> {
+    let Some(area) = canvas.take_dirty() else {
+        return Ok(());
+    };
+    graphics
+        .fill_contiguous(&area, canvas.pixels(&area).map(Into::into))
+        .map_err(|e| anyhow!("display draw failed: {:?}", e))?;
+    // the 3 bit mode has no partial update, the whole panel is refreshed.
+    // the driver's 1 bit mode has one, but switching modes for a popup
+    // would take the grays of the page under it
+    graphics
+        .display(&mut delay::Ets)
+        .map_err(|e| anyhow!("display refresh failed: {:?}", e))
+}
+
+/// read the time from the rtc
+///
+/// the PCF85063A time registers are read directly, so the time is a plain
+/// `RtcTime` whatever the driver returns
+pub fn rtc_time(i2c0bus: I2cBus0) -> Result<RtcTime> {
+    let mut regs = [0u8; 7];
+    i2c0bus
+        .acquire_i2c()
+        .write_read(RTC_ADDRESS, &[RTC_TIME_REGISTER], &mut regs)
+        .map_err(|e| anyhow!("rtc read failed: {:?}", e))?;
+    RtcTime::from_registers(&regs).ok_or_else(|| anyhow!("the rtc hasn't been set"))
+}
+
+/// set the system clock, so `SystemTime` gives the rtc's time
+pub fn set_system_time(time: &RtcTime) -> Result<()> {
+    let tv = sys::timeval {
+        tv_sec: time.unix_time() as _,
+        tv_usec: 0,
+    };
+    if unsafe { sys::settimeofday(&tv, std::ptr::null()) } != 0 {
+        return Err(anyhow!("unable to set the system time"));
+    }
+    Ok(())
+}
+
+/// set the rtc, in utc
+pub fn set_rtc_time(i2c0bus: I2cBus0, time: &RtcTime) -> Result<()> {
+    let mut buf = [0u8; 8];
+    buf[0] = RTC_TIME_REGISTER;
+    buf[1..].copy_from_slice(&time.registers());
+    i2c0bus
+        .acquire_i2c()
+        .write(RTC_ADDRESS, &buf)
+        .map_err(|e| anyhow!("rtc write failed: {:?}", e))
+}
+
+/// set the front light level, 0 to `MAX_LIGHT`
+///
+/// the MCP4018 potentiometer is written directly, the light is enabled by
+/// `FrontLight::setup`, a higher wiper value is a dimmer light
+pub fn set_front_light(i2c0bus: I2cBus0, level: u8) -> Result<()> {
+    let wiper = MAX_LIGHT - level.min(MAX_LIGHT);
+    i2c0bus
+        .acquire_i2c()
+        .write(LIGHT_ADDRESS, &[0, wiper])
+        .map_err(|e| anyhow!("front light write failed: {:?}", e))
+}
+
+/// give the threads the calling thread spawns their stacks from psram
+///
+/// the app's pagination and calibre threads open zipped books, the
+/// inflate state is made on the stack before it's boxed, internal ram
+/// can't spare that. Threads with such stacks must not write the flash,
+/// its cache is off then.
+pub fn spawn_stacks_in_psram() -> Result<()> {
+    let mut cfg = unsafe { sys::esp_pthread_get_default_config() };
+    cfg.stack_alloc_caps = sys::MALLOC_CAP_SPIRAM | sys::MALLOC_CAP_8BIT;
+    let result = unsafe { sys::esp_pthread_set_cfg(&cfg) };
+    if result != sys::ESP_OK {
+        return Err(anyhow!("can't set the thread stack caps: {}", result));
+    }
+    Ok(())
+}
+
+/// free and total bytes on the sdcard
+pub fn card_space() -> Result<(u64, u64)> {
+    let (mut total, mut free) = (0u64, 0u64);
+    let result =
+        unsafe { sys::esp_vfs_fat_info(b"/sdcard\0".as_ptr() as *const _, &mut total, &mut free) };
+    if result != sys::ESP_OK {
+        return Err(anyhow!("can't read the sdcard space: {}", result));
+    }
+    Ok((free, total))
+}
+
+/// width and height of the display in user coordinates, after rotation
+pub fn user_size(config: &EinkConfig) -> (u32, u32) {
+    let (w, h) = (
+        config.dimensions.width() as u32,
+        config.dimensions.height() as u32,
+    );
+    match config.rotation {
+        display::Rotation::Rotate90 | display::Rotation::Rotate270 => (h, w),
+        _ => (w, h),
+    }
+}
+
+/// the display rotation for a rotation in degrees
+pub fn rotation_from_degrees(degrees: u16) -> display::Rotation {
+    match degrees {
+        0 => display::Rotation::Rotate0,
+        90 => display::Rotation::Rotate90,
+        180 => display::Rotation::Rotate180,
+        _ => display::Rotation::Rotate270,
+    }
+}
+
 /// register a task for the touch sensor interrupt
 ///
 /// this is called from the task to register, following
@@ -0,0 +1,248 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::net::http::HttpClient;
+use crate::update::firmware::{
+    self, AppInfo, ImageVerifier, FAILED_FILE, FIRMWARE_FILE, FIRMWARE_SHA_FILE, INSTALLED_FILE,
+};
+use crate::update::remote::{self, Fetcher, Manifest};
+use anyhow::{anyhow, Result};
+use embedded_svc::{http::Method, io::Write, ota::SlotState};
+use esp_idf_svc::{
+    hal::reset,
+    http::client::{Configuration, EspHttpConnection, FollowRedirectsPolicy},
+    ota::EspOta,
+    sys,
+};
+use log::*;
+use std::{
+    fs::{self, File},
+    io::{self, Read},
+    path::Path,
+    time::Duration,
+};
+
+// how long to wait on an https server
+const HTTPS_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// the project name and version of the running firmware
+pub fn running_firmware() -> Result<(String, String)> {
+    let ota = EspOta::new()?;
+    let slot = ota.get_running_slot()?;
+    let info = slot
+        .firmware
+        .ok_or_else(|| anyhow!("no firmware info for slot {}", slot.label))?;
+    let project_name = info.description.as_deref().unwrap_or("").to_string();
+    Ok((project_name, info.version.to_string()))
+}
+
+/// mark a new image good or roll back to the last one
+///
+/// called once the board is set up, `healthy` says the display, touch
+/// and sdcard came up. An image that isn't marked is rolled back by the
+/// bootloader on the next reset, so a crash before this also rolls back.
+pub fn self_check(healthy: bool) -> Result<()> {
+    let mut ota = EspOta::new()?;
+    let slot = ota.get_running_slot()?;
+    if slot.state != SlotState::Unverified {
+        debug!("running slot {} is {:?}", slot.label, slot.state);
+        return Ok(());
+    }
+    if healthy {
+        info!("new firmware in {} passed its self check", slot.label);
+        ota.mark_running_slot_valid()?;
+        Ok(())
+    } else {
+        error!("new firmware in {} failed its self check", slot.label);
+        Err(ota.mark_running_slot_invalid_and_reboot().into())
+    }
+}
+
+/// write an image into the inactive slot and boot it next
+///
+/// `copy` streams the image into the writer it is given, checking it on
+/// the way, see `firmware::copy_image`. Only a good image is made the
+/// boot image.
+fn install(
+    total: Option<u64>,
+    copy: impl FnOnce(&mut dyn FnMut(&[u8]) -> Result<()>) -> Result<AppInfo>,
+) -> Result<AppInfo> {
+    let slot_size = unsafe {
+        let partition = sys::esp_ota_get_next_update_partition(std::ptr::null());
+        if partition.is_null() {
+            return Err(anyhow!("no ota slot to update, check partitions.csv"));
+        }
+        (*partition).size as u64
+    };
+    if let Some(total) = total.filter(|t| *t > slot_size) {
+        return Err(anyhow!(
+            "firmware is {} bytes, the slot is {} bytes",
+            total,
+            slot_size
+        ));
+    }
+    let mut ota = EspOta::new()?;
+    let mut update = ota.initiate_update()?;
+    let mut write = |data: &[u8]| {
+        update
+            .write_all(data)
+            .map_err(|e| anyhow!("firmware write failed: {:?}", e))
+    };
+    match copy(&mut write) {
+        Ok(info) => {
+            update.complete()?;
+            info!("firmware {} {} installed", info.project_name, info.version);
+            Ok(info)
+        }
+        Err(e) => {
+            update.abort()?;
+            Err(e)
+        }
+    }
+}
+
+/// install `firmware.bin` from the ereader dir if it is there
+///
+/// the file is renamed once it is installed, is the running version or
+/// fails its checks, so it is only tried once. The caller reboots after
+/// an install.
+pub fn update_from_sd_card(
+    ereader_dir: &Path,
+    progress: &mut dyn FnMut(u64, Option<u64>),
+) -> Result<Option<AppInfo>> {
+    let path = ereader_dir.join(FIRMWARE_FILE);
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let (project_name, version) = running_firmware()?;
+    let expected = match fs::read_to_string(ereader_dir.join(FIRMWARE_SHA_FILE)) {
+        Ok(text) => Some(firmware::parse_sha256(&text)?),
+        Err(_) => None,
+    };
+    let mut file = File::open(&path)?;
+    let total = file.metadata()?.len();
+    info!("firmware update from {:?}, {} bytes", path, total);
+    // look at the header first, the same version isn't written again
+    let mut head = Vec::new();
+    file.by_ref().take(512).read_to_end(&mut head)?;
+    let image = match firmware::parse_header(&head) {
+        Ok((_, _, image)) => image,
+        Err(e) => {
+            set_aside(&path, &ereader_dir.join(FAILED_FILE))?;
+            return Err(e);
+        }
+    };
+    if image.project_name == project_name && image.version == version {
+        info!("firmware {} is already running", version);
+        set_aside(&path, &ereader_dir.join(INSTALLED_FILE))?;
+        return Ok(None);
+    }
+    let mut reader = head.chain(file);
+    let verifier = ImageVerifier::new(&project_name, expected);
+    let copy = |write: &mut dyn FnMut(&[u8]) -> Result<()>| {
+        firmware::copy_image(&mut reader, Some(total), verifier, write, progress)
+    };
+    match install(Some(total), copy) {
+        Ok(info) => {
+            set_aside(&path, &ereader_dir.join(INSTALLED_FILE))?;
+            Ok(Some(info))
+        }
+        Err(e) => {
+            error!("firmware update failed: {}", e);
+            set_aside(&path, &ereader_dir.join(FAILED_FILE))?;
+            Err(e)
+        }
+    }
+}
+
+// rename the image so it isn't tried again, fat won't rename over a file
+fn set_aside(path: &Path, dest: &Path) -> Result<()> {
+    if dest.exists() {
+        fs::remove_file(dest)?;
+    }
+    fs::rename(path, dest)?;
+    Ok(())
+}
+
+/// Fetches update manifests and images, https through esp-idf's client
+/// with its certificate bundle, plain http with the `HttpClient`
+#[derive(Debug, Default)]
+pub struct EspFetcher {
+    http: HttpClient,
+}
+
+// the body of an https response
+struct HttpsBody(EspHttpConnection);
+
+impl Read for HttpsBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        embedded_svc::io::Read::read(&mut self.0, buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))
+    }
+}
+
+impl Fetcher for EspFetcher {
+    fn open(&self, url: &str) -> Result<(Box<dyn Read>, Option<u64>)> {
+        if !url.starts_with("https://") {
+            return self.http.open(url);
+        }
+        let mut conn = EspHttpConnection::new(&Configuration {
+            timeout: Some(HTTPS_TIMEOUT),
+            follow_redirects_policy: FollowRedirectsPolicy::FollowGetHead,
+            crt_bundle_attach: Some(sys::esp_crt_bundle_attach),
+            ..Default::default()
+        })?;
+        conn.initiate_request(Method::Get, url, &[])?;
+        conn.initiate_response()?;
+        if conn.status() != 200 {
+            return Err(anyhow!("http status {} for {}", conn.status(), url));
+        }
+        let len = conn.header("Content-Length").and_then(|l| l.parse().ok());
+        Ok((Box::new(HttpsBody(conn)), len))
+    }
+}
+
+/// install the release in a manifest, see `remote::check`
+///
+/// refused below the battery level of the manifest or `min_battery`,
+/// `battery` is from the `BatteryMonitor`. The caller reboots after.
+pub fn update_from_manifest(
+    fetcher: &dyn Fetcher,
+    manifest: &Manifest,
+    battery: f64,
+    min_battery: f64,
+    progress: &mut dyn FnMut(u64, Option<u64>),
+) -> Result<AppInfo> {
+    remote::check_battery(battery, min_battery, manifest)?;
+    let (project_name, _) = running_firmware()?;
+    let verifier = ImageVerifier::new(&project_name, Some(manifest.sha256()?));
+    info!(
+        "firmware update to {} from {}",
+        manifest.version, manifest.url
+    );
+    let (mut body, total) = fetcher.open(&manifest.url)?;
+    install(total, |write| {
+        firmware::copy_image(&mut body, total, verifier, write, progress)
+    })
+}
+
+/// can the firmware go back to the image in the other slot
+pub fn can_roll_back() -> bool {
+    unsafe { sys::esp_ota_check_rollback_is_possible() }
+}
+
+/// go back to the image in the other slot, this reboots
+pub fn roll_back() -> Result<()> {
+    if !can_roll_back() {
+        return Err(anyhow!("no earlier firmware to go back to"));
+    }
+    warn!("rolling back to the earlier firmware");
+    sys::esp!(unsafe { sys::esp_ota_mark_app_invalid_rollback_and_reboot() })?;
+    Ok(())
+}
+
+/// restart into the new firmware
+pub fn reboot() -> ! {
+    info!("rebooting into the new firmware");
+    reset::restart()
+}
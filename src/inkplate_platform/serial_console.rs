@@ -0,0 +1,130 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::app::event::AppEvent;
+use crate::console::command::{self, Command, ConsoleRequest};
+use crate::ui::touch::TouchEvent;
+use anyhow::{anyhow, Result};
+use esp_idf_svc::{log::EspLogger, sys};
+use log::*;
+use std::{
+    io::{self, BufRead, Write},
+    path::Path,
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+// uart receive buffer for typed lines
+const RX_BUFFER: i32 = 512;
+// how long the tasks command measures for
+const TASKS_MS: u32 = 1000;
+// how long to wait on the app thread
+const APP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// start the command shell on the serial console
+///
+/// touch events are injected into `app_ch` like ones from the sensor,
+/// commands needing the devices are sent to the app on it too
+pub fn start_console(app_ch: mpsc::Sender<AppEvent>) -> Result<()> {
+    // reads from stdin need the uart driver, otherwise they don't block
+    let uart = sys::CONFIG_ESP_CONSOLE_UART_NUM as i32;
+    unsafe {
+        sys::esp!(sys::uart_driver_install(
+            uart,
+            RX_BUFFER,
+            0,
+            0,
+            std::ptr::null_mut(),
+            0
+        ))?;
+        sys::esp_vfs_dev_uart_use_driver(uart);
+        // terminals send a carriage return for enter
+        sys::esp_vfs_dev_uart_port_set_rx_line_endings(
+            uart,
+            sys::esp_line_endings_t_ESP_LINE_ENDINGS_CR,
+        );
+    }
+    thread::Builder::new()
+        .name("console_thd".to_string())
+        .stack_size(8192)
+        .spawn(move || console_thread(app_ch))?;
+    Ok(())
+}
+
+fn console_thread(app_ch: mpsc::Sender<AppEvent>) {
+    info!("started console thread, type help for commands");
+    let stdin = io::stdin();
+    let mut line = String::new();
+    loop {
+        print!("> ");
+        let _ = io::stdout().flush();
+        line.clear();
+        if let Err(e) = stdin.lock().read_line(&mut line) {
+            warn!("console read failed: {}", e);
+            thread::sleep(Duration::from_secs(1));
+            continue;
+        }
+        let result = command::parse(&line).and_then(|cmd| match cmd {
+            None => Ok(String::new()),
+            Some(cmd) if cmd.needs_app() => ask_app(&app_ch, cmd),
+            Some(cmd) => run(cmd, &app_ch),
+        });
+        match result {
+            Ok(text) if text.is_empty() => (),
+            Ok(text) => println!("{}", text.trim_end()),
+            Err(e) => println!("error: {}", e),
+        }
+    }
+}
+
+// send a command to the app thread and wait for its answer
+fn ask_app(app_ch: &mpsc::Sender<AppEvent>, command: Command) -> Result<String> {
+    let (reply, answer) = mpsc::channel();
+    app_ch
+        .send(AppEvent::Console(ConsoleRequest { command, reply }))
+        .map_err(|_| anyhow!("the app isn't listening"))?;
+    answer
+        .recv_timeout(APP_TIMEOUT)
+        .map_err(|_| anyhow!("no answer from the app"))
+}
+
+// run a command that doesn't need the app
+fn run(cmd: Command, app_ch: &mpsc::Sender<AppEvent>) -> Result<String> {
+    match cmd {
+        Command::Help => Ok(command::HELP.to_string()),
+        Command::Heap => Ok(heap()),
+        Command::Tasks => {
+            let ticks = TASKS_MS * sys::configTICK_RATE_HZ / 1000;
+            // prints its table itself
+            sys::esp!(unsafe { sys::get_task_info(ticks as i32) })?;
+            Ok(String::new())
+        }
+        Command::Ls(dir) => command::list_dir(Path::new(&dir)),
+        Command::Touch(kind, x, y) => {
+            app_ch
+                .send(AppEvent::Touch(TouchEvent::with_position(kind, x, y)))
+                .map_err(|_| anyhow!("the app isn't listening"))?;
+            Ok(format!("sent {:?} at {}, {}", kind, x, y))
+        }
+        Command::LogLevel(target, level) => {
+            EspLogger.set_target_level(&target, level)?;
+            Ok(format!("{} logs at {}", target, level))
+        }
+        cmd => Err(anyhow!("{:?} needs the app", cmd)),
+    }
+}
+
+// free heap, all of it and the internal ram and psram parts
+fn heap() -> String {
+    unsafe {
+        format!(
+            "free {} bytes, minimum {}\ninternal {}, psram {}, largest block {}",
+            sys::esp_get_free_heap_size(),
+            sys::esp_get_minimum_free_heap_size(),
+            sys::heap_caps_get_free_size(sys::MALLOC_CAP_INTERNAL),
+            sys::heap_caps_get_free_size(sys::MALLOC_CAP_SPIRAM),
+            sys::heap_caps_get_largest_free_block(sys::MALLOC_CAP_DEFAULT),
+        )
+    }
+}
@@ -0,0 +1,203 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::config::settings::{LogSettings, Settings};
+use anyhow::{bail, Result};
+use esp_idf_svc::{
+    log::EspLogger,
+    nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault},
+};
+use log::*;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+// nvs namespace and keys
+const NAMESPACE: &str = "settings";
+const SETTINGS_KEY: &str = "toml";
+const MIRROR_HASH_KEY: &str = "mirror_hash";
+// largest settings blob
+const MAX_SIZE: usize = 4000;
+
+/// path of the user editable settings mirror
+pub const SETTINGS_MIRROR: &str = "/sdcard/ereader/settings.toml";
+
+/// Settings kept in NVS, mirrored to a toml file on the sdcard
+///
+/// NVS holds the settings in use. If the mirror was edited since it
+/// was last written, the edits are taken into NVS on load.
+pub struct SettingsStore {
+    nvs: EspNvs<NvsDefault>,
+    mirror: PathBuf,
+}
+
+impl SettingsStore {
+    /// open the store in the default nvs partition
+    pub fn new(partition: EspDefaultNvsPartition, mirror: &Path) -> Result<Self> {
+        let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+        Ok(Self {
+            nvs,
+            mirror: mirror.to_path_buf(),
+        })
+    }
+
+    /// the settings in nvs, without looking at the mirror
+    ///
+    /// used before the sdcard is mounted, settings that can't be read are
+    /// logged and left to the defaults
+    pub fn load_stored(&self) -> Option<Settings> {
+        let mut buf = vec![0u8; MAX_SIZE];
+        let blob = match self.nvs.get_raw(SETTINGS_KEY, &mut buf) {
+            Ok(blob) => blob?,
+            Err(e) => {
+                warn!("unable to read settings from nvs: {}", e);
+                return None;
+            }
+        };
+        let text = match std::str::from_utf8(blob) {
+            Ok(text) => text,
+            Err(e) => {
+                warn!("bad settings in nvs: {}", e);
+                return None;
+            }
+        };
+        match Settings::from_toml(text) {
+            Ok(settings) => Some(settings),
+            Err(e) => {
+                warn!("bad settings in nvs: {}", e);
+                None
+            }
+        }
+    }
+
+    /// load the settings, taking in edits to the mirror, defaults if there are none yet
+    ///
+    /// nvs is only written when the settings change, a mirror that doesn't
+    /// parse is kept as `settings.toml.bad` and written again
+    pub fn load(&mut self) -> Result<Settings> {
+        let stored = self.load_stored();
+        let mirror_hash = self.nvs.get_u64(MIRROR_HASH_KEY)?;
+        let text = match fs::read_to_string(&self.mirror) {
+            Ok(text) => text,
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    warn!("unable to read settings mirror {:?}: {}", self.mirror, e);
+                }
+                return self.rewrite_mirror(stored);
+            }
+        };
+        let hash = text_hash(&text);
+        if let (Some(settings), true) = (&stored, Some(hash) == mirror_hash) {
+            return Ok(settings.clone());
+        }
+        match Settings::from_toml(&text) {
+            Ok(edited) if Some(&edited) == stored.as_ref() => {
+                // an edit that changed nothing, like a comment
+                self.nvs.set_u64(MIRROR_HASH_KEY, hash)?;
+                Ok(edited)
+            }
+            Ok(edited) => {
+                info!("using edited settings from {:?}", self.mirror);
+                if let Err(e) = self.save(&edited) {
+                    warn!("unable to store the edited settings: {}", e);
+                }
+                Ok(edited)
+            }
+            Err(e) => {
+                warn!("bad settings in {:?}: {}", self.mirror, e);
+                let bad = self.mirror.with_extension("toml.bad");
+                if let Err(e) = fs::rename(&self.mirror, &bad) {
+                    warn!("unable to keep the bad settings as {:?}: {}", bad, e);
+                }
+                self.rewrite_mirror(stored)
+            }
+        }
+    }
+
+    // write the mirror from the stored settings, nvs too if it had none
+    fn rewrite_mirror(&mut self, stored: Option<Settings>) -> Result<Settings> {
+        match stored {
+            Some(settings) => {
+                self.save_mirror(&settings.to_toml()?)?;
+                Ok(settings)
+            }
+            None => {
+                let settings = Settings::default();
+                self.save(&settings)?;
+                Ok(settings)
+            }
+        }
+    }
+
+    /// save the settings to nvs and the mirror
+    ///
+    /// settings longer than `load_stored` can read back are refused
+    pub fn save(&mut self, settings: &Settings) -> Result<()> {
+        let text = settings.to_toml()?;
+        if text.len() > MAX_SIZE {
+            bail!(
+                "settings are {} bytes, the most is {}",
+                text.len(),
+                MAX_SIZE
+            );
+        }
+        self.nvs.set_raw(SETTINGS_KEY, text.as_bytes())?;
+        self.save_mirror(&text)
+    }
+
+    // write the mirror, and its hash to notice edits
+    fn save_mirror(&mut self, text: &str) -> Result<()> {
+        match write_mirror(&self.mirror, text) {
+            Ok(()) => {
+                self.nvs.set_u64(MIRROR_HASH_KEY, text_hash(text))?;
+            }
+            Err(e) => warn!("unable to write settings mirror {:?}: {}", self.mirror, e),
+        }
+        Ok(())
+    }
+
+    /// erase the stored settings and go back to the defaults
+    pub fn factory_reset(&mut self) -> Result<Settings> {
+        info!("settings factory reset");
+        self.nvs.remove(SETTINGS_KEY)?;
+        self.nvs.remove(MIRROR_HASH_KEY)?;
+        if self.mirror.exists() {
+            fs::remove_file(&self.mirror)?;
+        }
+        let settings = Settings::default();
+        self.save(&settings)?;
+        Ok(settings)
+    }
+}
+
+// write the mirror file, making the directory if needed
+fn write_mirror(path: &Path, text: &str) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, text)?;
+    Ok(())
+}
+
+// FNV-1a hash of the mirror text, to notice edits
+fn text_hash(text: &str) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in text.bytes() {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+/// set the log levels from the settings
+pub fn apply_log_settings(log: &LogSettings) {
+    if let Some(level) = LogSettings::level(&log.default) {
+        ::log::set_max_level(level);
+    }
+    for (target, level) in log.targets.iter() {
+        if let Some(level) = LogSettings::level(level) {
+            let _ = EspLogger.set_target_level(target, level);
+        }
+    }
+}
@@ -0,0 +1,57 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::net::http::{Stream, TlsConnect};
+use anyhow::Result;
+use esp_idf_svc::{
+    sys::EspError,
+    tls::{Config, EspTls, InternalSocket},
+};
+use std::{io, time::Duration};
+
+/// https for the library's clients, esp-tls with the certificate bundle
+#[derive(Debug, Default)]
+pub struct EspTlsConnect;
+
+impl TlsConnect for EspTlsConnect {
+    fn connect(&self, host: &str, port: u16, timeout: Duration) -> Result<Box<dyn Stream>> {
+        let mut tls = EspTls::new()?;
+        tls.connect(
+            host,
+            port,
+            &Config {
+                common_name: Some(host),
+                timeout_ms: timeout.as_millis() as u32,
+                use_crt_bundle_attach: true,
+                ..Default::default()
+            },
+        )?;
+        Ok(Box::new(TlsStream(tls)))
+    }
+}
+
+// a tls session as a std stream
+struct TlsStream(EspTls<InternalSocket>);
+
+// the session is only used by the thread making the request
+unsafe impl Send for TlsStream {}
+
+fn io_error(e: EspError) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+impl io::Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf).map_err(io_error)
+    }
+}
+
+impl io::Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf).map_err(io_error)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -1,7 +1,10 @@
 // Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
+use crate::app::event::AppEvent;
+use crate::config::settings::TouchSettings;
 use crate::inkplate_platform::inkplate::{self, I2c0, MplexOutputPin};
+use crate::ui::touch::{TouchEvent, TouchEventKind};
 use anyhow::Result;
 use esp_idf_svc::hal::{
     gpio::{self, Input, PinDriver},
@@ -13,49 +16,14 @@ use inkplate_drivers::{
     touch_sensor::{TouchSensor, TouchSensorPosition},
 };
 use log::*;
-use std::num::NonZeroU32;
 use std::{sync::mpsc, time::Duration};
 
-/// Event kind
-#[derive(Debug, Copy, Clone)]
-pub enum TouchEventKind {
-    None,
-    Tap,
-    Hold,
-    SwipeLeft,
-    SwipeRight,
-    PinchEnlarge,
-    PinchReduce,
-    Release,
-}
-
-/// The touch event
-#[derive(Debug, Copy, Clone)]
-pub struct TouchEvent {
-    kind: TouchEventKind,
-    x: u32,
-    y: u32,
-    dist: f32,
-}
-
-impl TouchEvent {
-    /// create a new touch event
-    pub fn new(kind: TouchEventKind) -> Self {
-        Self {
-            kind,
-            x: 0,
-            y: 0,
-            dist: 0.0,
-        }
-    }
-}
-
 // the state of the fsm
 #[derive(Debug, Copy, Clone)]
 enum TouchEventState {
     None,
     WaitNext { track1: Tracking1Position },
-    Holding,
+    Holding { track1: Tracking1Position },
     Swiping { track2: Tracking2Position },
     Pinching { dist: f32 },
 }
@@ -70,14 +38,7 @@ struct Tracking1Position {
 impl Tracking1Position {
     /// transform the touch sensor coordinates to user coordinates
     pub fn transform_coord(&mut self, txfm: &CoordTransform) {
-        self.x *= txfm.x_scale;
-        self.y *= txfm.y_scale;
-        match txfm.rotation {
-            Rotation::Rotate0 => {}
-            Rotation::Rotate90 => {}
-            Rotation::Rotate180 => {}
-            Rotation::Rotate270 => {}
-        }
+        (self.x, self.y) = txfm.to_user(self.x, self.y);
     }
 }
 
@@ -91,22 +52,11 @@ struct Tracking2Position {
 impl Tracking2Position {
     /// transform the touch sensor coordinates to user coordinates
     pub fn transform_coord(&mut self, txfm: &CoordTransform) {
-        self.x[0] *= txfm.x_scale;
-        self.y[0] *= txfm.y_scale;
-        self.x[1] *= txfm.x_scale;
-        self.y[1] *= txfm.y_scale;
-        match txfm.rotation {
-            Rotation::Rotate0 => {}
-            Rotation::Rotate90 => {}
-            Rotation::Rotate180 => {}
-            Rotation::Rotate270 => {}
-        }
+        (self.x[0], self.y[0]) = txfm.to_user(self.x[0], self.y[0]);
+        (self.x[1], self.y[1]) = txfm.to_user(self.x[1], self.y[1]);
     }
 }
 
-// this is distance where we go from tap or hold to swipe
-const DISTANCE_THRESHOLD: f32 = 30.0;
-
 impl From<TouchSensorPosition> for Tracking1Position {
     fn from(tsp: TouchSensorPosition) -> Tracking1Position {
         Tracking1Position {
@@ -125,6 +75,15 @@ impl From<TouchSensorPosition> for Tracking2Position {
     }
 }
 
+// the swipe direction from its start and end
+fn swipe_kind(track2: &Tracking2Position) -> TouchEventKind {
+    if track2.x[0] < track2.x[1] {
+        TouchEventKind::SwipeRight
+    } else {
+        TouchEventKind::SwipeLeft
+    }
+}
+
 // pythagorean distance between 2 points
 fn distance(x0: f32, y0: f32, x1: f32, y1: f32) -> f32 {
     let xsum = x1 - x0;
@@ -136,15 +95,46 @@ fn distance(x0: f32, y0: f32, x1: f32, y1: f32) -> f32 {
 struct CoordTransform {
     x_scale: f32,
     y_scale: f32,
+    width: f32,
+    height: f32,
     rotation: Rotation,
 }
 
+impl CoordTransform {
+    /// create the transform for a display config and touch sensor resolution
+    pub fn new(display_config: &Config, tres_x: u32, tres_y: u32) -> Self {
+        let width = display_config.dimensions.width() as f32;
+        let height = display_config.dimensions.height() as f32;
+        Self {
+            x_scale: width / tres_x as f32,
+            y_scale: height / tres_y as f32,
+            width,
+            height,
+            rotation: display_config.rotation,
+        }
+    }
+
+    /// scale to panel coordinates, then rotate to user coordinates
+    pub fn to_user(&self, x: f32, y: f32) -> (f32, f32) {
+        let x = (x * self.x_scale).clamp(0.0, self.width - 1.0);
+        let y = (y * self.y_scale).clamp(0.0, self.height - 1.0);
+        match self.rotation {
+            Rotation::Rotate0 => (x, y),
+            Rotation::Rotate90 => (y, self.width - 1.0 - x),
+            Rotation::Rotate180 => (self.width - 1.0 - x, self.height - 1.0 - y),
+            Rotation::Rotate270 => (self.height - 1.0 - y, x),
+        }
+    }
+}
+
 /// thread function for touch events
 pub fn touch_event_thread<'a>(
     mut touch_sensor: TouchSensor<'a, I2c0, MplexOutputPin<'a>, MplexOutputPin<'a>>,
-    touch_send_ch: mpsc::Sender<TouchEvent>,
+    touch_send_ch: mpsc::Sender<AppEvent>,
     display_config: Config,
+    display_config_ch: mpsc::Receiver<Config>,
     mut touch_sensor_int_pin: PinDriver<'a, gpio::Gpio36, Input>,
+    touch_settings: TouchSettings,
 ) -> Result<()> {
     info!("started touch event thread");
     unsafe {
@@ -153,16 +143,23 @@ pub fn touch_event_thread<'a>(
     touch_sensor_int_pin.enable_interrupt()?;
     // first get the touch sensor dimensions
     let tres = touch_sensor.resolution()?;
-    let transform = CoordTransform {
-        x_scale: display_config.dimensions.width() as f32 / tres.x() as f32,
-        y_scale: display_config.dimensions.height() as f32 / tres.y() as f32,
-        rotation: display_config.rotation,
-    };
+    let mut transform = CoordTransform::new(&display_config, tres.x() as u32, tres.y() as u32);
+    let idle = Duration::from_millis(touch_settings.idle_ms as u64);
+    let hold_release = Duration::from_millis(touch_settings.hold_release_ms as u64);
     let mut state = TouchEventState::None;
-    let mut timeout = Duration::from_millis(100_000);
+    let mut timeout = idle;
     loop {
         if let Some(notice) = task::wait_notification(timeout.as_millis() as u32) {
-            if notice == NonZeroU32::new(1).unwrap() {
+            // the notice bits are or'ed, a rotation and a touch can come together
+            if notice.get() & inkplate::DISPLAY_CONFIG_NOTICE != 0 {
+                while let Ok(config) = display_config_ch.try_recv() {
+                    debug!("touch transform updated for new display config");
+                    transform = CoordTransform::new(&config, tres.x() as u32, tres.y() as u32);
+                    state = TouchEventState::None;
+                    timeout = idle;
+                }
+            }
+            if notice.get() & inkplate::TOUCH_NOTICE != 0 {
                 trace!("touch sensor notification {}", notice);
                 let pos = touch_sensor.get_position()?;
                 trace!("state: {:?}", state);
@@ -171,29 +168,31 @@ pub fn touch_event_thread<'a>(
                         if pos.num_fingers == 1 {
                             let mut track1: Tracking1Position = pos.into();
                             track1.transform_coord(&transform);
-                            timeout = Duration::from_millis(500);
+                            timeout = Duration::from_millis(touch_settings.hold_ms as u64);
                             state = TouchEventState::WaitNext { track1 };
                         } else if pos.num_fingers == 2 {
                             let mut track2: Tracking2Position = pos.into();
                             track2.transform_coord(&transform);
                             let dist = distance(track2.x[0], track2.y[0], track2.x[1], track2.y[1]);
-                            timeout = Duration::from_millis(100_000);
+                            timeout = idle;
                             state = TouchEventState::Pinching { dist };
                         }
                     }
                     TouchEventState::WaitNext { track1 } => {
                         if pos.num_fingers == 0 {
                             // got a tap
-                            let mut event = TouchEvent::new(TouchEventKind::Tap);
-                            event.x = track1.x as u32;
-                            event.y = track1.y as u32;
-                            touch_send_ch.send(event)?;
+                            let event = TouchEvent::with_position(
+                                TouchEventKind::Tap,
+                                track1.x as u32,
+                                track1.y as u32,
+                            );
+                            touch_send_ch.send(AppEvent::Touch(event))?;
                             state = TouchEventState::None;
                         } else if pos.num_fingers == 1 {
                             let mut track1new: Tracking1Position = pos.into();
                             track1new.transform_coord(&transform);
                             let dist = distance(track1.x, track1.y, track1new.x, track1new.y);
-                            if dist > DISTANCE_THRESHOLD {
+                            if dist > touch_settings.distance_threshold {
                                 let track2 = Tracking2Position {
                                     x: [track1.x, track1new.x],
                                     y: [track1.y, track1new.y],
@@ -206,25 +205,40 @@ pub fn touch_event_thread<'a>(
                             let dist = distance(track.x[1], track.y[1], track.x[0], track.y[0]);
                             state = TouchEventState::Pinching { dist };
                         }
-                        timeout = Duration::from_millis(100_000);
+                        timeout = idle;
                     }
-                    TouchEventState::Holding => {
+                    TouchEventState::Holding { track1 } => {
                         if pos.num_fingers == 0 {
-                            let event = TouchEvent::new(TouchEventKind::Release);
-                            touch_send_ch.send(event)?;
+                            let event = TouchEvent::with_position(
+                                TouchEventKind::Release,
+                                track1.x as u32,
+                                track1.y as u32,
+                            );
+                            touch_send_ch.send(AppEvent::Touch(event))?;
                             state = TouchEventState::None;
+                        } else if pos.num_fingers == 1 {
+                            // a held finger moving is a drag, used for selections
+                            let mut track1new: Tracking1Position = pos.into();
+                            track1new.transform_coord(&transform);
+                            let dist = distance(track1.x, track1.y, track1new.x, track1new.y);
+                            if dist > touch_settings.drag_threshold {
+                                let event = TouchEvent::with_position(
+                                    TouchEventKind::Drag,
+                                    track1new.x as u32,
+                                    track1new.y as u32,
+                                );
+                                touch_send_ch.send(AppEvent::Touch(event))?;
+                                state = TouchEventState::Holding { track1: track1new };
+                            }
                         }
-                        // don't need to handle 1 or 2 finger case in holding, continue
+                        // don't need to handle 2 finger case in holding, continue
                         // with hold to release instead
-                        timeout = Duration::from_millis(1000);
+                        timeout = hold_release;
                     }
                     TouchEventState::Swiping { track2 } => {
                         if pos.num_fingers == 0 {
-                            let mut event = TouchEvent::new(TouchEventKind::SwipeLeft);
-                            if track2.x[0] < track2.x[1] {
-                                event.kind = TouchEventKind::SwipeRight;
-                            }
-                            touch_send_ch.send(event)?;
+                            let event = TouchEvent::new(swipe_kind(&track2));
+                            touch_send_ch.send(AppEvent::Touch(event))?;
                             state = TouchEventState::None;
                         } else if pos.num_fingers == 1 {
                             let mut track1new: Tracking1Position = pos.into();
@@ -240,12 +254,12 @@ pub fn touch_event_thread<'a>(
                             let dist = distance(track2.x[0], track2.y[0], track2.x[1], track2.y[1]);
                             state = TouchEventState::Pinching { dist };
                         }
-                        timeout = Duration::from_millis(100_000);
+                        timeout = idle;
                     }
                     TouchEventState::Pinching { dist } => {
                         if pos.num_fingers == 0 {
                             let event = TouchEvent::new(TouchEventKind::Release);
-                            touch_send_ch.send(event)?;
+                            touch_send_ch.send(AppEvent::Touch(event))?;
                             state = TouchEventState::None;
                         } else if pos.num_fingers == 2 {
                             let mut track2new: Tracking2Position = pos.into();
@@ -259,16 +273,17 @@ pub fn touch_event_thread<'a>(
                             let new_dist_diff = (dist - this_dist).abs();
                             trace!("distance diffs: {} {}", dist, new_dist_diff);
                             if new_dist_diff > 1.0 {
-                                let mut event = TouchEvent::new(TouchEventKind::PinchReduce);
-                                if dist < this_dist {
-                                    event.kind = TouchEventKind::PinchEnlarge;
-                                }
-                                event.dist = new_dist_diff;
-                                touch_send_ch.send(event)?;
+                                let kind = if dist < this_dist {
+                                    TouchEventKind::PinchEnlarge
+                                } else {
+                                    TouchEventKind::PinchReduce
+                                };
+                                let event = TouchEvent::pinch(kind, new_dist_diff);
+                                touch_send_ch.send(AppEvent::Touch(event))?;
                                 state = TouchEventState::Pinching { dist: this_dist };
                             }
                         }
-                        timeout = Duration::from_millis(100_000);
+                        timeout = idle;
                     }
                 }
             } else {
@@ -279,31 +294,36 @@ pub fn touch_event_thread<'a>(
         } else {
             trace!("wait timeout state: {:?}", state);
             match state {
-                TouchEventState::WaitNext { track1: _ } => {
-                    let event = TouchEvent::new(TouchEventKind::Hold);
-                    touch_send_ch.send(event)?;
-                    timeout = Duration::from_millis(1000);
-                    state = TouchEventState::Holding;
+                TouchEventState::WaitNext { track1 } => {
+                    let event = TouchEvent::with_position(
+                        TouchEventKind::Hold,
+                        track1.x as u32,
+                        track1.y as u32,
+                    );
+                    touch_send_ch.send(AppEvent::Touch(event))?;
+                    timeout = hold_release;
+                    state = TouchEventState::Holding { track1 };
                 }
-                TouchEventState::Holding => {
-                    let event = TouchEvent::new(TouchEventKind::Release);
-                    touch_send_ch.send(event)?;
-                    timeout = Duration::from_millis(100_000);
+                TouchEventState::Holding { track1 } => {
+                    let event = TouchEvent::with_position(
+                        TouchEventKind::Release,
+                        track1.x as u32,
+                        track1.y as u32,
+                    );
+                    touch_send_ch.send(AppEvent::Touch(event))?;
+                    timeout = idle;
                     state = TouchEventState::None;
                 }
                 TouchEventState::Pinching { dist: _ } => {
                     let event = TouchEvent::new(TouchEventKind::Release);
-                    touch_send_ch.send(event)?;
-                    timeout = Duration::from_millis(100_000);
+                    touch_send_ch.send(AppEvent::Touch(event))?;
+                    timeout = idle;
                     state = TouchEventState::None;
                 }
                 TouchEventState::Swiping { track2 } => {
-                    let mut event = TouchEvent::new(TouchEventKind::SwipeLeft);
-                    if track2.x[0] < track2.x[1] {
-                        event.kind = TouchEventKind::SwipeRight;
-                    }
-                    touch_send_ch.send(event)?;
-                    timeout = Duration::from_millis(100_000);
+                    let event = TouchEvent::new(swipe_kind(&track2));
+                    touch_send_ch.send(AppEvent::Touch(event))?;
+                    timeout = idle;
                     state = TouchEventState::None;
                 }
                 _ => {}
@@ -0,0 +1,242 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::net::wifi::{merge_scan, KnownNetworks, NetworkInfo, WifiCredentials, WifiState};
+use anyhow::{anyhow, Result};
+use esp_idf_svc::{
+    eventloop::EspSystemEventLoop,
+    hal::modem::Modem,
+    nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault},
+    wifi::{AuthMethod, BlockingWifi, ClientConfiguration, Configuration, EspWifi},
+};
+use log::*;
+use std::{
+    net::Ipv4Addr,
+    time::{Duration, Instant},
+};
+
+// nvs namespace and key of the known networks
+const NAMESPACE: &str = "wifi";
+const NETWORKS_KEY: &str = "networks";
+// largest known networks blob
+const MAX_SIZE: usize = 1024;
+
+/// The wifi radio, connected only while something needs the network
+///
+/// Callers `acquire` the connection before network use, `poll` is called
+/// from the main loop and shuts the radio down after the idle time.
+pub struct WifiManager {
+    wifi: BlockingWifi<EspWifi<'static>>,
+    nvs: EspNvs<NvsDefault>,
+    known: KnownNetworks,
+    state: WifiState,
+    started: bool,
+    last_used: Instant,
+    idle: Duration,
+}
+
+impl WifiManager {
+    /// create the manager, the radio stays off until needed
+    pub fn new(
+        modem: Modem,
+        sysloop: EspSystemEventLoop,
+        partition: EspDefaultNvsPartition,
+        idle_minutes: u32,
+    ) -> Result<Self> {
+        let nvs = EspNvs::new(partition.clone(), NAMESPACE, true)?;
+        let known = load_known(&nvs);
+        let wifi = BlockingWifi::wrap(
+            EspWifi::new(modem, sysloop.clone(), Some(partition))?,
+            sysloop,
+        )?;
+        Ok(Self {
+            wifi,
+            nvs,
+            known,
+            state: WifiState::Off,
+            started: false,
+            last_used: Instant::now(),
+            idle: Duration::from_secs(idle_minutes as u64 * 60),
+        })
+    }
+
+    /// the radio state, for the status bar
+    pub fn state(&self) -> &WifiState {
+        &self.state
+    }
+
+    /// the networks connected to before
+    pub fn known(&self) -> &KnownNetworks {
+        &self.known
+    }
+
+    /// the station mac address
+    pub fn mac(&self) -> Result<[u8; 6]> {
+        Ok(self.wifi.wifi().sta_netif().get_mac()?)
+    }
+
+    /// the broadcast address of the network connected to
+    pub fn broadcast(&self) -> Result<Ipv4Addr> {
+        let info = self.wifi.wifi().sta_netif().get_ip_info()?;
+        // the mask is a prefix length
+        let mask = u32::MAX
+            .checked_shl(32 - info.subnet.mask.0.min(32) as u32)
+            .unwrap_or(0);
+        Ok(Ipv4Addr::from(u32::from(info.ip) | !mask))
+    }
+
+    /// change the idle time before the radio is shut down
+    pub fn set_idle_minutes(&mut self, idle_minutes: u32) {
+        self.idle = Duration::from_secs(idle_minutes as u64 * 60);
+    }
+
+    // start the radio in station mode
+    fn start(&mut self) -> Result<()> {
+        if !self.started {
+            self.wifi
+                .set_configuration(&Configuration::Client(ClientConfiguration::default()))?;
+            self.wifi.start()?;
+            self.started = true;
+        }
+        Ok(())
+    }
+
+    /// scan for networks, strongest first
+    pub fn scan(&mut self) -> Result<Vec<NetworkInfo>> {
+        let previous = std::mem::replace(&mut self.state, WifiState::Scanning);
+        self.start()?;
+        let found = self.wifi.scan();
+        self.state = previous;
+        let found = found?
+            .into_iter()
+            .map(|ap| NetworkInfo {
+                ssid: ap.ssid.to_string(),
+                rssi: ap.signal_strength,
+                secured: ap.auth_method != AuthMethod::None,
+            })
+            .collect();
+        self.last_used = Instant::now();
+        Ok(merge_scan(found))
+    }
+
+    /// connect to a network, remembering it if the connection works
+    pub fn connect(&mut self, creds: &WifiCredentials) -> Result<()> {
+        if self.state.is_connected() {
+            self.wifi.disconnect()?;
+        }
+        self.start()?;
+        self.state = WifiState::Connecting(creds.ssid.clone());
+        info!("wifi connecting to {}", creds.ssid);
+        let auth_method = if creds.password.is_empty() {
+            AuthMethod::None
+        } else {
+            AuthMethod::WPA2Personal
+        };
+        let config = Configuration::Client(ClientConfiguration {
+            ssid: creds
+                .ssid
+                .as_str()
+                .try_into()
+                .map_err(|_| anyhow!("ssid too long"))?,
+            password: creds
+                .password
+                .as_str()
+                .try_into()
+                .map_err(|_| anyhow!("password too long"))?,
+            auth_method,
+            ..Default::default()
+        });
+        let connected = self
+            .wifi
+            .set_configuration(&config)
+            .and_then(|_| self.wifi.connect())
+            .and_then(|_| self.wifi.wait_netif_up());
+        if let Err(e) = connected {
+            warn!("wifi connect to {} failed: {}", creds.ssid, e);
+            self.state = WifiState::Failed(creds.ssid.clone());
+            return Err(e.into());
+        }
+        let ip = self.wifi.wifi().sta_netif().get_ip_info()?.ip;
+        info!("wifi connected to {}, ip {}", creds.ssid, ip);
+        self.state = WifiState::Connected {
+            ssid: creds.ssid.clone(),
+            ip,
+        };
+        self.last_used = Instant::now();
+        self.known.remember(creds.clone());
+        self.save_known()
+    }
+
+    /// make sure the network is up, connecting to a known network if needed
+    ///
+    /// call before each network use, it also holds off the idle shutdown
+    pub fn acquire(&mut self) -> Result<()> {
+        self.last_used = Instant::now();
+        if self.state.is_connected() && self.wifi.is_connected()? {
+            return Ok(());
+        }
+        let scan = self.scan()?;
+        let creds = self
+            .known
+            .pick(&scan)
+            .cloned()
+            .ok_or_else(|| anyhow!("no known wifi network in range"))?;
+        self.connect(&creds)
+    }
+
+    /// forget a known network
+    pub fn forget(&mut self, ssid: &str) -> Result<()> {
+        self.known.forget(ssid);
+        self.save_known()
+    }
+
+    /// shut the radio down once it has been idle long enough
+    ///
+    /// returns true if the state changed
+    pub fn poll(&mut self) -> bool {
+        if self.started && self.last_used.elapsed() >= self.idle {
+            info!("wifi idle, shutting down");
+            if let Err(e) = self.shutdown() {
+                warn!("wifi shutdown failed: {}", e);
+            }
+            return true;
+        }
+        false
+    }
+
+    /// disconnect and turn the radio off
+    pub fn shutdown(&mut self) -> Result<()> {
+        if self.started {
+            if self.wifi.is_connected()? {
+                self.wifi.disconnect()?;
+            }
+            self.wifi.stop()?;
+            self.started = false;
+        }
+        self.state = WifiState::Off;
+        Ok(())
+    }
+
+    // write the known networks to nvs
+    fn save_known(&mut self) -> Result<()> {
+        let blob = serde_json::to_vec(&self.known)?;
+        self.nvs.set_raw(NETWORKS_KEY, &blob)?;
+        Ok(())
+    }
+}
+
+// the known networks in nvs, none if missing or bad
+fn load_known(nvs: &EspNvs<NvsDefault>) -> KnownNetworks {
+    let mut buf = vec![0u8; MAX_SIZE];
+    match nvs.get_raw(NETWORKS_KEY, &mut buf) {
+        Ok(Some(blob)) => serde_json::from_slice(blob).unwrap_or_else(|e| {
+            warn!("bad known wifi networks in nvs: {}", e);
+            KnownNetworks::default()
+        }),
+        Ok(None) => KnownNetworks::default(),
+        Err(e) => {
+            warn!("unable to read known wifi networks: {}", e);
+            KnownNetworks::default()
+        }
+    }
+}
@@ -0,0 +1,75 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+// everything that doesn't need the board, so it builds and tests on the host
+pub mod app {
+    pub mod controller;
+    pub mod event;
+    pub mod library;
+    pub mod reader_screen;
+}
+pub mod config {
+    pub mod settings;
+}
+pub mod console {
+    pub mod command;
+}
+pub mod imaging {
+    pub mod decode;
+    pub mod dither;
+    pub mod source;
+    pub mod viewer;
+}
+pub mod net {
+    pub mod calibre;
+    pub mod file_manager;
+    pub mod http;
+    pub mod kosync;
+    pub mod opds;
+    pub mod wifi;
+}
+pub mod dict {
+    pub mod lemma;
+    pub mod stardict;
+}
+pub mod reader {
+    pub mod bookmarks;
+    pub mod content;
+    pub mod epub;
+    pub mod fb2;
+    pub mod footnote;
+    pub mod highlights;
+    pub mod history;
+    pub mod item_cache;
+    pub mod layout;
+    pub mod location;
+    pub mod markup;
+    pub mod menu;
+    pub mod page_map;
+    pub mod pagination;
+    pub mod search;
+    pub mod spread;
+    pub mod stats;
+    pub mod text_book;
+    pub mod toc;
+    pub mod typography;
+}
+pub mod update {
+    pub mod firmware;
+    pub mod remote;
+}
+pub mod ui {
+    pub mod canvas;
+    pub mod confirm;
+    pub mod keyboard;
+    pub mod list_view;
+    pub mod menu;
+    pub mod opds_browser;
+    pub mod popup;
+    pub mod progress;
+    pub mod settings_panel;
+    pub mod touch;
+    pub mod wifi_setup;
+}
+#[cfg(test)]
+mod test_util;
@@ -1,21 +1,38 @@
 pub mod inkplate_platform {
     pub mod battery;
+    pub mod http_server;
     pub mod inkplate;
+    pub mod ota;
+    pub mod serial_console;
+    pub mod settings_store;
+    pub mod tls;
     pub mod touch_event;
+    pub mod wifi_manager;
 }
-use crate::inkplate_platform::{inkplate, touch_event};
-use anyhow::Result;
-use ereader_support::page_loc_simpledb::PageLocSimpleDb;
-use ereader_support::{
-    app_controller::AppController, event_mgr::EventManager, fonts::FaceCacheProxy,
+use crate::app::{
+    controller::{BoardRequest, ShowProgress},
+    event::AppEvent,
+};
+use crate::console::command::Command;
+use crate::inkplate_platform::{
+    http_server, inkplate, ota, serial_console, settings_store, touch_event,
+    wifi_manager::WifiManager,
 };
+use crate::ui::{canvas::Canvas, progress::ProgressBar, touch::TouchEventKind};
+use anyhow::Result;
 use esp_idf_svc::{hal::delay, log::EspLogger};
-use inkplate_platform::inkplate::InkPlateDevices;
+use inkplate_ereader2::{app, console, net, reader, ui, update};
 use log::*;
-use static_cel::StaticCell;
+use std::io::Write;
 use std::sync::mpsc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+// how often the app is ticked
+const TICK_PERIOD: Duration = Duration::from_secs(1);
+// untouched this long the device counts as asleep, the open book's
+// progress is pushed as on a close
+const SLEEP_AFTER: Duration = Duration::from_secs(10 * 60);
 
 fn check_free_heap() {
     info!("Minimum free heap size: {} bytes", unsafe {
@@ -64,14 +81,47 @@ fn main() {
 }
 
 fn main_task() -> Result<()> {
-    // setup the board
-    let mut inkplate = inkplate::inkplate_setup()?;
+    // setup the board, a new firmware that can't is rolled back
+    let mut inkplate = match inkplate::inkplate_setup() {
+        Ok(inkplate) => inkplate,
+        Err(e) => {
+            error!("board setup failed: {}", e);
+            ota::self_check(false)?;
+            return Err(e);
+        }
+    };
+    let settings = inkplate.settings.take().unwrap();
+    let mut settings_store = inkplate.settings_store.take().unwrap();
+    settings_store::apply_log_settings(&settings.log);
+
+    // a new firmware is kept once the display, touch and sdcard are up
+    ota::self_check(inkplate::hardware_check(&mut inkplate))?;
+    let ereader_dir = std::path::Path::new(reader::location::EREADER_DIR);
+    // then an update left on the sdcard is installed
+    let (width, height) = inkplate::user_size(&inkplate.graphics.as_ref().unwrap().config());
+    let mut bar = ProgressBar::new("Updating firmware", width, height);
+    let mut progress = |done, total| {
+        if bar.update(done, total) {
+            info!("firmware update {}%", bar.percent().unwrap_or(0));
+        }
+    };
+    match ota::update_from_sd_card(ereader_dir, &mut progress) {
+        Ok(Some(_)) => ota::reboot(),
+        Ok(None) => (),
+        Err(e) => error!("firmware update from the sdcard failed: {}", e),
+    }
+
+    // touches and console commands come to this thread on one channel
+    let (app_send_ch, app_receive_ch) = mpsc::channel();
 
     // spawn the touch event thread
     let touch_sensor = inkplate.touch_sensor.take().unwrap();
     let touch_sensor_ip = inkplate.touch_sensor_int_pin.take().unwrap();
-    let (touch_send_ch, touch_receive_ch) = mpsc::channel();
-    let display_config = inkplate.graphics.unwrap().config();
+    // display config changes, see `inkplate::set_display_rotation`, go to the touch thread
+    let (display_config_send_ch, display_config_receive_ch) = mpsc::channel();
+    let display_config = inkplate.graphics.as_ref().unwrap().config();
+    let touch_settings = settings.touch;
+    let touch_send_ch = app_send_ch.clone();
     let _builder = thread::Builder::new()
         .name("touch_thd".to_string())
         .stack_size(20000)
@@ -80,145 +130,350 @@ fn main_task() -> Result<()> {
                 touch_sensor,
                 touch_send_ch,
                 display_config,
+                display_config_receive_ch,
                 touch_sensor_ip,
+                touch_settings,
             )
         });
 
-    let utc = inkplate.rtc.unwrap().get_datetime().unwrap();
-    info!("time from rtc: {}", utc);
+    // the serial console, it injects touch events too
+    serial_console::start_console(app_send_ch.clone())?;
+
+    let i2c0bus = inkplate.i2c0bus.take().unwrap();
+    // the system clock starts at the epoch, reading times and the stats
+    // come from it, so it is set from the rtc
+    match inkplate::rtc_time(i2c0bus).and_then(|t| {
+        inkplate::set_system_time(&t)?;
+        Ok(t)
+    }) {
+        Ok(t) => info!("time from rtc: {:?}", t),
+        Err(e) => warn!("no time from the rtc, set it with the console: {}", e),
+    }
     // read the battery
     let mut delay = delay::Ets;
     let mut adc1 = inkplate.adc1.take().unwrap();
     let mut bat_mon = inkplate.bat_mon.take().unwrap();
     let level = bat_mon.read_level(&mut adc1, &mut delay)?;
     info!("battery level: {}", level);
+    let mut graphics = inkplate.graphics.take().unwrap();
+    let mut canvas = Canvas::new(width, height);
 
-    loop {
-        let evt = touch_receive_ch.recv()?;
-        debug!("touch event: {:?}", evt);
-        check_free_heap();
-    }
-}
+    // the ticker, for work like pagination that finishes on its own
+    let tick_send_ch = app_send_ch.clone();
+    let _builder = thread::Builder::new()
+        .name("tick_thd".to_string())
+        .stack_size(4096)
+        .spawn(move || {
+            while tick_send_ch.send(AppEvent::Tick).is_ok() {
+                thread::sleep(TICK_PERIOD);
+            }
+        });
 
-static DRAW_FACE_CACHE: StaticCell<FaceCacheProxy> = StaticCell::new();
+    // the wifi radio, off until something needs the network
+    let mut wifi = WifiManager::new(
+        inkplate.modem.take().unwrap(),
+        inkplate.sysloop.take().unwrap(),
+        inkplate.nvs.take().unwrap(),
+        settings.wifi.idle_minutes,
+    )?;
 
-/*
-fn main_task() -> Result<()> {
-    let mut main_loop = InkplateMainLoopManager::new();
-    let mut evt_manager = InkplateMainEventManager::new();
-    let db = PageLocSimpleDb::new(Path::new("/sdcard/ereader/book.db"))?;
-    let (app_ctrl, draw_face_cache) = AppController::new(Path::new("/sdcard"), db);
-    let face_cache_ref: &'static FaceCacheProxy = DRAW_FACE_CACHE.init(draw_face_cache);
-    main_loop.init();
-    main_loop.run(evt_manager);
-}
- */
+    // the file server tells the library about uploads, through the app channel
+    let (library_send_ch, library_receive_ch) = mpsc::channel();
+    let library_app_ch = app_send_ch.clone();
+    let _builder = thread::Builder::new()
+        .name("library_thd".to_string())
+        .stack_size(4096)
+        .spawn(move || {
+            for () in library_receive_ch {
+                if library_app_ch.send(AppEvent::LibraryChanged).is_err() {
+                    break;
+                }
+            }
+        });
+    let mut file_server = None;
 
-struct InkplateMainLoopManager<'a> {
-    inkplate: Option<InkPlateDevices<'a>>,
-}
+    // sync servers and catalogs can be https
+    net::http::set_tls(Box::new(inkplate_platform::tls::EspTlsConnect));
 
-impl<'a> InkplateMainLoopManager<'a> {
-    pub fn new() -> Self {
-        Self { inkplate: None }
-    }
+    // progress sync, its device id comes from the wifi mac
+    let sync = net::kosync::SyncClient::new(&settings.sync, ereader_dir, &wifi.mac()?)?;
 
-    pub fn init(&mut self) -> Result<()> {
-        // setup the board
-        let mut inkplate = inkplate::inkplate_setup()?;
-
-        // spawn the touch event thread
-        let touch_sensor = inkplate.touch_sensor.take().unwrap();
-        let touch_sensor_ip = inkplate.touch_sensor_int_pin.take().unwrap();
-        let (touch_send_ch, touch_receive_ch) = mpsc::channel();
-        let display_config = inkplate.graphics.as_ref().unwrap().config();
-        let _builder = thread::Builder::new()
-            .name("touch_thd".to_string())
-            .stack_size(20000)
-            .spawn(move || {
-                touch_event::touch_event_thread(
-                    touch_sensor,
-                    touch_send_ch,
-                    display_config,
-                    touch_sensor_ip,
-                )
-            });
-
-        let utc = inkplate.rtc.unwrap().get_datetime().unwrap();
-        info!("time from rtc: {}", utc);
-        // read the battery
-        let mut delay = delay::Ets;
-        let mut adc1 = inkplate.adc1.take().unwrap();
-        let mut bat_mon = inkplate.bat_mon.take().unwrap();
-        let level = bat_mon.read_level(&mut adc1, &mut delay)?;
-        info!("battery level: {}", level);
-        self.inkplate.replace(inkplate);
-        OK(())
+    // the running version, releases newer than it are offered
+    let running_version = match ota::running_firmware() {
+        Ok((_, version)) => version,
+        Err(e) => {
+            warn!("can't read the running firmware: {}", e);
+            String::new()
+        }
+    };
+    let fetcher = ota::EspFetcher::default();
+
+    // the threads the app starts have big stacks, they go to psram
+    if let Err(e) = inkplate::spawn_stacks_in_psram() {
+        warn!("{}", e);
     }
-}
+    let books_dir = std::path::Path::new(app::library::BOOKS_DIR);
+    let mut app =
+        app::controller::AppController::new(books_dir, ereader_dir, settings, width, height);
+    app.set_sync(sync);
+    app.draw(&mut canvas);
+    inkplate::refresh(&mut graphics, &mut canvas)?;
 
-impl<EM> AppControllerRun<EM> for InkplateMainLoopManager
-where
-    EM: EventManager,
-{
-    fn run(
-        &mut self,
-        mut evt_mgr: EM,
-        mut app_ctrl: AppController,
-        face_cache: &'static FaceCacheProxy,
-    ) -> Result<()> {
-        evt_mgr.setup();
+    let mut last_touch = Instant::now();
+    let mut asleep = false;
+    loop {
+        // while the app is busy, it is ticked when there are no events
+        let event = match app_receive_ch.try_recv() {
+            Ok(event) => event,
+            Err(mpsc::TryRecvError::Empty) if app.busy() => AppEvent::Tick,
+            Err(_) => app_receive_ch.recv()?,
+        };
+        match event {
+            AppEvent::Touch(evt) => {
+                debug!("touch event: {:?}", evt);
+                last_touch = Instant::now();
+                asleep = false;
+                app.touch(&evt, &mut canvas);
+                check_free_heap();
+            }
+            AppEvent::Tick => {
+                // the radio stays up while the file server or calibre runs
+                if file_server.is_some() || app.needs_network() {
+                    if let Err(e) = wifi.acquire() {
+                        warn!("wifi lost while in use: {}", e);
+                    }
+                }
+                wifi.poll();
+                if !asleep && last_touch.elapsed() >= SLEEP_AFTER {
+                    app.sleep();
+                    asleep = true;
+                }
+                app.tick(&mut canvas);
+            }
+            AppEvent::LibraryChanged => app.library_changed(&mut canvas),
+            AppEvent::Console(request) => {
+                let answer = match &request.command {
+                    Command::Battery => match bat_mon.read_level(&mut adc1, &mut delay) {
+                        Ok(level) => format!("battery {:.2} V", level),
+                        Err(e) => format!("error: {}", e),
+                    },
+                    Command::RtcGet => match inkplate::rtc_time(i2c0bus) {
+                        Ok(t) => format!("rtc {:?}", t),
+                        Err(e) => format!("error: {}", e),
+                    },
+                    Command::RtcSet(t) => match inkplate::set_rtc_time(i2c0bus, t)
+                        .and_then(|_| inkplate::set_system_time(t))
+                    {
+                        Ok(()) => format!("rtc set to {:?}", t),
+                        Err(e) => format!("error: {}", e),
+                    },
+                    Command::Light(level) => match inkplate::set_front_light(i2c0bus, *level) {
+                        Ok(()) => format!("front light {}", level),
+                        Err(e) => format!("error: {}", e),
+                    },
+                    Command::Screenshot(path) => match screenshot(&canvas, path) {
+                        Ok(()) => format!("saved {}", path),
+                        Err(e) => format!("error: {}", e),
+                    },
+                    Command::GotoPage(page) => match app.reader().map(|r| r.goto_page(*page)) {
+                        Some(Ok(())) => {
+                            app.draw(&mut canvas);
+                            format!("at page {}", page)
+                        }
+                        Some(Err(e)) => format!("error: {}", e),
+                        None => "no book is open".to_string(),
+                    },
+                    Command::ExportHighlights => match app.reader() {
+                        Some(reader) => match reader.export_highlights() {
+                            Ok(path) => format!("exported to {}", path.display()),
+                            Err(e) => format!("error: {}", e),
+                        },
+                        None => "no book is open".to_string(),
+                    },
+                    Command::ExportStats => match app.reader() {
+                        Some(reader) => match reader.export_stats() {
+                            Ok(paths) => format!("exported to {:?}", paths),
+                            Err(e) => format!("error: {}", e),
+                        },
+                        None => "no book is open".to_string(),
+                    },
+                    cmd => format!("{:?} doesn't need the app", cmd),
+                };
+                let _ = request.reply.send(answer);
+            }
+        }
+        // handling a request can make more
         loop {
-            evt_mgr.event_loop_handler();
-            app_ctrl.event_loop_handler()?;
-            if let Some(ev) = evt_mgr.get_event() {
-                info!("event: {:?}", ev);
-                app_ctrl.input_event(ev)?;
+            let requests = app.take_requests();
+            if requests.is_empty() {
+                break;
             }
-            if let Some(page) = app_ctrl.get_page() {
-                info!("got page");
-                // draw the page
+            for request in requests {
+                match request {
+                    BoardRequest::Rotate(degrees) => {
+                        let config = inkplate::set_display_rotation(
+                            &mut graphics,
+                            degrees,
+                            &display_config_send_ch,
+                        )?;
+                        let (width, height) = inkplate::user_size(&config);
+                        canvas.resize(width, height);
+                        app.resize(width, height, &mut canvas);
+                    }
+                    BoardRequest::SaveSettings => {
+                        if let Err(e) = settings_store.save(app.settings()) {
+                            error!("can't save the settings: {}", e);
+                        }
+                    }
+                    BoardRequest::WifiScan => {
+                        let found = wifi.scan();
+                        app.networks_found(found, wifi.known(), &mut canvas);
+                    }
+                    BoardRequest::WifiConnect(creds) => {
+                        let result = wifi.connect(&creds);
+                        app.wifi_connected(&creds.ssid, result, wifi.known(), &mut canvas);
+                    }
+                    BoardRequest::WifiForget(ssid) => {
+                        if let Err(e) = wifi.forget(&ssid) {
+                            error!("can't forget wifi {}: {}", ssid, e);
+                        }
+                        app.networks_changed(wifi.known(), &mut canvas);
+                    }
+                    BoardRequest::Network => {
+                        match inkplate::card_space() {
+                            Ok((free, total)) => app.set_card_space(free, total),
+                            Err(e) => warn!("{}", e),
+                        }
+                        let network = wifi.acquire().and_then(|_| wifi.broadcast());
+                        let mut board = BoardProgress {
+                            graphics: &mut graphics,
+                            events: &app_receive_ch,
+                            kept: Vec::new(),
+                        };
+                        app.online(network, &mut canvas, &mut board);
+                        // what came in meanwhile is handled next
+                        for event in board.kept {
+                            let _ = app_send_ch.send(event);
+                        }
+                    }
+                    BoardRequest::StartFileServer => {
+                        let started = wifi.acquire().and_then(|_| {
+                            let ip = match wifi.state() {
+                                net::wifi::WifiState::Connected { ip, .. } => *ip,
+                                state => anyhow::bail!("wifi is {:?}", state),
+                            };
+                            let credentials = http_server::new_credentials();
+                            let pin = credentials.pin.clone();
+                            let server = http_server::start_file_server(
+                                books_dir,
+                                credentials,
+                                library_send_ch.clone(),
+                            )?;
+                            file_server = Some(server);
+                            Ok((format!("http://{}/", ip), pin))
+                        });
+                        app.file_server_started(started, &mut canvas);
+                    }
+                    BoardRequest::StopFileServer => {
+                        file_server = None;
+                        info!("file server stopped");
+                    }
+                    BoardRequest::CheckUpdate => {
+                        let manifest_url = app.settings().update.manifest_url.clone();
+                        let checked = wifi.acquire().and_then(|_| {
+                            update::remote::check(&fetcher, &manifest_url, &running_version)
+                        });
+                        app.update_checked(
+                            checked,
+                            &running_version,
+                            ota::can_roll_back(),
+                            &mut canvas,
+                        );
+                    }
+                    BoardRequest::RollBack => {
+                        // only comes back if it can't
+                        if let Err(e) = ota::roll_back() {
+                            app.update_failed(&e, &mut canvas);
+                        }
+                    }
+                    BoardRequest::Update(manifest) => {
+                        let min_battery = app.settings().update.min_battery;
+                        let (width, height) = inkplate::user_size(&graphics.config());
+                        let mut bar = ProgressBar::new("Updating firmware", width, height);
+                        let result = wifi
+                            .acquire()
+                            .and_then(|_| bat_mon.read_level(&mut adc1, &mut delay))
+                            .and_then(|level| {
+                                // the bar is drawn over the library as the image comes
+                                let mut progress = |done, total| {
+                                    if bar.update(done, total) {
+                                        info!("firmware update {}%", bar.percent().unwrap_or(0));
+                                        let _ = bar.draw(&mut canvas);
+                                        if let Err(e) =
+                                            inkplate::refresh(&mut graphics, &mut canvas)
+                                        {
+                                            warn!("{}", e);
+                                        }
+                                    }
+                                };
+                                ota::update_from_manifest(
+                                    &fetcher,
+                                    &manifest,
+                                    level,
+                                    min_battery,
+                                    &mut progress,
+                                )
+                            });
+                        match result {
+                            Ok(_) => ota::reboot(),
+                            Err(e) => app.update_failed(&e, &mut canvas),
+                        }
+                    }
+                    BoardRequest::FactoryReset => match settings_store.factory_reset() {
+                        // the defaults are taken up by a fresh start
+                        Ok(_) => esp_idf_svc::hal::reset::restart(),
+                        Err(e) => app.reset_failed(&e, &mut canvas),
+                    },
+                }
             }
-            let evt = touch_receive_ch.recv()?;
-            debug!("touch event: {:?}", evt);
-            check_free_heap();
         }
-        Ok(())
+        app.set_wifi_status(wifi.state().status_label(), &mut canvas);
+        inkplate::refresh(&mut graphics, &mut canvas)?;
     }
 }
 
-struct InkplateMainEventManager {
-    current_event: Option<Event>,
+// shows the app's downloads on the panel, a tap stops them
+struct BoardProgress<'a, 'b> {
+    graphics: &'a mut inkplate::Graphics<'b>,
+    events: &'a mpsc::Receiver<AppEvent>,
+    // events other than touches, sent again after
+    kept: Vec<AppEvent>,
 }
 
-impl InkplateMainEventManager {
-    pub fn new() -> Self {
-        Self {
-            current_event: None,
+impl ShowProgress for BoardProgress<'_, '_> {
+    fn show(&mut self, canvas: &mut Canvas) {
+        if let Err(e) = inkplate::refresh(self.graphics, canvas) {
+            warn!("{}", e);
         }
     }
-}
-
-impl EventManager for InkplateMainEventManager {
-    #[cfg(feature = "touch")]
-    fn show_calibration(&self) {}
-    #[cfg(feature = "touch")]
-    fn calibration_event(&self, ev: TouchEvent) -> bool {
-        true
-    }
-    //fn set_position(&mut self, x: u16, y: u16) {}
-    //fn get_position(&self) -> (u16, u16) {}
-    //fn to_user_coord(&self, x: u16, y: u16) -> (u16, u16) {
-    //    (0, 0)
-    //}
 
-    fn setup(&mut self) {}
-
-    fn event_loop_handler(&mut self) {
-        // does nothing
+    fn stopped(&mut self) -> bool {
+        while let Ok(event) = self.events.try_recv() {
+            match event {
+                AppEvent::Touch(evt) if evt.kind() == TouchEventKind::Tap => return true,
+                // the screen is busy, other touches and ticks are dropped
+                AppEvent::Touch(_) | AppEvent::Tick => (),
+                event => self.kept.push(event),
+            }
+        }
+        false
     }
+}
 
-    fn get_event(&mut self) -> Option<Event> {
-        self.current_event.take()
-    }
+// save the canvas as a pgm
+fn screenshot(canvas: &Canvas, path: &str) -> Result<()> {
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    canvas.write_pgm(&mut file)?;
+    file.flush()?;
+    Ok(())
 }
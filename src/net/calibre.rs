@@ -0,0 +1,783 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::config::settings::CalibreSettings;
+use anyhow::{anyhow, Result};
+use log::*;
+use serde_json::{json, Map, Value};
+use sha1::{Digest, Sha1};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream, UdpSocket},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// the udp ports calibre listens on for devices looking for it
+pub const BROADCAST_PORTS: [u16; 5] = [54982, 48123, 39001, 44044, 59678];
+// the start of calibre's answer to a broadcast
+const CALIBRE_REPLY: &str = "calibre wireless device client";
+// the protocol version this device speaks
+const PROTOCOL_VERSION: i64 = 1;
+// book formats sent to the device
+const EXTENSIONS: [&str; 5] = ["epub", "fb2", "txt", "md", "cbz"];
+// longest message accepted, book data is sent outside messages
+const MAX_MESSAGE: usize = 4 * 1024 * 1024;
+// longest message length, its digits and any white space before them
+const MAX_LENGTH_DIGITS: u64 = 16;
+// size of the copy buffer
+const CHUNK_SIZE: usize = 4096;
+// suffix of a book being received, renamed when complete
+const PARTIAL_SUFFIX: &str = ".part";
+
+/// The operation codes of the calibre smart device protocol
+pub mod opcode {
+    pub const OK: u32 = 0;
+    pub const SET_CALIBRE_DEVICE_INFO: u32 = 1;
+    pub const SET_CALIBRE_DEVICE_NAME: u32 = 2;
+    pub const GET_DEVICE_INFORMATION: u32 = 3;
+    pub const TOTAL_SPACE: u32 = 4;
+    pub const FREE_SPACE: u32 = 5;
+    pub const GET_BOOK_COUNT: u32 = 6;
+    pub const SEND_BOOKLISTS: u32 = 7;
+    pub const SEND_BOOK: u32 = 8;
+    pub const GET_INITIALIZATION_INFO: u32 = 9;
+    pub const BOOK_DONE: u32 = 11;
+    pub const NOOP: u32 = 12;
+    pub const DELETE_BOOK: u32 = 13;
+    pub const GET_BOOK_FILE_SEGMENT: u32 = 14;
+    pub const GET_BOOK_METADATA: u32 = 15;
+    pub const SEND_BOOK_METADATA: u32 = 16;
+    pub const DISPLAY_MESSAGE: u32 = 17;
+    pub const CALIBRE_BUSY: u32 = 18;
+    pub const SET_LIBRARY_INFO: u32 = 19;
+    pub const ERROR: u32 = 20;
+}
+
+/// the name and tcp address in calibre's answer to a broadcast
+///
+/// the answer looks like `calibre wireless device client (on host);8080,9090`,
+/// the last port is the one devices connect to
+pub fn parse_discovery_reply(reply: &str, from: IpAddr) -> Option<(String, SocketAddr)> {
+    let rest = reply.strip_prefix(CALIBRE_REPLY)?;
+    let (name, ports) = rest.rsplit_once(';')?;
+    let port = ports.rsplit(',').next()?.trim().parse().ok()?;
+    let name = name
+        .trim()
+        .trim_start_matches("(on ")
+        .trim_end_matches(')')
+        .to_string();
+    Some((name, SocketAddr::new(from, port)))
+}
+
+/// look for calibre on the local network
+///
+/// a packet goes to each of calibre's ports at `target`, the broadcast
+/// address on the device, and the answers are collected until `timeout`
+pub fn discover(target: IpAddr, timeout: Duration) -> Result<Vec<(String, SocketAddr)>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_broadcast(true)?;
+    for port in BROADCAST_PORTS {
+        if let Err(e) = socket.send_to(b"hello", (target, port)) {
+            debug!("calibre broadcast to port {} failed: {}", port, e);
+        }
+    }
+    let mut found: Vec<(String, SocketAddr)> = Vec::new();
+    let end = Instant::now() + timeout;
+    let mut buf = [0u8; 512];
+    while let Some(left) = end.checked_duration_since(Instant::now()) {
+        socket.set_read_timeout(Some(left.max(Duration::from_millis(1))))?;
+        let (n, from) = match socket.recv_from(&mut buf) {
+            Ok(r) => r,
+            Err(_) => break,
+        };
+        let reply = String::from_utf8_lossy(&buf[..n]);
+        if let Some(server) = parse_discovery_reply(&reply, from.ip()) {
+            if !found.iter().any(|(_, a)| *a == server.1) {
+                info!("found calibre {} at {}", server.0, server.1);
+                found.push(server);
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// read a message, the json length in ascii then `[opcode, {args}]`
+pub fn read_message<R: BufRead>(r: &mut R) -> Result<(u32, Value)> {
+    let mut digits = Vec::new();
+    r.take(MAX_LENGTH_DIGITS).read_until(b'[', &mut digits)?;
+    if digits.pop() != Some(b'[') {
+        if digits.is_empty() {
+            return Err(anyhow!("calibre closed the connection"));
+        }
+        return Err(anyhow!("bad calibre message length"));
+    }
+    let len: usize = std::str::from_utf8(&digits)?
+        .trim()
+        .parse()
+        .map_err(|_| anyhow!("bad calibre message length"))?;
+    if len == 0 || len > MAX_MESSAGE {
+        return Err(anyhow!("bad calibre message length {}", len));
+    }
+    let mut body = vec![0u8; len];
+    body[0] = b'[';
+    r.read_exact(&mut body[1..])?;
+    let value: Value = serde_json::from_slice(&body)?;
+    let op = value[0]
+        .as_u64()
+        .ok_or_else(|| anyhow!("bad calibre message"))?;
+    Ok((op as u32, value[1].clone()))
+}
+
+/// write a message
+pub fn write_message<W: Write>(w: &mut W, op: u32, args: &Value) -> Result<()> {
+    let body = serde_json::to_vec(&json!([op, args]))?;
+    write!(w, "{}", body.len())?;
+    w.write_all(&body)?;
+    w.flush()?;
+    Ok(())
+}
+
+/// something the app may want to show or act on
+#[derive(Debug, Clone, PartialEq)]
+pub enum CalibreEvent {
+    /// a book is arriving, bytes done of the total
+    Receiving {
+        title: String,
+        done: u64,
+        total: u64,
+    },
+    /// a book was stored, the library needs indexing
+    BookReceived(PathBuf),
+    BookDeleted(PathBuf),
+    /// a message from calibre, like a wrong password
+    Message(String),
+}
+
+/// The books calibre sent, with the metadata it sent with them
+///
+/// Stored in `<ereader>/calibre/metadata.json` keyed by the lpath, the
+/// path of the book under the books directory.
+#[derive(Debug)]
+pub struct CalibreLibrary {
+    books_dir: PathBuf,
+    path: PathBuf,
+    books: BTreeMap<String, Value>,
+}
+
+impl CalibreLibrary {
+    /// open the library, books no longer on the sdcard are dropped
+    pub fn open(ereader_dir: &Path, books_dir: &Path) -> Result<Self> {
+        let path = ereader_dir.join("calibre").join("metadata.json");
+        let mut books: BTreeMap<String, Value> = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_default(),
+            Err(_) => BTreeMap::new(),
+        };
+        books.retain(|lpath, _| books_dir.join(lpath).is_file());
+        Ok(Self {
+            books_dir: books_dir.to_path_buf(),
+            path,
+            books,
+        })
+    }
+
+    /// write the metadata back to the sdcard
+    pub fn save(&self) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&self.path, serde_json::to_vec(&self.books)?)?;
+        Ok(())
+    }
+
+    /// the metadata of the books, by lpath
+    pub fn books(&self) -> &BTreeMap<String, Value> {
+        &self.books
+    }
+
+    /// the file of a book, the lpath can't leave the books directory
+    pub fn book_path(&self, lpath: &str) -> Result<PathBuf> {
+        let mut path = self.books_dir.clone();
+        for part in lpath.split(['/', '\\']) {
+            match part {
+                "" | "." => {}
+                ".." => return Err(anyhow!("bad lpath {}", lpath)),
+                p => path.push(p),
+            }
+        }
+        if path == self.books_dir {
+            return Err(anyhow!("empty lpath"));
+        }
+        Ok(path)
+    }
+
+    /// mark a book read or unread, sent to calibre on the next connection
+    pub fn set_read(&mut self, book: &Path, read: bool) {
+        let lpath = book
+            .strip_prefix(&self.books_dir)
+            .map(|p| p.to_string_lossy().replace('\\', "/"));
+        if let Some(meta) = lpath.ok().and_then(|l| self.books.get_mut(&l)) {
+            meta["_is_read_"] = json!(read);
+        }
+    }
+
+    fn insert(&mut self, lpath: &str, mut meta: Value) {
+        if !meta.is_object() {
+            meta = json!({});
+        }
+        meta["lpath"] = json!(lpath);
+        self.books.insert(lpath.to_string(), meta);
+    }
+}
+
+/// A connection to calibre, which sends the commands
+pub struct CalibreSession {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    settings: CalibreSettings,
+    library: CalibreLibrary,
+    free_space: u64,
+    total_space: u64,
+}
+
+impl CalibreSession {
+    /// connect to calibre at an address from `discover`
+    ///
+    /// the space is that of the sdcard, for calibre to show
+    pub fn connect(
+        addr: SocketAddr,
+        settings: &CalibreSettings,
+        library: CalibreLibrary,
+        free_space: u64,
+        total_space: u64,
+    ) -> Result<Self> {
+        let stream = TcpStream::connect_timeout(&addr, Duration::from_secs(10))?;
+        stream.set_nodelay(true)?;
+        info!("connected to calibre at {}", addr);
+        Ok(Self {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+            settings: settings.clone(),
+            library,
+            free_space,
+            total_space,
+        })
+    }
+
+    /// a handle to end the session from another thread, with `shutdown`
+    pub fn stream(&self) -> Result<TcpStream> {
+        Ok(self.writer.get_ref().try_clone()?)
+    }
+
+    /// answer calibre until it disconnects or ejects the device
+    pub fn run(&mut self, events: &mut dyn FnMut(CalibreEvent)) -> Result<()> {
+        let result = self.serve(events);
+        self.library.save()?;
+        result
+    }
+
+    fn serve(&mut self, events: &mut dyn FnMut(CalibreEvent)) -> Result<()> {
+        loop {
+            let (op, args) = match read_message(&mut self.reader) {
+                Ok(m) => m,
+                Err(e) => {
+                    info!("calibre session ended: {}", e);
+                    return Ok(());
+                }
+            };
+            debug!("calibre op {}", op);
+            match op {
+                opcode::GET_INITIALIZATION_INFO => self.init_info(&args)?,
+                opcode::GET_DEVICE_INFORMATION => self.device_info()?,
+                opcode::SET_CALIBRE_DEVICE_INFO | opcode::SET_CALIBRE_DEVICE_NAME => {
+                    self.ok(&json!({}))?
+                }
+                opcode::SET_LIBRARY_INFO => self.ok(&json!({}))?,
+                opcode::TOTAL_SPACE => {
+                    self.ok(&json!({ "total_space_on_device": self.total_space }))?
+                }
+                opcode::FREE_SPACE => {
+                    self.ok(&json!({ "free_space_on_device": self.free_space }))?
+                }
+                opcode::GET_BOOK_COUNT => self.book_count()?,
+                opcode::SEND_BOOKLISTS => (),
+                opcode::SEND_BOOK_METADATA => self.book_metadata(&args),
+                opcode::SEND_BOOK => self.receive_book(&args, events)?,
+                opcode::DELETE_BOOK => self.delete_books(&args, events)?,
+                opcode::GET_BOOK_FILE_SEGMENT => self.send_book(&args)?,
+                opcode::DISPLAY_MESSAGE => {
+                    let msg = args["message"].as_str().unwrap_or("").to_string();
+                    // kind 1 is a bad password
+                    if args["messageKind"].as_i64() == Some(1) {
+                        events(CalibreEvent::Message("Wrong calibre password".to_string()));
+                    } else if !msg.is_empty() {
+                        events(CalibreEvent::Message(msg));
+                    }
+                }
+                opcode::NOOP => {
+                    if args.get("ejecting").is_some() {
+                        self.ok(&json!({}))?;
+                        info!("calibre ejected the device");
+                        return Ok(());
+                    } else if let Some(index) = args["priKey"].as_u64() {
+                        // the metadata of a book, by its place in the count
+                        let meta = self.library.books().values().nth(index as usize).cloned();
+                        self.ok(&meta.unwrap_or_else(|| json!({})))?;
+                    } else if args.get("count").is_none() {
+                        // a keep alive
+                        self.ok(&json!({}))?;
+                    }
+                }
+                opcode::CALIBRE_BUSY => debug!("calibre is busy"),
+                op => {
+                    warn!("unknown calibre op {}", op);
+                    write_message(&mut self.writer, opcode::ERROR, &json!({}))?;
+                }
+            }
+        }
+    }
+
+    fn ok(&mut self, args: &Value) -> Result<()> {
+        write_message(&mut self.writer, opcode::OK, args)
+    }
+
+    fn init_info(&mut self, args: &Value) -> Result<()> {
+        let server_version = args["serverProtocolVersion"].as_i64().unwrap_or(0);
+        let challenge = args["passwordChallenge"].as_str().unwrap_or("");
+        let password_hash = if challenge.is_empty() || self.settings.password.is_empty() {
+            String::new()
+        } else {
+            password_hash(challenge, &self.settings.password)
+        };
+        let path_lengths: Map<String, Value> = EXTENSIONS
+            .iter()
+            .map(|e| (e.to_string(), json!(255)))
+            .collect();
+        self.ok(&json!({
+            "versionOK": server_version >= PROTOCOL_VERSION,
+            "appName": "inkplate-ereader",
+            "deviceKind": "InkPlate",
+            "deviceName": self.settings.device_name,
+            "ccVersionNumber": 1,
+            "acceptedExtensions": EXTENSIONS,
+            "extensionPathLengths": path_lengths,
+            "passwordHash": password_hash,
+            "canStreamBooks": true,
+            "canStreamMetadata": true,
+            "canReceiveBookBinary": true,
+            "canDeleteMultipleBooks": true,
+            "canUseCachedMetadata": false,
+            "canSendOkToSendbook": true,
+            "canAcceptLibraryInfo": true,
+            "cacheUsesLpaths": true,
+            "useUuidFileNames": false,
+            "coverHeight": 0,
+            "maxBookContentPacketLen": CHUNK_SIZE,
+        }))
+    }
+
+    fn device_info(&mut self) -> Result<()> {
+        self.ok(&json!({
+            "device_info": {
+                "device_name": self.settings.device_name,
+                "device_store_uuid": self.device_uuid(),
+            },
+            "version": PROTOCOL_VERSION,
+            "device_version": env!("CARGO_PKG_VERSION"),
+        }))
+    }
+
+    // the id calibre keeps this device's books under
+    fn device_uuid(&self) -> String {
+        if !self.settings.device_uuid.is_empty() {
+            return self.settings.device_uuid.clone();
+        }
+        let hash = Sha1::digest(self.settings.device_name.as_bytes());
+        hash.iter().take(16).map(|b| format!("{:02x}", b)).collect()
+    }
+
+    // the count, then the metadata of each book streamed after it
+    fn book_count(&mut self) -> Result<()> {
+        let books: Vec<Value> = self.library.books().values().cloned().collect();
+        self.ok(&json!({
+            "count": books.len(),
+            "willStream": true,
+            "willScan": false,
+        }))?;
+        for meta in books {
+            self.ok(&meta)?;
+        }
+        Ok(())
+    }
+
+    // updated metadata, like the read status, for a book on the device
+    fn book_metadata(&mut self, args: &Value) {
+        let meta = &args["data"];
+        if let Some(lpath) = meta["lpath"].as_str() {
+            if self.library.books().contains_key(lpath) {
+                self.library.insert(lpath, meta.clone());
+            }
+        }
+    }
+
+    fn receive_book(&mut self, args: &Value, events: &mut dyn FnMut(CalibreEvent)) -> Result<()> {
+        let lpath = args["lpath"]
+            .as_str()
+            .ok_or_else(|| anyhow!("calibre sent a book without an lpath"))?
+            .to_string();
+        let length = args["length"].as_u64().unwrap_or(0);
+        let meta = args["metadata"].clone();
+        let title = meta["title"].as_str().unwrap_or(&lpath).to_string();
+        let wants_ok = args["wantsSendOkToSendbook"].as_bool().unwrap_or(false);
+        let dest = match self.library.book_path(&lpath) {
+            Ok(dest) => dest,
+            Err(e) => {
+                warn!("refused a book from calibre: {}", e);
+                if wants_ok {
+                    // calibre waits for the ok, so no bytes follow
+                    let msg = json!({ "message": e.to_string() });
+                    return write_message(&mut self.writer, opcode::ERROR, &msg);
+                }
+                // the bytes follow anyway, skip them to stay in step
+                let skipped = io::copy(&mut (&mut self.reader).take(length), &mut io::sink())?;
+                if skipped < length {
+                    return Err(anyhow!("calibre stopped sending {}", lpath));
+                }
+                events(CalibreEvent::Message(format!("Refused book {}", lpath)));
+                return Ok(());
+            }
+        };
+        if wants_ok {
+            self.ok(&json!({ "lpath": lpath }))?;
+        }
+
+        // the book follows as raw bytes
+        if let Some(dir) = dest.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut partial = dest.clone().into_os_string();
+        partial.push(PARTIAL_SUFFIX);
+        let partial = PathBuf::from(partial);
+        let mut out = BufWriter::new(fs::File::create(&partial)?);
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        let mut done = 0;
+        while done < length {
+            let want = (length - done).min(CHUNK_SIZE as u64) as usize;
+            let n = self.reader.read(&mut buf[..want])?;
+            if n == 0 {
+                drop(out);
+                let _ = fs::remove_file(&partial);
+                return Err(anyhow!("calibre stopped sending {}", lpath));
+            }
+            out.write_all(&buf[..n])?;
+            done += n as u64;
+            events(CalibreEvent::Receiving {
+                title: title.clone(),
+                done,
+                total: length,
+            });
+        }
+        out.flush()?;
+        drop(out);
+        // fat can't rename over an existing file
+        if dest.exists() {
+            fs::remove_file(&dest)?;
+        }
+        fs::rename(&partial, &dest)?;
+        info!("received {} bytes to {:?}", length, dest);
+        self.library.insert(&lpath, meta);
+        self.library.save()?;
+        self.free_space = self.free_space.saturating_sub(length);
+        events(CalibreEvent::BookReceived(dest));
+        Ok(())
+    }
+
+    // an ok, then an ok with the uuid of each deleted book
+    fn delete_books(&mut self, args: &Value, events: &mut dyn FnMut(CalibreEvent)) -> Result<()> {
+        let lpaths: Vec<String> = args["lpaths"]
+            .as_array()
+            .map(|a| {
+                a.iter()
+                    .filter_map(|l| l.as_str().map(str::to_string))
+                    .collect()
+            })
+            .unwrap_or_default();
+        self.ok(&json!({}))?;
+        for lpath in lpaths {
+            let uuid = self.library.books().get(&lpath).map(|m| m["uuid"].clone());
+            let path = match self.library.book_path(&lpath) {
+                Ok(path) => path,
+                Err(e) => {
+                    warn!("refused to delete a book for calibre: {}", e);
+                    self.ok(&json!({ "uuid": Value::Null }))?;
+                    continue;
+                }
+            };
+            if path.is_file() {
+                let size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+                fs::remove_file(&path)?;
+                self.free_space += size;
+                info!("calibre deleted {:?}", path);
+                events(CalibreEvent::BookDeleted(path));
+            }
+            self.library.books.remove(&lpath);
+            self.ok(&json!({ "uuid": uuid.unwrap_or(Value::Null) }))?;
+        }
+        self.library.save()
+    }
+
+    // send a book back to calibre, an ok with the length then the bytes
+    fn send_book(&mut self, args: &Value) -> Result<()> {
+        let lpath = args["lpath"].as_str().unwrap_or("");
+        let opened = self
+            .library
+            .book_path(lpath)
+            .and_then(|path| Ok(fs::File::open(path)?));
+        let mut file = match opened {
+            Ok(file) => file,
+            Err(e) => {
+                write_message(
+                    &mut self.writer,
+                    opcode::ERROR,
+                    &json!({ "message": e.to_string() }),
+                )?;
+                return Ok(());
+            }
+        };
+        let length = file.metadata()?.len();
+        self.ok(&json!({ "fileLength": length, "canStreamBinary": true }))?;
+        io::copy(&mut file, &mut self.writer)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// the answer to calibre's password challenge
+pub fn password_hash(challenge: &str, password: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(password.as_bytes());
+    hasher.update(challenge.as_bytes());
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+    use std::{io::Cursor, net::TcpListener, thread};
+
+    // calibre's end of a session, with the device's session on a thread
+    struct Calibre {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+        session: thread::JoinHandle<Vec<CalibreEvent>>,
+        dir: PathBuf,
+    }
+
+    impl Calibre {
+        // start a session with books already sent, `(lpath, contents)`
+        fn start(name: &str, books: &[(&str, &str)]) -> Self {
+            let dir = temp_dir(name);
+            let books_dir = dir.join("books");
+            let mut library = CalibreLibrary::open(&dir, &books_dir).unwrap();
+            for (lpath, contents) in books {
+                let path = library.book_path(lpath).unwrap();
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, contents).unwrap();
+                library.insert(
+                    lpath,
+                    json!({ "title": lpath, "uuid": format!("u-{}", lpath) }),
+                );
+            }
+            let settings = CalibreSettings {
+                password: "secret".to_string(),
+                ..Default::default()
+            };
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let session = thread::spawn(move || {
+                let mut session =
+                    CalibreSession::connect(addr, &settings, library, 1000, 2000).unwrap();
+                let mut events = Vec::new();
+                session.run(&mut |e| events.push(e)).unwrap();
+                events
+            });
+            let (stream, _) = listener.accept().unwrap();
+            Self {
+                reader: BufReader::new(stream.try_clone().unwrap()),
+                writer: stream,
+                session,
+                dir,
+            }
+        }
+
+        fn send(&mut self, op: u32, args: Value) {
+            write_message(&mut self.writer, op, &args).unwrap();
+        }
+
+        fn reply(&mut self) -> (u32, Value) {
+            read_message(&mut self.reader).unwrap()
+        }
+
+        // a keep alive, its answer shows the session read all before it
+        fn sync(&mut self) {
+            self.send(opcode::NOOP, json!({}));
+            assert_eq!(self.reply(), (opcode::OK, json!({})));
+        }
+
+        // eject the device, the events of the session
+        fn eject(mut self) -> (Vec<CalibreEvent>, PathBuf) {
+            self.send(opcode::NOOP, json!({ "ejecting": true }));
+            assert_eq!(self.reply().0, opcode::OK);
+            (self.session.join().unwrap(), self.dir)
+        }
+    }
+
+    #[test]
+    fn messages_are_length_prefixed() {
+        let mut out = Vec::new();
+        write_message(&mut out, opcode::OK, &json!({ "a": 1 })).unwrap();
+        assert_eq!(out, b"11[0,{\"a\":1}]");
+        let mut input = Cursor::new(out);
+        assert_eq!(read_message(&mut input).unwrap(), (0, json!({ "a": 1 })));
+        assert!(read_message(&mut input).is_err());
+        // a length with no end isn't read without limit
+        let mut endless = io::repeat(b'9').take(1 << 20);
+        let e = read_message(&mut BufReader::new(&mut endless)).unwrap_err();
+        assert_eq!(e.to_string(), "bad calibre message length");
+        let mut huge = Cursor::new(b"99999999[0,{}]".to_vec());
+        assert!(read_message(&mut huge).is_err());
+    }
+
+    #[test]
+    fn discovery_replies() {
+        let from = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 5));
+        let (name, addr) =
+            parse_discovery_reply("calibre wireless device client (on desk);8080,9090", from)
+                .unwrap();
+        assert_eq!(name, "desk");
+        assert_eq!(addr, SocketAddr::new(from, 9090));
+        assert!(parse_discovery_reply("hello", from).is_none());
+    }
+
+    #[test]
+    fn init_answers_the_password_challenge() {
+        let mut calibre = Calibre::start("calibre_init", &[]);
+        let info = json!({ "serverProtocolVersion": 1, "passwordChallenge": "abc" });
+        calibre.send(opcode::GET_INITIALIZATION_INFO, info);
+        let (op, args) = calibre.reply();
+        assert_eq!(op, opcode::OK);
+        assert_eq!(args["versionOK"], true);
+        assert_eq!(
+            args["passwordHash"],
+            "338127540dccbe48589a0ff30875548fc24c74c8"
+        );
+        assert_eq!(args["acceptedExtensions"][0], "epub");
+        calibre.send(opcode::FREE_SPACE, json!({}));
+        assert_eq!(calibre.reply().1["free_space_on_device"], 1000);
+        let (_, dir) = calibre.eject();
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn book_count_streams_the_metadata() {
+        let mut calibre = Calibre::start("calibre_count", &[("a.epub", "a"), ("b/c.txt", "c")]);
+        calibre.send(opcode::GET_BOOK_COUNT, json!({ "canStream": true }));
+        let (_, count) = calibre.reply();
+        assert_eq!(count["count"], 2);
+        assert_eq!(count["willStream"], true);
+        assert_eq!(calibre.reply().1["lpath"], "a.epub");
+        assert_eq!(calibre.reply().1["lpath"], "b/c.txt");
+        let (_, dir) = calibre.eject();
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn books_are_received_sent_and_deleted() {
+        let mut calibre = Calibre::start("calibre_books", &[]);
+        let book = json!({
+            "lpath": "Author/Book.epub",
+            "length": 10,
+            "wantsSendOkToSendbook": true,
+            "metadata": { "title": "Book", "uuid": "u1" },
+        });
+        calibre.send(opcode::SEND_BOOK, book);
+        assert_eq!(
+            calibre.reply(),
+            (opcode::OK, json!({ "lpath": "Author/Book.epub" }))
+        );
+        calibre.writer.write_all(b"0123456789").unwrap();
+        calibre.sync();
+        let path = calibre.dir.join("books/Author/Book.epub");
+        assert_eq!(fs::read(&path).unwrap(), b"0123456789");
+
+        calibre.send(
+            opcode::GET_BOOK_FILE_SEGMENT,
+            json!({ "lpath": "Author/Book.epub" }),
+        );
+        let (op, args) = calibre.reply();
+        assert_eq!((op, args["fileLength"].as_u64()), (opcode::OK, Some(10)));
+        let mut data = [0u8; 10];
+        calibre.reader.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"0123456789");
+
+        calibre.send(
+            opcode::DELETE_BOOK,
+            json!({ "lpaths": ["Author/Book.epub"] }),
+        );
+        assert_eq!(calibre.reply(), (opcode::OK, json!({})));
+        assert_eq!(calibre.reply(), (opcode::OK, json!({ "uuid": "u1" })));
+        assert!(!path.exists());
+
+        let (events, dir) = calibre.eject();
+        assert_eq!(
+            events.last(),
+            Some(&CalibreEvent::BookDeleted(path.clone()))
+        );
+        assert!(events.contains(&CalibreEvent::BookReceived(path)));
+        let library = CalibreLibrary::open(&dir, &dir.join("books")).unwrap();
+        assert!(library.books().is_empty());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn lpaths_stay_in_the_books_dir() {
+        let mut calibre = Calibre::start("calibre_lpath", &[("a.epub", "a")]);
+        let evil = json!({ "lpath": "../evil.epub", "length": 4, "wantsSendOkToSendbook": true });
+        calibre.send(opcode::SEND_BOOK, evil);
+        assert_eq!(calibre.reply().0, opcode::ERROR);
+        // without the ok the bytes come anyway and are skipped
+        let evil = json!({ "lpath": "a\\..\\..\\evil.epub", "length": 4 });
+        calibre.send(opcode::SEND_BOOK, evil);
+        calibre.writer.write_all(b"evil").unwrap();
+        calibre.sync();
+        calibre.send(
+            opcode::GET_BOOK_FILE_SEGMENT,
+            json!({ "lpath": "../a.epub" }),
+        );
+        assert_eq!(calibre.reply().0, opcode::ERROR);
+        calibre.send(
+            opcode::DELETE_BOOK,
+            json!({ "lpaths": ["../books/a.epub"] }),
+        );
+        assert_eq!(calibre.reply(), (opcode::OK, json!({})));
+        assert_eq!(calibre.reply(), (opcode::OK, json!({ "uuid": null })));
+
+        let (events, dir) = calibre.eject();
+        assert_eq!(
+            events,
+            vec![CalibreEvent::Message(
+                "Refused book a\\..\\..\\evil.epub".to_string()
+            )]
+        );
+        assert!(!dir.join("evil.epub").exists());
+        assert!(dir.join("books/a.epub").exists());
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,720 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::imaging::source::natural_cmp;
+use anyhow::{anyhow, Result};
+use log::*;
+use serde::Serialize;
+use std::{
+    fmt, fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+// the web page, small enough to keep in flash, names are only ever
+// put in the page as text
+const INDEX_HTML: &str = r#"<!DOCTYPE html>
+<html><head><meta charset="utf-8"><meta name="viewport" content="width=device-width">
+<title>eReader files</title>
+<style>body{font-family:sans-serif;margin:1em}td{padding:2px 8px}a{cursor:pointer;color:#00e}</style>
+</head><body>
+<form id="login" hidden><p>PIN shown on the eReader <input id="pin" inputmode="numeric"
+ autocomplete="off"> <button>Open</button></p></form>
+<div id="files" hidden>
+<h2 id="path"></h2>
+<p><input type="file" id="upload" multiple> <button id="send">Upload</button>
+<button id="mkdir">New folder</button> <span id="status"></span></p>
+<table id="list"></table>
+</div>
+<script>
+let cur = "/";
+let token = sessionStorage.getItem("token");
+const $ = id => document.getElementById(id);
+const q = p => encodeURIComponent(p);
+const join = n => (cur.endsWith("/") ? cur : cur + "/") + n;
+// every api call carries the token in a header, which other sites can't send
+async function api(method, url, body) {
+  const r = await fetch(url, {method, body, headers: {"X-Token": token || ""}});
+  if (r.status == 401) { showLogin(); throw new Error("login needed"); }
+  if (!r.ok) { const t = await r.text(); alert(t); throw new Error(t); }
+  return r;
+}
+function showLogin() {
+  sessionStorage.removeItem("token");
+  $("files").hidden = true;
+  $("login").hidden = false;
+}
+$("login").onsubmit = async ev => {
+  ev.preventDefault();
+  const r = await fetch("/api/login?pin=" + q($("pin").value), {method: "POST"});
+  if (!r.ok) { alert(await r.text()); return; }
+  token = (await r.json()).token;
+  sessionStorage.setItem("token", token);
+  $("login").hidden = true;
+  list("/");
+};
+function link(text, action) {
+  const a = document.createElement("a");
+  a.textContent = text;
+  a.addEventListener("click", action);
+  return a;
+}
+function row(...cells) {
+  const tr = document.createElement("tr");
+  for (const c of cells) {
+    const td = document.createElement("td");
+    if (c instanceof Node) td.appendChild(c); else td.textContent = c;
+    tr.appendChild(td);
+  }
+  return tr;
+}
+async function list(p) {
+  const d = await (await api("GET", "/api/list?path=" + q(p))).json();
+  cur = d.path;
+  $("files").hidden = false;
+  $("path").textContent = cur;
+  const rows = [];
+  if (cur != "/") rows.push(row(link("..", () => list(cur.replace(/\/[^\/]*$/, "") || "/"))));
+  for (const e of d.entries) {
+    const p = join(e.name);
+    rows.push(row(e.dir ? link(e.name + "/", () => list(p)) : link(e.name, () => get(p, e.name)),
+      e.dir ? "" : String(e.size), link("rename", () => ren(p)), link("delete", () => del(p))));
+  }
+  $("list").replaceChildren(...rows);
+}
+async function get(p, name) {
+  const blob = await (await api("GET", "/api/file?path=" + q(p))).blob();
+  const a = document.createElement("a");
+  a.href = URL.createObjectURL(blob);
+  a.download = name;
+  a.click();
+  URL.revokeObjectURL(a.href);
+}
+$("send").onclick = async () => {
+  const st = $("status");
+  try {
+    for (const f of $("upload").files) {
+      st.textContent = "uploading " + f.name;
+      await api("PUT", "/api/file?path=" + q(join(f.name)), f);
+    }
+  } finally { st.textContent = ""; }
+  list(cur);
+};
+$("mkdir").onclick = async () => {
+  const name = prompt("Folder name");
+  if (name) { await api("POST", "/api/mkdir?path=" + q(join(name))); list(cur); }
+};
+async function ren(p) {
+  const to = prompt("New name", p);
+  if (!to || to == p) return;
+  await api("POST", "/api/rename?from=" + q(p) + "&to=" + q(to));
+  list(cur);
+}
+async function del(p) {
+  if (!confirm("Delete " + p + "?")) return;
+  await api("DELETE", "/api/file?path=" + q(p));
+  list(cur);
+}
+if (token) list("/").catch(() => {}); else showLogin();
+</script></body></html>
+"#;
+
+// the header holding the session token
+pub const TOKEN_HEADER: &str = "X-Token";
+// wrong pins allowed before logins are refused, until the server restarts
+const MAX_PIN_TRIES: u32 = 5;
+// the eReader's own directory under the root, it holds the wifi passwords
+const PRIVATE_DIR: &str = "ereader";
+
+// suffix of a file being uploaded, renamed when complete
+const PARTIAL_SUFFIX: &str = ".part";
+// size of the copy buffer
+const CHUNK_SIZE: usize = 4096;
+
+/// The body of a response
+#[derive(Debug, PartialEq)]
+pub enum Body {
+    Bytes(Vec<u8>),
+    /// a file to stream back, for downloads
+    File(PathBuf),
+}
+
+/// The response to a request, for the http server to send
+#[derive(Debug, PartialEq)]
+pub struct FileResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    /// file name for a download, sent as a content disposition
+    pub file_name: Option<String>,
+    pub body: Body,
+    /// the files changed, so the library needs indexing
+    pub library_changed: bool,
+}
+
+impl FileResponse {
+    fn new(status: u16, content_type: &'static str, body: Vec<u8>) -> Self {
+        Self {
+            status,
+            content_type,
+            file_name: None,
+            body: Body::Bytes(body),
+            library_changed: false,
+        }
+    }
+
+    fn text(status: u16, text: &str) -> Self {
+        Self::new(status, "text/plain", text.as_bytes().to_vec())
+    }
+
+    fn changed(mut self) -> Self {
+        self.library_changed = true;
+        self
+    }
+
+    /// the reason phrase for the status
+    pub fn status_message(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            201 => "Created",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            409 => "Conflict",
+            _ => "Internal Server Error",
+        }
+    }
+
+    /// copy the body to a writer
+    pub fn write_body<W: Write>(&self, out: &mut W) -> Result<()> {
+        match &self.body {
+            Body::Bytes(bytes) => out.write_all(bytes)?,
+            Body::File(path) => {
+                io::copy(&mut fs::File::open(path)?, out)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A request that can't be done as asked
+#[derive(Debug)]
+struct RequestError(u16, String);
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.1)
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+fn bad_request(msg: &str) -> anyhow::Error {
+    anyhow!(RequestError(400, msg.to_string()))
+}
+
+fn forbidden(msg: &str) -> anyhow::Error {
+    anyhow!(RequestError(403, msg.to_string()))
+}
+
+/// What a browser needs to use the file manager
+///
+/// The pin is shown on the display, a browser trades it for the token,
+/// which it then sends with every api request. Both should be new random
+/// values each time the server starts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Credentials {
+    pub pin: String,
+    pub token: String,
+}
+
+/// Login response
+#[derive(Debug, Serialize)]
+struct Login<'a> {
+    token: &'a str,
+}
+
+/// A directory entry in a listing
+#[derive(Debug, Serialize)]
+struct Entry {
+    name: String,
+    dir: bool,
+    size: u64,
+}
+
+/// A directory listing
+#[derive(Debug, Serialize)]
+struct Listing {
+    path: String,
+    entries: Vec<Entry>,
+}
+
+/// The file manager behind the http server
+///
+/// It knows nothing of the server, requests come in as a method, a uri,
+/// the token header and a body reader so any http server can drive it.
+/// Paths in requests are relative to the root and can't leave it, or go
+/// into the eReader's own directory.
+#[derive(Debug)]
+pub struct FileManager {
+    root: PathBuf,
+    credentials: Credentials,
+    failed_logins: AtomicU32,
+}
+
+impl FileManager {
+    /// serve the files under `root`, `/sdcard` on the device
+    pub fn new(root: &Path, credentials: Credentials) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            credentials,
+            failed_logins: AtomicU32::new(0),
+        }
+    }
+
+    /// handle a request, `token` is the value of the `TOKEN_HEADER` header
+    pub fn handle<R: Read>(
+        &self,
+        method: &str,
+        uri: &str,
+        token: Option<&str>,
+        body: &mut R,
+    ) -> FileResponse {
+        let (route, query) = uri.split_once('?').unwrap_or((uri, ""));
+        let authorized = token == Some(self.credentials.token.as_str());
+        let result = match (method, route) {
+            ("GET", "/") | ("GET", "/index.html") => Ok(FileResponse::new(
+                200,
+                "text/html",
+                INDEX_HTML.as_bytes().to_vec(),
+            )),
+            ("POST", "/api/login") => self.login(query),
+            (_, r) if r.starts_with("/api/") && !authorized => Ok(FileResponse::text(
+                401,
+                "log in with the pin shown on the eReader",
+            )),
+            ("GET", "/api/list") => self.list(query),
+            ("GET", "/api/file") => self.download(query),
+            ("PUT", "/api/file") => self.upload(query, body),
+            ("DELETE", "/api/file") => self.delete(query),
+            ("POST", "/api/rename") => self.rename(query),
+            ("POST", "/api/mkdir") => self.mkdir(query),
+            (_, "/")
+            | (_, "/api/login")
+            | (_, "/api/list")
+            | (_, "/api/file")
+            | (_, "/api/rename")
+            | (_, "/api/mkdir") => Ok(FileResponse::text(405, "method not allowed")),
+            _ => Ok(FileResponse::text(404, "not found")),
+        };
+        result.unwrap_or_else(|e| {
+            let status = error_status(&e);
+            if status >= 500 {
+                warn!("file manager {} {} failed: {}", method, uri, e);
+            }
+            FileResponse::text(status, &e.to_string())
+        })
+    }
+
+    // trade the pin for the token, a few wrong pins lock logins out
+    fn login(&self, query: &str) -> Result<FileResponse> {
+        if self.failed_logins.load(Ordering::Relaxed) >= MAX_PIN_TRIES {
+            return Err(forbidden(
+                "too many wrong pins, restart file transfer on the eReader",
+            ));
+        }
+        let pin = query_param(query, "pin").unwrap_or_default();
+        if pin.trim() != self.credentials.pin {
+            let tries = self.failed_logins.fetch_add(1, Ordering::Relaxed) + 1;
+            warn!("wrong file manager pin, {} of {}", tries, MAX_PIN_TRIES);
+            return Err(forbidden("wrong pin"));
+        }
+        let login = Login {
+            token: &self.credentials.token,
+        };
+        Ok(FileResponse::new(
+            200,
+            "application/json",
+            serde_json::to_vec(&login)?,
+        ))
+    }
+
+    /// the file system path for a request path, which must stay under the root
+    /// and out of the eReader's own directory
+    pub fn resolve(&self, path: &str) -> Result<PathBuf> {
+        let mut resolved = self.root.clone();
+        let mut first = true;
+        for part in path.split('/') {
+            match part {
+                "" | "." => {}
+                ".." => return Err(bad_request("path leaves the root")),
+                // fat drops trailing dots and spaces, so `ereader.` is `ereader`
+                p if p.contains('\\')
+                    || p.contains('\0')
+                    || p.ends_with('.')
+                    || p.ends_with(' ') =>
+                {
+                    return Err(bad_request("bad path"));
+                }
+                p if first && p.eq_ignore_ascii_case(PRIVATE_DIR) => {
+                    return Err(forbidden("the eReader's own files can't be changed"));
+                }
+                p => {
+                    first = false;
+                    resolved.push(p)
+                }
+            }
+        }
+        Ok(resolved)
+    }
+
+    // the resolved `path` query parameter
+    fn path_param(&self, query: &str, name: &str) -> Result<(String, PathBuf)> {
+        let path = query_param(query, name).ok_or_else(|| bad_request("missing path"))?;
+        let resolved = self.resolve(&path)?;
+        Ok((path, resolved))
+    }
+
+    fn list(&self, query: &str) -> Result<FileResponse> {
+        let path = query_param(query, "path").unwrap_or_else(|| "/".to_string());
+        let dir = self.resolve(&path)?;
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if dir == self.root && name.eq_ignore_ascii_case(PRIVATE_DIR) {
+                continue;
+            }
+            entries.push(Entry {
+                name,
+                dir: meta.is_dir(),
+                size: meta.len(),
+            });
+        }
+        entries.sort_by(|a, b| {
+            b.dir
+                .cmp(&a.dir)
+                .then_with(|| natural_cmp(&a.name, &b.name))
+        });
+        let listing = Listing {
+            path: normalize(&path),
+            entries,
+        };
+        Ok(FileResponse::new(
+            200,
+            "application/json",
+            serde_json::to_vec(&listing)?,
+        ))
+    }
+
+    fn download(&self, query: &str) -> Result<FileResponse> {
+        let (_, file) = self.path_param(query, "path")?;
+        if !file.is_file() {
+            return Err(anyhow!(RequestError(404, "no such file".to_string())));
+        }
+        Ok(FileResponse {
+            status: 200,
+            content_type: "application/octet-stream",
+            file_name: file.file_name().map(|n| n.to_string_lossy().into_owned()),
+            body: Body::File(file),
+            library_changed: false,
+        })
+    }
+
+    fn upload<R: Read>(&self, query: &str, body: &mut R) -> Result<FileResponse> {
+        let (path, file) = self.path_param(query, "path")?;
+        if file == self.root || file.is_dir() {
+            return Err(bad_request("not a file path"));
+        }
+        if let Some(dir) = file.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // write to a partial file so a dropped upload doesn't leave a broken book
+        let mut partial = file.clone().into_os_string();
+        partial.push(PARTIAL_SUFFIX);
+        let partial = PathBuf::from(partial);
+        let size = match copy_to(body, &partial) {
+            Ok(size) => size,
+            Err(e) => {
+                let _ = fs::remove_file(&partial);
+                return Err(e);
+            }
+        };
+        // fat can't rename over an existing file
+        if file.exists() {
+            fs::remove_file(&file)?;
+        }
+        fs::rename(&partial, &file)?;
+        info!("uploaded {} bytes to {:?}", size, file);
+        Ok(FileResponse::text(201, &format!("uploaded {}", normalize(&path))).changed())
+    }
+
+    fn delete(&self, query: &str) -> Result<FileResponse> {
+        let (path, file) = self.path_param(query, "path")?;
+        if file == self.root {
+            return Err(bad_request("can't delete the root"));
+        }
+        if file.is_dir() {
+            fs::remove_dir_all(&file)?;
+        } else {
+            fs::remove_file(&file)?;
+        }
+        info!("deleted {:?}", file);
+        Ok(FileResponse::text(200, &format!("deleted {}", normalize(&path))).changed())
+    }
+
+    fn rename(&self, query: &str) -> Result<FileResponse> {
+        let (_, from) = self.path_param(query, "from")?;
+        let (to_path, to) = self.path_param(query, "to")?;
+        if from == self.root || to == self.root {
+            return Err(bad_request("can't rename the root"));
+        }
+        if !from.exists() {
+            return Err(anyhow!(RequestError(404, "no such file".to_string())));
+        }
+        if to.exists() {
+            return Err(anyhow!(RequestError(409, "target exists".to_string())));
+        }
+        if let Some(dir) = to.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::rename(&from, &to)?;
+        info!("renamed {:?} to {:?}", from, to);
+        Ok(FileResponse::text(200, &format!("renamed to {}", normalize(&to_path))).changed())
+    }
+
+    fn mkdir(&self, query: &str) -> Result<FileResponse> {
+        let (path, dir) = self.path_param(query, "path")?;
+        fs::create_dir_all(&dir)?;
+        Ok(FileResponse::text(
+            201,
+            &format!("created {}", normalize(&path)),
+        ))
+    }
+}
+
+// copy a request body to a file, returns the size
+fn copy_to<R: Read>(body: &mut R, path: &Path) -> Result<u64> {
+    let mut out = io::BufWriter::new(fs::File::create(path)?);
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut size = 0;
+    loop {
+        let n = body.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        out.write_all(&buf[..n])?;
+        size += n as u64;
+    }
+    out.flush()?;
+    Ok(size)
+}
+
+// the http status for an error
+fn error_status(e: &anyhow::Error) -> u16 {
+    if let Some(RequestError(status, _)) = e.downcast_ref::<RequestError>() {
+        return *status;
+    }
+    match e.downcast_ref::<io::Error>().map(|e| e.kind()) {
+        Some(io::ErrorKind::NotFound) => 404,
+        Some(io::ErrorKind::AlreadyExists) => 409,
+        _ => 500,
+    }
+}
+
+// a request path with single slashes and no trailing slash
+fn normalize(path: &str) -> String {
+    let parts: Vec<&str> = path.split('/').filter(|p| !p.is_empty()).collect();
+    format!("/{}", parts.join("/"))
+}
+
+/// a decoded query parameter
+pub fn query_param(query: &str, name: &str) -> Option<String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('=').or(Some((pair, ""))))
+        .find(|(k, _)| percent_decode(k) == name)
+        .map(|(_, v)| percent_decode(v))
+}
+
+/// decode `%xx` escapes and `+` as a space
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 3 <= bytes.len() => {
+                match std::str::from_utf8(&bytes[i + 1..i + 3])
+                    .ok()
+                    .and_then(|h| u8::from_str_radix(h, 16).ok())
+                {
+                    Some(b) => {
+                        out.push(b);
+                        i += 2;
+                    }
+                    None => out.push(b'%'),
+                }
+            }
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    const TOKEN: &str = "0123456789abcdef";
+
+    fn manager(name: &str) -> FileManager {
+        let credentials = Credentials {
+            pin: "123456".to_string(),
+            token: TOKEN.to_string(),
+        };
+        FileManager::new(&temp_dir(name), credentials)
+    }
+
+    fn request(fm: &FileManager, method: &str, uri: &str, body: &[u8]) -> FileResponse {
+        fm.handle(method, uri, Some(TOKEN), &mut &body[..])
+    }
+
+    fn body(response: &FileResponse) -> String {
+        let mut out = Vec::new();
+        response.write_body(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn paths_stay_under_the_root() {
+        let fm = manager("fm_resolve");
+        assert_eq!(
+            fm.resolve("/books/a.epub").unwrap(),
+            fm.root.join("books/a.epub")
+        );
+        assert_eq!(fm.resolve("./books//").unwrap(), fm.root.join("books"));
+        for bad in [
+            "../x",
+            "/books/../../x",
+            "a\\..\\b",
+            "a\0b",
+            "books./a",
+            "a ",
+        ] {
+            assert_eq!(error_status(&fm.resolve(bad).unwrap_err()), 400, "{}", bad);
+        }
+        for private in ["/ereader/settings.toml", "EReader", "/./ereader"] {
+            assert_eq!(
+                error_status(&fm.resolve(private).unwrap_err()),
+                403,
+                "{}",
+                private
+            );
+        }
+        // only the top level ereader directory is private
+        assert!(fm.resolve("/books/ereader").is_ok());
+        // encoded dots are decoded before the path is checked
+        let response = request(&fm, "GET", "/api/list?path=%2e%2e%2Fetc", b"");
+        assert_eq!(response.status, 400);
+        let _ = fs::remove_dir_all(&fm.root);
+    }
+
+    #[test]
+    fn api_needs_the_token() {
+        let fm = manager("fm_login");
+        assert_eq!(fm.handle("GET", "/", None, &mut io::empty()).status, 200);
+        let list = "/api/list?path=/";
+        assert_eq!(fm.handle("GET", list, None, &mut io::empty()).status, 401);
+        assert_eq!(
+            fm.handle("GET", list, Some("x"), &mut io::empty()).status,
+            401
+        );
+        let mkdir = fm.handle("POST", "/api/mkdir?path=a", None, &mut io::empty());
+        assert_eq!(mkdir.status, 401);
+        assert!(!fm.root.join("a").exists());
+
+        let login = fm.handle("POST", "/api/login?pin=123456", None, &mut io::empty());
+        assert_eq!(login.status, 200);
+        assert_eq!(body(&login), format!("{{\"token\":\"{}\"}}", TOKEN));
+        assert_eq!(request(&fm, "GET", list, b"").status, 200);
+        let _ = fs::remove_dir_all(&fm.root);
+    }
+
+    #[test]
+    fn wrong_pins_lock_out_logins() {
+        let fm = manager("fm_pins");
+        for _ in 0..MAX_PIN_TRIES {
+            let login = fm.handle("POST", "/api/login?pin=1", None, &mut io::empty());
+            assert_eq!(login.status, 403);
+        }
+        let login = fm.handle("POST", "/api/login?pin=123456", None, &mut io::empty());
+        assert_eq!(login.status, 403);
+        let _ = fs::remove_dir_all(&fm.root);
+    }
+
+    #[test]
+    fn upload_rename_delete() {
+        let fm = manager("fm_files");
+        fs::create_dir_all(fm.root.join("ereader")).unwrap();
+        let response = request(&fm, "PUT", "/api/file?path=/books/a%20b.txt", b"text");
+        assert_eq!(response.status, 201);
+        assert!(response.library_changed);
+        let file = fm.root.join("books/a b.txt");
+        assert_eq!(fs::read(&file).unwrap(), b"text");
+        assert!(!fm.root.join("books/a b.txt.part").exists());
+
+        // a failed upload leaves no partial file and keeps the old one
+        struct Broken;
+        impl Read for Broken {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::ConnectionReset, "dropped"))
+            }
+        }
+        let uri = "/api/file?path=/books/a%20b.txt";
+        assert_eq!(fm.handle("PUT", uri, Some(TOKEN), &mut Broken).status, 500);
+        assert!(!fm.root.join("books/a b.txt.part").exists());
+        assert_eq!(fs::read(&file).unwrap(), b"text");
+
+        let listing = body(&request(&fm, "GET", "/api/list?path=/", b""));
+        assert!(listing.contains("\"books\""));
+        assert!(!listing.contains("ereader"));
+
+        let rename = "/api/rename?from=/books/a%20b.txt&to=/c.txt";
+        assert_eq!(request(&fm, "POST", rename, b"").status, 200);
+        assert_eq!(fs::read(fm.root.join("c.txt")).unwrap(), b"text");
+        assert_eq!(request(&fm, "POST", rename, b"").status, 404);
+        let into_private = "/api/rename?from=/c.txt&to=/ereader/c.txt";
+        assert_eq!(request(&fm, "POST", into_private, b"").status, 403);
+
+        assert_eq!(request(&fm, "DELETE", "/api/file?path=/", b"").status, 400);
+        assert_eq!(request(&fm, "DELETE", "/api/file?path=.", b"").status, 400);
+        assert_eq!(
+            request(&fm, "DELETE", "/api/file?path=/ereader", b"").status,
+            403
+        );
+        assert_eq!(
+            request(&fm, "DELETE", "/api/file?path=/books", b"").status,
+            200
+        );
+        assert!(!fm.root.join("books").exists());
+        assert!(fm.root.join("ereader").exists());
+        let _ = fs::remove_dir_all(&fm.root);
+    }
+
+    #[test]
+    fn query_params_are_decoded() {
+        assert_eq!(percent_decode("a%20b+c%2F%e2%82%ac"), "a b c/€");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+        assert_eq!(query_param("a=1&path=%2e%2e&b", "path").unwrap(), "..");
+        assert_eq!(query_param("a=1&b", "b").unwrap(), "");
+        assert!(query_param("a=1", "path").is_none());
+    }
+}
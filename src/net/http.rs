@@ -0,0 +1,645 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use log::*;
+use std::{
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Duration,
+};
+
+// redirects followed before giving up
+const MAX_REDIRECTS: usize = 5;
+// longest header line
+const MAX_LINE: usize = 8192;
+// size of the copy buffer
+const CHUNK_SIZE: usize = 4096;
+/// suffix of a file being downloaded, kept to resume from
+pub const PARTIAL_SUFFIX: &str = ".part";
+
+// the board's tls, see `set_tls`
+static TLS: OnceLock<Box<dyn TlsConnect>> = OnceLock::new();
+
+/// A connection the client writes a request to and reads the response from
+pub trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+/// Opens tls connections for `https://` urls
+///
+/// The library has no tls of its own, the board sets one with `set_tls`.
+pub trait TlsConnect: Send + Sync {
+    /// a connection to a host with the handshake done
+    fn connect(&self, host: &str, port: u16, timeout: Duration) -> Result<Box<dyn Stream>>;
+}
+
+/// let the clients use https, set once at startup
+pub fn set_tls(tls: Box<dyn TlsConnect>) {
+    if TLS.set(tls).is_err() {
+        warn!("tls is already set");
+    }
+}
+
+/// is a url one the clients can get, http or https
+pub fn is_web_url(url: &str) -> bool {
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+/// The parts of an `http://` or `https://` url
+#[derive(Debug, Clone, PartialEq)]
+pub struct Url {
+    pub https: bool,
+    pub host: String,
+    pub port: u16,
+    /// path and query, starting with `/`
+    pub path: String,
+}
+
+impl Url {
+    /// parse an absolute http or https url
+    pub fn parse(url: &str) -> Result<Self> {
+        let (https, rest) = match url.strip_prefix("https://") {
+            Some(rest) => (true, rest),
+            None => (
+                false,
+                url.strip_prefix("http://")
+                    .ok_or_else(|| anyhow!("only http and https urls are supported: {}", url))?,
+            ),
+        };
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(i) if rest[i..].starts_with('/') => (&rest[..i], rest[i..].to_string()),
+            Some(i) => (&rest[..i], format!("/{}", &rest[i..])),
+            None => (rest, "/".to_string()),
+        };
+        let path = path.split('#').next().unwrap_or("/").to_string();
+        // drop any user info
+        let authority = authority.rsplit('@').next().unwrap_or(authority);
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((h, p)) => (h, p.parse().map_err(|_| anyhow!("bad port in {}", url))?),
+            None if https => (authority, 443),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(anyhow!("no host in {}", url));
+        }
+        Ok(Self {
+            https,
+            host: host.to_string(),
+            port,
+            path,
+        })
+    }
+
+    /// the url as text
+    pub fn to_url(&self) -> String {
+        let (scheme, default_port) = if self.https {
+            ("https", 443)
+        } else {
+            ("http", 80)
+        };
+        if self.port == default_port {
+            format!("{}://{}{}", scheme, self.host, self.path)
+        } else {
+            format!("{}://{}:{}{}", scheme, self.host, self.port, self.path)
+        }
+    }
+}
+
+/// resolve a link from a page against the page's url, http or https
+pub fn resolve_url(base: &str, href: &str) -> String {
+    if href.contains("://") {
+        return href.to_string();
+    }
+    let Some((scheme, rest)) = base.split_once("://") else {
+        return href.to_string();
+    };
+    if let Some(rest) = href.strip_prefix("//") {
+        return format!("{}://{}", scheme, rest);
+    }
+    let rest = rest.split('#').next().unwrap_or("");
+    let (authority, base_path) = match rest.find(['/', '?']) {
+        Some(i) if rest[i..].starts_with('/') => (&rest[..i], &rest[i..]),
+        Some(i) => (&rest[..i], "/"),
+        None => (rest, "/"),
+    };
+    let origin = format!("{}://{}", scheme, authority);
+    if href.starts_with('/') {
+        return format!("{}{}", origin, href);
+    }
+    let base_path = base_path.split('?').next().unwrap_or("/");
+    if href.starts_with('?') {
+        return format!("{}{}{}", origin, base_path, href);
+    }
+    let dir = &base_path[..base_path.rfind('/').map(|i| i + 1).unwrap_or(0)];
+    let joined = format!("{}{}", dir, href);
+    let (path, query) = match joined.split_once('?') {
+        Some((p, q)) => (p, format!("?{}", q)),
+        None => (joined.as_str(), String::new()),
+    };
+    // collapse `.` and `..` segments
+    let mut parts: Vec<&str> = Vec::new();
+    for seg in path.split('/').skip(1) {
+        match seg {
+            "." => {}
+            ".." => {
+                parts.pop();
+            }
+            s => parts.push(s),
+        }
+    }
+    if path.ends_with("/.") || path.ends_with("/..") {
+        parts.push("");
+    }
+    format!("{}/{}{}", origin, parts.join("/"), query)
+}
+
+/// A response, with the body still to read
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Box<dyn Read + Send>,
+}
+
+impl HttpResponse {
+    /// a header value, names are not case sensitive
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// the body length, if the server sent it
+    pub fn content_length(&self) -> Option<u64> {
+        self.header("Content-Length")
+            .and_then(|v| v.trim().parse().ok())
+    }
+
+    /// read the whole body
+    pub fn into_bytes(mut self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        self.body.read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+/// A small http client on std sockets
+///
+/// Enough for catalogs and servers on the local network, it speaks
+/// HTTP/1.0 so bodies are never chunked. https goes through the board's
+/// tls, see `set_tls`.
+#[derive(Debug, Clone)]
+pub struct HttpClient {
+    timeout: Duration,
+    auth: Option<String>,
+}
+
+impl Default for HttpClient {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(20),
+            auth: None,
+        }
+    }
+}
+
+impl HttpClient {
+    /// use basic authentication
+    pub fn with_auth(mut self, username: &str, password: &str) -> Self {
+        let token =
+            base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", username, password));
+        self.auth = Some(format!("Basic {}", token));
+        self
+    }
+
+    /// get a url, from byte `offset` if not zero, following redirects
+    pub fn get(&self, url: &str, offset: u64) -> Result<HttpResponse> {
+        let range = format!("bytes={}-", offset);
+        let headers: &[(&str, &str)] = if offset > 0 {
+            &[("Range", &range)]
+        } else {
+            &[]
+        };
+        let mut url = url.to_string();
+        for _ in 0..MAX_REDIRECTS {
+            let resp = self.request("GET", &url, headers, &[])?;
+            if matches!(resp.status, 301 | 302 | 303 | 307 | 308) {
+                let location = resp
+                    .header("Location")
+                    .ok_or_else(|| anyhow!("redirect without a location"))?;
+                url = resolve_url(&url, location);
+                debug!("redirected to {}", url);
+                continue;
+            }
+            return Ok(resp);
+        }
+        Err(anyhow!("too many redirects for {}", url))
+    }
+
+    /// get a url and read the whole body, failing on an error status
+    pub fn get_bytes(&self, url: &str) -> Result<Vec<u8>> {
+        let resp = self.get(url, 0)?;
+        if resp.status != 200 {
+            return Err(anyhow!("http status {} for {}", resp.status, url));
+        }
+        resp.into_bytes()
+    }
+
+    /// send a request with extra headers and a body
+    pub fn request(
+        &self,
+        method: &str,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> Result<HttpResponse> {
+        let parsed = Url::parse(url)?;
+        let mut stream = self.connect(&parsed)?;
+
+        let mut head = format!(
+            "{} {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: inkplate-ereader\r\nConnection: close\r\n",
+            method, parsed.path, parsed.host
+        );
+        for (name, value) in headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if let Some(auth) = &self.auth {
+            head.push_str(&format!("Authorization: {}\r\n", auth));
+        }
+        if !body.is_empty() || method != "GET" {
+            head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes())?;
+        stream.write_all(body)?;
+
+        let mut reader = BufReader::new(stream);
+        let status_line = read_line(&mut reader)?;
+        let status = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| anyhow!("bad status line '{}'", status_line))?;
+        let mut headers = Vec::new();
+        loop {
+            let line = read_line(&mut reader)?;
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                headers.push((name.trim().to_string(), value.trim().to_string()));
+            }
+        }
+        let mut resp = HttpResponse {
+            status,
+            headers,
+            body: Box::new(std::io::empty()),
+        };
+        resp.body = match resp.content_length() {
+            Some(len) => Box::new(reader.take(len)),
+            None => Box::new(reader),
+        };
+        Ok(resp)
+    }
+
+    // a connection to the url's host, through the board's tls for https
+    fn connect(&self, url: &Url) -> Result<Box<dyn Stream>> {
+        if url.https {
+            let tls = TLS
+                .get()
+                .ok_or_else(|| anyhow!("no tls for https://{}", url.host))?;
+            return tls.connect(&url.host, url.port, self.timeout);
+        }
+        let addr = (url.host.as_str(), url.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow!("no address for {}", url.host))?;
+        let stream = TcpStream::connect_timeout(&addr, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        Ok(Box::new(stream))
+    }
+}
+
+// a header line without the line ending
+fn read_line<R: BufRead>(reader: &mut R) -> Result<String> {
+    let mut line = Vec::new();
+    reader.take(MAX_LINE as u64).read_until(b'\n', &mut line)?;
+    if !line.ends_with(b"\n") {
+        return Err(anyhow!("bad http header"));
+    }
+    Ok(String::from_utf8_lossy(&line).trim_end().to_string())
+}
+
+/// download a url to a file, resuming a partial download
+///
+/// the data goes to `<dest>.part` first, which is kept if the download
+/// fails so the next try continues from where it stopped. `progress`
+/// gets the bytes done and the total, if known, after each chunk, and
+/// returns false to stop the download there.
+pub fn download(
+    client: &HttpClient,
+    url: &str,
+    dest: &Path,
+    progress: &mut dyn FnMut(u64, Option<u64>) -> bool,
+) -> Result<PathBuf> {
+    let mut partial = dest.to_path_buf().into_os_string();
+    partial.push(PARTIAL_SUFFIX);
+    let partial = PathBuf::from(partial);
+    if let Some(dir) = dest.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut have = fs::metadata(&partial).map(|m| m.len()).unwrap_or(0);
+    let mut resp = client.get(url, have)?;
+    let range = resp.header("Content-Range").and_then(content_range);
+    // a resume the server can't continue, download all of it
+    let restart = match (resp.status, range) {
+        (206, Some((Some(start), _))) => start != have,
+        (206, _) => true,
+        // the server says the range is past the end, the partial file is
+        // complete only if it has every byte
+        (416, Some((_, Some(total)))) if total == have && have > 0 => {
+            progress(have, Some(total));
+            finish_download(&partial, dest)?;
+            return Ok(dest.to_path_buf());
+        }
+        (416, _) => true,
+        _ => false,
+    };
+    if restart && have > 0 {
+        warn!("unable to resume {} at {}, starting again", url, have);
+        have = 0;
+        resp = client.get(url, 0)?;
+    }
+    let (mut done, mut file) = match resp.status {
+        206 if have > 0 => {
+            info!("resuming {} at {}", url, have);
+            let file = fs::OpenOptions::new().append(true).open(&partial)?;
+            (have, file)
+        }
+        200 => (0, fs::File::create(&partial)?),
+        status => return Err(anyhow!("http status {} for {}", status, url)),
+    };
+    let total = resp.content_length().map(|len| len + done);
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut going = progress(done, total);
+    while going {
+        let n = resp.body.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        file.write_all(&buf[..n])?;
+        done += n as u64;
+        going = progress(done, total);
+    }
+    file.flush()?;
+    drop(file);
+    if !going {
+        return Err(anyhow!("download of {} stopped at {} bytes", url, done));
+    }
+    if let Some(total) = total {
+        if done < total {
+            return Err(anyhow!(
+                "download of {} stopped at {} of {}",
+                url,
+                done,
+                total
+            ));
+        }
+    }
+    finish_download(&partial, dest)?;
+    info!("downloaded {} bytes to {:?}", done, dest);
+    Ok(dest.to_path_buf())
+}
+
+// the first byte and the total size in a `Content-Range` header, either
+// `bytes 100-199/200` or `bytes */200`, the total may be `*` if unknown
+fn content_range(value: &str) -> Option<(Option<u64>, Option<u64>)> {
+    let (range, total) = value.trim().strip_prefix("bytes")?.trim().split_once('/')?;
+    let start = match range.trim() {
+        "*" => None,
+        r => Some(r.split_once('-')?.0.trim().parse().ok()?),
+    };
+    Some((start, total.trim().parse().ok()))
+}
+
+// move a complete download into place
+fn finish_download(partial: &Path, dest: &Path) -> Result<()> {
+    // fat can't rename over an existing file
+    if dest.exists() {
+        fs::remove_file(dest)?;
+    }
+    fs::rename(partial, dest)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{response, temp_dir, TestServer};
+
+    const BOOK: &[u8] = b"0123456789abcdefghij";
+
+    // the byte the request's range starts at
+    fn range_start(request: &str) -> usize {
+        request
+            .lines()
+            .find_map(|l| l.strip_prefix("Range: bytes="))
+            .map(|r| r.trim_end_matches('-').parse().unwrap())
+            .unwrap_or(0)
+    }
+
+    // serve the book, honoring ranges
+    fn serve_book(request: &str) -> Vec<u8> {
+        match range_start(request) {
+            0 => response("200 OK", &[], BOOK),
+            start if start >= BOOK.len() => {
+                let range = format!("Content-Range: bytes */{}", BOOK.len());
+                response("416 Range Not Satisfiable", &[&range], b"")
+            }
+            start => {
+                let range = format!("Content-Range: bytes {}-19/{}", start, BOOK.len());
+                response("206 Partial Content", &[&range], &BOOK[start..])
+            }
+        }
+    }
+
+    fn download_with_part(name: &str, part: &[u8], server: &TestServer) -> Vec<u8> {
+        let dir = temp_dir(name);
+        let dest = dir.join("book.txt");
+        fs::write(dir.join("book.txt.part"), part).unwrap();
+        let url = format!("{}/book.txt", server.url);
+        let mut last = (0, None);
+        download(&HttpClient::default(), &url, &dest, &mut |d, t| {
+            last = (d, t);
+            true
+        })
+        .unwrap();
+        assert_eq!(last, (BOOK.len() as u64, Some(BOOK.len() as u64)));
+        assert!(!dir.join("book.txt.part").exists());
+        let data = fs::read(&dest).unwrap();
+        let _ = fs::remove_dir_all(dir);
+        data
+    }
+
+    #[test]
+    fn urls_resolve() {
+        let url = Url::parse("http://a.org:8080/opds/root.xml?x=1#top").unwrap();
+        assert_eq!((url.host.as_str(), url.port), ("a.org", 8080));
+        assert_eq!(url.path, "/opds/root.xml?x=1");
+        assert!(Url::parse("ftp://a.org/").is_err());
+        let url = Url::parse("https://a.org?q").unwrap();
+        assert_eq!((url.https, url.port, url.path.as_str()), (true, 443, "/?q"));
+        assert_eq!(url.to_url(), "https://a.org/?q");
+        let base = "http://a.org/opds/new/root.xml";
+        assert_eq!(resolve_url(base, "b.xml"), "http://a.org/opds/new/b.xml");
+        assert_eq!(
+            resolve_url(base, "../b.xml?p=2"),
+            "http://a.org/opds/b.xml?p=2"
+        );
+        assert_eq!(resolve_url(base, "/b.xml"), "http://a.org/b.xml");
+        assert_eq!(
+            resolve_url(base, "?p=2"),
+            "http://a.org/opds/new/root.xml?p=2"
+        );
+        assert_eq!(resolve_url(base, "//c.org/x"), "http://c.org/x");
+        let base = "https://a.org:8443/releases/latest.json?v=1#top";
+        assert_eq!(
+            resolve_url(base, "fw.bin"),
+            "https://a.org:8443/releases/fw.bin"
+        );
+        assert_eq!(resolve_url(base, "/fw.bin"), "https://a.org:8443/fw.bin");
+        assert_eq!(
+            resolve_url(base, "//cdn.org/fw.bin"),
+            "https://cdn.org/fw.bin"
+        );
+        assert_eq!(
+            resolve_url("https://a.org", "fw.bin"),
+            "https://a.org/fw.bin"
+        );
+        assert_eq!(
+            resolve_url("https://a.org?x", "fw.bin"),
+            "https://a.org/fw.bin"
+        );
+    }
+
+    #[test]
+    fn content_ranges() {
+        assert_eq!(
+            content_range("bytes 100-199/200"),
+            Some((Some(100), Some(200)))
+        );
+        assert_eq!(content_range("bytes */200"), Some((None, Some(200))));
+        assert_eq!(content_range("bytes 0-9/*"), Some((Some(0), None)));
+        assert_eq!(content_range("items 0-9/10"), None);
+    }
+
+    #[test]
+    fn redirects_are_followed() {
+        let server = TestServer::start(2, |request| {
+            if request.starts_with("GET /old ") {
+                response("302 Found", &["Location: /new"], b"")
+            } else {
+                response("200 OK", &[], b"moved")
+            }
+        });
+        let client = HttpClient::default().with_auth("me", "pw");
+        let body = client.get_bytes(&format!("{}/old", server.url)).unwrap();
+        assert_eq!(body, b"moved");
+        let requests = server.requests();
+        assert!(requests[1].starts_with("GET /new HTTP/1.0\r\n"));
+        assert!(requests[1].contains("Authorization: Basic bWU6cHc=\r\n"));
+    }
+
+    #[test]
+    fn download_resumes_a_part() {
+        let server = TestServer::start(1, serve_book);
+        assert_eq!(download_with_part("http_resume", &BOOK[..8], &server), BOOK);
+        assert!(server.requests()[0].contains("Range: bytes=8-\r\n"));
+    }
+
+    #[test]
+    fn complete_part_is_kept_on_416() {
+        let server = TestServer::start(1, serve_book);
+        assert_eq!(download_with_part("http_416", BOOK, &server), BOOK);
+    }
+
+    #[test]
+    fn download_restarts_when_the_part_is_wrong() {
+        // longer than the book, the 416 total doesn't match
+        let server = TestServer::start(2, serve_book);
+        let long = [BOOK, b"junk"].concat();
+        assert_eq!(download_with_part("http_long", &long, &server), BOOK);
+        let requests = server.requests();
+        assert!(!requests[1].contains("Range:"));
+
+        // a 206 that doesn't start where the part ends
+        let server = TestServer::start(2, |request| {
+            if range_start(request) == 0 {
+                response("200 OK", &[], BOOK)
+            } else {
+                response(
+                    "206 Partial Content",
+                    &["Content-Range: bytes 0-19/20"],
+                    BOOK,
+                )
+            }
+        });
+        assert_eq!(
+            download_with_part("http_bad_206", &BOOK[..5], &server),
+            BOOK
+        );
+    }
+
+    #[test]
+    fn short_download_keeps_the_part() {
+        let server = TestServer::start(1, |_| {
+            let mut resp = response("200 OK", &[], BOOK);
+            resp.truncate(resp.len() - 5);
+            resp
+        });
+        let dir = temp_dir("http_short");
+        let dest = dir.join("book.txt");
+        let url = format!("{}/book.txt", server.url);
+        assert!(download(&HttpClient::default(), &url, &dest, &mut |_, _| true).is_err());
+        assert_eq!(fs::read(dir.join("book.txt.part")).unwrap(), &BOOK[..15]);
+        assert!(!dest.exists());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn stopped_download_keeps_the_part() {
+        let server = TestServer::start(1, |_| response("200 OK", &[], BOOK));
+        let dir = temp_dir("http_stopped");
+        let dest = dir.join("book.txt");
+        let url = format!("{}/book.txt", server.url);
+        // stopped after the first chunk
+        let stopped = download(&HttpClient::default(), &url, &dest, &mut |d, _| d == 0);
+        assert!(stopped.is_err());
+        assert_eq!(fs::read(dir.join("book.txt.part")).unwrap(), BOOK);
+        assert!(!dest.exists());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    // plain tcp standing in for the board's tls
+    struct PlainTls;
+
+    impl TlsConnect for PlainTls {
+        fn connect(&self, host: &str, port: u16, _timeout: Duration) -> Result<Box<dyn Stream>> {
+            Ok(Box::new(TcpStream::connect((host, port))?))
+        }
+    }
+
+    #[test]
+    fn https_goes_through_the_boards_tls() {
+        let server = TestServer::start(1, |_| response("200 OK", &[], BOOK));
+        let url = format!("{}/book.txt", server.url.replace("http://", "https://"));
+        set_tls(Box::new(PlainTls));
+        assert_eq!(HttpClient::default().get_bytes(&url).unwrap(), BOOK);
+    }
+}
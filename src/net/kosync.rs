@@ -0,0 +1,492 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::config::settings::SyncSettings;
+use crate::net::http::HttpClient;
+use crate::reader::location::ContentLoc;
+use crate::ui::confirm::ConfirmDialog;
+use anyhow::{anyhow, Result};
+use log::*;
+use md5::{Digest, Md5};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+// the api version koreader asks for
+const ACCEPT: &str = "application/vnd.koreader.v1+json";
+// bytes hashed at each sample point of a book
+const SAMPLE_SIZE: usize = 1024;
+// a remote position this much further, in percent, is worth a prompt
+const FURTHER_PERCENT: f64 = 0.5;
+
+/// The progress of one document, as the sync server stores it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Progress {
+    /// the document hash, see `document_hash`
+    pub document: String,
+    /// an xpointer into the book
+    pub progress: String,
+    /// 0.0 to 1.0
+    pub percentage: f64,
+    pub device: String,
+    pub device_id: String,
+    /// unix time the server got it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<i64>,
+}
+
+/// the hash koreader uses to match a book across devices
+///
+/// an md5 of 1 KB samples at growing offsets, so it is quick to compute
+/// on large books and is the same for the same file everywhere
+pub fn document_hash(path: &Path) -> Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Md5::new();
+    let mut buf = [0u8; SAMPLE_SIZE];
+    // the start, then 1 KB, 4 KB, 16 KB and on up to 1 GB
+    for i in 0..=11u32 {
+        let offset = match i {
+            0 => 0,
+            i => (SAMPLE_SIZE as u64) << (2 * (i - 1)),
+        };
+        file.seek(SeekFrom::Start(offset))?;
+        let n = read_full(&mut file, &mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex(&hasher.finalize()))
+}
+
+// read until the buffer is full or the file ends
+fn read_full<R: Read>(r: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match r.read(&mut buf[n..])? {
+            0 => break,
+            m => n += m,
+        }
+    }
+    Ok(n)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// the user key sent to the server, an md5 of the password
+pub fn user_key(password: &str) -> String {
+    hex(&Md5::digest(password.as_bytes()))
+}
+
+/// the xpointer for a location, the start of its spine item
+///
+/// offsets into an item don't map to koreader's dom, the percentage
+/// carries the finer position
+pub fn progress_xpointer(loc: &ContentLoc) -> String {
+    format!("/body/DocFragment[{}]/body", loc.item + 1)
+}
+
+/// the spine item an xpointer points into
+pub fn xpointer_item(progress: &str) -> Option<u32> {
+    let start = progress.find("DocFragment[")? + "DocFragment[".len();
+    let len = progress[start..].find(']')?;
+    progress[start..start + len]
+        .parse::<u32>()
+        .ok()
+        .filter(|i| *i > 0)
+        .map(|i| i - 1)
+}
+
+/// where a remote progress lands in this book
+///
+/// `from_percent` turns a percentage into a location. Its answer is used
+/// when it falls in the item of the xpointer, otherwise the start of that
+/// item, as page sizes differ between devices.
+pub fn remote_location(
+    remote: &Progress,
+    from_percent: impl FnOnce(f64) -> ContentLoc,
+) -> ContentLoc {
+    let by_percent = from_percent(remote.percentage.clamp(0.0, 1.0));
+    match xpointer_item(&remote.progress) {
+        Some(item) if item != by_percent.item => ContentLoc::new(item, 0),
+        _ => by_percent,
+    }
+}
+
+/// the device id for a wifi mac address, 32 hex digits like koreader's
+pub fn device_id_from_mac(mac: &[u8]) -> String {
+    hex(&Md5::digest(mac)).to_ascii_uppercase()
+}
+
+/// the question asked when another device is further in the book
+pub fn jump_prompt(remote: &Progress, width: u32, height: u32) -> ConfirmDialog {
+    let question = format!(
+        "Jump to furthest position? {} is at {:.1}%.",
+        remote.device,
+        remote.percentage * 100.0
+    );
+    ConfirmDialog::new(&question, width, height)
+}
+
+/// is the remote progress far enough ahead to offer a jump
+pub fn is_further(local_percentage: f64, remote: &Progress, device_id: &str) -> bool {
+    remote.device_id != device_id
+        && (remote.percentage - local_percentage) * 100.0 >= FURTHER_PERCENT
+}
+
+/// A client for a KOReader progress sync server
+///
+/// Pushes that fail, when there is no network, are kept in
+/// `sync_pending.json` and sent with the next push.
+#[derive(Debug)]
+pub struct SyncClient {
+    http: HttpClient,
+    server: String,
+    username: String,
+    key: String,
+    device: String,
+    device_id: String,
+    pending_path: PathBuf,
+    pending: BTreeMap<String, Progress>,
+}
+
+impl SyncClient {
+    /// create the client from the settings, none if sync isn't set up
+    ///
+    /// `mac` is the wifi mac address, for the device id when the settings have none
+    pub fn new(settings: &SyncSettings, ereader_dir: &Path, mac: &[u8]) -> Result<Option<Self>> {
+        if settings.server.is_empty() || settings.username.is_empty() {
+            return Ok(None);
+        }
+        let pending_path = ereader_dir.join("sync_pending.json");
+        let pending = match fs::read(&pending_path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_default(),
+            Err(_) => BTreeMap::new(),
+        };
+        Ok(Some(Self {
+            http: HttpClient::default(),
+            server: settings.server.trim_end_matches('/').to_string(),
+            username: settings.username.clone(),
+            key: user_key(&settings.password),
+            device: settings.device_name.clone(),
+            device_id: if settings.device_id.is_empty() {
+                device_id_from_mac(mac)
+            } else {
+                settings.device_id.clone()
+            },
+            pending_path,
+            pending,
+        }))
+    }
+
+    /// the id this device sends with its progress
+    pub fn device_id(&self) -> &str {
+        &self.device_id
+    }
+
+    // send a request with the auth headers, the json body of the response
+    fn call(&self, method: &str, path: &str, body: &[u8]) -> Result<serde_json::Value> {
+        let url = format!("{}{}", self.server, path);
+        let headers = [
+            ("Accept", ACCEPT),
+            ("Content-Type", "application/json"),
+            ("x-auth-user", self.username.as_str()),
+            ("x-auth-key", self.key.as_str()),
+        ];
+        let resp = self.http.request(method, &url, &headers, body)?;
+        let status = resp.status;
+        let bytes = resp.into_bytes()?;
+        let value = serde_json::from_slice(&bytes).unwrap_or(serde_json::Value::Null);
+        if !(200..300).contains(&status) {
+            let msg = value["message"].as_str().unwrap_or("");
+            return Err(anyhow!("sync server status {} {}", status, msg));
+        }
+        Ok(value)
+    }
+
+    /// check the user name and password
+    pub fn authorize(&self) -> Result<()> {
+        self.call("GET", "/users/auth", &[])?;
+        Ok(())
+    }
+
+    /// create the user on the server
+    pub fn register(&self) -> Result<()> {
+        let body = serde_json::json!({ "username": self.username, "password": self.key });
+        self.call("POST", "/users/create", &serde_json::to_vec(&body)?)?;
+        Ok(())
+    }
+
+    /// the progress of this device, for a push
+    pub fn progress(&self, document: &str, loc: &ContentLoc, percentage: f64) -> Progress {
+        Progress {
+            document: document.to_string(),
+            progress: progress_xpointer(loc),
+            percentage: (percentage.clamp(0.0, 1.0) * 10_000.0).round() / 10_000.0,
+            device: self.device.clone(),
+            device_id: self.device_id.clone(),
+            timestamp: None,
+        }
+    }
+
+    /// send progress, called when a book is closed or the device sleeps
+    ///
+    /// on failure it is kept to send later
+    pub fn push(&mut self, progress: Progress) -> Result<()> {
+        self.pending.insert(progress.document.clone(), progress);
+        let result = self.send_pending();
+        self.save_pending()?;
+        result
+    }
+
+    // send the waiting pushes, the latest one of each book
+    fn send_pending(&mut self) -> Result<()> {
+        while let Some(document) = self.pending.keys().next().cloned() {
+            let progress = &self.pending[&document];
+            self.call("PUT", "/syncs/progress", &serde_json::to_vec(progress)?)?;
+            debug!("synced progress of {}", document);
+            self.pending.remove(&document);
+        }
+        Ok(())
+    }
+
+    fn save_pending(&self) -> Result<()> {
+        if self.pending.is_empty() {
+            if self.pending_path.exists() {
+                fs::remove_file(&self.pending_path)?;
+            }
+        } else {
+            fs::write(&self.pending_path, serde_json::to_vec(&self.pending)?)?;
+        }
+        Ok(())
+    }
+
+    /// pull when a book is opened, the remote progress if it is further
+    ///
+    /// the percentage is how far the reader is at the saved location, see
+    /// `load_location`
+    pub fn on_open(&self, book_path: &Path, local_percentage: f64) -> Result<Option<Progress>> {
+        let remote = self.pull(&document_hash(book_path)?)?;
+        Ok(remote.filter(|r| is_further(local_percentage, r, &self.device_id)))
+    }
+
+    /// push when a book is closed or the device sleeps
+    ///
+    /// the location is the one saved with `save_location`, the percentage
+    /// how far into the book it is
+    pub fn on_close(&mut self, book_path: &Path, loc: &ContentLoc, percentage: f64) -> Result<()> {
+        let progress = self.progress(&document_hash(book_path)?, loc, percentage);
+        self.push(progress)
+    }
+
+    /// the progress on the server, called when a book is opened
+    pub fn pull(&self, document: &str) -> Result<Option<Progress>> {
+        let value = self.call("GET", &format!("/syncs/progress/{}", document), &[])?;
+        // the server answers `{}` for a document it doesn't know
+        if value.get("percentage").is_none() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_value(value)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{response, temp_dir, TestServer};
+
+    const MAC: [u8; 6] = [0x24, 0x0a, 0xc4, 1, 2, 3];
+
+    fn settings(server: &str) -> SyncSettings {
+        SyncSettings {
+            server: format!("{}/", server),
+            username: "reader".to_string(),
+            password: "secret".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn json(request: &str) -> serde_json::Value {
+        let body = &request[request.find("\r\n\r\n").unwrap() + 4..];
+        serde_json::from_str(body).unwrap()
+    }
+
+    #[test]
+    fn document_hash_samples_like_koreader() {
+        let dir = temp_dir("kosync_hash");
+        let small = dir.join("small.txt");
+        fs::write(&small, "hello").unwrap();
+        // a file under 1 KB is one sample
+        assert_eq!(
+            document_hash(&small).unwrap(),
+            "5d41402abc4b2a76b9719d911017c592"
+        );
+        let book = dir.join("book.txt");
+        let data: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+        fs::write(&book, data).unwrap();
+        assert_eq!(
+            document_hash(&book).unwrap(),
+            "e77dcca7f22a949ae8492c260ca19f32"
+        );
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn xpointers_and_locations() {
+        let loc = ContentLoc::new(4, 120);
+        assert_eq!(progress_xpointer(&loc), "/body/DocFragment[5]/body");
+        assert_eq!(
+            xpointer_item("/body/DocFragment[5]/body/p[3]/text().17"),
+            Some(4)
+        );
+        assert_eq!(xpointer_item("/body/DocFragment[0]"), None);
+        let remote = Progress {
+            document: String::new(),
+            progress: progress_xpointer(&loc),
+            percentage: 0.5,
+            device: "phone".to_string(),
+            device_id: "B".to_string(),
+            timestamp: None,
+        };
+        assert_eq!(
+            remote_location(&remote, |_| ContentLoc::new(4, 77)),
+            ContentLoc::new(4, 77)
+        );
+        assert_eq!(
+            remote_location(&remote, |_| ContentLoc::new(3, 77)),
+            ContentLoc::new(4, 0)
+        );
+        assert!(is_further(0.4, &remote, "A"));
+        assert!(!is_further(0.499, &remote, "A"));
+        assert!(!is_further(0.1, &remote, "B"));
+    }
+
+    #[test]
+    fn device_id_from_the_mac() {
+        let dir = temp_dir("kosync_id");
+        let client = SyncClient::new(&settings("http://s"), &dir, &MAC)
+            .unwrap()
+            .unwrap();
+        assert_eq!(client.device_id(), "0C5095AC2CA4B5C192D06069CD79A29E");
+        let named = SyncSettings {
+            device_id: "mine".to_string(),
+            ..settings("http://s")
+        };
+        let client = SyncClient::new(&named, &dir, &MAC).unwrap().unwrap();
+        assert_eq!(client.device_id(), "mine");
+        assert!(SyncClient::new(&SyncSettings::default(), &dir, &MAC)
+            .unwrap()
+            .is_none());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn auth_push_and_pull() {
+        let server = TestServer::start(4, |request| {
+            let path = request.split_whitespace().nth(1).unwrap_or("");
+            match path {
+                "/users/auth" => response("200 OK", &[], br#"{"authorized":"OK"}"#),
+                "/syncs/progress" => response("200 OK", &[], br#"{"document":"abc"}"#),
+                "/syncs/progress/abc" => response(
+                    "200 OK",
+                    &[],
+                    br#"{"document":"abc","progress":"/body/DocFragment[2]/body",
+                        "percentage":0.25,"device":"phone","device_id":"B","timestamp":1700000000}"#,
+                ),
+                _ => response("200 OK", &[], b"{}"),
+            }
+        });
+        let dir = temp_dir("kosync_server");
+        let mut client = SyncClient::new(&settings(&server.url), &dir, &MAC)
+            .unwrap()
+            .unwrap();
+        client.authorize().unwrap();
+        let progress = client.progress("abc", &ContentLoc::new(1, 10), 0.123456);
+        client.push(progress).unwrap();
+        let remote = client.pull("abc").unwrap().unwrap();
+        assert_eq!(remote.device, "phone");
+        assert_eq!(remote.timestamp, Some(1_700_000_000));
+        // the server knows nothing of this book
+        assert!(client.pull("def").unwrap().is_none());
+
+        let requests = server.requests();
+        assert!(requests[0].starts_with("GET /users/auth HTTP/1.0\r\n"));
+        assert!(requests[0].contains("x-auth-user: reader\r\n"));
+        assert!(requests[0].contains("x-auth-key: 5ebe2294ecd0e0f08eab7690d2a6ee69\r\n"));
+        assert!(requests[0].contains("Accept: application/vnd.koreader.v1+json\r\n"));
+        assert!(requests[1].starts_with("PUT /syncs/progress HTTP/1.0\r\n"));
+        let pushed = json(&requests[1]);
+        assert_eq!(pushed["document"], "abc");
+        assert_eq!(pushed["progress"], "/body/DocFragment[2]/body");
+        assert_eq!(pushed["percentage"], 0.1235);
+        assert_eq!(pushed["device_id"], "0C5095AC2CA4B5C192D06069CD79A29E");
+        assert!(pushed.get("timestamp").is_none());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn failed_pushes_are_sent_later() {
+        let dir = temp_dir("kosync_pending");
+        // a port nothing listens on
+        let down = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}", listener.local_addr().unwrap())
+        };
+        let mut client = SyncClient::new(&settings(&down), &dir, &MAC)
+            .unwrap()
+            .unwrap();
+        let loc = ContentLoc::new(0, 0);
+        assert!(client.push(client.progress("a", &loc, 0.1)).is_err());
+        assert!(client.push(client.progress("b", &loc, 0.2)).is_err());
+        assert!(client.push(client.progress("a", &loc, 0.3)).is_err());
+        assert!(dir.join("sync_pending.json").exists());
+
+        let server = TestServer::start(3, |_| response("200 OK", &[], b"{}"));
+        let mut client = SyncClient::new(&settings(&server.url), &dir, &MAC)
+            .unwrap()
+            .unwrap();
+        client.push(client.progress("c", &loc, 0.4)).unwrap();
+        assert!(!dir.join("sync_pending.json").exists());
+        let sent: Vec<(String, f64)> = server
+            .requests()
+            .iter()
+            .map(|r| {
+                let p = json(r);
+                (
+                    p["document"].as_str().unwrap().to_string(),
+                    p["percentage"].as_f64().unwrap(),
+                )
+            })
+            .collect();
+        // only the latest progress of each book
+        let expected = [("a", 0.3), ("b", 0.2), ("c", 0.4)];
+        let expected: Vec<(String, f64)> =
+            expected.iter().map(|(d, p)| (d.to_string(), *p)).collect();
+        assert_eq!(sent, expected);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn server_errors_carry_the_message() {
+        let server = TestServer::start(1, |_| {
+            response(
+                "401 Unauthorized",
+                &[],
+                br#"{"code":2001,"message":"Unauthorized"}"#,
+            )
+        });
+        let dir = temp_dir("kosync_401");
+        let client = SyncClient::new(&settings(&server.url), &dir, &MAC)
+            .unwrap()
+            .unwrap();
+        let e = client.authorize().unwrap_err();
+        assert_eq!(e.to_string(), "sync server status 401 Unauthorized");
+        let _ = fs::remove_dir_all(dir);
+    }
+}
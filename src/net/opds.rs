@@ -0,0 +1,446 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::net::http::{self, resolve_url, HttpClient};
+use crate::reader::fb2::attr;
+use anyhow::{anyhow, Result};
+use log::*;
+use quick_xml::events::Event;
+use std::path::{Path, PathBuf};
+
+/// where downloaded books go
+pub const BOOKS_DIR: &str = "/sdcard/books";
+
+// the acquisition link relations all start with this
+const ACQUISITION_REL: &str = "http://opds-spec.org/acquisition";
+// longest file name for a downloaded book, fat allows 255
+const MAX_NAME_LEN: usize = 120;
+
+// book formats that can be read, best first, with their extensions
+const BOOK_TYPES: [(&str, &str); 6] = [
+    ("application/epub+zip", "epub"),
+    ("application/x-fictionbook+xml", "fb2"),
+    ("application/x-zip-compressed-fb2", "fb2.zip"),
+    ("application/x-cbz", "cbz"),
+    ("application/vnd.comicbook+zip", "cbz"),
+    ("text/plain", "txt"),
+];
+
+/// A link in a feed or an entry
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OpdsLink {
+    pub rel: String,
+    /// absolute url
+    pub href: String,
+    pub mime: String,
+    pub title: String,
+}
+
+impl OpdsLink {
+    /// does the link lead to another catalog feed
+    pub fn is_catalog(&self) -> bool {
+        self.mime.starts_with("application/atom+xml") && !self.rel.starts_with(ACQUISITION_REL)
+    }
+
+    /// is the link a book download
+    pub fn is_acquisition(&self) -> bool {
+        self.rel.starts_with(ACQUISITION_REL)
+    }
+}
+
+/// A book or a navigation entry in a feed
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OpdsEntry {
+    pub title: String,
+    pub authors: Vec<String>,
+    pub summary: String,
+    pub links: Vec<OpdsLink>,
+}
+
+impl OpdsEntry {
+    /// the feed a navigation entry leads to
+    pub fn navigation(&self) -> Option<&OpdsLink> {
+        self.links.iter().find(|l| l.is_catalog())
+    }
+
+    /// the best download link in a format that can be read
+    pub fn acquisition(&self) -> Option<&OpdsLink> {
+        BOOK_TYPES.iter().find_map(|(mime, _)| {
+            self.links
+                .iter()
+                .find(|l| l.is_acquisition() && mime_type(&l.mime) == *mime)
+        })
+    }
+
+    /// the name to save a download as, `<title> - <author>.<ext>`
+    pub fn file_name(&self, link: &OpdsLink) -> String {
+        let ext = BOOK_TYPES
+            .iter()
+            .find(|(mime, _)| mime_type(&link.mime) == *mime)
+            .map(|(_, ext)| *ext)
+            .unwrap_or("epub");
+        let title = if self.title.is_empty() {
+            "book"
+        } else {
+            &self.title
+        };
+        let name = match self.authors.first() {
+            Some(author) => format!("{} - {}", title, author),
+            None => title.to_string(),
+        };
+        format!("{}.{}", safe_file_name(&name), ext)
+    }
+}
+
+/// A catalog feed, navigation or acquisition
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OpdsFeed {
+    pub title: String,
+    pub entries: Vec<OpdsEntry>,
+    pub links: Vec<OpdsLink>,
+}
+
+impl OpdsFeed {
+    fn link(&self, rel: &str) -> Option<&OpdsLink> {
+        self.links.iter().find(|l| l.rel == rel)
+    }
+
+    /// the next page of a paged feed
+    pub fn next(&self) -> Option<&str> {
+        self.link("next").map(|l| l.href.as_str())
+    }
+
+    /// the search link, an OpenSearch description or an atom url template
+    pub fn search(&self) -> Option<&OpdsLink> {
+        self.link("search")
+    }
+}
+
+// the mime type without its parameters
+fn mime_type(mime: &str) -> &str {
+    mime.split(';').next().unwrap_or("").trim()
+}
+
+// replace characters fat or the reader can't handle
+fn safe_file_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .take(MAX_NAME_LEN)
+        .collect();
+    name.trim().trim_end_matches('.').to_string()
+}
+
+// the elements the parser looks at, others only add their text
+const TRACKED: [&[u8]; 6] = [
+    b"entry", b"author", b"title", b"name", b"summary", b"content",
+];
+
+// where in the feed the parser is
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    None,
+    Title,
+    AuthorName,
+    Summary,
+}
+
+/// parse an atom feed, links are made absolute against `base_url`
+pub fn parse_feed(xml: &[u8], base_url: &str) -> Result<OpdsFeed> {
+    let mut reader = quick_xml::Reader::from_reader(xml);
+    reader.check_end_names(false);
+    let mut feed = OpdsFeed::default();
+    let mut entry: Option<OpdsEntry> = None;
+    let mut in_author = false;
+    let mut field = Field::None;
+    let mut text = String::new();
+    let mut buf = Vec::new();
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            Event::Start(e) | Event::Empty(e) if e.local_name().as_ref() == b"link" => {
+                let decoder = reader.decoder();
+                let link = OpdsLink {
+                    rel: attr(&e, decoder, b"rel").unwrap_or_else(|| "alternate".to_string()),
+                    href: resolve_url(base_url, &attr(&e, decoder, b"href").unwrap_or_default()),
+                    mime: attr(&e, decoder, b"type").unwrap_or_default(),
+                    title: attr(&e, decoder, b"title").unwrap_or_default(),
+                };
+                match &mut entry {
+                    Some(entry) => entry.links.push(link),
+                    None => feed.links.push(link),
+                }
+            }
+            Event::Start(e) => {
+                // markup inside a summary only adds its text
+                let tracked = match e.local_name().as_ref() {
+                    b"entry" => {
+                        entry = Some(OpdsEntry::default());
+                        Some(Field::None)
+                    }
+                    b"author" => {
+                        in_author = true;
+                        Some(Field::None)
+                    }
+                    b"title" => Some(Field::Title),
+                    b"name" if in_author => Some(Field::AuthorName),
+                    b"summary" | b"content" if entry.is_some() => Some(Field::Summary),
+                    _ => None,
+                };
+                if let Some(tracked) = tracked {
+                    field = tracked;
+                    text.clear();
+                }
+            }
+            Event::End(e) => {
+                let name = e.local_name();
+                if !TRACKED.contains(&name.as_ref()) {
+                    // markup inside a summary, keep its words apart
+                    text.push(' ');
+                    buf.clear();
+                    continue;
+                }
+                let value = text.trim().to_string();
+                match (name.as_ref(), &mut entry) {
+                    (b"entry", Some(_)) => feed.entries.extend(entry.take()),
+                    (b"author", _) => in_author = false,
+                    (b"title", Some(entry)) if field == Field::Title => entry.title = value,
+                    (b"title", None) if field == Field::Title => feed.title = value,
+                    (b"name", Some(entry)) if field == Field::AuthorName => {
+                        entry.authors.push(value)
+                    }
+                    // the summary wins over the content
+                    (b"summary" | b"content", Some(entry))
+                        if field == Field::Summary && entry.summary.is_empty() =>
+                    {
+                        entry.summary = value
+                    }
+                    _ => (),
+                }
+                field = Field::None;
+                text.clear();
+            }
+            Event::Text(t) if field != Field::None => match t.unescape() {
+                Ok(t) => text.push_str(&t),
+                Err(_) => text.push_str(&reader.decoder().decode(&t)?),
+            },
+            Event::CData(t) if field != Field::None => {
+                text.push_str(&reader.decoder().decode(&t)?);
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+        buf.clear();
+    }
+    Ok(feed)
+}
+
+/// the atom url template in an OpenSearch description
+pub fn parse_opensearch(xml: &[u8], base_url: &str) -> Result<Option<String>> {
+    let mut reader = quick_xml::Reader::from_reader(xml);
+    let mut buf = Vec::new();
+    let mut template = None;
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            Event::Start(e) | Event::Empty(e) if e.local_name().as_ref() == b"Url" => {
+                let decoder = reader.decoder();
+                let mime = attr(&e, decoder, b"type").unwrap_or_default();
+                if let Some(t) = attr(&e, decoder, b"template") {
+                    // prefer the atom template, any template will do otherwise
+                    if mime.starts_with("application/atom+xml") {
+                        return Ok(Some(resolve_url(base_url, &t)));
+                    }
+                    template.get_or_insert_with(|| resolve_url(base_url, &t));
+                }
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+        buf.clear();
+    }
+    Ok(template)
+}
+
+/// fill in the search terms of an OpenSearch url template
+///
+/// optional parameters, like `{startPage?}`, are left empty
+pub fn search_url(template: &str, terms: &str) -> String {
+    let mut url = String::with_capacity(template.len() + terms.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        url.push_str(&rest[..start]);
+        let Some(len) = rest[start..].find('}') else {
+            break;
+        };
+        let name = &rest[start + 1..start + len];
+        if name.trim_end_matches('?') == "searchTerms" {
+            url.push_str(&percent_encode(terms));
+        }
+        rest = &rest[start + len + 1..];
+    }
+    url.push_str(rest);
+    url
+}
+
+/// encode text for a url query
+pub fn percent_encode(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for b in text.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(b as char)
+            }
+            b => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+/// A client for one OPDS catalog
+#[derive(Debug, Clone)]
+pub struct OpdsClient {
+    http: HttpClient,
+}
+
+impl OpdsClient {
+    /// a client, with basic authentication if a user name is given
+    pub fn new(username: &str, password: &str) -> Self {
+        let http = if username.is_empty() {
+            HttpClient::default()
+        } else {
+            HttpClient::default().with_auth(username, password)
+        };
+        Self { http }
+    }
+
+    /// fetch and parse a feed
+    pub fn feed(&self, url: &str) -> Result<OpdsFeed> {
+        debug!("opds feed {}", url);
+        parse_feed(&self.http.get_bytes(url)?, url)
+    }
+
+    /// search the catalog of a feed
+    pub fn search(&self, feed: &OpdsFeed, terms: &str) -> Result<OpdsFeed> {
+        let link = feed
+            .search()
+            .ok_or_else(|| anyhow!("the catalog has no search"))?;
+        let template = if link
+            .mime
+            .starts_with("application/opensearchdescription+xml")
+        {
+            parse_opensearch(&self.http.get_bytes(&link.href)?, &link.href)?
+                .ok_or_else(|| anyhow!("no search template in {}", link.href))?
+        } else {
+            link.href.clone()
+        };
+        self.feed(&search_url(&template, terms))
+    }
+
+    /// download a book into `books_dir`, resuming an earlier try
+    ///
+    /// `progress` gets the bytes done and the total, if known, and returns
+    /// false to stop, see `http::download`
+    pub fn download(
+        &self,
+        entry: &OpdsEntry,
+        books_dir: &Path,
+        progress: &mut dyn FnMut(u64, Option<u64>) -> bool,
+    ) -> Result<PathBuf> {
+        let link = entry
+            .acquisition()
+            .ok_or_else(|| anyhow!("no readable format for {}", entry.title))?;
+        let dest = books_dir.join(entry.file_name(link));
+        info!("downloading {} to {:?}", link.href, dest);
+        http::download(&self.http, &link.href, &dest, progress)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{response, temp_dir, TestServer};
+    use std::fs;
+
+    const FEED: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <title>New books</title>
+  <link rel="search" type="application/opensearchdescription+xml" href="/search.xml"/>
+  <link rel="next" type="application/atom+xml" href="new?page=2"/>
+  <entry>
+    <title>Moby &amp; Dick</title>
+    <author><name>Herman Melville</name></author>
+    <summary>A <b>whale</b>of a tale</summary>
+    <content>not used</content>
+    <link rel="http://opds-spec.org/acquisition" type="application/pdf" href="/b/1.pdf"/>
+    <link rel="http://opds-spec.org/acquisition" type="application/epub+zip" href="/b/1.epub"/>
+  </entry>
+  <entry>
+    <title>Poetry</title>
+    <link rel="subsection" type="application/atom+xml;profile=opds-catalog" href="poetry"/>
+  </entry>
+</feed>"#;
+
+    const OPENSEARCH: &str = r#"<OpenSearchDescription xmlns="http://a9.com/-/spec/opensearch/1.1/">
+  <Url type="text/html" template="/html?q={searchTerms}"/>
+  <Url type="application/atom+xml" template="/find?q={searchTerms}&amp;p={startPage?}"/>
+</OpenSearchDescription>"#;
+
+    #[test]
+    fn feed_entries_and_links() {
+        let feed = parse_feed(FEED.as_bytes(), "http://a.org/opds/new").unwrap();
+        assert_eq!(feed.title, "New books");
+        assert_eq!(feed.next(), Some("http://a.org/opds/new?page=2"));
+        assert_eq!(feed.search().unwrap().href, "http://a.org/search.xml");
+        let book = &feed.entries[0];
+        assert_eq!(book.title, "Moby & Dick");
+        assert_eq!(book.authors, vec!["Herman Melville"]);
+        assert_eq!(book.summary, "A whale of a tale");
+        let link = book.acquisition().unwrap();
+        assert_eq!(link.href, "http://a.org/b/1.epub");
+        assert_eq!(book.file_name(link), "Moby & Dick - Herman Melville.epub");
+        let nav = feed.entries[1].navigation().unwrap();
+        assert_eq!(nav.href, "http://a.org/opds/poetry");
+        assert!(feed.entries[1].acquisition().is_none());
+    }
+
+    #[test]
+    fn search_templates() {
+        let template = parse_opensearch(OPENSEARCH.as_bytes(), "http://a.org/search.xml")
+            .unwrap()
+            .unwrap();
+        assert_eq!(template, "http://a.org/find?q={searchTerms}&p={startPage?}");
+        assert_eq!(
+            search_url(&template, "moby dick/é"),
+            "http://a.org/find?q=moby%20dick%2F%C3%A9&p="
+        );
+        assert_eq!(safe_file_name(" a/b: c?. "), "a_b_ c_");
+    }
+
+    #[test]
+    fn search_and_download_from_a_catalog() {
+        let server = TestServer::start(4, |request| {
+            let path = request.split_whitespace().nth(1).unwrap_or("");
+            match path {
+                "/opds" => response("200 OK", &[], FEED.as_bytes()),
+                "/search.xml" => response("200 OK", &[], OPENSEARCH.as_bytes()),
+                "/find?q=whale&p=" => response("200 OK", &[], FEED.as_bytes()),
+                "/b/1.epub" => response("200 OK", &[], b"epub"),
+                _ => response("404 Not Found", &[], b""),
+            }
+        });
+        let client = OpdsClient::new("", "");
+        let feed = client.feed(&format!("{}/opds", server.url)).unwrap();
+        let found = client.search(&feed, "whale").unwrap();
+        let dir = temp_dir("opds");
+        let book = client
+            .download(&found.entries[0], &dir, &mut |_, _| true)
+            .unwrap();
+        assert_eq!(book, dir.join("Moby & Dick - Herman Melville.epub"));
+        assert_eq!(fs::read(&book).unwrap(), b"epub");
+        assert_eq!(server.requests().len(), 4);
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,190 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use serde::{Deserialize, Serialize};
+use std::net::Ipv4Addr;
+
+// networks remembered, the least recently used is dropped
+const MAX_KNOWN: usize = 5;
+
+/// A network and its password
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WifiCredentials {
+    pub ssid: String,
+    pub password: String,
+}
+
+/// The networks connected to before, most recent first
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct KnownNetworks {
+    networks: Vec<WifiCredentials>,
+}
+
+impl KnownNetworks {
+    /// the networks, most recent first
+    pub fn networks(&self) -> &[WifiCredentials] {
+        &self.networks
+    }
+
+    /// the credentials of a network
+    pub fn find(&self, ssid: &str) -> Option<&WifiCredentials> {
+        self.networks.iter().find(|n| n.ssid == ssid)
+    }
+
+    /// remember a network that connected, it becomes the most recent
+    pub fn remember(&mut self, creds: WifiCredentials) {
+        self.forget(&creds.ssid);
+        self.networks.insert(0, creds);
+        self.networks.truncate(MAX_KNOWN);
+    }
+
+    /// forget a network
+    pub fn forget(&mut self, ssid: &str) {
+        self.networks.retain(|n| n.ssid != ssid);
+    }
+
+    /// the known network to use from a scan, the most recently used one in range
+    pub fn pick(&self, scan: &[NetworkInfo]) -> Option<&WifiCredentials> {
+        self.networks
+            .iter()
+            .find(|n| scan.iter().any(|s| s.ssid == n.ssid))
+    }
+}
+
+/// A network found by a scan
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetworkInfo {
+    pub ssid: String,
+    /// signal strength in dBm
+    pub rssi: i8,
+    pub secured: bool,
+}
+
+impl NetworkInfo {
+    /// signal strength as 0 to 4 bars
+    pub fn bars(&self) -> u8 {
+        match self.rssi {
+            r if r >= -55 => 4,
+            r if r >= -67 => 3,
+            r if r >= -75 => 2,
+            r if r >= -85 => 1,
+            _ => 0,
+        }
+    }
+}
+
+/// tidy scan results, one entry per network with its strongest signal, strongest first
+pub fn merge_scan(mut found: Vec<NetworkInfo>) -> Vec<NetworkInfo> {
+    found.retain(|n| !n.ssid.is_empty());
+    found.sort_by_key(|n| std::cmp::Reverse(n.rssi));
+    let mut merged: Vec<NetworkInfo> = Vec::with_capacity(found.len());
+    for n in found {
+        if !merged.iter().any(|m| m.ssid == n.ssid) {
+            merged.push(n);
+        }
+    }
+    merged
+}
+
+/// The state of the wifi radio, shown in the status bar
+#[derive(Debug, Clone, PartialEq)]
+pub enum WifiState {
+    Off,
+    Scanning,
+    Connecting(String),
+    Connected { ssid: String, ip: Ipv4Addr },
+    Failed(String),
+}
+
+impl WifiState {
+    /// short text for the status bar
+    pub fn status_label(&self) -> String {
+        match self {
+            WifiState::Off => String::new(),
+            WifiState::Scanning => "WiFi scan".to_string(),
+            WifiState::Connecting(_) => "WiFi ...".to_string(),
+            WifiState::Connected { .. } => "WiFi".to_string(),
+            WifiState::Failed(_) => "WiFi !".to_string(),
+        }
+    }
+
+    /// is the radio connected
+    pub fn is_connected(&self) -> bool {
+        matches!(self, WifiState::Connected { .. })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn creds(ssid: &str) -> WifiCredentials {
+        WifiCredentials {
+            ssid: ssid.to_string(),
+            password: format!("{}-pass", ssid),
+        }
+    }
+
+    fn found(ssid: &str, rssi: i8) -> NetworkInfo {
+        NetworkInfo {
+            ssid: ssid.to_string(),
+            rssi,
+            secured: true,
+        }
+    }
+
+    fn ssids(known: &KnownNetworks) -> Vec<&str> {
+        known.networks().iter().map(|n| n.ssid.as_str()).collect()
+    }
+
+    #[test]
+    fn remember_keeps_the_most_recent() {
+        let mut known = KnownNetworks::default();
+        for ssid in ["a", "b", "c"] {
+            known.remember(creds(ssid));
+        }
+        assert_eq!(ssids(&known), ["c", "b", "a"]);
+        // again with a new password moves it to the front
+        known.remember(WifiCredentials {
+            ssid: "a".to_string(),
+            password: "new".to_string(),
+        });
+        assert_eq!(ssids(&known), ["a", "c", "b"]);
+        assert_eq!(known.find("a").unwrap().password, "new");
+        for ssid in ["d", "e", "f"] {
+            known.remember(creds(ssid));
+        }
+        assert_eq!(ssids(&known), ["f", "e", "d", "a", "c"]);
+        known.forget("d");
+        assert_eq!(ssids(&known), ["f", "e", "a", "c"]);
+        assert_eq!(known.find("d"), None);
+    }
+
+    #[test]
+    fn pick_the_most_recent_in_range() {
+        let mut known = KnownNetworks::default();
+        for ssid in ["home", "work", "cafe"] {
+            known.remember(creds(ssid));
+        }
+        // signal strength doesn't matter, recent use does
+        let scan = [found("home", -40), found("work", -80), found("other", -30)];
+        assert_eq!(known.pick(&scan), Some(&creds("work")));
+        assert_eq!(known.pick(&[found("other", -30)]), None);
+        assert_eq!(KnownNetworks::default().pick(&scan), None);
+    }
+
+    #[test]
+    fn scans_are_merged() {
+        let scan = vec![
+            found("a", -80),
+            found("", -20),
+            found("b", -60),
+            found("a", -50),
+            found("c", -90),
+        ];
+        let merged = merge_scan(scan);
+        assert_eq!(merged, [found("a", -50), found("b", -60), found("c", -90)]);
+        let bars: Vec<u8> = merged.iter().map(|n| n.bars()).collect();
+        assert_eq!(bars, [4, 3, 0]);
+    }
+}
@@ -0,0 +1,220 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::reader::location::{book_data_dir, load_json, save_json, ContentLoc, PageRange};
+use crate::ui::list_view::{ListAction, ListRow, ListView};
+use crate::ui::touch::{TouchEvent, TouchEventKind};
+use anyhow::Result;
+use embedded_graphics::{
+    pixelcolor::GrayColor,
+    prelude::*,
+    primitives::{PrimitiveStyle, Triangle},
+};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+// size of the corner that adds a bookmark when tapped or held, also the dog-ear size
+const CORNER_SIZE: u32 = 60;
+
+/// A named bookmark
+///
+/// The location is a content offset, not a page number, so the bookmark
+/// is still good after the book is paginated again
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Bookmark {
+    pub name: String,
+    pub loc: ContentLoc,
+    /// unix time the bookmark was created
+    pub created: i64,
+}
+
+/// The bookmarks of one book, stored next to `book.db`
+#[derive(Debug)]
+pub struct BookmarkDb {
+    path: PathBuf,
+    bookmarks: Vec<Bookmark>,
+}
+
+impl BookmarkDb {
+    /// open the bookmarks for a book, an empty db if there are none yet
+    pub fn open(ereader_dir: &Path, book_path: &Path) -> Self {
+        let path = book_data_dir(ereader_dir, book_path).join("bookmarks.json");
+        let bookmarks = load_json(&path);
+        debug!("opened bookmarks {:?}", path);
+        Self { path, bookmarks }
+    }
+
+    /// write the bookmarks back to the sdcard
+    pub fn save(&self) -> Result<()> {
+        save_json(&self.path, &self.bookmarks)
+    }
+
+    /// the bookmarks, in content order
+    pub fn bookmarks(&self) -> &[Bookmark] {
+        &self.bookmarks
+    }
+
+    /// add a bookmark, keeping content order
+    pub fn add(&mut self, name: &str, loc: ContentLoc, created: i64) {
+        let idx = self.bookmarks.partition_point(|b| b.loc <= loc);
+        self.bookmarks.insert(
+            idx,
+            Bookmark {
+                name: name.to_string(),
+                loc,
+                created,
+            },
+        );
+    }
+
+    /// remove a bookmark
+    pub fn remove(&mut self, index: usize) -> Option<Bookmark> {
+        if index < self.bookmarks.len() {
+            Some(self.bookmarks.remove(index))
+        } else {
+            None
+        }
+    }
+
+    /// rename a bookmark
+    pub fn rename(&mut self, index: usize, name: &str) {
+        if let Some(b) = self.bookmarks.get_mut(index) {
+            b.name = name.to_string();
+        }
+    }
+
+    /// the first bookmark shown on a page
+    pub fn on_page(&self, page: &PageRange) -> Option<&Bookmark> {
+        self.bookmarks.iter().find(|b| page.contains(&b.loc))
+    }
+
+    /// add a bookmark at the start of the page, or remove the ones on it
+    ///
+    /// returns true if a bookmark was added
+    pub fn toggle(&mut self, page: &PageRange, name: &str, created: i64) -> bool {
+        let count = self.bookmarks.len();
+        self.bookmarks.retain(|b| !page.contains(&b.loc));
+        if self.bookmarks.len() == count {
+            self.add(name, page.start, created);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// is this a bookmark gesture, a Tap or a Hold on the dog-ear corner, top right
+///
+/// a Hold elsewhere on the page is left for selections and dictionary lookups
+pub fn is_bookmark_gesture(evt: &TouchEvent, width: u32) -> bool {
+    let in_corner = evt.x().saturating_add(CORNER_SIZE) >= width && evt.y() < CORNER_SIZE;
+    matches!(evt.kind(), TouchEventKind::Tap | TouchEventKind::Hold) && in_corner
+}
+
+/// draw the dog-ear in the top right corner of the page
+pub fn draw_dog_ear<D>(display: &mut D, width: u32) -> Result<(), D::Error>
+where
+    D: DrawTarget,
+    D::Color: GrayColor,
+{
+    let right = width as i32 - 1;
+    let size = CORNER_SIZE as i32;
+    Triangle::new(
+        Point::new(right - size, 0),
+        Point::new(right, 0),
+        Point::new(right, size),
+    )
+    .into_styled(PrimitiveStyle::with_fill(D::Color::BLACK))
+    .draw(display)
+}
+
+/// The bookmark list screen, selecting a bookmark jumps to it
+#[derive(Debug)]
+pub struct BookmarkListView {
+    list: ListView,
+}
+
+impl BookmarkListView {
+    /// create the view for a display size
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            list: ListView::new("Bookmarks", width, height),
+        }
+    }
+
+    /// draw the list, marking the bookmark on the current page
+    pub fn draw<D>(
+        &self,
+        display: &mut D,
+        db: &BookmarkDb,
+        page: &PageRange,
+    ) -> Result<(), D::Error>
+    where
+        D: DrawTarget,
+        D::Color: GrayColor,
+    {
+        let rows: Vec<ListRow> = db
+            .bookmarks()
+            .iter()
+            .map(|b| ListRow {
+                text: &b.name,
+                indent: 0,
+                marked: page.contains(&b.loc),
+            })
+            .collect();
+        self.list.draw(display, &rows)
+    }
+
+    /// handle a touch event, a selected bookmark is returned as a `Select` of its index
+    pub fn touch(&mut self, evt: &TouchEvent, db: &BookmarkDb) -> ListAction {
+        self.list.touch(evt, db.bookmarks().len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+    use std::fs;
+
+    fn page(from: u32, to: u32) -> PageRange {
+        PageRange::new(ContentLoc::new(0, from), ContentLoc::new(0, to))
+    }
+
+    #[test]
+    fn gesture_is_in_the_corner() {
+        let at = |kind, x, y| is_bookmark_gesture(&TouchEvent::with_position(kind, x, y), 758);
+        assert!(at(TouchEventKind::Tap, 757, 0));
+        assert!(at(TouchEventKind::Hold, 700, 59));
+        assert!(!at(TouchEventKind::Hold, 300, 500));
+        assert!(!at(TouchEventKind::Tap, 697, 10));
+        assert!(!at(TouchEventKind::Tap, 750, 60));
+        assert!(!at(TouchEventKind::Drag, 750, 10));
+    }
+
+    #[test]
+    fn toggle_save_and_open() {
+        let dir = temp_dir("bookmarks");
+        let book = Path::new("/sdcard/books/a.epub");
+        let mut db = BookmarkDb::open(&dir, book);
+        assert!(db.bookmarks().is_empty());
+        assert!(db.toggle(&page(500, 900), "two", 2));
+        assert!(db.toggle(&page(0, 500), "one", 1));
+        assert_eq!(db.on_page(&page(0, 500)).unwrap().name, "one");
+        db.save().unwrap();
+
+        let mut db = BookmarkDb::open(&dir, book);
+        let names: Vec<&str> = db.bookmarks().iter().map(|b| b.name.as_str()).collect();
+        assert_eq!(names, vec!["one", "two"]);
+        assert_eq!(db.bookmarks()[1].loc, ContentLoc::new(0, 500));
+        // a second toggle on the page removes its bookmark
+        assert!(!db.toggle(&page(400, 800), "again", 3));
+        assert_eq!(db.bookmarks().len(), 1);
+        assert!(db.on_page(&page(400, 800)).is_none());
+        db.rename(0, "first");
+        assert_eq!(db.remove(0).unwrap().name, "first");
+        assert!(db.remove(0).is_none());
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,64 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::reader::footnote::extract_note;
+use crate::reader::location::ContentLoc;
+use crate::reader::toc::TocEntry;
+use anyhow::{anyhow, Result};
+
+/// Access to the content of the open book
+///
+/// Items are the entries of the book spine, offsets are the same byte
+/// offsets used by `ContentLoc`. Content is read in pieces so a whole
+/// item never has to be in memory.
+pub trait BookContent {
+    /// number of items in the spine
+    fn item_count(&self) -> u32;
+
+    /// title of the chapter holding an item, if known
+    fn item_title(&self, item: u32) -> Option<String>;
+
+    /// read raw item content starting at `offset`, returns the bytes read, 0 at the end
+    fn read_item(&mut self, item: u32, offset: u32, buf: &mut [u8]) -> Result<usize>;
+
+    /// is the item content markup that should be skipped when reading as text
+    fn is_markup(&self, _item: u32) -> bool {
+        true
+    }
+
+    /// the table of contents, empty if the book has none
+    fn toc(&mut self) -> Result<Vec<TocEntry>> {
+        Ok(Vec::new())
+    }
+
+    /// read an embedded resource such as an image, by its path in the book
+    fn read_resource(&mut self, href: &str) -> Result<Vec<u8>> {
+        Err(anyhow!("book has no resource {}", href))
+    }
+
+    /// the path of a resource an item refers to, such as the source of an
+    /// image, for `read_resource`
+    fn resource_path(&mut self, href: &str, _from_item: u32) -> Option<String> {
+        Some(href.to_string())
+    }
+
+    /// path of the cover image, if the book has one
+    fn cover_href(&mut self) -> Option<String> {
+        None
+    }
+
+    /// the text of the footnote a link in `from_item` points to
+    ///
+    /// The default reads the document named in the href and takes the
+    /// text of the element with the fragment id.
+    fn footnote(&mut self, href: &str, _from_item: u32) -> Option<String> {
+        let (path, id) = href.split_once('#')?;
+        let markup = self.read_resource(path).ok()?;
+        extract_note(&String::from_utf8_lossy(&markup), id)
+    }
+
+    /// where an internal link goes, `from_item` is the item holding the link
+    fn resolve_link(&mut self, _href: &str, _from_item: u32) -> Option<ContentLoc> {
+        None
+    }
+}
@@ -0,0 +1,746 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::reader::content::BookContent;
+use crate::reader::fb2::attr;
+use crate::reader::footnote::{extract_note, markup_text};
+use crate::reader::location::ContentLoc;
+use crate::reader::toc::TocEntry;
+use anyhow::{anyhow, Result};
+use log::*;
+use quick_xml::events::Event;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+};
+
+// the rest of a bigger entry is cut off, it would not fit in memory
+const MAX_ENTRY: u64 = 8 * 1024 * 1024;
+
+/// is the path an EPUB book
+pub fn is_epub(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("epub"))
+}
+
+/// The metadata of an EPUB book
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EpubInfo {
+    pub title: String,
+    pub authors: Vec<String>,
+    pub language: String,
+}
+
+// an entry of the navigation document, before it is resolved to a location
+#[derive(Debug, Clone, PartialEq)]
+struct NavEntry {
+    title: String,
+    level: u8,
+    // path in the zip and fragment
+    path: String,
+    fragment: Option<String>,
+}
+
+/// An EPUB book, read from the zip as it is needed
+///
+/// Items are the documents of the spine. Entries of a zip can only be
+/// read from their start, so the item read last is kept whole.
+pub struct EpubBook {
+    archive: zip::ZipArchive<BufReader<File>>,
+    info: EpubInfo,
+    // paths in the zip of the spine documents
+    spine: Vec<String>,
+    nav: Vec<NavEntry>,
+    cover: Option<String>,
+    current: Option<(u32, Vec<u8>)>,
+}
+
+impl EpubBook {
+    /// open a book, reading its package document and table of contents
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut archive = zip::ZipArchive::new(BufReader::new(File::open(path)?))?;
+        let container = read_entry(&mut archive, "META-INF/container.xml")?;
+        let opf_path = rootfile(&container)
+            .ok_or_else(|| anyhow!("no package document in {}", path.display()))?;
+        let opf = read_entry(&mut archive, &opf_path)?;
+        let package = Package::parse(&opf, dir_of(&opf_path))?;
+        if package.spine.is_empty() {
+            return Err(anyhow!("empty spine in {}", path.display()));
+        }
+        // the epub 2 ncx, or else the epub 3 navigation document
+        let nav = match (&package.ncx, &package.nav) {
+            (Some(ncx), _) => read_entry(&mut archive, ncx)
+                .map(|xml| parse_ncx(&xml, dir_of(ncx)))
+                .unwrap_or_else(|e| {
+                    warn!("bad ncx {}: {}", ncx, e);
+                    Vec::new()
+                }),
+            (None, Some(nav)) => read_entry(&mut archive, nav)
+                .map(|xml| parse_nav(&xml, dir_of(nav)))
+                .unwrap_or_else(|e| {
+                    warn!("bad navigation document {}: {}", nav, e);
+                    Vec::new()
+                }),
+            (None, None) => Vec::new(),
+        };
+        debug!(
+            "opened epub {:?}, {} items, {} toc entries",
+            path,
+            package.spine.len(),
+            nav.len()
+        );
+        Ok(Self {
+            archive,
+            info: package.info,
+            spine: package.spine,
+            nav,
+            cover: package.cover,
+            current: None,
+        })
+    }
+
+    /// the title, authors and language
+    pub fn info(&self) -> &EpubInfo {
+        &self.info
+    }
+
+    // the item, read whole
+    fn item(&mut self, item: u32) -> Result<&[u8]> {
+        if !matches!(&self.current, Some((i, _)) if *i == item) {
+            let path = self
+                .spine
+                .get(item as usize)
+                .ok_or_else(|| anyhow!("no item {}", item))?;
+            let data = read_entry(&mut self.archive, path)?;
+            self.current = Some((item, data));
+        }
+        Ok(&self.current.as_ref().unwrap().1)
+    }
+
+    // the zip path and fragment of a link in an item
+    fn link_target<'a>(&self, href: &'a str, from_item: u32) -> Option<(String, Option<&'a str>)> {
+        let from = self.spine.get(from_item as usize)?;
+        let (path, fragment) = match href.split_once('#') {
+            Some((path, fragment)) => (path, Some(fragment)),
+            None => (href, None),
+        };
+        let path = if path.is_empty() {
+            from.clone()
+        } else {
+            join_path(dir_of(from), path)
+        };
+        Some((path, fragment))
+    }
+
+    // the location of a path and fragment, the start of the element with
+    // the fragment id or of the item
+    fn locate(&mut self, path: &str, fragment: Option<&str>) -> Option<ContentLoc> {
+        let item = self.spine.iter().position(|p| p == path)? as u32;
+        let offset = match fragment {
+            Some(id) => self
+                .item(item)
+                .ok()
+                .and_then(|data| id_offset(data, id))
+                .unwrap_or(0),
+            None => 0,
+        };
+        Some(ContentLoc::new(item, offset))
+    }
+}
+
+impl BookContent for EpubBook {
+    fn item_count(&self) -> u32 {
+        self.spine.len() as u32
+    }
+
+    fn item_title(&self, item: u32) -> Option<String> {
+        self.nav
+            .iter()
+            .rev()
+            .find(|e| {
+                self.spine
+                    .iter()
+                    .position(|p| *p == e.path)
+                    .is_some_and(|i| i as u32 <= item)
+            })
+            .map(|e| e.title.clone())
+    }
+
+    fn read_item(&mut self, item: u32, offset: u32, buf: &mut [u8]) -> Result<usize> {
+        let data = self.item(item)?;
+        let rest = data.get(offset as usize..).unwrap_or(&[]);
+        let n = rest.len().min(buf.len());
+        buf[..n].copy_from_slice(&rest[..n]);
+        Ok(n)
+    }
+
+    fn toc(&mut self) -> Result<Vec<TocEntry>> {
+        let nav = self.nav.clone();
+        Ok(nav
+            .into_iter()
+            .filter_map(|e| {
+                let loc = self.locate(&e.path, e.fragment.as_deref())?;
+                Some(TocEntry {
+                    title: e.title,
+                    level: e.level,
+                    loc,
+                })
+            })
+            .collect())
+    }
+
+    fn read_resource(&mut self, href: &str) -> Result<Vec<u8>> {
+        read_entry(&mut self.archive, href)
+    }
+
+    fn resource_path(&mut self, href: &str, from_item: u32) -> Option<String> {
+        self.link_target(href, from_item).map(|(path, _)| path)
+    }
+
+    fn cover_href(&mut self) -> Option<String> {
+        self.cover.clone()
+    }
+
+    fn footnote(&mut self, href: &str, from_item: u32) -> Option<String> {
+        let (path, id) = self.link_target(href, from_item)?;
+        let markup = self.read_resource(&path).ok()?;
+        extract_note(&String::from_utf8_lossy(&markup), id?)
+    }
+
+    fn resolve_link(&mut self, href: &str, from_item: u32) -> Option<ContentLoc> {
+        let (path, fragment) = self.link_target(href, from_item)?;
+        self.locate(&path, fragment)
+    }
+}
+
+// read an entry of the zip whole
+fn read_entry(archive: &mut zip::ZipArchive<BufReader<File>>, name: &str) -> Result<Vec<u8>> {
+    let entry = archive.by_name(name)?;
+    let mut data = Vec::with_capacity(entry.size().min(MAX_ENTRY) as usize);
+    entry.take(MAX_ENTRY).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// the directory of a path in the zip, with its trailing `/`
+pub fn dir_of(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(i) => &path[..i + 1],
+        None => "",
+    }
+}
+
+/// a relative href from a document in `dir` as a path in the zip
+pub fn join_path(dir: &str, href: &str) -> String {
+    let href = decode_href(href);
+    let mut parts: Vec<&str> = if href.starts_with('/') {
+        Vec::new()
+    } else {
+        dir.split('/').filter(|p| !p.is_empty()).collect()
+    };
+    for part in href.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+// decode the `%xx` escapes of an href
+fn decode_href(href: &str) -> String {
+    let bytes = href.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// the offset just after the start tag with an id
+fn id_offset(data: &[u8], id: &str) -> Option<u32> {
+    let id = decode_href(id);
+    for quote in ['"', '\''] {
+        let pattern = format!("id={}{}{}", quote, id, quote);
+        let Some(at) = data
+            .windows(pattern.len())
+            .position(|w| w == pattern.as_bytes())
+        else {
+            continue;
+        };
+        let end = data[at..].iter().position(|&b| b == b'>')?;
+        return Some((at + end + 1) as u32);
+    }
+    None
+}
+
+// the path of the package document from container.xml
+fn rootfile(xml: &[u8]) -> Option<String> {
+    let mut reader = quick_xml::Reader::from_reader(xml);
+    let mut buf = Vec::new();
+    loop {
+        match reader.read_event_into(&mut buf).ok()? {
+            Event::Start(e) | Event::Empty(e) if e.local_name().as_ref() == b"rootfile" => {
+                return attr(&e, reader.decoder(), b"full-path");
+            }
+            Event::Eof => return None,
+            _ => (),
+        }
+        buf.clear();
+    }
+}
+
+// what the package document says
+#[derive(Debug, Default)]
+struct Package {
+    info: EpubInfo,
+    spine: Vec<String>,
+    ncx: Option<String>,
+    nav: Option<String>,
+    cover: Option<String>,
+}
+
+impl Package {
+    fn parse(xml: &[u8], dir: &str) -> Result<Self> {
+        let mut reader = quick_xml::Reader::from_reader(xml);
+        let mut buf = Vec::new();
+        let mut package = Package::default();
+        // manifest id to path
+        let mut manifest = HashMap::new();
+        let mut idrefs = Vec::new();
+        let mut ncx_id = None;
+        let mut cover_id = None;
+        let mut element = Vec::new();
+        loop {
+            let event = reader.read_event_into(&mut buf)?;
+            let decoder = reader.decoder();
+            match event {
+                Event::Start(e) | Event::Empty(e) => {
+                    element = e.local_name().as_ref().to_vec();
+                    match element.as_slice() {
+                        b"item" => {
+                            let id = attr(&e, decoder, b"id").unwrap_or_default();
+                            let href = attr(&e, decoder, b"href").unwrap_or_default();
+                            let path = join_path(dir, &href);
+                            let properties = attr(&e, decoder, b"properties").unwrap_or_default();
+                            let properties: Vec<&str> = properties.split_whitespace().collect();
+                            if properties.contains(&"nav") {
+                                package.nav = Some(path.clone());
+                            }
+                            if properties.contains(&"cover-image") {
+                                package.cover = Some(path.clone());
+                            }
+                            manifest.insert(id, path);
+                        }
+                        b"itemref" => idrefs.extend(attr(&e, decoder, b"idref")),
+                        b"spine" => ncx_id = attr(&e, decoder, b"toc"),
+                        b"meta" if attr(&e, decoder, b"name").as_deref() == Some("cover") => {
+                            cover_id = attr(&e, decoder, b"content")
+                        }
+                        _ => (),
+                    }
+                }
+                Event::Text(t) => {
+                    let text = match t.unescape() {
+                        Ok(text) => text.into_owned(),
+                        Err(_) => decoder.decode(&t)?.into_owned(),
+                    };
+                    let text = text.trim().to_string();
+                    let info = &mut package.info;
+                    match element.as_slice() {
+                        b"title" if info.title.is_empty() => info.title = text,
+                        b"creator" if !text.is_empty() => info.authors.push(text),
+                        b"language" if info.language.is_empty() => info.language = text,
+                        _ => (),
+                    }
+                }
+                Event::End(_) => element.clear(),
+                Event::Eof => break,
+                _ => (),
+            }
+            buf.clear();
+        }
+        package.spine = idrefs
+            .iter()
+            .filter_map(|id| manifest.get(id).cloned())
+            .collect();
+        package.ncx = ncx_id.and_then(|id| manifest.get(&id).cloned());
+        if package.cover.is_none() {
+            package.cover = cover_id.and_then(|id| manifest.get(&id).cloned());
+        }
+        Ok(package)
+    }
+}
+
+// split an href into its path in the zip and fragment
+fn nav_target(dir: &str, href: &str) -> (String, Option<String>) {
+    match href.split_once('#') {
+        Some((path, fragment)) => (join_path(dir, path), Some(fragment.to_string())),
+        None => (join_path(dir, href), None),
+    }
+}
+
+// the text of a text event, with the entities html has and xml doesn't
+fn event_text(t: &quick_xml::events::BytesText, decoder: quick_xml::Decoder) -> String {
+    match t.unescape() {
+        Ok(text) => text.into_owned(),
+        Err(_) => markup_text(&decoder.decode(t).unwrap_or_default()),
+    }
+}
+
+// the entries of an epub 2 ncx, navPoints nest for the levels
+fn parse_ncx(xml: &[u8], dir: &str) -> Vec<NavEntry> {
+    let mut reader = quick_xml::Reader::from_reader(xml);
+    let mut buf = Vec::new();
+    let mut entries = Vec::new();
+    let mut depth: u8 = 0;
+    let mut in_text = false;
+    let mut title = String::new();
+    while let Ok(event) = reader.read_event_into(&mut buf) {
+        match event {
+            Event::Start(e) => match e.local_name().as_ref() {
+                b"navPoint" => {
+                    depth = depth.saturating_add(1);
+                    title.clear();
+                }
+                b"text" => in_text = true,
+                _ => (),
+            },
+            Event::Empty(e) if e.local_name().as_ref() == b"content" && depth > 0 => {
+                if let Some(src) = attr(&e, reader.decoder(), b"src") {
+                    let (path, fragment) = nav_target(dir, &src);
+                    entries.push(NavEntry {
+                        title: title.trim().to_string(),
+                        level: depth - 1,
+                        path,
+                        fragment,
+                    });
+                }
+            }
+            Event::Text(t) if in_text => title.push_str(&event_text(&t, reader.decoder())),
+            Event::End(e) => match e.local_name().as_ref() {
+                b"navPoint" => depth = depth.saturating_sub(1),
+                b"text" => in_text = false,
+                _ => (),
+            },
+            Event::Eof => break,
+            _ => (),
+        }
+        buf.clear();
+    }
+    entries
+}
+
+// the entries of the toc nav of an epub 3 navigation document, lists nest
+// for the levels
+fn parse_nav(xml: &[u8], dir: &str) -> Vec<NavEntry> {
+    let mut reader = quick_xml::Reader::from_reader(xml);
+    reader.check_end_names(false);
+    let mut buf = Vec::new();
+    let mut entries = Vec::new();
+    // depth of the toc nav element, and of the lists in it
+    let mut nav_depth = None;
+    let mut depth = 0;
+    let mut lists: u8 = 0;
+    let mut link: Option<(String, String)> = None;
+    while let Ok(event) = reader.read_event_into(&mut buf) {
+        match event {
+            Event::Start(e) => {
+                depth += 1;
+                let decoder = reader.decoder();
+                match e.local_name().as_ref() {
+                    b"nav" if nav_depth.is_none() => {
+                        let kind = attr(&e, decoder, b"type").unwrap_or_default();
+                        if kind.split_whitespace().any(|k| k == "toc") {
+                            nav_depth = Some(depth);
+                        }
+                    }
+                    b"ol" if nav_depth.is_some() => lists = lists.saturating_add(1),
+                    b"a" if nav_depth.is_some() => {
+                        link = attr(&e, decoder, b"href").map(|href| (href, String::new()))
+                    }
+                    _ => (),
+                }
+            }
+            Event::Text(t) => {
+                if let Some((_, title)) = &mut link {
+                    title.push_str(&event_text(&t, reader.decoder()));
+                }
+            }
+            Event::End(e) => {
+                match e.local_name().as_ref() {
+                    b"a" => {
+                        if let Some((href, title)) = link.take() {
+                            let (path, fragment) = nav_target(dir, &href);
+                            let title = title.split_whitespace().collect::<Vec<_>>().join(" ");
+                            entries.push(NavEntry {
+                                title,
+                                level: lists.saturating_sub(1),
+                                path,
+                                fragment,
+                            });
+                        }
+                    }
+                    b"ol" if nav_depth.is_some() => lists = lists.saturating_sub(1),
+                    _ => (),
+                }
+                if nav_depth == Some(depth) {
+                    break;
+                }
+                depth -= 1;
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+        buf.clear();
+    }
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{temp_dir, zip_file};
+
+    const CONTAINER: &str = r#"<?xml version="1.0"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles>
+    <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>"#;
+
+    const OPF: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="2.0">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:title>A Test &amp; Book</dc:title>
+    <dc:creator>Ann Author</dc:creator>
+    <dc:language>en</dc:language>
+    <meta name="cover" content="cover-img"/>
+  </metadata>
+  <manifest>
+    <item id="ncx" href="toc.ncx" media-type="application/x-dtbncx+xml"/>
+    <item id="c1" href="text/chapter%201.xhtml" media-type="application/xhtml+xml"/>
+    <item id="c2" href="text/c2.xhtml" media-type="application/xhtml+xml"/>
+    <item id="cover-img" href="images/cover.jpg" media-type="image/jpeg"/>
+  </manifest>
+  <spine toc="ncx">
+    <itemref idref="c1"/>
+    <itemref idref="c2"/>
+  </spine>
+</package>"#;
+
+    const NCX: &str = r#"<?xml version="1.0"?>
+<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/">
+  <navMap>
+    <navPoint id="n1"><navLabel><text>One</text></navLabel><content src="text/chapter%201.xhtml"/>
+      <navPoint id="n2"><navLabel><text>One, part two</text></navLabel><content src="text/chapter%201.xhtml#p2"/></navPoint>
+    </navPoint>
+    <navPoint id="n3"><navLabel><text>Two</text></navLabel><content src="text/c2.xhtml"/></navPoint>
+  </navMap>
+</ncx>"#;
+
+    const C1: &str = r#"<html><body><p>first</p><h2 id="p2">Part</h2><p><a href="c2.xhtml#end">on</a></p></body></html>"#;
+    const C2: &str = r#"<html><body><p>second</p><p id='end'>end</p></body></html>"#;
+
+    fn write_epub(path: &Path, files: &[(&str, &str)]) {
+        let files: Vec<(&str, &[u8])> = files.iter().map(|(n, d)| (*n, d.as_bytes())).collect();
+        zip_file(path, &files);
+    }
+
+    #[test]
+    fn paths() {
+        assert_eq!(dir_of("OEBPS/text/c1.xhtml"), "OEBPS/text/");
+        assert_eq!(dir_of("c1.xhtml"), "");
+        assert_eq!(
+            join_path("OEBPS/text/", "../images/a%20b.png"),
+            "OEBPS/images/a b.png"
+        );
+        assert_eq!(
+            join_path("OEBPS/", "./text/c+1.xhtml"),
+            "OEBPS/text/c+1.xhtml"
+        );
+        assert_eq!(join_path("", "c1.xhtml"), "c1.xhtml");
+    }
+
+    #[test]
+    fn epub2_book() {
+        let dir = temp_dir("epub2");
+        let path = dir.join("book.epub");
+        write_epub(
+            &path,
+            &[
+                ("mimetype", "application/epub+zip"),
+                ("META-INF/container.xml", CONTAINER),
+                ("OEBPS/content.opf", OPF),
+                ("OEBPS/toc.ncx", NCX),
+                ("OEBPS/text/chapter 1.xhtml", C1),
+                ("OEBPS/text/c2.xhtml", C2),
+                ("OEBPS/images/cover.jpg", "jpeg"),
+            ],
+        );
+        let mut book = EpubBook::open(&path).unwrap();
+        assert_eq!(
+            book.info(),
+            &EpubInfo {
+                title: "A Test & Book".to_string(),
+                authors: vec!["Ann Author".to_string()],
+                language: "en".to_string(),
+            }
+        );
+        assert_eq!(book.item_count(), 2);
+        let mut buf = [0u8; 5];
+        assert_eq!(book.read_item(1, 12, &mut buf).unwrap(), 5);
+        assert_eq!(&buf, b"<p>se");
+        assert_eq!(book.read_item(1, C2.len() as u32, &mut buf).unwrap(), 0);
+
+        let part2 = C1.find("Part").unwrap() as u32;
+        assert_eq!(
+            book.toc().unwrap(),
+            vec![
+                TocEntry {
+                    title: "One".to_string(),
+                    level: 0,
+                    loc: ContentLoc::new(0, 0),
+                },
+                TocEntry {
+                    title: "One, part two".to_string(),
+                    level: 1,
+                    loc: ContentLoc::new(0, part2),
+                },
+                TocEntry {
+                    title: "Two".to_string(),
+                    level: 0,
+                    loc: ContentLoc::new(1, 0),
+                },
+            ]
+        );
+        assert_eq!(book.item_title(0).as_deref(), Some("One, part two"));
+        assert_eq!(book.item_title(1).as_deref(), Some("Two"));
+
+        let end = C2.find("end</p>").unwrap() as u32;
+        assert_eq!(
+            book.resolve_link("c2.xhtml#end", 0),
+            Some(ContentLoc::new(1, end))
+        );
+        assert_eq!(book.resolve_link("#p2", 0), Some(ContentLoc::new(0, part2)));
+        assert_eq!(book.resolve_link("missing.xhtml", 0), None);
+
+        assert_eq!(book.cover_href().as_deref(), Some("OEBPS/images/cover.jpg"));
+        assert_eq!(
+            book.read_resource("OEBPS/images/cover.jpg").unwrap(),
+            b"jpeg"
+        );
+        // images are found from the chapter holding them
+        assert_eq!(
+            book.resource_path("../images/cover.jpg", 1).as_deref(),
+            Some("OEBPS/images/cover.jpg")
+        );
+    }
+
+    #[test]
+    fn epub3_navigation_document() {
+        let opf = r#"<package xmlns="http://www.idpf.org/2007/opf" version="3.0">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/"><dc:title>Three</dc:title></metadata>
+  <manifest>
+    <item id="nav" href="nav.xhtml" properties="nav" media-type="application/xhtml+xml"/>
+    <item id="c1" href="c1.xhtml" media-type="application/xhtml+xml"/>
+    <item id="img" href="cover.png" properties="cover-image" media-type="image/png"/>
+  </manifest>
+  <spine><itemref idref="c1"/></spine>
+</package>"#;
+        let nav = r#"<html xmlns:epub="http://www.idpf.org/2007/ops"><body>
+<nav epub:type="landmarks"><ol><li><a href="c1.xhtml">Start</a></li></ol></nav>
+<nav epub:type="toc"><ol>
+  <li><a href="c1.xhtml">Chapter
+      <em>one</em></a>
+    <ol><li><a href="c1.xhtml#s1">Section&nbsp;1</a></li></ol></li>
+</ol></nav></body></html>"#;
+        let dir = temp_dir("epub3");
+        let path = dir.join("book.epub");
+        write_epub(
+            &path,
+            &[
+                ("META-INF/container.xml", &CONTAINER.replace("OEBPS/", "")),
+                ("content.opf", opf),
+                ("nav.xhtml", nav),
+                ("c1.xhtml", r#"<p>one</p><p id="s1">two</p>"#),
+            ],
+        );
+        let mut book = EpubBook::open(&path).unwrap();
+        let toc: Vec<(String, u8, ContentLoc)> = book
+            .toc()
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.title, e.level, e.loc))
+            .collect();
+        assert_eq!(
+            toc,
+            vec![
+                ("Chapter one".to_string(), 0, ContentLoc::new(0, 0)),
+                ("Section 1".to_string(), 1, ContentLoc::new(0, 21)),
+            ]
+        );
+        assert_eq!(book.cover_href().as_deref(), Some("cover.png"));
+    }
+
+    #[test]
+    fn nested_tocs_are_flattened() {
+        let levels = |entries: Vec<NavEntry>| -> Vec<(String, u8)> {
+            entries.into_iter().map(|e| (e.path, e.level)).collect()
+        };
+        let want = vec![
+            ("t/a.xhtml".to_string(), 0),
+            ("t/b.xhtml".to_string(), 1),
+            ("t/c.xhtml".to_string(), 2),
+            ("t/d.xhtml".to_string(), 1),
+            ("t/e.xhtml".to_string(), 0),
+        ];
+        let ncx = br#"<ncx><navMap>
+<navPoint><navLabel><text>A</text></navLabel><content src="a.xhtml"/>
+  <navPoint><navLabel><text>B</text></navLabel><content src="b.xhtml"/>
+    <navPoint><navLabel><text>C</text></navLabel><content src="c.xhtml"/></navPoint>
+  </navPoint>
+  <navPoint><navLabel><text>D</text></navLabel><content src="d.xhtml"/></navPoint>
+</navPoint>
+<navPoint><navLabel><text>E</text></navLabel><content src="e.xhtml"/></navPoint>
+</navMap></ncx>"#;
+        assert_eq!(levels(parse_ncx(ncx, "t/")), want);
+        let nav = br#"<html><body><nav type="toc"><ol>
+<li><a href="a.xhtml">A</a><ol>
+  <li><a href="b.xhtml">B</a><ol><li><a href="c.xhtml">C</a></li></ol></li>
+  <li><a href="d.xhtml">D</a></li>
+</ol></li>
+<li><a href="e.xhtml">E</a></li>
+</ol></nav><nav type="page-list"><ol><li><a href="f.xhtml">F</a></li></ol></nav>
+</body></html>"#;
+        assert_eq!(levels(parse_nav(nav, "t/")), want);
+    }
+
+    #[test]
+    fn not_an_epub() {
+        let dir = temp_dir("epub-bad");
+        let path = dir.join("bad.epub");
+        write_epub(&path, &[("mimetype", "application/epub+zip")]);
+        assert!(EpubBook::open(&path).is_err());
+        assert!(is_epub(Path::new("/sdcard/a.EPUB")));
+        assert!(!is_epub(Path::new("/sdcard/a.txt")));
+    }
+}
@@ -0,0 +1,853 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::reader::content::BookContent;
+use crate::reader::item_cache::{escape_xml, source_stamp, CachedHeading, ItemReader, ItemWriter};
+use crate::reader::location::{book_data_dir, ContentLoc};
+use crate::reader::toc::TocEntry;
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use log::*;
+use quick_xml::{
+    events::{BytesStart, Event},
+    Decoder,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+// bump when the conversion changes, so cached books are converted again
+const CONVERT_VERSION: u32 = 1;
+// a note longer than this is cut for the popup
+const MAX_NOTE_LEN: usize = 2000;
+// base64 characters decoded at a time, a multiple of 4
+const BINARY_CHUNK: usize = 4096;
+
+/// is the path an FB2 book, `.fb2` or `.fb2.zip`
+pub fn is_fb2(path: &Path) -> bool {
+    let name = path.to_string_lossy().to_ascii_lowercase();
+    name.ends_with(".fb2") || name.ends_with(".fb2.zip")
+}
+
+/// The title-info of an FB2 book
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Fb2Info {
+    pub title: String,
+    pub authors: Vec<String>,
+    pub genres: Vec<String>,
+    pub language: String,
+    pub date: String,
+    pub annotation: String,
+    /// series name and number
+    pub sequence: Option<(String, u32)>,
+    /// binary id of the cover image
+    pub cover: Option<String>,
+}
+
+/// What the cache holds about a converted book
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Fb2Meta {
+    version: u32,
+    source_len: u64,
+    source_modified: u64,
+    info: Fb2Info,
+    items: Vec<Option<String>>,
+    headings: Vec<CachedHeading>,
+    /// locations of the element ids, targets of internal links
+    anchors: BTreeMap<String, ContentLoc>,
+    /// footnote text by id
+    notes: BTreeMap<String, String>,
+}
+
+// the parts of the document being converted
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Part {
+    None,
+    TitleInfo,
+    Body,
+    Notes,
+    Binary,
+}
+
+// A name being collected from its parts
+#[derive(Debug, Default)]
+struct Author {
+    first: String,
+    middle: String,
+    last: String,
+    nickname: String,
+}
+
+impl Author {
+    fn name(&self) -> String {
+        let name = [&self.first, &self.middle, &self.last]
+            .iter()
+            .filter(|s| !s.is_empty())
+            .map(|s| s.trim())
+            .collect::<Vec<_>>()
+            .join(" ");
+        if name.is_empty() {
+            self.nickname.trim().to_string()
+        } else {
+            name
+        }
+    }
+}
+
+// the xhtml for an fb2 element in a body, its open and close tags
+fn html_tags(name: &[u8]) -> Option<(&'static str, &'static str)> {
+    Some(match name {
+        b"p" => ("<p>", "</p>\n"),
+        b"v" => ("<p class=\"v\">", "</p>\n"),
+        b"subtitle" => ("<p class=\"subtitle\"><strong>", "</strong></p>\n"),
+        b"text-author" => ("<p class=\"text-author\"><em>", "</em></p>\n"),
+        b"epigraph" => ("<blockquote class=\"epigraph\">", "</blockquote>\n"),
+        b"cite" => ("<blockquote>", "</blockquote>\n"),
+        b"annotation" => ("<blockquote class=\"annotation\">", "</blockquote>\n"),
+        b"poem" => ("<div class=\"poem\">", "</div>\n"),
+        b"stanza" => ("<div class=\"stanza\">", "</div>\n"),
+        b"emphasis" => ("<em>", "</em>"),
+        b"strong" => ("<strong>", "</strong>"),
+        b"strikethrough" => ("<del>", "</del>"),
+        b"sub" => ("<sub>", "</sub>"),
+        b"sup" => ("<sup>", "</sup>"),
+        b"code" => ("<code>", "</code>"),
+        b"table" => ("<table>", "</table>\n"),
+        b"tr" => ("<tr>", "</tr>\n"),
+        b"td" => ("<td>", "</td>"),
+        b"th" => ("<th>", "</th>"),
+        _ => return None,
+    })
+}
+
+/// an attribute by its local name, so `l:href` and `xlink:href` both match
+pub fn attr(e: &BytesStart, decoder: Decoder, name: &[u8]) -> Option<String> {
+    e.attributes().flatten().find_map(|a| {
+        if a.key.local_name().as_ref() == name {
+            let value = decoder.decode(&a.value).ok()?;
+            let value = match quick_xml::escape::unescape(&value) {
+                Ok(v) => v.into_owned(),
+                Err(_) => value.into_owned(),
+            };
+            Some(value)
+        } else {
+            None
+        }
+    })
+}
+
+// Converts the fb2 xml to xhtml items while it is read
+struct Converter {
+    out: ItemWriter,
+    binary_dir: PathBuf,
+    info: Fb2Info,
+    anchors: BTreeMap<String, ContentLoc>,
+    notes: BTreeMap<String, String>,
+    part: Part,
+    // names of the open elements
+    path: Vec<Vec<u8>>,
+    // close tags of the open xhtml elements, `None` for elements without xhtml
+    html: Vec<Option<&'static str>>,
+    section_depth: u8,
+    // title being collected, plain text and xhtml
+    title: Option<(String, String)>,
+    author: Author,
+    text: String,
+    binary: Option<BinaryWriter>,
+    // note being collected, its id and text
+    note: Option<(String, String)>,
+}
+
+impl Converter {
+    fn new(dir: &Path) -> Result<Self> {
+        let out = ItemWriter::new(dir)?;
+        let binary_dir = dir.join("binary");
+        fs::create_dir_all(&binary_dir)?;
+        Ok(Self {
+            out,
+            binary_dir,
+            info: Fb2Info::default(),
+            anchors: BTreeMap::new(),
+            notes: BTreeMap::new(),
+            part: Part::None,
+            path: Vec::new(),
+            html: Vec::new(),
+            section_depth: 0,
+            title: None,
+            author: Author::default(),
+            text: String::new(),
+            binary: None,
+            note: None,
+        })
+    }
+
+    fn in_element(&self, name: &[u8]) -> bool {
+        self.path.iter().any(|n| n == name)
+    }
+
+    // write xhtml to the title being collected, or to the item
+    fn write(&mut self, html: &str) -> Result<()> {
+        match &mut self.title {
+            Some((_, title_html)) => {
+                title_html.push_str(html);
+                Ok(())
+            }
+            None => self.out.write(html),
+        }
+    }
+
+    // items may only split with no xhtml element open
+    fn block_end(&mut self) -> Result<()> {
+        if self.title.is_none() && self.html.iter().all(|h| h.is_none()) {
+            self.out.block_end()?;
+        }
+        Ok(())
+    }
+
+    fn start(&mut self, e: &BytesStart, decoder: Decoder, empty: bool) -> Result<()> {
+        let name = e.local_name().as_ref().to_vec();
+        match (self.part, name.as_slice()) {
+            (_, b"title-info") => self.part = Part::TitleInfo,
+            (Part::TitleInfo, b"author") => self.author = Author::default(),
+            (Part::TitleInfo, b"sequence") => {
+                if let Some(series) = attr(e, decoder, b"name") {
+                    let number = attr(e, decoder, b"number")
+                        .and_then(|n| n.parse().ok())
+                        .unwrap_or(0);
+                    self.info.sequence = Some((series, number));
+                }
+            }
+            (Part::TitleInfo, b"image") if self.in_element(b"coverpage") => {
+                self.info.cover =
+                    attr(e, decoder, b"href").map(|h| h.trim_start_matches('#').to_string());
+            }
+            (_, b"body") => {
+                let notes = matches!(
+                    attr(e, decoder, b"name").as_deref(),
+                    Some("notes" | "comments")
+                );
+                self.part = if notes { Part::Notes } else { Part::Body };
+                // each body starts an item, the notes are read apart from the text
+                self.out.end_item()?;
+            }
+            (_, b"binary") => {
+                self.part = Part::Binary;
+                self.binary = match attr(e, decoder, b"id") {
+                    Some(id) if !id.is_empty() => Some(BinaryWriter::new(&self.binary_dir, &id)?),
+                    _ => {
+                        warn!("fb2 binary without an id");
+                        None
+                    }
+                };
+            }
+            (Part::Body | Part::Notes, _) => self.body_start(e, decoder, &name, empty)?,
+            _ => (),
+        }
+        if !empty {
+            self.path.push(name);
+        }
+        self.text.clear();
+        Ok(())
+    }
+
+    fn body_start(
+        &mut self,
+        e: &BytesStart,
+        decoder: Decoder,
+        name: &[u8],
+        empty: bool,
+    ) -> Result<()> {
+        // top level sections are chapters, start each in its own item
+        if name == b"section" && self.section_depth == 0 && self.part == Part::Body {
+            self.out.end_item()?;
+        }
+        if let Some(id) = attr(e, decoder, b"id") {
+            if self.part == Part::Notes && name == b"section" {
+                self.note = Some((id.clone(), String::new()));
+            }
+            self.anchors.insert(id, self.out.loc()?);
+        }
+        match name {
+            b"section" => {
+                self.section_depth += 1;
+                if !empty {
+                    self.html.push(None);
+                }
+            }
+            b"title" => {
+                self.title = Some((String::new(), String::new()));
+                if !empty {
+                    self.html.push(None);
+                }
+            }
+            b"p" if self.title.is_some() => {
+                // title lines are joined into one heading
+                if let Some((plain, html)) = &mut self.title {
+                    if !plain.is_empty() {
+                        plain.push(' ');
+                        html.push_str("<br/>");
+                    }
+                }
+                if !empty {
+                    self.html.push(None);
+                }
+            }
+            b"empty-line" => self.write("<p>&#160;</p>\n")?,
+            b"image" => {
+                let href = attr(e, decoder, b"href").unwrap_or_default();
+                let mut html = String::from("<p class=\"image\"><img src=\"");
+                escape_xml(href.trim_start_matches('#'), &mut html);
+                html.push_str("\" alt=\"\"/></p>\n");
+                self.write(&html)?;
+                if !empty {
+                    self.html.push(None);
+                }
+            }
+            b"a" => {
+                let href = attr(e, decoder, b"href").unwrap_or_default();
+                let note = attr(e, decoder, b"type").as_deref() == Some("note");
+                let mut html = String::from("<a href=\"");
+                escape_xml(&href, &mut html);
+                html.push_str(if note { "\" class=\"noteref\">" } else { "\">" });
+                if empty {
+                    html.push_str("</a>");
+                } else {
+                    self.html.push(Some("</a>"));
+                }
+                self.write(&html)?;
+            }
+            _ => match html_tags(name) {
+                Some((open, close)) => {
+                    self.write(open)?;
+                    if empty {
+                        self.write(close)?;
+                    } else {
+                        self.html.push(Some(close));
+                    }
+                }
+                None => {
+                    if !empty {
+                        self.html.push(None);
+                    }
+                }
+            },
+        }
+        Ok(())
+    }
+
+    fn end(&mut self) -> Result<()> {
+        let name = self.path.pop().unwrap_or_default();
+        match (self.part, name.as_slice()) {
+            (Part::TitleInfo, b"title-info") => self.part = Part::None,
+            (Part::TitleInfo, b"book-title") => self.info.title = self.text.trim().to_string(),
+            (Part::TitleInfo, b"genre") => self.info.genres.push(self.text.trim().to_string()),
+            (Part::TitleInfo, b"lang") => self.info.language = self.text.trim().to_string(),
+            (Part::TitleInfo, b"date") => self.info.date = self.text.trim().to_string(),
+            (Part::TitleInfo, b"first-name") => self.author.first = self.text.clone(),
+            (Part::TitleInfo, b"middle-name") => self.author.middle = self.text.clone(),
+            (Part::TitleInfo, b"last-name") => self.author.last = self.text.clone(),
+            (Part::TitleInfo, b"nickname") => self.author.nickname = self.text.clone(),
+            (Part::TitleInfo, b"author") => {
+                let name = self.author.name();
+                if !name.is_empty() {
+                    self.info.authors.push(name);
+                }
+            }
+            (Part::TitleInfo, b"p") if self.in_element(b"annotation") => {
+                if !self.info.annotation.is_empty() {
+                    self.info.annotation.push('\n');
+                }
+                self.info.annotation.push_str(self.text.trim());
+            }
+            (Part::Binary, b"binary") => {
+                if let Some(binary) = self.binary.take() {
+                    binary.finish();
+                }
+                self.part = Part::None;
+            }
+            (Part::Body | Part::Notes, b"body") => {
+                self.out.end_item()?;
+                self.part = Part::None;
+            }
+            (Part::Body | Part::Notes, _) => self.body_end(&name)?,
+            _ => (),
+        }
+        self.text.clear();
+        Ok(())
+    }
+
+    fn body_end(&mut self, name: &[u8]) -> Result<()> {
+        if let Some(close) = self.html.pop().flatten() {
+            self.write(close)?;
+        }
+        match name {
+            b"title" => {
+                if let Some((plain, html)) = self.title.take() {
+                    let level = self.section_depth + 1;
+                    let plain = plain.split_whitespace().collect::<Vec<_>>().join(" ");
+                    if self.part == Part::Notes && self.section_depth > 0 {
+                        // note titles are their numbers, shown but not in the toc
+                        self.write(&format!(
+                            "<p class=\"note-title\"><strong>{}</strong></p>\n",
+                            html
+                        ))?;
+                    } else if !plain.is_empty() {
+                        self.out.heading(level, &plain, &html)?;
+                    }
+                }
+            }
+            b"section" => {
+                self.section_depth = self.section_depth.saturating_sub(1);
+                if let Some((id, text)) = self.note.take() {
+                    let mut text = text.trim().to_string();
+                    if text.len() > MAX_NOTE_LEN {
+                        let mut end = MAX_NOTE_LEN;
+                        while !text.is_char_boundary(end) {
+                            end -= 1;
+                        }
+                        text.truncate(end);
+                        text.push('…');
+                    }
+                    self.notes.insert(id, text);
+                }
+            }
+            b"p" | b"v" => {
+                if let Some((_, text)) = &mut self.note {
+                    text.push('\n');
+                }
+            }
+            _ => (),
+        }
+        self.block_end()
+    }
+
+    fn text(&mut self, text: &str) -> Result<()> {
+        match self.part {
+            Part::TitleInfo => self.text.push_str(text),
+            Part::Binary => self.binary_text(text.as_bytes()),
+            Part::Body | Part::Notes => {
+                if let Some((plain, _)) = &mut self.title {
+                    plain.push_str(text);
+                } else if let Some((_, note)) = &mut self.note {
+                    if self.path.last().map(|n| n != b"section").unwrap_or(false) {
+                        note.push_str(text);
+                    }
+                }
+                // whitespace between blocks isn't content
+                if self.html.iter().any(|h| h.is_some()) || self.title.is_some() {
+                    let mut html = String::with_capacity(text.len());
+                    escape_xml(text, &mut html);
+                    self.write(&html)?;
+                }
+            }
+            Part::None => (),
+        }
+        Ok(())
+    }
+
+    // base64 of a binary, decoded as it arrives
+    fn binary_text(&mut self, text: &[u8]) {
+        if let Some(binary) = &mut self.binary {
+            if let Err(e) = binary.write(text) {
+                warn!("bad fb2 binary {}: {}", binary.id, e);
+                if let Some(binary) = self.binary.take() {
+                    binary.discard();
+                }
+            }
+        }
+    }
+
+    fn convert<R: BufRead>(mut self, r: R) -> Result<Fb2Meta> {
+        let mut reader = quick_xml::Reader::from_reader(r);
+        reader.check_end_names(false);
+        let mut buf = Vec::new();
+        loop {
+            match reader.read_event_into(&mut buf)? {
+                Event::Start(e) => self.start(&e, reader.decoder(), false)?,
+                Event::Empty(e) => self.start(&e, reader.decoder(), true)?,
+                Event::End(_) => self.end()?,
+                // base64 has nothing to unescape, it goes straight to the file
+                Event::Text(t) if self.part == Part::Binary => self.binary_text(&t),
+                Event::Text(t) => {
+                    // undefined entities in the text are kept as they are
+                    let text = match t.unescape() {
+                        Ok(text) => text.into_owned(),
+                        Err(_) => reader.decoder().decode(&t)?.into_owned(),
+                    };
+                    self.text(&text)?;
+                }
+                Event::CData(t) => {
+                    let text = reader.decoder().decode(&t)?.into_owned();
+                    self.text(&text)?;
+                }
+                Event::Eof => break,
+                _ => (),
+            }
+            buf.clear();
+        }
+        let (items, headings) = self.out.finish()?;
+        Ok(Fb2Meta {
+            version: CONVERT_VERSION,
+            source_len: 0,
+            source_modified: 0,
+            info: self.info,
+            items,
+            headings,
+            anchors: self.anchors,
+            notes: self.notes,
+        })
+    }
+}
+
+// Decodes a base64 binary to its file a chunk at a time, so neither the
+// text nor the image is held whole
+struct BinaryWriter {
+    id: String,
+    path: PathBuf,
+    part_path: PathBuf,
+    out: BufWriter<File>,
+    // base64 characters not decoded yet, whitespace removed
+    pending: Vec<u8>,
+    decoded: Vec<u8>,
+}
+
+impl BinaryWriter {
+    fn new(dir: &Path, id: &str) -> Result<Self> {
+        let path = binary_path(dir, id);
+        let mut part = OsString::from(path.as_os_str());
+        part.push(".part");
+        let part_path = PathBuf::from(part);
+        Ok(Self {
+            id: id.to_string(),
+            out: BufWriter::new(File::create(&part_path)?),
+            path,
+            part_path,
+            pending: Vec::with_capacity(BINARY_CHUNK),
+            decoded: Vec::with_capacity(BINARY_CHUNK / 4 * 3),
+        })
+    }
+
+    fn write(&mut self, text: &[u8]) -> Result<()> {
+        for b in text.iter().filter(|b| !b.is_ascii_whitespace()) {
+            self.pending.push(*b);
+            if self.pending.len() == BINARY_CHUNK {
+                self.decode()?;
+            }
+        }
+        Ok(())
+    }
+
+    // decode and write the pending characters
+    fn decode(&mut self) -> Result<()> {
+        self.decoded.clear();
+        base64::engine::general_purpose::STANDARD.decode_vec(&self.pending, &mut self.decoded)?;
+        self.out.write_all(&self.decoded)?;
+        self.pending.clear();
+        Ok(())
+    }
+
+    // the binary is done, the file only gets its name if it all decoded
+    fn finish(mut self) {
+        let result = self
+            .decode()
+            .and_then(|_| Ok(self.out.flush()?))
+            .and_then(|_| Ok(fs::rename(&self.part_path, &self.path)?));
+        if let Err(e) = result {
+            warn!("bad fb2 binary {}: {}", self.id, e);
+            self.discard();
+        }
+    }
+
+    fn discard(self) {
+        drop(self.out);
+        let _ = fs::remove_file(&self.part_path);
+    }
+}
+
+// binary ids can be any xml id, keep them safe as file names
+fn binary_path(dir: &Path, id: &str) -> PathBuf {
+    let name: String = id
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    dir.join(name)
+}
+
+/// An FB2 book
+///
+/// The xml is converted once, while it is read, to xhtml items in the book
+/// data directory, so it paginates like an EPUB. Binaries are decoded to
+/// files there, and footnotes are kept by id for popups.
+pub struct Fb2Book {
+    dir: PathBuf,
+    meta: Fb2Meta,
+    items: ItemReader,
+}
+
+impl Fb2Book {
+    /// open a book, converting it if the cache is missing or stale
+    pub fn open(ereader_dir: &Path, book_path: &Path) -> Result<Self> {
+        let dir = book_data_dir(ereader_dir, book_path).join("fb2");
+        let (len, modified) = source_stamp(book_path)?;
+        let meta_path = dir.join("meta.json");
+        let cached: Option<Fb2Meta> = fs::read(&meta_path)
+            .ok()
+            .and_then(|b| serde_json::from_slice(&b).ok());
+        let meta = match cached {
+            Some(meta)
+                if meta.version == CONVERT_VERSION
+                    && meta.source_len == len
+                    && meta.source_modified == modified =>
+            {
+                meta
+            }
+            _ => {
+                info!("converting fb2 {:?}", book_path);
+                let mut meta = Self::convert(book_path, &dir)?;
+                meta.source_len = len;
+                meta.source_modified = modified;
+                fs::write(&meta_path, serde_json::to_vec(&meta)?)?;
+                meta
+            }
+        };
+        Ok(Self {
+            items: ItemReader::new(&dir),
+            dir,
+            meta,
+        })
+    }
+
+    fn convert(book_path: &Path, dir: &Path) -> Result<Fb2Meta> {
+        let conv = Converter::new(dir)?;
+        let name = book_path.to_string_lossy().to_ascii_lowercase();
+        if name.ends_with(".zip") {
+            let mut archive = zip::ZipArchive::new(BufReader::new(File::open(book_path)?))?;
+            let index = (0..archive.len())
+                .find(|i| {
+                    archive
+                        .by_index(*i)
+                        .map(|e| e.name().to_ascii_lowercase().ends_with(".fb2"))
+                        .unwrap_or(false)
+                })
+                .ok_or_else(|| anyhow!("no fb2 in {:?}", book_path))?;
+            let entry = archive.by_index(index)?;
+            conv.convert(BufReader::new(entry))
+        } else {
+            conv.convert(BufReader::new(File::open(book_path)?))
+        }
+    }
+
+    /// the title-info metadata
+    pub fn info(&self) -> &Fb2Info {
+        &self.meta.info
+    }
+
+    /// the location of an element id, the target of `#id` links
+    pub fn anchor(&self, id: &str) -> Option<ContentLoc> {
+        self.meta.anchors.get(id.trim_start_matches('#')).copied()
+    }
+
+    /// the text of a footnote, by the href of its link
+    pub fn note(&self, href: &str) -> Option<&str> {
+        self.meta
+            .notes
+            .get(href.trim_start_matches('#'))
+            .map(|s| s.as_str())
+    }
+}
+
+impl BookContent for Fb2Book {
+    fn item_count(&self) -> u32 {
+        self.meta.items.len() as u32
+    }
+
+    fn item_title(&self, item: u32) -> Option<String> {
+        self.meta.items.get(item as usize).cloned().flatten()
+    }
+
+    fn read_item(&mut self, item: u32, offset: u32, buf: &mut [u8]) -> Result<usize> {
+        self.items.read(item, offset, buf)
+    }
+
+    fn toc(&mut self) -> Result<Vec<TocEntry>> {
+        Ok(self.meta.headings.iter().map(|h| h.toc_entry()).collect())
+    }
+
+    fn read_resource(&mut self, href: &str) -> Result<Vec<u8>> {
+        let path = binary_path(&self.dir.join("binary"), href.trim_start_matches('#'));
+        Ok(fs::read(path)?)
+    }
+
+    fn cover_href(&mut self) -> Option<String> {
+        self.meta.info.cover.clone()
+    }
+
+    fn footnote(&mut self, href: &str, _from_item: u32) -> Option<String> {
+        self.note(href).map(|n| n.to_string())
+    }
+
+    fn resolve_link(&mut self, href: &str, _from_item: u32) -> Option<ContentLoc> {
+        self.anchor(href)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{temp_dir, zip_file};
+
+    const BOOK: &str = r##"<?xml version="1.0" encoding="utf-8"?>
+<FictionBook xmlns="http://www.gribuser.ru/xml/fictionbook/2.0" xmlns:l="http://www.w3.org/1999/xlink">
+<description><title-info>
+  <genre>adventure</genre>
+  <author><first-name>Herman</first-name><last-name>Melville</last-name></author>
+  <author><nickname>Ish</nickname></author>
+  <book-title>Moby Dick</book-title>
+  <annotation><p>A whale.</p><p>A captain.</p></annotation>
+  <coverpage><image l:href="#cover.png"/></coverpage>
+  <lang>en</lang>
+  <sequence name="Sea" number="2"/>
+</title-info></description>
+<body>
+  <section id="ch1"><title><p>Chapter 1</p><p>Loomings</p></title>
+    <p>Call me <emphasis>Ishmael</emphasis>.<a l:href="#n1" type="note">1</a></p>
+    <section><title><p>Part</p></title><p>More.</p></section>
+  </section>
+  <section id="ch2"><title><p>Chapter 2</p></title><p>See <a l:href="#ch1">one</a>.</p></section>
+</body>
+<body name="notes">
+  <section id="n1"><title><p>1</p></title><p>A footnote.</p></section>
+</body>
+<binary id="cover.png" content-type="image/png">AQID</binary>
+</FictionBook>"##;
+
+    // an item, as text
+    fn item(book: &mut Fb2Book, item: u32) -> String {
+        let mut buf = vec![0u8; 4096];
+        let n = book.read_item(item, 0, &mut buf).unwrap();
+        String::from_utf8(buf[..n].to_vec()).unwrap()
+    }
+
+    fn open(name: &str) -> (PathBuf, Fb2Book) {
+        let dir = temp_dir(name);
+        let path = dir.join("moby.fb2");
+        fs::write(&path, BOOK).unwrap();
+        let book = Fb2Book::open(&dir.join("ereader"), &path).unwrap();
+        (dir, book)
+    }
+
+    #[test]
+    fn title_info_and_cover() {
+        let (dir, mut book) = open("fb2-info");
+        let info = book.info().clone();
+        assert_eq!(info.title, "Moby Dick");
+        assert_eq!(info.authors, ["Herman Melville", "Ish"]);
+        assert_eq!(info.genres, ["adventure"]);
+        assert_eq!(info.language, "en");
+        assert_eq!(info.annotation, "A whale.\nA captain.");
+        assert_eq!(info.sequence, Some(("Sea".to_string(), 2)));
+        assert_eq!(book.cover_href().as_deref(), Some("cover.png"));
+        assert_eq!(book.read_resource("#cover.png").unwrap(), [1, 2, 3]);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn sections_make_items_and_the_toc() {
+        let (dir, mut book) = open("fb2-toc");
+        // a chapter an item, then the notes
+        assert_eq!(book.item_count(), 3);
+        assert_eq!(book.item_title(1).as_deref(), Some("Chapter 2"));
+        let toc: Vec<_> = book
+            .toc()
+            .unwrap()
+            .into_iter()
+            .map(|t| (t.title, t.level, t.loc.item))
+            .collect();
+        let entry = |title: &str, level, item| (title.to_string(), level, item);
+        assert_eq!(
+            toc,
+            [
+                entry("Chapter 1 Loomings", 1, 0),
+                entry("Part", 2, 0),
+                entry("Chapter 2", 1, 1),
+            ]
+        );
+        let first = item(&mut book, 0);
+        assert!(first.contains("<h2>Chapter 1<br/>Loomings</h2>"));
+        assert!(first.contains("<p>Call me <em>Ishmael</em>."));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn links_and_footnotes() {
+        let (dir, mut book) = open("fb2-notes");
+        let first = item(&mut book, 0);
+        assert!(first.contains("<a href=\"#n1\" class=\"noteref\">1</a>"));
+        assert_eq!(book.footnote("#n1", 0).as_deref(), Some("A footnote."));
+        assert_eq!(book.footnote("#n2", 0), None);
+        let ch1 = book.resolve_link("#ch1", 1).unwrap();
+        assert_eq!(ch1.item, 0);
+        assert!(item(&mut book, 1).contains("<a href=\"#ch1\">one</a>"));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn zipped_books_are_unpacked() {
+        let dir = temp_dir("fb2-zip");
+        let path = dir.join("moby.fb2.zip");
+        zip_file(&path, &[("moby.fb2", BOOK.as_bytes())]);
+        assert!(is_fb2(&path));
+        let mut book = Fb2Book::open(&dir.join("ereader"), &path).unwrap();
+        assert_eq!(book.info().title, "Moby Dick");
+        assert!(item(&mut book, 1).contains("Chapter 2"));
+
+        zip_file(&path, &[("readme.txt", b"no book")]);
+        fs::remove_dir_all(dir.join("ereader")).unwrap();
+        assert!(Fb2Book::open(&dir.join("ereader"), &path).is_err());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn binaries_are_decoded_to_files() {
+        let dir = temp_dir("fb2-binary");
+        let image: Vec<u8> = (0..10_000u32).map(|i| (i * 7) as u8).collect();
+        let encoded = base64::engine::general_purpose::STANDARD.encode(&image);
+        // wrapped at 76 like most fb2 files, so chunks don't line up with lines
+        let lines: Vec<&str> = encoded
+            .as_bytes()
+            .chunks(76)
+            .map(|l| std::str::from_utf8(l).unwrap())
+            .collect();
+        let xml = format!(
+            "<FictionBook><body><section><p>text</p></section></body>\
+             <binary id=\"cover.jpg\" content-type=\"image/jpeg\">\n{}\n</binary>\
+             <binary id=\"bad\">AAA*</binary><binary>AAAA</binary></FictionBook>",
+            lines.join("\n")
+        );
+        Converter::new(&dir)
+            .unwrap()
+            .convert(xml.as_bytes())
+            .unwrap();
+        let binaries = dir.join("binary");
+        assert_eq!(fs::read(binaries.join("cover.jpg")).unwrap(), image);
+        // a bad binary leaves nothing behind
+        let mut names: Vec<String> = fs::read_dir(&binaries)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["cover.jpg"]);
+        let _ = fs::remove_dir_all(dir);
+    }
+}
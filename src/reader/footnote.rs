@@ -0,0 +1,181 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::reader::content::BookContent;
+use crate::reader::markup::{entity, is_block, tag_name};
+use crate::reader::page_map::PageMap;
+use crate::ui::popup::Popup;
+use crate::ui::touch::{TouchEvent, TouchEventKind};
+use log::*;
+
+// part of the display the note covers
+const NOTE_PERCENT: u32 = 35;
+// elements that end a note given by an anchor inside it
+const BLOCK_ENDS: [&str; 5] = ["</p>", "</div>", "</li>", "</aside>", "</dd>"];
+
+/// What a tap on the page asks for
+#[derive(Debug)]
+pub enum LinkTap {
+    /// not on a link
+    None,
+    /// show a footnote, the page stays put
+    Note(Popup),
+    /// go to the link target
+    Follow(String),
+}
+
+/// check a tap for a link, footnote references open a popup
+///
+/// The popup is drawn over the bottom of the page, only its area changes
+/// on the canvas, and the same area is redrawn from the page when it
+/// closes. The board still refreshes the whole panel, see
+/// `inkplate::refresh`.
+pub fn tap_link<C>(
+    content: &mut C,
+    map: &PageMap,
+    evt: &TouchEvent,
+    width: u32,
+    height: u32,
+) -> LinkTap
+where
+    C: BookContent + ?Sized,
+{
+    if evt.kind() != TouchEventKind::Tap {
+        return LinkTap::None;
+    }
+    let link = match map.link_at(evt.x(), evt.y()) {
+        Some(link) => link,
+        None => return LinkTap::None,
+    };
+    if link.note {
+        match content.footnote(&link.href, link.item) {
+            Some(text) => {
+                return LinkTap::Note(Popup::new("Note", &text, width, height, NOTE_PERCENT))
+            }
+            None => warn!("no footnote text for {}, following the link", link.href),
+        }
+    }
+    LinkTap::Follow(link.href.clone())
+}
+
+/// the text of the element with `id` in an xhtml document
+///
+/// Notes given by an anchor, `<p><a id="n1"/>1. text</p>`, take the
+/// text to the end of the block holding the anchor.
+pub fn extract_note(markup: &str, id: &str) -> Option<String> {
+    let pos = [format!("id=\"{}\"", id), format!("id='{}'", id)]
+        .iter()
+        .filter_map(|a| markup.find(a.as_str()))
+        .min()?;
+    let start = markup[..pos].rfind('<')?;
+    let name: String = markup[start + 1..]
+        .chars()
+        .take_while(|c| c.is_alphanumeric() || *c == ':')
+        .collect();
+    let tag_end = pos + markup[pos..].find('>')?;
+    let self_closing = markup[..tag_end].ends_with('/');
+    let body = &markup[tag_end + 1..];
+
+    let element_text = if self_closing {
+        String::new()
+    } else {
+        markup_text(&body[..element_end(body, &name)])
+    };
+    // an anchor only marks the place, or holds just the note number
+    let text = if name == "a" || element_text.chars().count() < 4 {
+        let end = BLOCK_ENDS
+            .iter()
+            .filter_map(|e| body.find(e))
+            .min()
+            .unwrap_or(body.len());
+        markup_text(&body[..end])
+    } else {
+        element_text
+    };
+    if text.is_empty() {
+        None
+    } else {
+        Some(text)
+    }
+}
+
+// offset of the tag closing the element `name` that `body` starts inside
+fn element_end(body: &str, name: &str) -> usize {
+    let open = format!("<{}", name);
+    let close = format!("</{}", name);
+    let mut depth = 1;
+    let mut i = 0;
+    while let Some(lt) = body[i..].find('<') {
+        let at = i + lt;
+        let rest = &body[at..];
+        let gt = rest.find('>').map(|g| at + g).unwrap_or(body.len());
+        if rest.starts_with(&close) {
+            depth -= 1;
+            if depth == 0 {
+                return at;
+            }
+        } else if rest.starts_with(&open) && !body[..gt].ends_with('/') {
+            // only the same element, not one whose name starts the same
+            let next = rest[open.len()..].chars().next();
+            if next.map(|c| c == '>' || c.is_whitespace()).unwrap_or(false) {
+                depth += 1;
+            }
+        }
+        i = gt.min(body.len() - 1) + 1;
+        if i >= body.len() {
+            break;
+        }
+    }
+    body.len()
+}
+
+/// the text of xhtml, without tags, block ends become line breaks
+pub fn markup_text(markup: &str) -> String {
+    let mut out = String::with_capacity(markup.len());
+    let mut rest = markup;
+    while let Some(c) = rest.chars().next() {
+        match c {
+            '<' => {
+                // a tag cut off at the end isn't text
+                let end = match rest.find('>') {
+                    Some(end) => end,
+                    None => break,
+                };
+                let (name, _) = tag_name(&rest[1..end]);
+                if is_block(&name) && !out.ends_with('\n') {
+                    out.push('\n');
+                }
+                rest = &rest[end + 1..];
+            }
+            '&' => {
+                let end = rest.find(';').filter(|e| *e < 10);
+                match end.and_then(|e| entity(&rest[1..e])) {
+                    Some(ch) => {
+                        out.push(ch);
+                        rest = &rest[end.unwrap_or_default() + 1..];
+                    }
+                    None => {
+                        out.push('&');
+                        rest = &rest[1..];
+                    }
+                }
+            }
+            c if c.is_whitespace() => {
+                if !out.ends_with(char::is_whitespace) && !out.is_empty() {
+                    out.push(' ');
+                }
+                rest = &rest[c.len_utf8()..];
+            }
+            c => {
+                out.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    // tidy the spaces around line breaks
+    out.split('\n')
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
@@ -0,0 +1,406 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::reader::location::{book_data_dir, load_json, save_json, ContentLoc, PageRange};
+use crate::reader::page_map::PageMap;
+use crate::ui::touch::{TouchEvent, TouchEventKind};
+use anyhow::Result;
+use embedded_graphics::{
+    pixelcolor::GrayColor,
+    prelude::*,
+    primitives::{PrimitiveStyle, Rectangle},
+};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+};
+
+// thickness of the highlight bar under the words
+const BAR_HEIGHT: u32 = 3;
+
+/// A highlighted passage, with an optional note
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Highlight {
+    pub start: ContentLoc,
+    /// end of the passage, exclusive
+    pub end: ContentLoc,
+    pub text: String,
+    pub note: Option<String>,
+    /// unix time the highlight was created
+    pub created: i64,
+}
+
+/// The highlights of one book, stored next to `book.db`
+#[derive(Debug)]
+pub struct HighlightDb {
+    path: PathBuf,
+    highlights: Vec<Highlight>,
+}
+
+impl HighlightDb {
+    /// open the highlights for a book, an empty db if there are none yet
+    pub fn open(ereader_dir: &Path, book_path: &Path) -> Self {
+        let path = book_data_dir(ereader_dir, book_path).join("highlights.json");
+        let highlights = load_json(&path);
+        debug!("opened highlights {:?}", path);
+        Self { path, highlights }
+    }
+
+    /// write the highlights back to the sdcard
+    pub fn save(&self) -> Result<()> {
+        save_json(&self.path, &self.highlights)
+    }
+
+    /// the highlights, in content order
+    pub fn highlights(&self) -> &[Highlight] {
+        &self.highlights
+    }
+
+    /// add a highlight, keeping content order, returns its index
+    ///
+    /// Highlights it overlaps are merged into it, their notes kept.
+    pub fn add(&mut self, mut highlight: Highlight) -> usize {
+        let (overlapping, rest): (Vec<_>, Vec<_>) = std::mem::take(&mut self.highlights)
+            .into_iter()
+            .partition(|h| h.start < highlight.end && highlight.start < h.end);
+        self.highlights = rest;
+        for h in overlapping {
+            highlight = merge(h, highlight);
+        }
+        let idx = self
+            .highlights
+            .partition_point(|h| h.start <= highlight.start);
+        self.highlights.insert(idx, highlight);
+        idx
+    }
+
+    /// remove a highlight
+    pub fn remove(&mut self, index: usize) -> Option<Highlight> {
+        if index < self.highlights.len() {
+            Some(self.highlights.remove(index))
+        } else {
+            None
+        }
+    }
+
+    /// set or clear the note of a highlight
+    pub fn set_note(&mut self, index: usize, note: Option<&str>) {
+        if let Some(h) = self.highlights.get_mut(index) {
+            h.note = note.filter(|n| !n.is_empty()).map(|n| n.to_string());
+        }
+    }
+
+    /// index of the highlight covering a location
+    pub fn at(&self, loc: &ContentLoc) -> Option<usize> {
+        self.highlights
+            .iter()
+            .position(|h| h.start <= *loc && *loc < h.end)
+    }
+
+    /// the highlights overlapping a page
+    pub fn on_page<'a>(&'a self, page: &'a PageRange) -> impl Iterator<Item = &'a Highlight> {
+        self.highlights
+            .iter()
+            .filter(move |h| h.start < page.end && page.start < h.end)
+    }
+}
+
+// one highlight covering two overlapping ones
+fn merge(a: Highlight, b: Highlight) -> Highlight {
+    let (first, second) = if a.start <= b.start { (a, b) } else { (b, a) };
+    let text = if first.end >= second.end {
+        first.text
+    } else {
+        join_overlap(&first.text, &second.text)
+    };
+    let note = match (first.note, second.note) {
+        (Some(n), Some(m)) => Some(format!("{}\n{}", n, m)),
+        (n, m) => n.or(m),
+    };
+    Highlight {
+        start: first.start,
+        end: first.end.max(second.end),
+        text,
+        note,
+        created: first.created.min(second.created),
+    }
+}
+
+// the words of `first` then those of `second` it doesn't end with
+fn join_overlap(first: &str, second: &str) -> String {
+    let a: Vec<&str> = first.split(' ').collect();
+    let b: Vec<&str> = second.split(' ').collect();
+    let shared = (0..=a.len().min(b.len()))
+        .rev()
+        .find(|k| a[a.len() - k..] == b[..*k])
+        .unwrap_or(0);
+    a.iter()
+        .chain(&b[shared..])
+        .copied()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Text selection with a long press and drag
+///
+/// A Hold starts the selection on the word under the finger, Drag events
+/// extend it, and the Release finishes it
+#[derive(Debug, Default)]
+pub struct Selection {
+    anchor: Option<usize>,
+    current: usize,
+}
+
+impl Selection {
+    /// create an empty selection
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// is a selection being made
+    pub fn active(&self) -> bool {
+        self.anchor.is_some()
+    }
+
+    /// word indices of the current selection
+    pub fn range(&self) -> Option<(usize, usize)> {
+        self.anchor
+            .map(|a| (a.min(self.current), a.max(self.current)))
+    }
+
+    /// handle a touch event, returns a new highlight once the finger is released
+    pub fn touch(&mut self, evt: &TouchEvent, map: &PageMap, created: i64) -> Option<Highlight> {
+        match evt.kind() {
+            TouchEventKind::Hold => {
+                self.anchor = map.word_at(evt.x(), evt.y());
+                if let Some(a) = self.anchor {
+                    self.current = a;
+                }
+                None
+            }
+            TouchEventKind::Drag => {
+                if self.anchor.is_some() {
+                    if let Some(i) = map.nearest_word(evt.x(), evt.y()) {
+                        self.current = i;
+                    }
+                }
+                None
+            }
+            TouchEventKind::Release => {
+                let (a, b) = self.range()?;
+                self.anchor = None;
+                selection_highlight(map, a, b, created)
+            }
+            _ => {
+                self.anchor = None;
+                None
+            }
+        }
+    }
+}
+
+// build the highlight for the words between 2 indices
+fn selection_highlight(map: &PageMap, a: usize, b: usize, created: i64) -> Option<Highlight> {
+    let words = map.words_between(a, b);
+    let first = words.first()?;
+    let last = words.last()?;
+    let text = words
+        .iter()
+        .map(|w| w.text.as_str())
+        .collect::<Vec<_>>()
+        .join(" ");
+    Some(Highlight {
+        start: first.loc,
+        end: ContentLoc::new(last.loc.item, last.loc.offset + last.text.len() as u32),
+        text,
+        note: None,
+        created,
+    })
+}
+
+/// draw a bar under the highlighted words on the page
+pub fn draw_highlights<D>(
+    display: &mut D,
+    map: &PageMap,
+    db: &HighlightDb,
+    page: &PageRange,
+) -> Result<(), D::Error>
+where
+    D: DrawTarget,
+    D::Color: GrayColor,
+{
+    let style = PrimitiveStyle::with_fill(D::Color::BLACK);
+    for h in db.on_page(page) {
+        for w in map.words_in(h.start, h.end) {
+            let bottom = w.rect.top_left.y + w.rect.size.height as i32;
+            Rectangle::new(
+                Point::new(w.rect.top_left.x, bottom),
+                Size::new(w.rect.size.width, BAR_HEIGHT),
+            )
+            .into_styled(style)
+            .draw(display)?;
+        }
+    }
+    Ok(())
+}
+
+/// the file name used for a book title, FAT doesn't allow some characters
+fn export_file_name(title: &str) -> String {
+    let name: String = title
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let name = name.trim().trim_end_matches('.');
+    if name.is_empty() {
+        "untitled.md".to_string()
+    } else {
+        format!("{}.md", name)
+    }
+}
+
+/// format the highlights and notes of a book as markdown
+pub fn highlights_markdown(title: &str, db: &HighlightDb) -> String {
+    let mut md = String::new();
+    let _ = writeln!(md, "# {}\n", title);
+    for h in db.highlights() {
+        for line in h.text.lines() {
+            let _ = writeln!(md, "> {}", line);
+        }
+        let _ = writeln!(md, "\n*Location {}:{}*\n", h.start.item, h.start.offset);
+        if let Some(note) = &h.note {
+            let _ = writeln!(md, "**Note:** {}\n", note);
+        }
+        let _ = writeln!(md, "---\n");
+    }
+    md
+}
+
+/// write the highlights to `<ereader_dir>/exports/<title>.md`, returns the file written
+pub fn export_markdown(ereader_dir: &Path, title: &str, db: &HighlightDb) -> Result<PathBuf> {
+    let dir = ereader_dir.join("exports");
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(export_file_name(title));
+    fs::write(&path, highlights_markdown(title, db))?;
+    info!(
+        "exported {} highlights to {:?}",
+        db.highlights().len(),
+        path
+    );
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    // a highlight of words in item 0, each 3 bytes and a space
+    fn words(first: u32, last: u32) -> Highlight {
+        Highlight {
+            start: ContentLoc::new(0, first * 4),
+            end: ContentLoc::new(0, last * 4 + 3),
+            text: (first..=last)
+                .map(|w| format!("w{:02}", w))
+                .collect::<Vec<_>>()
+                .join(" "),
+            note: None,
+            created: first as i64,
+        }
+    }
+
+    fn db(name: &str) -> HighlightDb {
+        HighlightDb::open(&temp_dir(name), Path::new("/books/book.epub"))
+    }
+
+    #[test]
+    fn highlights_keep_content_order() {
+        let mut db = db("highlights-order");
+        assert_eq!(db.add(words(10, 12)), 0);
+        assert_eq!(db.add(words(1, 2)), 0);
+        assert_eq!(db.add(words(5, 6)), 1);
+        let starts: Vec<u32> = db.highlights().iter().map(|h| h.start.offset).collect();
+        assert_eq!(starts, [4, 20, 40]);
+        assert_eq!(db.at(&ContentLoc::new(0, 22)), Some(1));
+        // the end is exclusive
+        assert_eq!(db.at(&ContentLoc::new(0, 27)), None);
+        let page = PageRange {
+            start: ContentLoc::new(0, 10),
+            end: ContentLoc::new(0, 40),
+        };
+        assert_eq!(db.on_page(&page).count(), 2);
+        assert_eq!(db.remove(1).unwrap().text, "w05 w06");
+        assert_eq!(db.remove(5), None);
+    }
+
+    #[test]
+    fn overlapping_highlights_merge() {
+        let mut db = db("highlights-merge");
+        db.add(words(1, 3));
+        db.set_note(0, Some("first"));
+        db.add(words(8, 9));
+        db.set_note(1, Some("second"));
+        db.add(words(12, 13));
+        // overlaps the end of one and the start of the next
+        assert_eq!(db.add(words(3, 8)), 0);
+        assert_eq!(db.highlights().len(), 2);
+        let h = &db.highlights()[0];
+        assert_eq!((h.start, h.end), (words(1, 1).start, words(9, 9).end));
+        assert_eq!(h.text, "w01 w02 w03 w04 w05 w06 w07 w08 w09");
+        assert_eq!(h.note.as_deref(), Some("first\nsecond"));
+        assert_eq!(h.created, 1);
+
+        // one inside another keeps the outer text
+        assert_eq!(db.add(words(4, 5)), 0);
+        assert_eq!(db.highlights()[0].text, words(1, 9).text);
+        // touching isn't overlapping
+        db.add(words(10, 11));
+        assert_eq!(db.highlights().len(), 3);
+        // covering several replaces them all
+        assert_eq!(db.add(words(0, 20)), 0);
+        assert_eq!(
+            db.highlights(),
+            [Highlight {
+                note: Some("first\nsecond".to_string()),
+                created: 0,
+                ..words(0, 20)
+            }]
+        );
+    }
+
+    #[test]
+    fn highlights_are_saved() {
+        let root = temp_dir("highlights-save");
+        let book = Path::new("/books/book.epub");
+        let mut db = HighlightDb::open(&root, book);
+        db.add(words(1, 2));
+        db.set_note(0, Some("note"));
+        db.save().unwrap();
+        let mut db = HighlightDb::open(&root, book);
+        assert_eq!(db.highlights()[0].note.as_deref(), Some("note"));
+        db.set_note(0, Some(""));
+        assert_eq!(db.highlights()[0].note, None);
+    }
+
+    #[test]
+    fn markdown_export() {
+        let root = temp_dir("highlights-export");
+        let mut db = HighlightDb::open(&root, Path::new("/books/book.epub"));
+        db.add(words(1, 2));
+        db.set_note(0, Some("why"));
+        let path = export_markdown(&root, "A: B?", &db).unwrap();
+        assert_eq!(path.file_name().unwrap(), "A_ B_.md");
+        let md = fs::read_to_string(path).unwrap();
+        assert_eq!(
+            md,
+            "# A: B?\n\n> w01 w02\n\n*Location 0:4*\n\n**Note:** why\n\n---\n\n"
+        );
+        assert_eq!(export_file_name(" .. "), "untitled.md");
+    }
+}
@@ -0,0 +1,106 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::reader::content::BookContent;
+use crate::reader::location::{book_data_dir, load_json, save_json, ContentLoc};
+use crate::ui::touch::{TouchEvent, TouchEventKind};
+use anyhow::Result;
+use log::*;
+use std::{path::Path, path::PathBuf};
+
+// oldest entries are dropped past this
+const MAX_HISTORY: usize = 50;
+// size of the top left corner that goes back
+const CORNER_SIZE: u32 = 60;
+
+/// The places links jumped away from, per book
+///
+/// Saved on every change, so going back works after a sleep.
+#[derive(Debug)]
+pub struct NavHistory {
+    path: PathBuf,
+    back: Vec<ContentLoc>,
+}
+
+impl NavHistory {
+    /// load the history of a book
+    pub fn open(ereader_dir: &Path, book_path: &Path) -> Self {
+        let path = book_data_dir(ereader_dir, book_path).join("history.json");
+        let back = load_json(&path);
+        debug!("opened history {:?}", path);
+        Self { path, back }
+    }
+
+    /// write the history back to the sdcard
+    pub fn save(&self) -> Result<()> {
+        save_json(&self.path, &self.back)
+    }
+
+    /// is there somewhere to go back to
+    pub fn can_go_back(&self) -> bool {
+        !self.back.is_empty()
+    }
+
+    /// remember a place before leaving it
+    pub fn push(&mut self, loc: ContentLoc) -> Result<()> {
+        self.back.push(loc);
+        if self.back.len() > MAX_HISTORY {
+            self.back.remove(0);
+        }
+        self.save()
+    }
+
+    /// the place to go back to
+    pub fn pop(&mut self) -> Result<Option<ContentLoc>> {
+        let loc = self.back.pop();
+        if loc.is_some() {
+            self.save()?;
+        }
+        Ok(loc)
+    }
+
+    /// forget the history
+    pub fn clear(&mut self) -> Result<()> {
+        self.back.clear();
+        self.save()
+    }
+}
+
+/// is the link outside the book
+pub fn is_external_link(href: &str) -> bool {
+    href.contains("://") || href.starts_with("mailto:") || href.starts_with("tel:")
+}
+
+/// follow a link from `current`, returns where to go
+///
+/// The current place is pushed on the history first, so Back returns to it.
+pub fn follow_link<C>(
+    content: &mut C,
+    history: &mut NavHistory,
+    href: &str,
+    current: ContentLoc,
+) -> Result<Option<ContentLoc>>
+where
+    C: BookContent + ?Sized,
+{
+    if is_external_link(href) {
+        info!("not following external link {}", href);
+        return Ok(None);
+    }
+    match content.resolve_link(href, current.item) {
+        Some(target) => {
+            history.push(current)?;
+            debug!("link {} from {:?} to {:?}", href, current, target);
+            Ok(Some(target))
+        }
+        None => {
+            warn!("link target not found {}", href);
+            Ok(None)
+        }
+    }
+}
+
+/// is this the back gesture, a Tap in the top left corner
+pub fn is_back_gesture(evt: &TouchEvent) -> bool {
+    evt.kind() == TouchEventKind::Tap && evt.x() < CORNER_SIZE && evt.y() < CORNER_SIZE
+}
@@ -0,0 +1,190 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::reader::location::ContentLoc;
+use crate::reader::toc::TocEntry;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+// items are split near this size, at a block boundary
+const ITEM_SIZE: u64 = 64 * 1024;
+
+/// escape text for xhtml
+pub fn escape_xml(text: &str, out: &mut String) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            _ => out.push(c),
+        }
+    }
+}
+
+/// size and modification time of a book file, a converted cache is stale when they change
+pub fn source_stamp(path: &Path) -> Result<(u64, u64)> {
+    let meta = fs::metadata(path)?;
+    let modified = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    Ok((meta.len(), modified))
+}
+
+/// A heading of a converted book
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CachedHeading {
+    pub title: String,
+    pub level: u8,
+    pub loc: ContentLoc,
+}
+
+impl CachedHeading {
+    /// the toc entry for the heading
+    pub fn toc_entry(&self) -> TocEntry {
+        TocEntry {
+            title: self.title.clone(),
+            level: self.level,
+            loc: self.loc,
+        }
+    }
+}
+
+fn item_path(dir: &Path, item: u32) -> PathBuf {
+    dir.join(format!("{}.xhtml", item))
+}
+
+/// Writes a converted book as xhtml items in its cache directory
+///
+/// Books that aren't EPUBs are converted once, so they paginate like an
+/// EPUB with the same typography. Items are kept small enough to read a
+/// piece at a time.
+pub struct ItemWriter {
+    dir: PathBuf,
+    out: Option<(BufWriter<File>, u64)>,
+    items: Vec<Option<String>>,
+    headings: Vec<CachedHeading>,
+}
+
+impl ItemWriter {
+    /// create the writer, removing items of an older conversion
+    pub fn new(dir: &Path) -> Result<Self> {
+        if dir.exists() {
+            fs::remove_dir_all(dir)?;
+        }
+        fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            out: None,
+            items: Vec::new(),
+            headings: Vec::new(),
+        })
+    }
+
+    // the writer for the current item, starting one if needed
+    fn writer(&mut self) -> Result<&mut (BufWriter<File>, u64)> {
+        if self.out.is_none() {
+            let item = self.items.len() as u32;
+            let title = self.headings.last().map(|h| h.title.clone());
+            let mut w = BufWriter::new(File::create(item_path(&self.dir, item))?);
+            let head = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<html xmlns=\"http://www.w3.org/1999/xhtml\"><head><title></title></head><body>\n";
+            w.write_all(head.as_bytes())?;
+            self.items.push(title);
+            self.out = Some((w, head.len() as u64));
+        }
+        Ok(self.out.as_mut().unwrap())
+    }
+
+    /// where the next write goes
+    pub fn loc(&mut self) -> Result<ContentLoc> {
+        let offset = self.writer()?.1 as u32;
+        Ok(ContentLoc::new(self.items.len() as u32 - 1, offset))
+    }
+
+    /// write xhtml to the current item
+    pub fn write(&mut self, html: &str) -> Result<()> {
+        let (w, len) = self.writer()?;
+        w.write_all(html.as_bytes())?;
+        *len += html.len() as u64;
+        Ok(())
+    }
+
+    /// write a heading and add it to the toc, `level` 1 is the top
+    pub fn heading(&mut self, level: u8, title: &str, html: &str) -> Result<()> {
+        let loc = self.loc()?;
+        // an item is titled by its first heading
+        let item = loc.item as usize;
+        if self.headings.last().map(|h| h.loc.item as usize) != Some(item) {
+            self.items[item] = Some(title.to_string());
+        }
+        self.headings.push(CachedHeading {
+            title: title.to_string(),
+            level: level.saturating_sub(1),
+            loc,
+        });
+        let tag = level.clamp(1, 6);
+        self.write(&format!("<h{0}>{1}</h{0}>\n", tag, html))
+    }
+
+    /// a block ended, start a new item if this one is large
+    pub fn block_end(&mut self) -> Result<()> {
+        if self.out.as_ref().map(|o| o.1 >= ITEM_SIZE).unwrap_or(false) {
+            self.end_item()?;
+        }
+        Ok(())
+    }
+
+    /// end the current item, the next write starts a new one
+    pub fn end_item(&mut self) -> Result<()> {
+        if let Some((mut w, _)) = self.out.take() {
+            w.write_all(b"</body></html>\n")?;
+            w.flush()?;
+        }
+        Ok(())
+    }
+
+    /// finish the last item, returns the item titles and headings
+    pub fn finish(mut self) -> Result<(Vec<Option<String>>, Vec<CachedHeading>)> {
+        if self.items.is_empty() {
+            // an empty book still has an item to open
+            self.writer()?;
+        }
+        self.end_item()?;
+        Ok((self.items, self.headings))
+    }
+}
+
+/// Reads the items of a converted book
+pub struct ItemReader {
+    dir: PathBuf,
+    file: Option<(u32, File)>,
+}
+
+impl ItemReader {
+    /// create a reader for a cache directory
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            file: None,
+        }
+    }
+
+    /// read item content at `offset`, returns the bytes read, 0 at the end
+    pub fn read(&mut self, item: u32, offset: u32, buf: &mut [u8]) -> Result<usize> {
+        if self.file.as_ref().map(|f| f.0) != Some(item) {
+            self.file = Some((item, File::open(item_path(&self.dir, item))?));
+        }
+        let (_, file) = self.file.as_mut().unwrap();
+        file.seek(SeekFrom::Start(offset as u64))?;
+        Ok(file.read(buf)?)
+    }
+}
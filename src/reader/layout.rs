@@ -0,0 +1,1344 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::imaging::decode::{fit_size, image_size};
+use crate::reader::content::BookContent;
+use crate::reader::location::ContentLoc;
+use crate::reader::markup::{entity, is_block, is_hidden, tag_name};
+use crate::reader::page_map::PageMap;
+use crate::reader::typography::{FontSource, Typography};
+use anyhow::{anyhow, Result};
+use embedded_graphics::{
+    mono_font::{iso_8859_1, MonoFont, MonoTextStyle},
+    pixelcolor::GrayColor,
+    prelude::*,
+    primitives::{Line, PrimitiveStyle, Rectangle},
+    text::{Baseline, Text},
+};
+use log::*;
+use std::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+// items are read in pieces of this size
+const CHUNK_SIZE: usize = 16 * 1024;
+// the rest of a bigger item is cut off, it would not fit in memory
+const MAX_ITEM: usize = 2 * 1024 * 1024;
+
+/// space under the text for the page number
+pub const FOOTER_HEIGHT: u32 = 30;
+
+/// the family that sets the body text in bold
+pub const BOLD_FAMILY: &str = "Bold";
+
+// dots per inch of the 6 inch 1024x758 panel, a point is 1/72 inch
+const PANEL_DPI: u32 = 212;
+
+// the faces of one cell size, there are no outline fonts so other sizes
+// come from scaling these
+struct Faces {
+    regular: &'static MonoFont<'static>,
+    bold: Option<&'static MonoFont<'static>>,
+    italic: Option<&'static MonoFont<'static>>,
+}
+
+const FACES: [Faces; 7] = [
+    Faces {
+        regular: &iso_8859_1::FONT_6X13,
+        bold: Some(&iso_8859_1::FONT_6X13_BOLD),
+        italic: Some(&iso_8859_1::FONT_6X13_ITALIC),
+    },
+    Faces {
+        regular: &iso_8859_1::FONT_7X13,
+        bold: Some(&iso_8859_1::FONT_7X13_BOLD),
+        italic: Some(&iso_8859_1::FONT_7X13_ITALIC),
+    },
+    Faces {
+        regular: &iso_8859_1::FONT_7X14,
+        bold: Some(&iso_8859_1::FONT_7X14_BOLD),
+        italic: None,
+    },
+    Faces {
+        regular: &iso_8859_1::FONT_8X13,
+        bold: Some(&iso_8859_1::FONT_8X13_BOLD),
+        italic: Some(&iso_8859_1::FONT_8X13_ITALIC),
+    },
+    Faces {
+        regular: &iso_8859_1::FONT_9X15,
+        bold: Some(&iso_8859_1::FONT_9X15_BOLD),
+        italic: None,
+    },
+    Faces {
+        regular: &iso_8859_1::FONT_9X18,
+        bold: Some(&iso_8859_1::FONT_9X18_BOLD),
+        italic: None,
+    },
+    Faces {
+        regular: &iso_8859_1::FONT_10X20,
+        bold: None,
+        italic: None,
+    },
+];
+
+/// The fonts the renderer has, for the typography panel
+///
+/// These are the latin-1 bitmap faces of embedded-graphics, scaled up.
+/// Text is mapped to latin-1 where it can be, see `push_plain`, other
+/// characters are drawn as `?`, so books in greek, cyrillic or cjk
+/// scripts don't read.
+#[derive(Debug, Default)]
+pub struct BitmapFonts;
+
+impl FontSource for BitmapFonts {
+    fn families(&self) -> Vec<String> {
+        vec![Typography::default().font_family, BOLD_FAMILY.to_string()]
+    }
+}
+
+// a bitmap face drawn at a whole number scale
+#[derive(Clone, Copy)]
+struct Face {
+    font: &'static MonoFont<'static>,
+    scale: u32,
+}
+
+impl fmt::Debug for Face {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Face")
+            .field("cell", &self.font.character_size)
+            .field("scale", &self.scale)
+            .finish()
+    }
+}
+
+impl Face {
+    fn advance(&self) -> u32 {
+        (self.font.character_size.width + self.font.character_spacing) * self.scale
+    }
+
+    fn width(&self, text: &str) -> u32 {
+        text.chars().count() as u32 * self.advance()
+    }
+
+    fn height(&self) -> u32 {
+        self.font.character_size.height * self.scale
+    }
+}
+
+// how a run of text is set
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Style {
+    bold: bool,
+    italic: bool,
+    // 1 to 6 in a heading
+    heading: u8,
+}
+
+// the faces for a typography
+#[derive(Debug, Clone, Copy)]
+struct Fonts {
+    faces: usize,
+    scale: u32,
+    bold_body: bool,
+}
+
+impl Fonts {
+    fn new(typography: &Typography) -> Self {
+        // a point is about 2.9 pixels on the panel
+        let target = typography.font_size as u32 * PANEL_DPI / 72;
+        let mut best = (0, 1, u32::MAX);
+        for (i, faces) in FACES.iter().enumerate() {
+            for scale in 1..=4 {
+                let off = (faces.regular.character_size.height * scale).abs_diff(target);
+                if off < best.2 {
+                    best = (i, scale, off);
+                }
+            }
+        }
+        Self {
+            faces: best.0,
+            scale: best.1,
+            bold_body: typography.font_family == BOLD_FAMILY,
+        }
+    }
+
+    fn face(&self, style: Style) -> Face {
+        let faces = &FACES[self.faces];
+        let font = if style.italic {
+            faces.italic.unwrap_or(faces.regular)
+        } else if style.bold || style.heading > 0 || self.bold_body {
+            faces.bold.unwrap_or(faces.regular)
+        } else {
+            faces.regular
+        };
+        // the top headings are a size up
+        let scale = match style.heading {
+            1 | 2 => self.scale + 1,
+            _ => self.scale,
+        };
+        Face { font, scale }
+    }
+}
+
+// a link the text is in
+#[derive(Debug, Clone, PartialEq)]
+struct Link {
+    href: String,
+    note: bool,
+}
+
+// what the tokenizer finds in an item
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    // text without spaces in one style, pieces with no space between
+    // are one word
+    Text {
+        text: String,
+        offset: u32,
+        // just past the text in the item
+        end: u32,
+        style: Style,
+        link: Option<Link>,
+    },
+    // an image, from the start of its tag to just past it
+    Image {
+        src: String,
+        offset: u32,
+        end: u32,
+    },
+    Space,
+    LineBreak,
+    BlockEnd,
+}
+
+// state of the tokenizer between characters
+#[derive(Default)]
+struct Tokenizer {
+    bold: u32,
+    italic: u32,
+    heading: u8,
+    pre: u32,
+    hidden: Option<String>,
+    link: Option<Link>,
+    text: String,
+    start: u32,
+    end: u32,
+}
+
+impl Tokenizer {
+    fn style(&self) -> Style {
+        Style {
+            bold: self.bold > 0,
+            italic: self.italic > 0,
+            heading: self.heading,
+        }
+    }
+
+    // send the text so far, false when the sink has seen enough
+    fn flush(&mut self, sink: &mut dyn FnMut(Token) -> bool) -> bool {
+        if self.text.is_empty() {
+            return true;
+        }
+        sink(Token::Text {
+            text: std::mem::take(&mut self.text),
+            offset: self.start,
+            end: self.end,
+            style: self.style(),
+            link: self.link.clone(),
+        })
+    }
+
+    fn char(
+        &mut self,
+        ch: char,
+        offset: u32,
+        len: u32,
+        sink: &mut dyn FnMut(Token) -> bool,
+    ) -> bool {
+        if ch == '\n' && self.pre > 0 {
+            return self.flush(sink) && sink(Token::LineBreak);
+        }
+        if ch.is_whitespace() && ch != '\u{a0}' {
+            return self.flush(sink) && sink(Token::Space);
+        }
+        if self.text.is_empty() {
+            self.start = offset;
+        }
+        self.end = offset + len;
+        push_plain(&mut self.text, ch);
+        true
+    }
+
+    // handle what is between `<` and `>`, the tag is from `offset` to `end`
+    fn tag(
+        &mut self,
+        tag: &str,
+        offset: u32,
+        end: u32,
+        sink: &mut dyn FnMut(Token) -> bool,
+    ) -> bool {
+        let (name, closing) = tag_name(tag);
+        let empty = tag.ends_with('/');
+        if let Some(hidden) = &self.hidden {
+            if closing && *hidden == name {
+                self.hidden = None;
+            }
+            return true;
+        }
+        if !closing && !empty && is_hidden(&name) {
+            self.hidden = Some(name);
+            return true;
+        }
+        match name.as_str() {
+            "b" | "strong" => self.bold = count(self.bold, closing, empty),
+            "i" | "em" | "cite" => self.italic = count(self.italic, closing, empty),
+            "a" if closing => self.link = None,
+            "a" => {
+                self.link = attr(tag, "href").map(|href| Link {
+                    href,
+                    note: ["epub:type", "class", "role"]
+                        .iter()
+                        .filter_map(|a| attr(tag, a))
+                        .any(|v| v.contains("noteref")),
+                })
+            }
+            _ => {}
+        }
+        // svg covers use `image`
+        let src = match name.as_str() {
+            "img" if !closing => attr(tag, "src"),
+            "image" if !closing => attr(tag, "xlink:href").or_else(|| attr(tag, "href")),
+            _ => None,
+        };
+        if let Some(src) = src {
+            return sink(Token::Image { src, offset, end });
+        }
+        if name == "br" {
+            return sink(Token::LineBreak);
+        }
+        if is_block(&name) {
+            if name == "pre" {
+                self.pre = count(self.pre, closing, empty);
+            }
+            if let Some(level) = name.strip_prefix('h').and_then(|l| l.parse::<u8>().ok()) {
+                self.heading = if closing { 0 } else { level };
+            }
+            return sink(Token::BlockEnd);
+        }
+        true
+    }
+}
+
+// nesting count after an opening or closing tag
+fn count(n: u32, closing: bool, empty: bool) -> u32 {
+    match (closing, empty) {
+        (true, _) => n.saturating_sub(1),
+        (false, true) => n,
+        (false, false) => n + 1,
+    }
+}
+
+/// the value of an attribute in what is between `<` and `>`
+pub fn attr(tag: &str, name: &str) -> Option<String> {
+    let mut rest = tag;
+    while let Some(i) = rest.find(name) {
+        let before = rest[..i].chars().last();
+        let after = rest[i + name.len()..].trim_start();
+        rest = &rest[i + name.len()..];
+        if !matches!(before, Some(c) if c.is_whitespace()) {
+            continue;
+        }
+        let Some(value) = after.strip_prefix('=') else {
+            continue;
+        };
+        let value = value.trim_start();
+        let quote = value.chars().next()?;
+        let value = if quote == '"' || quote == '\'' {
+            let value = &value[1..];
+            &value[..value.find(quote)?]
+        } else {
+            value
+                .split(|c: char| c.is_whitespace() || c == '/')
+                .next()?
+        };
+        return Some(value.replace("&amp;", "&"));
+    }
+    None
+}
+
+// add a character as the fonts can draw it, they only have latin-1
+fn push_plain(text: &mut String, ch: char) {
+    match ch {
+        '\u{2018}' | '\u{2019}' | '\u{201a}' | '\u{2032}' => text.push('\''),
+        '\u{201c}' | '\u{201d}' | '\u{201e}' | '\u{2033}' => text.push('"'),
+        '\u{2010}'..='\u{2015}' | '\u{2212}' => text.push('-'),
+        '\u{2026}' => text.push_str("..."),
+        '\u{2022}' => text.push('\u{b7}'),
+        '\u{a0}' | '\u{2009}' | '\u{202f}' => text.push(' '),
+        '\u{ad}' | '\u{200b}'..='\u{200d}' | '\u{feff}' => {}
+        _ => text.push(ch),
+    }
+}
+
+// the character at the start of some bytes and its length, bytes that are
+// not utf8 are latin-1
+fn next_char(bytes: &[u8]) -> (char, usize) {
+    let end = bytes.len().min(4);
+    let valid = match std::str::from_utf8(&bytes[..end]) {
+        Ok(s) => s,
+        Err(e) => std::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap(),
+    };
+    match valid.chars().next() {
+        Some(ch) => (ch, ch.len_utf8()),
+        None => (bytes[0] as char, 1),
+    }
+}
+
+// split an item into tokens, until the sink returns false
+fn tokenize(data: &[u8], markup: bool, sink: &mut dyn FnMut(Token) -> bool) {
+    let mut state = Tokenizer::default();
+    let mut pos = 0;
+    while pos < data.len() {
+        let (mut ch, mut len) = next_char(&data[pos..]);
+        if markup && ch == '<' {
+            if !state.flush(sink) {
+                return;
+            }
+            let rest = &data[pos..];
+            let end = if rest.starts_with(b"<!--") {
+                find(rest, b"-->").map(|e| e + 2)
+            } else {
+                find(rest, b">")
+            };
+            // an unclosed tag ends the item
+            let Some(end) = end else { break };
+            let tag = String::from_utf8_lossy(&rest[1..end]);
+            if !state.tag(&tag, pos as u32, (pos + end + 1) as u32, sink) {
+                return;
+            }
+            pos += end + 1;
+            continue;
+        }
+        if markup && ch == '&' {
+            let rest = &data[pos + 1..];
+            if let Some(semi) = rest.iter().take(12).position(|&b| b == b';') {
+                if let Some(c) = std::str::from_utf8(&rest[..semi]).ok().and_then(entity) {
+                    ch = c;
+                    len = semi + 2;
+                }
+            }
+        }
+        if !markup && ch == '\n' {
+            if !(state.flush(sink) && sink(Token::BlockEnd)) {
+                return;
+            }
+        } else if state.hidden.is_none() && !state.char(ch, pos as u32, len as u32, sink) {
+            return;
+        }
+        pos += len;
+    }
+    if state.flush(sink) {
+        sink(Token::BlockEnd);
+    }
+}
+
+fn find(bytes: &[u8], pattern: &[u8]) -> Option<usize> {
+    bytes.windows(pattern.len()).position(|w| w == pattern)
+}
+
+// a piece of text placed on a line
+#[derive(Debug, Clone)]
+struct Piece {
+    text: String,
+    offset: u32,
+    end: u32,
+    face: Face,
+    link: Option<Link>,
+    // a space comes before it, pieces without are part of the word before
+    space: bool,
+    // the resource path of an image, which fills the piece
+    image: Option<String>,
+    x: i32,
+    // from the top of the line
+    y: i32,
+    width: u32,
+}
+
+// a line of text on a page
+#[derive(Debug, Clone)]
+struct TextLine {
+    y: i32,
+    height: u32,
+    pieces: Vec<Piece>,
+}
+
+/// A laid out page of an item
+#[derive(Debug, Clone)]
+pub struct Page {
+    item: u32,
+    lines: Vec<TextLine>,
+}
+
+impl Page {
+    /// where the page starts, the first word on it
+    pub fn start(&self) -> ContentLoc {
+        let offset = self
+            .lines
+            .iter()
+            .flat_map(|l| l.pieces.first())
+            .map(|p| p.offset)
+            .next()
+            .unwrap_or(0);
+        ContentLoc::new(self.item, offset)
+    }
+
+    /// just past the last word on the page
+    pub fn end(&self) -> ContentLoc {
+        let end = self
+            .lines
+            .iter()
+            .rev()
+            .flat_map(|l| l.pieces.last())
+            .map(|p| p.end)
+            .next()
+            .unwrap_or(0);
+        ContentLoc::new(self.item, end)
+    }
+
+    /// draw the page, `origin` is where the page is on the screen, for the map
+    pub fn draw<D>(&self, display: &mut D, origin: Point, map: &mut PageMap) -> Result<(), D::Error>
+    where
+        D: DrawTarget,
+        D::Color: GrayColor,
+    {
+        for line in &self.lines {
+            for piece in line.pieces.iter().filter(|p| p.image.is_none()) {
+                let top_left = Point::new(piece.x, line.y + piece.y);
+                let size = Size::new(piece.width, piece.face.height());
+                draw_text(display, &piece.text, top_left, piece.face)?;
+                let rect = Rectangle::new(top_left + origin, size);
+                if let Some(link) = &piece.link {
+                    let y = top_left.y + size.height as i32 - 1;
+                    Line::new(
+                        Point::new(top_left.x, y),
+                        Point::new(top_left.x + size.width as i32 - 1, y),
+                    )
+                    .into_styled(PrimitiveStyle::with_stroke(D::Color::BLACK, 1))
+                    .draw(display)?;
+                    map.add_link(rect, &link.href, link.note, self.item);
+                }
+                map.add_word(rect, ContentLoc::new(self.item, piece.offset), &piece.text);
+            }
+        }
+        Ok(())
+    }
+
+    /// the images on the page, their resource paths and where they go
+    ///
+    /// They are left to the caller to draw, the page doesn't hold the book.
+    pub fn images(&self) -> Vec<(&str, Rectangle)> {
+        self.lines
+            .iter()
+            .flat_map(|l| l.pieces.iter().map(move |p| (l, p)))
+            .filter_map(|(line, piece)| {
+                let top_left = Point::new(piece.x, line.y + piece.y);
+                let size = Size::new(piece.width, line.height);
+                Some((piece.image.as_deref()?, Rectangle::new(top_left, size)))
+            })
+            .collect()
+    }
+}
+
+// draw text at a scale, a scaled glyph pixel is a square
+fn draw_text<D>(display: &mut D, text: &str, top_left: Point, face: Face) -> Result<(), D::Error>
+where
+    D: DrawTarget,
+    D::Color: GrayColor,
+{
+    let style = MonoTextStyle::new(face.font, D::Color::BLACK);
+    if face.scale == 1 {
+        Text::with_baseline(text, top_left, style, Baseline::Top).draw(display)?;
+    } else {
+        let mut scaled = Scaled {
+            target: display,
+            origin: top_left,
+            scale: face.scale,
+        };
+        Text::with_baseline(text, Point::zero(), style, Baseline::Top).draw(&mut scaled)?;
+    }
+    Ok(())
+}
+
+// a draw target that scales up what is drawn on it
+struct Scaled<'a, D> {
+    target: &'a mut D,
+    origin: Point,
+    scale: u32,
+}
+
+impl<D: DrawTarget> Dimensions for Scaled<'_, D> {
+    fn bounding_box(&self) -> Rectangle {
+        let area = self.target.bounding_box();
+        let top_left = (area.top_left - self.origin) / self.scale as i32;
+        Rectangle::new(top_left, area.size / self.scale + Size::new(1, 1))
+    }
+}
+
+impl<D: DrawTarget> DrawTarget for Scaled<'_, D> {
+    type Color = D::Color;
+    type Error = D::Error;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(p, color) in pixels {
+            let top_left = self.origin + p * self.scale as i32;
+            self.target.fill_solid(
+                &Rectangle::new(top_left, Size::new_equal(self.scale)),
+                color,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// the resource path and size of an image source
+type ImageSize<'a> = dyn FnMut(&str) -> Option<(String, u32, u32)> + 'a;
+
+// breaks tokens into lines and lines into pages
+struct Flow<'a> {
+    layout: &'a PageLayout,
+    item: u32,
+    // the word being collected, its pieces are glued
+    word: Vec<Piece>,
+    line: Vec<Piece>,
+    line_width: u32,
+    // the next line starts a paragraph
+    paragraph: bool,
+    page: Page,
+    y: i32,
+    images: &'a mut ImageSize<'a>,
+    emit: &'a mut dyn FnMut(Page) -> bool,
+}
+
+impl<'a> Flow<'a> {
+    fn new(
+        layout: &'a PageLayout,
+        item: u32,
+        images: &'a mut ImageSize<'a>,
+        emit: &'a mut dyn FnMut(Page) -> bool,
+    ) -> Self {
+        Self {
+            layout,
+            item,
+            word: Vec::new(),
+            line: Vec::new(),
+            line_width: 0,
+            paragraph: true,
+            page: Page {
+                item,
+                lines: Vec::new(),
+            },
+            y: layout.top(),
+            images,
+            emit,
+        }
+    }
+
+    fn token(&mut self, token: Token) -> bool {
+        match token {
+            Token::Text {
+                text,
+                offset,
+                end,
+                style,
+                link,
+            } => {
+                let face = self.layout.fonts.face(style);
+                self.word.push(Piece {
+                    width: face.width(&text),
+                    text,
+                    offset,
+                    end,
+                    face,
+                    link,
+                    space: false,
+                    image: None,
+                    x: 0,
+                    y: 0,
+                });
+                true
+            }
+            Token::Image { src, offset, end } => self.image(&src, offset, end),
+            Token::Space => self.end_word(),
+            Token::LineBreak => {
+                if !self.end_word() {
+                    return false;
+                }
+                if self.line.is_empty() {
+                    // an empty line, as for `<br/><br/>`
+                    let height = self
+                        .layout
+                        .line_height(self.layout.fonts.face(Style::default()));
+                    return self.place(TextLine {
+                        y: 0,
+                        height,
+                        pieces: Vec::new(),
+                    });
+                }
+                self.end_line(false)
+            }
+            Token::BlockEnd => {
+                let more = self.end_word() && self.end_line(false);
+                self.paragraph = true;
+                more
+            }
+        }
+    }
+
+    // add the word to the line, on a new line if it doesn't fit
+    fn end_word(&mut self) -> bool {
+        if self.word.is_empty() {
+            return true;
+        }
+        let width = self.layout.text_width();
+        let space = self.space();
+        let mut word = std::mem::take(&mut self.word);
+        let word_width: u32 = word.iter().map(|p| p.width).sum();
+        let full = !self.line.is_empty() && self.line_width + space + word_width > width;
+        if full && !self.end_line(self.layout.typography.justify) {
+            return false;
+        }
+        if !self.line.is_empty() {
+            word[0].space = true;
+            self.line_width += space;
+        }
+        for piece in word {
+            // a word longer than a line is broken between characters
+            let mut piece = piece;
+            while self.line_width + piece.width > width {
+                let fit = ((width - self.line_width) / piece.face.advance()) as usize;
+                if fit == 0 {
+                    if self.line.is_empty() {
+                        // not even a character fits, draw it cut off
+                        break;
+                    }
+                    if !self.end_line(false) {
+                        return false;
+                    }
+                    piece.space = false;
+                    continue;
+                }
+                let split = piece
+                    .text
+                    .char_indices()
+                    .nth(fit)
+                    .map_or(piece.text.len(), |(i, _)| i);
+                let rest = Piece {
+                    text: piece.text[split..].to_string(),
+                    offset: (piece.offset + split as u32).min(piece.end),
+                    width: piece.face.width(&piece.text[split..]),
+                    space: false,
+                    ..piece.clone()
+                };
+                piece.text.truncate(split);
+                piece.end = rest.offset;
+                piece.width = piece.face.width(&piece.text);
+                self.line_width += piece.width;
+                self.line.push(piece);
+                if !self.end_line(false) {
+                    return false;
+                }
+                piece = rest;
+            }
+            self.line_width += piece.width;
+            self.line.push(piece);
+        }
+        true
+    }
+
+    // an image on a line of its own, shrunk to fit a page
+    fn image(&mut self, src: &str, offset: u32, end: u32) -> bool {
+        if !(self.end_word() && self.end_line(false)) {
+            return false;
+        }
+        let Some((path, width, height)) = (self.images)(src) else {
+            return true;
+        };
+        let max_height = (self.layout.bottom() - self.layout.top()).max(1) as u32;
+        let text_width = self.layout.text_width();
+        let (width, height) = fit_size(width, height, text_width, max_height);
+        let piece = Piece {
+            text: String::new(),
+            offset,
+            end,
+            face: self.layout.fonts.face(Style::default()),
+            link: None,
+            space: false,
+            image: Some(path),
+            x: self.layout.typography.margin as i32 + ((text_width - width) / 2) as i32,
+            y: 0,
+            width,
+        };
+        self.paragraph = true;
+        let more = self.place(TextLine {
+            y: 0,
+            height,
+            pieces: vec![piece],
+        });
+        self.paragraph = true;
+        more
+    }
+
+    fn space(&self) -> u32 {
+        self.layout.fonts.face(Style::default()).advance()
+    }
+
+    // finish the line and place it on the page
+    fn end_line(&mut self, justify: bool) -> bool {
+        if self.line.is_empty() {
+            return true;
+        }
+        let mut pieces = std::mem::take(&mut self.line);
+        let natural = std::mem::take(&mut self.line_width);
+        let gaps = pieces.iter().filter(|p| p.space).count() as u32;
+        let extra = if justify && gaps > 0 {
+            self.layout.text_width().saturating_sub(natural)
+        } else {
+            0
+        };
+        let space = self.space();
+        let cell = pieces.iter().map(|p| p.face.height()).max().unwrap_or(0);
+        let mut x = self.layout.typography.margin as i32;
+        let mut gap = 0;
+        for piece in pieces.iter_mut() {
+            if piece.space {
+                // spread the extra over the gaps, the first ones get the remainder
+                let share = extra / gaps + u32::from(gap < extra % gaps);
+                x += (space + share) as i32;
+                gap += 1;
+            }
+            piece.x = x;
+            // pieces of a smaller face sit on the bottom of the line
+            piece.y = (cell - piece.face.height()) as i32;
+            x += piece.width as i32;
+        }
+        let height = pieces
+            .iter()
+            .map(|p| self.layout.line_height(p.face))
+            .max()
+            .unwrap_or(0)
+            .max(cell);
+        self.place(TextLine {
+            y: 0,
+            height,
+            pieces,
+        })
+    }
+
+    // put a line on the page, on the next page if it doesn't fit
+    fn place(&mut self, mut line: TextLine) -> bool {
+        let mut spacing = if self.paragraph && !self.page.lines.is_empty() {
+            self.layout.typography.paragraph_spacing as i32
+        } else {
+            0
+        };
+        self.paragraph = false;
+        if !self.page.lines.is_empty()
+            && self.y + spacing + line.height as i32 > self.layout.bottom()
+        {
+            if !self.end_page() {
+                return false;
+            }
+            spacing = 0;
+        }
+        line.y = self.y + spacing;
+        self.y = line.y + line.height as i32;
+        self.page.lines.push(line);
+        true
+    }
+
+    fn end_page(&mut self) -> bool {
+        self.y = self.layout.top();
+        let page = std::mem::replace(
+            &mut self.page,
+            Page {
+                item: self.item,
+                lines: Vec::new(),
+            },
+        );
+        // blank lines alone don't make a page
+        if page.lines.iter().all(|l| l.pieces.is_empty()) {
+            return true;
+        }
+        (self.emit)(page)
+    }
+
+    fn finish(&mut self) {
+        if self.end_word() && self.end_line(false) {
+            self.end_page();
+        }
+    }
+}
+
+/// Lays out the items of a book in pages with the bitmap fonts
+///
+/// Every item starts on a new page. Pages are not kept, a page is laid
+/// out again from the start of its item when it is shown.
+#[derive(Debug, Clone)]
+pub struct PageLayout {
+    typography: Typography,
+    size: Size,
+    fonts: Fonts,
+}
+
+impl PageLayout {
+    /// create a layout for pages of a size
+    pub fn new(typography: &Typography, size: Size) -> Self {
+        Self {
+            typography: typography.clone(),
+            size,
+            fonts: Fonts::new(typography),
+        }
+    }
+
+    /// the typography the pages are laid out with
+    pub fn typography(&self) -> &Typography {
+        &self.typography
+    }
+
+    /// the size of a page
+    pub fn size(&self) -> Size {
+        self.size
+    }
+
+    fn top(&self) -> i32 {
+        self.typography.margin as i32
+    }
+
+    // the bottom of the text, above the footer
+    fn bottom(&self) -> i32 {
+        self.size.height as i32 - self.typography.margin as i32 - FOOTER_HEIGHT as i32
+    }
+
+    fn text_width(&self) -> u32 {
+        self.size
+            .width
+            .saturating_sub(2 * self.typography.margin as u32)
+            .max(1)
+    }
+
+    fn line_height(&self, face: Face) -> u32 {
+        face.height() * self.typography.line_height as u32 / 100
+    }
+
+    /// lay out an item, `page` gets each page until it returns false
+    pub fn layout_item<C, F>(&self, content: &mut C, item: u32, mut page: F) -> Result<()>
+    where
+        C: BookContent + ?Sized,
+        F: FnMut(Page) -> bool,
+    {
+        let data = read_item(content, item)?;
+        let markup = content.is_markup(item);
+        // images are sized from their headers, they are drawn by the caller
+        let mut images = |src: &str| {
+            let path = content.resource_path(src, item)?;
+            let bytes = match content.read_resource(&path) {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    debug!("no image {}: {}", path, e);
+                    return None;
+                }
+            };
+            let (width, height) = image_size(&bytes)?;
+            Some((path, width, height))
+        };
+        let mut flow = Flow::new(self, item, &mut images, &mut page);
+        let mut more = true;
+        tokenize(&data, markup, &mut |token| {
+            more = flow.token(token);
+            more
+        });
+        if more {
+            flow.finish();
+        }
+        Ok(())
+    }
+
+    /// the start of every page of the book, for the repaginator
+    pub fn paginate<C>(&self, content: &mut C, cancel: &AtomicBool) -> Result<Vec<ContentLoc>>
+    where
+        C: BookContent + ?Sized,
+    {
+        let mut starts = Vec::new();
+        for item in 0..content.item_count() {
+            if cancel.load(Ordering::Relaxed) {
+                return Err(anyhow!("pagination cancelled"));
+            }
+            self.layout_item(content, item, |page| {
+                starts.push(page.start());
+                !cancel.load(Ordering::Relaxed)
+            })?;
+        }
+        Ok(starts)
+    }
+
+    /// the page holding a location, the first with text after it, or the
+    /// last page of the item, none if the item has no text
+    pub fn page_at<C>(&self, content: &mut C, loc: ContentLoc) -> Result<Option<Page>>
+    where
+        C: BookContent + ?Sized,
+    {
+        let mut found = None;
+        self.layout_item(content, loc.item, |page| {
+            let done = page.end() > loc;
+            found = Some(page);
+            !done
+        })?;
+        Ok(found)
+    }
+}
+
+// read a whole item
+fn read_item<C: BookContent + ?Sized>(content: &mut C, item: u32) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    loop {
+        let n = content.read_item(item, data.len() as u32, &mut buf)?;
+        if n == 0 {
+            break;
+        }
+        data.extend_from_slice(&buf[..n]);
+        if data.len() > MAX_ITEM {
+            warn!("item {} is too big, the end is cut off", item);
+            break;
+        }
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::bmp;
+    use crate::ui::canvas::Canvas;
+    use embedded_graphics::pixelcolor::Gray8;
+
+    struct Items(Vec<&'static str>);
+
+    impl BookContent for Items {
+        fn item_count(&self) -> u32 {
+            self.0.len() as u32
+        }
+
+        fn item_title(&self, _item: u32) -> Option<String> {
+            None
+        }
+
+        fn read_item(&mut self, item: u32, offset: u32, buf: &mut [u8]) -> Result<usize> {
+            let rest = &self.0[item as usize].as_bytes()[offset as usize..];
+            let n = rest.len().min(buf.len());
+            buf[..n].copy_from_slice(&rest[..n]);
+            Ok(n)
+        }
+
+        // a wide picture is the only resource
+        fn read_resource(&mut self, href: &str) -> Result<Vec<u8>> {
+            match href {
+                "pic.bmp" => Ok(bmp(120, 20, &[[0; 3]; 2400])),
+                _ => Err(anyhow!("no {}", href)),
+            }
+        }
+    }
+
+    fn tokens(markup: &str) -> Vec<Token> {
+        let mut tokens = Vec::new();
+        tokenize(markup.as_bytes(), true, &mut |t| {
+            tokens.push(t);
+            true
+        });
+        tokens
+    }
+
+    fn words(tokens: &[Token]) -> Vec<(String, u32)> {
+        tokens
+            .iter()
+            .filter_map(|t| match t {
+                Token::Text { text, offset, .. } => Some((text.clone(), *offset)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // small pages of the 6x13 face, 10 characters a line, 3 lines a page
+    fn small() -> PageLayout {
+        let typography = Typography {
+            font_size: 4,
+            line_height: 100,
+            margin: 0,
+            paragraph_spacing: 0,
+            justify: false,
+            ..Typography::default()
+        };
+        PageLayout::new(&typography, Size::new(60, 39 + FOOTER_HEIGHT))
+    }
+
+    fn lines(page: &Page) -> Vec<String> {
+        page.lines
+            .iter()
+            .map(|l| {
+                let mut s = String::new();
+                for p in &l.pieces {
+                    if p.space {
+                        s.push(' ');
+                    }
+                    s.push_str(&p.text);
+                }
+                s
+            })
+            .collect()
+    }
+
+    #[test]
+    fn font_sizes() {
+        let fonts = Fonts::new(&Typography::default());
+        // 12 points are 35 pixels, the 9x18 face doubled
+        assert_eq!(fonts.face(Style::default()).height(), 36);
+        assert_eq!(fonts.face(Style::default()).advance(), 18);
+        let heading = fonts.face(Style {
+            heading: 1,
+            ..Style::default()
+        });
+        assert_eq!(heading.height(), 54);
+        let big = Fonts::new(&Typography {
+            font_size: 32,
+            ..Typography::default()
+        });
+        assert_eq!(big.face(Style::default()).height(), 80);
+        assert_eq!(
+            BitmapFonts.families(),
+            vec!["Default".to_string(), "Bold".to_string()]
+        );
+    }
+
+    #[test]
+    fn tokens_of_markup() {
+        let markup = "<html><head><title>T</title></head>\
+                      <body><p>It&#8217;s wo<em>rd</em> &amp; more</p>\
+                      <!-- a <p> comment --><p>a<br/>b</p></body></html>";
+        let t = tokens(markup);
+        assert_eq!(
+            words(&t),
+            vec![
+                ("It's".to_string(), 44),
+                ("wo".to_string(), 55),
+                ("rd".to_string(), 61),
+                ("&".to_string(), 69),
+                ("more".to_string(), 75),
+                ("a".to_string(), 108),
+                ("b".to_string(), 114),
+            ]
+        );
+        // the glued piece keeps its style
+        assert!(matches!(&t[5], Token::Text { style, .. } if style.italic));
+        assert!(t.contains(&Token::LineBreak));
+        assert_eq!(t.last(), Some(&Token::BlockEnd));
+    }
+
+    #[test]
+    fn note_links() {
+        let t = tokens(
+            r#"<p>see<a epub:type="noteref" href="n.xhtml#n1">1</a> and <a href='c2.xhtml'>two</a></p>"#,
+        );
+        let links: Vec<Option<Link>> = t
+            .iter()
+            .filter_map(|t| match t {
+                Token::Text { link, .. } => Some(link.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(links[0], None);
+        assert_eq!(
+            links[1],
+            Some(Link {
+                href: "n.xhtml#n1".to_string(),
+                note: true
+            })
+        );
+        assert_eq!(
+            links[3],
+            Some(Link {
+                href: "c2.xhtml".to_string(),
+                note: false
+            })
+        );
+        assert_eq!(
+            attr(r#"img alt="x" src="a&amp;b.png"/"#, "src").as_deref(),
+            Some("a&b.png")
+        );
+        assert_eq!(attr(r#"img data-src="x""#, "src"), None);
+    }
+
+    #[test]
+    fn lines_and_pages() {
+        let layout = small();
+        let mut book = Items(vec![
+            "<p>one two three four five six seven</p><p>eight nine</p>",
+            "<p>abcdefghijklmnopqrstuvwxyz</p>",
+            "<p> </p>",
+        ]);
+        let mut pages = Vec::new();
+        layout
+            .layout_item(&mut book, 0, |p| {
+                pages.push(lines(&p));
+                true
+            })
+            .unwrap();
+        assert_eq!(
+            pages,
+            vec![
+                vec!["one two", "three four", "five six"],
+                vec!["seven", "eight nine"],
+            ]
+        );
+        let mut pages = Vec::new();
+        layout
+            .layout_item(&mut book, 1, |p| {
+                pages.push((p.start(), lines(&p)));
+                true
+            })
+            .unwrap();
+        // a word longer than a line is broken
+        assert_eq!(pages[0].1, vec!["abcdefghij", "klmnopqrst", "uvwxyz"]);
+        assert_eq!(pages[0].0, ContentLoc::new(1, 3));
+
+        let starts = layout.paginate(&mut book, &AtomicBool::new(false)).unwrap();
+        assert_eq!(
+            starts,
+            vec![
+                ContentLoc::new(0, 3),
+                ContentLoc::new(0, 31),
+                ContentLoc::new(1, 3)
+            ]
+        );
+        let page = layout
+            .page_at(&mut book, ContentLoc::new(0, 50))
+            .unwrap()
+            .unwrap();
+        assert_eq!(page.start(), ContentLoc::new(0, 31));
+        assert!(layout
+            .page_at(&mut book, ContentLoc::new(2, 0))
+            .unwrap()
+            .is_none());
+        assert!(layout.paginate(&mut book, &AtomicBool::new(true)).is_err());
+    }
+
+    #[test]
+    fn images_sit_on_lines_of_their_own() {
+        let layout = small();
+        let markup = r#"<p>one</p><img src="pic.bmp"/><img src="gone.png"/><p>two</p>"#;
+        let mut book = Items(vec![markup]);
+        let mut pages = Vec::new();
+        layout
+            .layout_item(&mut book, 0, |p| {
+                pages.push(p);
+                true
+            })
+            .unwrap();
+        // shrunk to the width, a missing one is left out
+        assert_eq!(pages.len(), 1);
+        assert_eq!(lines(&pages[0]), vec!["one", "", "two"]);
+        assert_eq!(
+            pages[0].images(),
+            vec![(
+                "pic.bmp",
+                Rectangle::new(Point::new(0, 13), Size::new(60, 10))
+            )]
+        );
+        // the image has a place in the item
+        let img = markup.find("<img").unwrap() as u32;
+        assert_eq!(pages[0].lines[1].pieces[0].offset, img);
+        let mut canvas = Canvas::new(60, 69);
+        pages[0]
+            .draw(&mut canvas, Point::zero(), &mut PageMap::new())
+            .unwrap();
+        assert_eq!(canvas.pixel(Point::new(30, 18)), Gray8::WHITE);
+    }
+
+    #[test]
+    fn justified_lines_fill_the_width() {
+        let layout = PageLayout::new(
+            &Typography {
+                justify: true,
+                ..small().typography
+            },
+            Size::new(60, 100),
+        );
+        let mut book = Items(vec!["<p>aa bb cc dd ee</p>"]);
+        let page = layout
+            .page_at(&mut book, ContentLoc::new(0, 0))
+            .unwrap()
+            .unwrap();
+        let first = &page.lines[0].pieces;
+        // aa bb cc is 48 of 60 pixels, the two gaps share the rest
+        let xs: Vec<i32> = first.iter().map(|p| p.x).collect();
+        assert_eq!(xs, vec![0, 24, 48]);
+        // the last line of a paragraph is not justified
+        let last: Vec<i32> = page.lines[1].pieces.iter().map(|p| p.x).collect();
+        assert_eq!(last, vec![0, 18]);
+    }
+
+    #[test]
+    fn drawing_fills_the_map() {
+        let layout = small();
+        let mut book = Items(vec![r#"<p>go <a href="c.xhtml">there</a></p>"#]);
+        let page = layout
+            .page_at(&mut book, ContentLoc::new(0, 0))
+            .unwrap()
+            .unwrap();
+        let mut canvas = Canvas::new(60, 69);
+        let mut map = PageMap::new();
+        page.draw(&mut canvas, Point::new(100, 0), &mut map)
+            .unwrap();
+        let words: Vec<(&str, Rectangle)> = map
+            .words()
+            .iter()
+            .map(|w| (w.text.as_str(), w.rect))
+            .collect();
+        assert_eq!(
+            words,
+            vec![
+                ("go", Rectangle::new(Point::new(100, 0), Size::new(12, 13))),
+                (
+                    "there",
+                    Rectangle::new(Point::new(118, 0), Size::new(30, 13))
+                ),
+            ]
+        );
+        assert_eq!(
+            map.link_at(120, 5).map(|l| l.href.as_str()),
+            Some("c.xhtml")
+        );
+        // something was drawn, and the link is underlined
+        assert!(canvas
+            .pixels(&canvas.bounding_box())
+            .any(|c| c == Gray8::BLACK));
+        assert_eq!(canvas.pixel(Point::new(20, 12)), Gray8::BLACK);
+    }
+
+    #[test]
+    fn scaled_text_is_drawn_bigger() {
+        let mut one = Canvas::new(20, 20);
+        let mut two = Canvas::new(20, 20);
+        let font = &iso_8859_1::FONT_6X13;
+        draw_text(&mut one, "I", Point::zero(), Face { font, scale: 1 }).unwrap();
+        draw_text(&mut two, "I", Point::new(1, 1), Face { font, scale: 2 }).unwrap();
+        for p in one.bounding_box().points().filter(|p| p.x < 9 && p.y < 9) {
+            let c = one.pixel(p);
+            assert_eq!(two.pixel(Point::new(1, 1) + p * 2), c);
+            assert_eq!(two.pixel(Point::new(2, 2) + p * 2), c);
+        }
+    }
+}
@@ -0,0 +1,163 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use anyhow::Result;
+use log::*;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// directory on the sdcard holding the reader databases
+pub const EREADER_DIR: &str = "/sdcard/ereader";
+
+/// A location in the book content
+///
+/// The index of the item in the book spine and a byte offset into that
+/// item. It does not depend on the pagination, so it survives font and
+/// size changes. A book's is saved in `location.json` in its data
+/// directory, see `save_location`.
+#[derive(
+    Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub struct ContentLoc {
+    pub item: u32,
+    pub offset: u32,
+}
+
+impl ContentLoc {
+    /// create a new location
+    pub fn new(item: u32, offset: u32) -> Self {
+        Self { item, offset }
+    }
+}
+
+/// The content range shown on a page, `start` inclusive, `end` exclusive
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PageRange {
+    pub start: ContentLoc,
+    pub end: ContentLoc,
+}
+
+impl PageRange {
+    /// create a new page range
+    pub fn new(start: ContentLoc, end: ContentLoc) -> Self {
+        Self { start, end }
+    }
+
+    /// is the location shown on this page
+    pub fn contains(&self, loc: &ContentLoc) -> bool {
+        self.start <= *loc && *loc < self.end
+    }
+}
+
+/// key for a book, stable across runs, used to name the per book data directory
+pub fn book_key(book_path: &Path) -> String {
+    // FNV-1a, std hashers aren't guaranteed stable between releases
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in book_path.to_string_lossy().bytes() {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    format!("{:016x}", hash)
+}
+
+/// the directory holding the data for one book, `<ereader>/books/<key>`
+pub fn book_data_dir(ereader_dir: &Path, book_path: &Path) -> PathBuf {
+    ereader_dir.join("books").join(book_key(book_path))
+}
+
+/// the reading location saved for a book, the start if there is none
+pub fn load_location(ereader_dir: &Path, book_path: &Path) -> ContentLoc {
+    load_json(&book_data_dir(ereader_dir, book_path).join("location.json"))
+}
+
+/// save the reading location of a book
+pub fn save_location(ereader_dir: &Path, book_path: &Path, loc: &ContentLoc) -> Result<()> {
+    save_json(
+        &book_data_dir(ereader_dir, book_path).join("location.json"),
+        loc,
+    )
+}
+
+/// read a json file, the default if there is none
+///
+/// a file that doesn't parse is logged and kept as `<name>.bad`, so
+/// saving the default doesn't lose it
+pub fn load_json<T: DeserializeOwned + Default>(path: &Path) -> T {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        // a save cut off between removing the old file and the rename
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            match fs::read(sibling(path, "tmp")) {
+                Ok(data) => data,
+                Err(_) => return T::default(),
+            }
+        }
+        Err(e) => {
+            warn!("unable to read {:?}: {}", path, e);
+            return T::default();
+        }
+    };
+    serde_json::from_slice(&data).unwrap_or_else(|e| {
+        warn!("bad json in {:?}: {}", path, e);
+        let bad = sibling(path, "bad");
+        if let Err(e) = fs::rename(path, &bad) {
+            warn!("unable to keep {:?} as {:?}: {}", path, bad, e);
+        }
+        T::default()
+    })
+}
+
+/// write a json file, making its directory if needed
+///
+/// it is written to `<name>.tmp` first and renamed, so a reset part way
+/// through leaves a whole file, old or new
+pub fn save_json<T: Serialize + ?Sized>(path: &Path, value: &T) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = sibling(path, "tmp");
+    fs::write(&tmp, serde_json::to_vec(value)?)?;
+    // fat won't rename over a file, the old one goes first
+    if fs::rename(&tmp, path).is_err() {
+        fs::remove_file(path)?;
+        fs::rename(&tmp, path)?;
+    }
+    Ok(())
+}
+
+// `<name>.<ext>` next to a file
+fn sibling(path: &Path, ext: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(ext);
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    #[test]
+    fn json_files_survive_bad_data() {
+        let dir = temp_dir("location");
+        let path = dir.join("books").join("x").join("location.json");
+        assert_eq!(load_json::<ContentLoc>(&path), ContentLoc::default());
+        save_json(&path, &ContentLoc::new(3, 40)).unwrap();
+        save_json(&path, &ContentLoc::new(4, 50)).unwrap();
+        assert_eq!(load_json::<ContentLoc>(&path), ContentLoc::new(4, 50));
+        assert!(!sibling(&path, "tmp").exists());
+        // a save cut off after the old file went
+        fs::rename(&path, sibling(&path, "tmp")).unwrap();
+        assert_eq!(load_json::<ContentLoc>(&path), ContentLoc::new(4, 50));
+        // a bad file gives the default and is kept aside
+        fs::write(&path, b"{\"item\": 1,").unwrap();
+        assert_eq!(load_json::<ContentLoc>(&path), ContentLoc::default());
+        assert!(!path.exists());
+        assert_eq!(fs::read(sibling(&path, "bad")).unwrap(), b"{\"item\": 1,");
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,106 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+// what the xhtml tags and entities of a book mean for its text
+
+/// does the element start a new block, so it separates words
+pub fn is_block(name: &str) -> bool {
+    matches!(
+        name,
+        "address"
+            | "article"
+            | "aside"
+            | "blockquote"
+            | "body"
+            | "br"
+            | "dd"
+            | "div"
+            | "dl"
+            | "dt"
+            | "figcaption"
+            | "figure"
+            | "footer"
+            | "h1"
+            | "h2"
+            | "h3"
+            | "h4"
+            | "h5"
+            | "h6"
+            | "header"
+            | "hr"
+            | "li"
+            | "nav"
+            | "ol"
+            | "p"
+            | "pre"
+            | "section"
+            | "table"
+            | "td"
+            | "th"
+            | "tr"
+            | "ul"
+    )
+}
+
+/// is the element's text not part of the book's text
+pub fn is_hidden(name: &str) -> bool {
+    matches!(name, "head" | "title" | "style" | "script")
+}
+
+/// the name of a tag from what is between `<` and `>`, lowercased, and
+/// whether it closes an element
+pub fn tag_name(tag: &str) -> (String, bool) {
+    let closing = tag.starts_with('/');
+    let name = tag
+        .trim_start_matches('/')
+        .split(|c: char| c.is_whitespace() || c == '/')
+        .next()
+        .unwrap_or("");
+    // drop a namespace prefix, `xhtml:p` is a `p`
+    let name = name.rsplit(':').next().unwrap_or(name);
+    (name.to_ascii_lowercase(), closing)
+}
+
+/// the character for an entity name, without `&` and `;`
+pub fn entity(name: &str) -> Option<char> {
+    match name {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some(' '),
+        _ => {
+            let code =
+                if let Some(hex) = name.strip_prefix("#x").or_else(|| name.strip_prefix("#X")) {
+                    u32::from_str_radix(hex, 16).ok()
+                } else {
+                    name.strip_prefix('#')?.parse().ok()
+                };
+            code.and_then(char::from_u32)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tag_names() {
+        assert_eq!(tag_name("p class=\"x\""), ("p".to_string(), false));
+        assert_eq!(tag_name("/H2"), ("h2".to_string(), true));
+        assert_eq!(tag_name("br/"), ("br".to_string(), false));
+        assert_eq!(tag_name("xhtml:div"), ("div".to_string(), false));
+        assert!(is_block("blockquote") && !is_block("em") && !is_block("span"));
+    }
+
+    #[test]
+    fn entities() {
+        assert_eq!(entity("amp"), Some('&'));
+        assert_eq!(entity("#233"), Some('é'));
+        assert_eq!(entity("#xE9"), Some('é'));
+        assert_eq!(entity("bogus"), None);
+        assert_eq!(entity("#xD800"), None);
+    }
+}
@@ -0,0 +1,36 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::ui::menu::Menu;
+
+/// The screens reachable from the reader menu
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ReaderMenuItem {
+    Contents,
+    Bookmarks,
+    Search,
+    Typography,
+    Rotate,
+    Stats,
+    Back,
+    Library,
+}
+
+/// the menu shown over the page when the top of the page is tapped
+///
+/// Back is shown after following a link, Books closes the book.
+pub fn reader_menu(width: u32, can_go_back: bool) -> Menu<ReaderMenuItem> {
+    let mut items = vec![
+        ("Contents", ReaderMenuItem::Contents),
+        ("Bookmarks", ReaderMenuItem::Bookmarks),
+        ("Search", ReaderMenuItem::Search),
+        ("Aa", ReaderMenuItem::Typography),
+        ("Rotate", ReaderMenuItem::Rotate),
+        ("Stats", ReaderMenuItem::Stats),
+        ("Books", ReaderMenuItem::Library),
+    ];
+    if can_go_back {
+        items.insert(0, ("Back", ReaderMenuItem::Back));
+    }
+    Menu::new(&items, width)
+}
@@ -0,0 +1,151 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::reader::location::ContentLoc;
+use embedded_graphics::{prelude::*, primitives::Rectangle};
+
+/// A word drawn on the page, and where it came from in the content
+#[derive(Debug, Clone)]
+pub struct WordBox {
+    pub rect: Rectangle,
+    pub loc: ContentLoc,
+    pub text: String,
+}
+
+/// A link drawn on the page
+#[derive(Debug, Clone)]
+pub struct LinkBox {
+    pub rect: Rectangle,
+    pub href: String,
+    /// marked as a footnote reference, `epub:type="noteref"` or an fb2 note
+    pub note: bool,
+    /// the item holding the link, relative hrefs start from it
+    pub item: u32,
+}
+
+// links are often small superscripts, taps this close still hit them
+const LINK_SLOP: u32 = 12;
+
+/// Map of what was drawn where on the current page
+///
+/// Filled in while the page is drawn, used to turn touch positions
+/// back into content locations
+#[derive(Debug, Default)]
+pub struct PageMap {
+    words: Vec<WordBox>,
+    links: Vec<LinkBox>,
+}
+
+impl PageMap {
+    /// create an empty map
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// forget the previous page
+    pub fn clear(&mut self) {
+        self.words.clear();
+        self.links.clear();
+    }
+
+    /// add a word, words are added in content order
+    pub fn add_word(&mut self, rect: Rectangle, loc: ContentLoc, text: &str) {
+        self.words.push(WordBox {
+            rect,
+            loc,
+            text: text.to_string(),
+        });
+    }
+
+    /// add a link, a link broken over lines is added once per line
+    pub fn add_link(&mut self, rect: Rectangle, href: &str, note: bool, item: u32) {
+        self.links.push(LinkBox {
+            rect,
+            href: href.to_string(),
+            note,
+            item,
+        });
+    }
+
+    /// the links on the page
+    pub fn links(&self) -> &[LinkBox] {
+        &self.links
+    }
+
+    /// the link at a user coordinate, the nearest one within a few pixels
+    ///
+    /// Touch events are already in user coordinates, mapped by the
+    /// `CoordTransform` of the touch thread, so they match the drawn page.
+    pub fn link_at(&self, x: u32, y: u32) -> Option<&LinkBox> {
+        let p = Point::new(x as i32, y as i32);
+        self.links
+            .iter()
+            .filter(|l| {
+                let slop = Size::new(LINK_SLOP * 2, LINK_SLOP * 2);
+                let area = Rectangle::new(
+                    l.rect.top_left - Point::new(LINK_SLOP as i32, LINK_SLOP as i32),
+                    l.rect.size + slop,
+                );
+                area.contains(p)
+            })
+            .min_by_key(|l| {
+                let c = l.rect.center();
+                (c.x - p.x).abs() + (c.y - p.y).abs()
+            })
+    }
+
+    /// the words on the page
+    pub fn words(&self) -> &[WordBox] {
+        &self.words
+    }
+
+    /// index of the word at a user coordinate
+    pub fn word_at(&self, x: u32, y: u32) -> Option<usize> {
+        let p = Point::new(x as i32, y as i32);
+        self.words.iter().position(|w| w.rect.contains(p))
+    }
+
+    /// index of the word nearest a user coordinate, on the closest line
+    pub fn nearest_word(&self, x: u32, y: u32) -> Option<usize> {
+        let (x, y) = (x as i32, y as i32);
+        self.words
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, w)| {
+                let c = w.rect.center();
+                // weight the vertical distance so we stay on the line
+                (c.y - y).abs() * 4 + (c.x - x).abs()
+            })
+            .map(|(i, _)| i)
+    }
+
+    /// the words between two word indices, in either order
+    pub fn words_between(&self, a: usize, b: usize) -> &[WordBox] {
+        let (first, last) = if a <= b { (a, b) } else { (b, a) };
+        let last = last.min(self.words.len().saturating_sub(1));
+        if first > last || self.words.is_empty() {
+            &[]
+        } else {
+            &self.words[first..=last]
+        }
+    }
+
+    /// the words covering a content range, `end` exclusive
+    pub fn words_in(&self, start: ContentLoc, end: ContentLoc) -> impl Iterator<Item = &WordBox> {
+        self.words
+            .iter()
+            .filter(move |w| w.loc >= start && w.loc < end)
+    }
+
+    /// the words overlapping a content range, `end` exclusive
+    pub fn words_overlapping(
+        &self,
+        start: ContentLoc,
+        end: ContentLoc,
+    ) -> impl Iterator<Item = &WordBox> {
+        self.words.iter().filter(move |w| {
+            let word_end = ContentLoc::new(w.loc.item, w.loc.offset + w.text.len() as u32);
+            w.loc < end && start < word_end
+        })
+    }
+}
@@ -0,0 +1,126 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::reader::location::ContentLoc;
+use crate::reader::typography::Typography;
+use anyhow::Result;
+use log::*;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+};
+
+// opening a zipped book makes the inflate state, about 43k, on the stack
+// before it's boxed, the board gives this thread a stack in psram
+const STACK_SIZE: usize = 96 * 1024;
+
+/// The start location of every page, for one typography
+#[derive(Debug, Clone, Default)]
+pub struct PageIndex {
+    pub typography: Typography,
+    pub starts: Vec<ContentLoc>,
+}
+
+impl PageIndex {
+    /// number of pages
+    pub fn page_count(&self) -> u32 {
+        self.starts.len() as u32
+    }
+
+    /// page holding a location
+    pub fn page_of(&self, loc: &ContentLoc) -> Option<u32> {
+        match self.starts.partition_point(|s| s <= loc) {
+            0 => None,
+            n => Some(n as u32 - 1),
+        }
+    }
+
+    /// start location of a page
+    pub fn page_start(&self, page: u32) -> Option<ContentLoc> {
+        self.starts.get(page as usize).copied()
+    }
+}
+
+/// Lays the book out again in a background thread
+///
+/// `paginate` is given the typography and a cancel flag it should check
+/// now and then. Starting a new layout cancels the one running.
+pub struct Repaginator {
+    cancel: Option<Arc<AtomicBool>>,
+    result_ch: Option<mpsc::Receiver<Result<PageIndex>>>,
+}
+
+impl Repaginator {
+    /// create the repaginator, nothing runs until `start`
+    pub fn new() -> Self {
+        Self {
+            cancel: None,
+            result_ch: None,
+        }
+    }
+
+    /// start laying out pages for a typography
+    pub fn start<F>(&mut self, typography: Typography, mut paginate: F) -> Result<()>
+    where
+        F: FnMut(&Typography, &AtomicBool) -> Result<Vec<ContentLoc>> + Send + 'static,
+    {
+        self.cancel();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let thread_cancel = cancel.clone();
+        let (send_ch, receive_ch) = mpsc::channel();
+        thread::Builder::new()
+            .name("paginate_thd".to_string())
+            .stack_size(STACK_SIZE)
+            .spawn(move || {
+                info!("pagination started");
+                let result = paginate(&typography, &thread_cancel)
+                    .map(|starts| PageIndex { typography, starts });
+                if !thread_cancel.load(Ordering::Relaxed) {
+                    let _ = send_ch.send(result);
+                }
+            })?;
+        self.cancel = Some(cancel);
+        self.result_ch = Some(receive_ch);
+        Ok(())
+    }
+
+    /// stop a running layout
+    pub fn cancel(&mut self) {
+        if let Some(cancel) = self.cancel.take() {
+            cancel.store(true, Ordering::Relaxed);
+        }
+        self.result_ch = None;
+    }
+
+    /// is a layout running
+    pub fn running(&self) -> bool {
+        self.result_ch.is_some()
+    }
+
+    /// the finished page index, if the layout is done
+    pub fn poll(&mut self) -> Option<Result<PageIndex>> {
+        let result = self.result_ch.as_ref()?.try_recv();
+        match result {
+            Ok(index) => {
+                self.cancel = None;
+                self.result_ch = None;
+                Some(index)
+            }
+            Err(mpsc::TryRecvError::Empty) => None,
+            Err(mpsc::TryRecvError::Disconnected) => {
+                self.cancel = None;
+                self.result_ch = None;
+                None
+            }
+        }
+    }
+}
+
+impl Default for Repaginator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,514 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::reader::content::BookContent;
+use crate::reader::location::ContentLoc;
+use crate::reader::markup::{entity, is_block, is_hidden, tag_name};
+use crate::reader::page_map::PageMap;
+use crate::ui::list_view::{ListAction, ListRow, ListView};
+use crate::ui::touch::{TouchEvent, TouchEventKind};
+use anyhow::Result;
+use embedded_graphics::{
+    pixelcolor::GrayColor,
+    prelude::*,
+    primitives::{PrimitiveStyle, Rectangle},
+};
+use log::*;
+
+// bytes read from the book at a time
+const CHUNK_SIZE: usize = 4096;
+// characters of context kept each side of a hit
+const CONTEXT: usize = 40;
+// stop after this many hits
+const MAX_HITS: usize = 200;
+
+/// A match found in the book
+#[derive(Debug, Clone)]
+pub struct SearchHit {
+    pub start: ContentLoc,
+    /// end of the match, exclusive
+    pub end: ContentLoc,
+    /// text around the match
+    pub context: String,
+    pub chapter: Option<String>,
+}
+
+// a text character, and the content offset it came from
+#[derive(Debug, Copy, Clone)]
+struct TextChar {
+    ch: char,
+    offset: u32,
+    len: u8,
+}
+
+// longest tag name kept, longer ones aren't block or hidden elements
+const TAG_NAME_LEN: usize = 12;
+
+// markup skipping state, carried between chunks
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Markup {
+    Text,
+    // the start of the tag, and was its last character a '/'
+    Tag {
+        name: [u8; TAG_NAME_LEN],
+        len: u8,
+        slash: bool,
+    },
+    Entity {
+        start: u32,
+        len: u8,
+        value: [u8; 8],
+    },
+}
+
+/// Search through the book a chunk at a time
+///
+/// Call `step` until it returns false, between steps the caller can check
+/// for a tap and `cancel` the search
+#[derive(Debug)]
+pub struct Searcher {
+    pattern: Vec<char>,
+    item: u32,
+    offset: u32,
+    buf: Vec<u8>,
+    window: Vec<TextChar>,
+    scanned: usize,
+    markup: Markup,
+    // an element whose text isn't searched, like the head, until it closes
+    hidden: Option<String>,
+    hits: Vec<SearchHit>,
+    done: bool,
+}
+
+impl Searcher {
+    /// start a search for a phrase, case and whitespace are ignored
+    pub fn new(phrase: &str) -> Self {
+        let mut pattern = Vec::new();
+        for ch in phrase.trim().chars() {
+            push_normalized(&mut pattern, ch, |p, c| p.push(c), |p| p.last().copied());
+        }
+        Self {
+            done: pattern.is_empty(),
+            pattern,
+            item: 0,
+            offset: 0,
+            buf: vec![0; CHUNK_SIZE],
+            window: Vec::new(),
+            scanned: 0,
+            markup: Markup::Text,
+            hidden: None,
+            hits: Vec::new(),
+        }
+    }
+
+    /// the hits found so far
+    pub fn hits(&self) -> &[SearchHit] {
+        &self.hits
+    }
+
+    /// has the search finished
+    pub fn done(&self) -> bool {
+        self.done
+    }
+
+    /// the item being searched, for progress
+    pub fn item(&self) -> u32 {
+        self.item
+    }
+
+    /// stop the search, the hits so far are kept
+    pub fn cancel(&mut self) {
+        debug!("search cancelled at item {}", self.item);
+        self.done = true;
+    }
+
+    /// a tap cancels the search, returns true if it was cancelled
+    pub fn touch(&mut self, evt: &TouchEvent) -> bool {
+        if !self.done && evt.kind() == TouchEventKind::Tap {
+            self.cancel();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// search the next chunk, returns false when the search is done
+    pub fn step<B: BookContent + ?Sized>(&mut self, book: &mut B) -> Result<bool> {
+        if self.done {
+            return Ok(false);
+        }
+        if self.item >= book.item_count() || self.hits.len() >= MAX_HITS {
+            self.done = true;
+            return Ok(false);
+        }
+        let n = book.read_item(self.item, self.offset, &mut self.buf)?;
+        if n == 0 {
+            // end of the item, finish matching what is left
+            self.flush_entity();
+            self.scan(book, true);
+            self.item += 1;
+            self.offset = 0;
+            self.window.clear();
+            self.scanned = 0;
+            self.markup = Markup::Text;
+            self.hidden = None;
+            return Ok(true);
+        }
+        let markup = book.is_markup(self.item);
+        let used = self.decode(n, markup);
+        self.offset += used as u32;
+        self.scan(book, false);
+        Ok(true)
+    }
+
+    // decode the buffer into the window, returns the bytes used, an incomplete
+    // utf8 sequence at the end is left for the next read
+    fn decode(&mut self, n: usize, markup: bool) -> usize {
+        let mut pos = 0;
+        while pos < n {
+            let (ch, len) = match std::str::from_utf8(&self.buf[pos..n.min(pos + 4)]) {
+                Ok(s) => first_char(s),
+                Err(e) if e.valid_up_to() > 0 => {
+                    first_char(std::str::from_utf8(&self.buf[pos..pos + e.valid_up_to()]).unwrap())
+                }
+                // incomplete sequence at the end of the buffer, and not the whole item
+                Err(e) if e.error_len().is_none() && n == self.buf.len() && pos > 0 => break,
+                // not utf8, treat as latin-1
+                Err(_) => (self.buf[pos] as char, 1),
+            };
+            let offset = self.offset + pos as u32;
+            pos += len;
+            if markup {
+                self.markup_char(ch, offset, len as u8);
+            } else {
+                self.push_char(ch, offset, len as u8);
+            }
+        }
+        pos
+    }
+
+    // handle a character of marked up content
+    fn markup_char(&mut self, ch: char, offset: u32, len: u8) {
+        match self.markup {
+            Markup::Text => match ch {
+                '<' => {
+                    self.markup = Markup::Tag {
+                        name: [0; TAG_NAME_LEN],
+                        len: 0,
+                        slash: false,
+                    }
+                }
+                '&' => {
+                    self.markup = Markup::Entity {
+                        start: offset,
+                        len: 0,
+                        value: [0; 8],
+                    }
+                }
+                _ => self.push_text(ch, offset, len),
+            },
+            Markup::Tag {
+                mut name,
+                len: mut nlen,
+                slash,
+            } => {
+                if ch == '>' {
+                    self.markup = Markup::Text;
+                    let tag = std::str::from_utf8(&name[..nlen as usize]).unwrap_or("");
+                    self.end_tag(tag, slash, offset, len);
+                } else {
+                    // enough of the tag for its name
+                    if (nlen as usize) < name.len() && ch.is_ascii() {
+                        name[nlen as usize] = ch as u8;
+                        nlen += 1;
+                    }
+                    self.markup = Markup::Tag {
+                        name,
+                        len: nlen,
+                        slash: ch == '/',
+                    };
+                }
+            }
+            Markup::Entity {
+                start,
+                len: elen,
+                mut value,
+            } => {
+                let full = elen as usize >= value.len();
+                if ch == ';' {
+                    let name = std::str::from_utf8(&value[..elen as usize]).unwrap_or("");
+                    self.markup = Markup::Text;
+                    let size = (offset + len as u32 - start) as u8;
+                    self.push_text(entity(name).unwrap_or(' '), start, size);
+                } else if full || !ch.is_ascii_alphanumeric() && ch != '#' {
+                    // not an entity after all, a bare '&' in the text
+                    self.flush_entity();
+                    self.markup_char(ch, offset, len);
+                } else {
+                    value[elen as usize] = ch as u8;
+                    self.markup = Markup::Entity {
+                        start,
+                        len: elen + 1,
+                        value,
+                    };
+                }
+            }
+        }
+    }
+
+    // a tag has ended, only block elements separate words, hidden ones
+    // have their text skipped up to the tag closing them
+    fn end_tag(&mut self, tag: &str, empty: bool, offset: u32, len: u8) {
+        let (name, closing) = tag_name(tag);
+        match &self.hidden {
+            Some(hidden) if closing && *hidden == name => self.hidden = None,
+            Some(_) => (),
+            None if is_hidden(&name) && !closing && !empty => {
+                self.hidden = Some(name);
+            }
+            None if is_block(&name) => self.push_char(' ', offset, len),
+            None => (),
+        }
+    }
+
+    // an '&' that didn't start an entity, or one cut off by the end of the item,
+    // is text
+    fn flush_entity(&mut self) {
+        if let Markup::Entity { start, len, value } = self.markup {
+            self.markup = Markup::Text;
+            self.push_text('&', start, 1);
+            for (i, b) in value[..len as usize].iter().enumerate() {
+                self.push_text(*b as char, start + 1 + i as u32, 1);
+            }
+        }
+    }
+
+    // add a character of the book's text, unless it is in a hidden element
+    fn push_text(&mut self, ch: char, offset: u32, len: u8) {
+        if self.hidden.is_none() {
+            self.push_char(ch, offset, len);
+        }
+    }
+
+    // add a text character to the window
+    fn push_char(&mut self, ch: char, offset: u32, len: u8) {
+        push_normalized(
+            &mut self.window,
+            ch,
+            |w, c| w.push(TextChar { ch: c, offset, len }),
+            |w| w.last().map(|t| t.ch),
+        );
+    }
+
+    // look for the pattern in the window, positions without their full context
+    // after them are left for the next chunk unless this is the end of the item
+    fn scan<B: BookContent + ?Sized>(&mut self, book: &B, end: bool) {
+        let plen = self.pattern.len();
+        let last = if end {
+            (self.window.len() + 1).saturating_sub(plen)
+        } else {
+            self.window.len().saturating_sub(plen + CONTEXT)
+        };
+        let mut i = self.scanned;
+        while i < last && self.hits.len() < MAX_HITS {
+            if self.window[i..i + plen]
+                .iter()
+                .zip(self.pattern.iter())
+                .all(|(t, p)| t.ch == *p)
+            {
+                let first = self.window[i];
+                let end_char = self.window[i + plen - 1];
+                let from = i.saturating_sub(CONTEXT);
+                let to = (i + plen + CONTEXT).min(self.window.len());
+                let context: String = self.window[from..to].iter().map(|t| t.ch).collect();
+                self.hits.push(SearchHit {
+                    start: ContentLoc::new(self.item, first.offset),
+                    end: ContentLoc::new(self.item, end_char.offset + end_char.len as u32),
+                    context: context.trim().to_string(),
+                    chapter: book.item_title(self.item),
+                });
+                i += plen;
+            } else {
+                i += 1;
+            }
+        }
+        self.scanned = self.scanned.max(i);
+        // only keep what is needed as context for the next chunk
+        let keep_from = self.scanned.saturating_sub(CONTEXT);
+        if !end && keep_from > 0 {
+            self.window.drain(..keep_from);
+            self.scanned -= keep_from;
+        }
+    }
+}
+
+// first char of a non empty string, and its length
+fn first_char(s: &str) -> (char, usize) {
+    let ch = s.chars().next().unwrap_or('\u{fffd}');
+    (ch, ch.len_utf8())
+}
+
+// add a character lowercased, with runs of whitespace as one space
+fn push_normalized<T>(
+    out: &mut T,
+    ch: char,
+    mut push: impl FnMut(&mut T, char),
+    last: impl Fn(&T) -> Option<char>,
+) {
+    if ch.is_whitespace() {
+        if !matches!(last(out), Some(' ') | None) {
+            push(out, ' ');
+        }
+    } else {
+        for c in ch.to_lowercase() {
+            push(out, c);
+        }
+    }
+}
+
+/// search the whole book, `cancelled` is checked between chunks
+pub fn search_book<B, F>(book: &mut B, phrase: &str, mut cancelled: F) -> Result<Vec<SearchHit>>
+where
+    B: BookContent + ?Sized,
+    F: FnMut() -> bool,
+{
+    let mut searcher = Searcher::new(phrase);
+    while searcher.step(book)? {
+        if cancelled() {
+            searcher.cancel();
+        }
+    }
+    info!("search for '{}': {} hits", phrase, searcher.hits().len());
+    Ok(searcher.hits)
+}
+
+/// The search results screen, selecting a hit jumps to it
+#[derive(Debug)]
+pub struct SearchResultsView {
+    list: ListView,
+}
+
+impl SearchResultsView {
+    /// create the view for a display size
+    pub fn new(phrase: &str, width: u32, height: u32) -> Self {
+        Self {
+            list: ListView::new(&format!("Search: {}", phrase), width, height),
+        }
+    }
+
+    /// draw the hits, `page_of` gives the page number of a location if known
+    pub fn draw<D, P>(
+        &self,
+        display: &mut D,
+        hits: &[SearchHit],
+        page_of: P,
+    ) -> Result<(), D::Error>
+    where
+        D: DrawTarget,
+        D::Color: GrayColor,
+        P: Fn(&ContentLoc) -> Option<u32>,
+    {
+        let lines: Vec<String> = hits
+            .iter()
+            .map(|h| {
+                let mut line = String::new();
+                if let Some(chapter) = &h.chapter {
+                    line.push_str(chapter);
+                }
+                if let Some(page) = page_of(&h.start) {
+                    line.push_str(&format!(" p.{}", page + 1));
+                }
+                format!("{}: {}", line.trim(), h.context)
+            })
+            .collect();
+        let rows: Vec<ListRow> = lines.iter().map(|l| ListRow::new(l)).collect();
+        self.list.draw(display, &rows)
+    }
+
+    /// handle a touch event, a selected hit is returned as a `Select` of its index
+    pub fn touch(&mut self, evt: &TouchEvent, hits: &[SearchHit]) -> ListAction {
+        self.list.touch(evt, hits.len())
+    }
+}
+
+/// draw a box around the words of a match on the page
+pub fn draw_match<D>(display: &mut D, map: &PageMap, hit: &SearchHit) -> Result<(), D::Error>
+where
+    D: DrawTarget,
+    D::Color: GrayColor,
+{
+    let style = PrimitiveStyle::with_stroke(D::Color::BLACK, 2);
+    for w in map.words_overlapping(hit.start, hit.end) {
+        Rectangle::new(
+            w.rect.top_left - Point::new(2, 2),
+            w.rect.size + Size::new(4, 4),
+        )
+        .into_styled(style)
+        .draw(display)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a book of xhtml items, read a few bytes at a time to carry the
+    // markup state between reads
+    struct Items(Vec<&'static str>);
+
+    impl BookContent for Items {
+        fn item_count(&self) -> u32 {
+            self.0.len() as u32
+        }
+
+        fn item_title(&self, _item: u32) -> Option<String> {
+            None
+        }
+
+        fn read_item(&mut self, item: u32, offset: u32, buf: &mut [u8]) -> Result<usize> {
+            let rest = &self.0[item as usize].as_bytes()[offset as usize..];
+            let n = rest.len().min(buf.len()).min(5);
+            buf[..n].copy_from_slice(&rest[..n]);
+            Ok(n)
+        }
+    }
+
+    fn find(items: &[&'static str], phrase: &str) -> Vec<(ContentLoc, ContentLoc)> {
+        let hits = search_book(&mut Items(items.to_vec()), phrase, || false).unwrap();
+        hits.iter().map(|h| (h.start, h.end)).collect()
+    }
+
+    #[test]
+    fn only_blocks_separate_words() {
+        let item = "<p>wo<em>rd</em> one</p><p>two</p>";
+        assert_eq!(find(&[item], "word").len(), 1);
+        assert_eq!(find(&[item], "one two").len(), 1);
+        assert!(find(&[item], "onetwo").is_empty());
+        assert!(find(&[item], "wo rd").is_empty());
+    }
+
+    #[test]
+    fn head_is_skipped() {
+        let item = "<html><head><title>Secret</title><style>p.secret {}</style></head>\
+                    <body><title/><p>a secret</p></body></html>";
+        let hits = find(&[item], "secret");
+        assert_eq!(hits.len(), 1);
+        let at = item.rfind("secret").unwrap() as u32;
+        assert_eq!(
+            hits[0],
+            (ContentLoc::new(0, at), ContentLoc::new(0, at + 6))
+        );
+    }
+
+    #[test]
+    fn entities() {
+        let items = ["<p>caf&#233; &amp; cake</p>", "<p>fish &amp"];
+        assert_eq!(find(&items, "café & cake").len(), 1);
+        // an entity cut off by the end of the item is text
+        assert_eq!(
+            find(&items, "fish &amp"),
+            vec![(ContentLoc::new(1, 3), ContentLoc::new(1, 12))]
+        );
+    }
+}
@@ -0,0 +1,221 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::reader::location::{ContentLoc, PageRange};
+use crate::reader::pagination::PageIndex;
+use embedded_graphics::{
+    draw_target::{Clipped, Cropped, DrawTargetExt},
+    pixelcolor::GrayColor,
+    prelude::*,
+    primitives::{Line, PrimitiveStyle, Rectangle},
+};
+
+/// Where pages go on the display
+///
+/// In landscape with spreads enabled two facing pages are shown side by
+/// side, otherwise one page fills the display. Views start on even pages,
+/// so a page is always shown in the same position. Pages are laid out at
+/// `page_size`, locations stay content locations so they work in both
+/// orientations.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SpreadLayout {
+    size: Size,
+    gutter: u32,
+    two_pages: bool,
+}
+
+impl SpreadLayout {
+    /// create the layout for a display size in user coordinates
+    pub fn new(width: u32, height: u32, spread: bool, gutter: u32) -> Self {
+        Self {
+            size: Size::new(width, height),
+            gutter,
+            two_pages: spread && width > height,
+        }
+    }
+
+    /// pages shown at once
+    pub fn pages_per_view(&self) -> u32 {
+        if self.two_pages {
+            2
+        } else {
+            1
+        }
+    }
+
+    /// size each page is laid out at
+    pub fn page_size(&self) -> Size {
+        if self.two_pages {
+            Size::new(
+                self.size.width.saturating_sub(self.gutter) / 2,
+                self.size.height,
+            )
+        } else {
+            self.size
+        }
+    }
+
+    /// the display areas of the pages of a view, left to right
+    pub fn page_areas(&self) -> Vec<Rectangle> {
+        let page = self.page_size();
+        let mut areas = vec![Rectangle::new(Point::zero(), page)];
+        if self.two_pages {
+            let x = (self.size.width - page.width) as i32;
+            areas.push(Rectangle::new(Point::new(x, 0), page));
+        }
+        areas
+    }
+
+    /// first page of the view showing `page`
+    pub fn view_start(&self, page: u32) -> u32 {
+        page - page % self.pages_per_view()
+    }
+
+    /// first page of the next view, if there is one
+    pub fn next_view(&self, page: u32, page_count: u32) -> Option<u32> {
+        let next = self.view_start(page) + self.pages_per_view();
+        if next < page_count {
+            Some(next)
+        } else {
+            None
+        }
+    }
+
+    /// first page of the previous view, if there is one
+    pub fn prev_view(&self, page: u32) -> Option<u32> {
+        self.view_start(page).checked_sub(self.pages_per_view())
+    }
+
+    /// the content shown by the view starting at `first`
+    pub fn view_range(&self, index: &PageIndex, first: u32) -> Option<PageRange> {
+        let start = index.page_start(first)?;
+        let end = index
+            .page_start(first + self.pages_per_view())
+            .unwrap_or(ContentLoc::new(u32::MAX, u32::MAX));
+        Some(PageRange::new(start, end))
+    }
+
+    /// draw the view starting at `first`, `draw_page` draws one page with its area as the origin
+    pub fn draw<D, F>(
+        &self,
+        display: &mut D,
+        first: u32,
+        page_count: u32,
+        mut draw_page: F,
+    ) -> Result<(), D::Error>
+    where
+        D: DrawTarget,
+        D::Color: GrayColor,
+        F: FnMut(&mut Clipped<'_, Cropped<'_, D>>, u32) -> Result<(), D::Error>,
+    {
+        display.clear(D::Color::WHITE)?;
+        for (i, area) in self.page_areas().iter().enumerate() {
+            let page = first + i as u32;
+            if page < page_count {
+                // cropping moves the origin, clipping keeps the page in its area
+                let mut cropped = display.cropped(area);
+                let bounds = Rectangle::new(Point::zero(), area.size);
+                draw_page(&mut cropped.clipped(&bounds), page)?;
+            }
+        }
+        if self.two_pages {
+            let x = (self.size.width / 2) as i32;
+            Line::new(Point::new(x, 0), Point::new(x, self.size.height as i32 - 1))
+                .into_styled(PrimitiveStyle::with_stroke(D::Color::BLACK, 1))
+                .draw(display)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_graphics::{mock_display::MockDisplay, pixelcolor::Gray8};
+
+    #[test]
+    fn one_page_in_portrait_or_without_spreads() {
+        for layout in [
+            SpreadLayout::new(758, 1024, true, 20),
+            SpreadLayout::new(1024, 758, false, 20),
+        ] {
+            assert_eq!(layout.pages_per_view(), 1);
+            assert_eq!(layout.page_areas().len(), 1);
+            assert_eq!(layout.view_start(5), 5);
+            assert_eq!(layout.next_view(5, 6), None);
+            assert_eq!(layout.prev_view(5), Some(4));
+            assert_eq!(layout.prev_view(0), None);
+        }
+    }
+
+    #[test]
+    fn spread_in_landscape() {
+        let layout = SpreadLayout::new(1024, 758, true, 20);
+        assert_eq!(layout.pages_per_view(), 2);
+        assert_eq!(layout.page_size(), Size::new(502, 758));
+        assert_eq!(
+            layout.page_areas(),
+            vec![
+                Rectangle::new(Point::zero(), Size::new(502, 758)),
+                Rectangle::new(Point::new(522, 0), Size::new(502, 758)),
+            ]
+        );
+        // views start on even pages
+        assert_eq!(layout.view_start(5), 4);
+        assert_eq!(layout.next_view(3, 5), Some(4));
+        assert_eq!(layout.next_view(4, 5), None);
+        assert_eq!(layout.prev_view(3), Some(0));
+        assert_eq!(layout.prev_view(1), None);
+        // a gutter wider than the display leaves empty pages
+        assert_eq!(
+            SpreadLayout::new(30, 20, true, 40).page_size(),
+            Size::new(0, 20)
+        );
+    }
+
+    #[test]
+    fn view_ranges() {
+        let index = PageIndex {
+            starts: vec![
+                ContentLoc::new(0, 0),
+                ContentLoc::new(0, 900),
+                ContentLoc::new(1, 0),
+            ],
+            ..Default::default()
+        };
+        let layout = SpreadLayout::new(1024, 758, true, 20);
+        let first = layout.view_range(&index, 0).unwrap();
+        assert_eq!(
+            first,
+            PageRange::new(ContentLoc::new(0, 0), ContentLoc::new(1, 0))
+        );
+        let last = layout.view_range(&index, 2).unwrap();
+        assert_eq!(last.start, ContentLoc::new(1, 0));
+        assert_eq!(last.end, ContentLoc::new(u32::MAX, u32::MAX));
+        assert!(layout.view_range(&index, 4).is_none());
+    }
+
+    #[test]
+    fn pages_are_drawn_in_their_areas() {
+        let layout = SpreadLayout::new(21, 4, true, 1);
+        let mut display: MockDisplay<Gray8> = MockDisplay::new();
+        display.set_allow_overdraw(true);
+        let mut drawn = Vec::new();
+        layout
+            .draw(&mut display, 0, 2, |page, n| {
+                drawn.push(n);
+                // a page draws past its right edge, it is clipped
+                page.fill_solid(
+                    &Rectangle::new(Point::zero(), Size::new(20, 1)),
+                    Gray8::new(n as u8 + 1),
+                )
+            })
+            .unwrap();
+        assert_eq!(drawn, vec![0, 1]);
+        assert_eq!(display.get_pixel(Point::new(9, 0)), Some(Gray8::new(1)));
+        assert_eq!(display.get_pixel(Point::new(10, 0)), Some(Gray8::BLACK));
+        assert_eq!(display.get_pixel(Point::new(11, 0)), Some(Gray8::new(2)));
+        assert_eq!(display.get_pixel(Point::new(20, 0)), Some(Gray8::new(2)));
+        assert_eq!(display.get_pixel(Point::new(9, 1)), Some(Gray8::WHITE));
+    }
+}
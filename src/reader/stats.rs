@@ -0,0 +1,502 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::reader::location::{book_key, load_json, save_json};
+use crate::ui::list_view::{ListAction, ListRow, ListView};
+use crate::ui::touch::TouchEvent;
+use anyhow::Result;
+use embedded_graphics::{pixelcolor::GrayColor, prelude::*};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+};
+
+// a longer gap between page turns is a break, not reading
+const MAX_TURN_SECS: i64 = 10 * 60;
+// pages needed before a reading speed is trusted
+const MIN_PAGES_FOR_SPEED: u32 = 10;
+const SECS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// Reading done on one day
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DayStats {
+    pub seconds: u64,
+    pub pages: u32,
+}
+
+/// Reading done in one book
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BookStats {
+    pub title: String,
+    pub seconds: u64,
+    pub pages: u32,
+    pub sessions: u32,
+    /// unix times, from the system clock the rtc sets at start
+    pub first_read: i64,
+    pub last_read: i64,
+}
+
+impl BookStats {
+    /// pages read per hour, once enough pages were read
+    pub fn pages_per_hour(&self) -> Option<f32> {
+        pages_per_hour(self.pages, self.seconds)
+    }
+}
+
+fn pages_per_hour(pages: u32, seconds: u64) -> Option<f32> {
+    if pages < MIN_PAGES_FOR_SPEED || seconds == 0 {
+        None
+    } else {
+        Some(pages as f32 * 3600.0 / seconds as f32)
+    }
+}
+
+/// Everything stored, books by their key
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct StatsData {
+    pub books: BTreeMap<String, BookStats>,
+    /// days since the epoch
+    pub days: BTreeMap<i64, DayStats>,
+}
+
+/// Reading statistics, kept from the times of page turns
+///
+/// The time between page turns is reading time, gaps longer than a few
+/// minutes are breaks and count only up to the limit. Stored in
+/// `<ereader_dir>/stats.json`, saved when a book is closed or the device
+/// sleeps.
+#[derive(Debug)]
+pub struct ReadingStats {
+    path: PathBuf,
+    data: StatsData,
+    // key of the open book and the time of its last page turn
+    book: Option<String>,
+    last_turn: i64,
+}
+
+impl ReadingStats {
+    /// load the stats
+    pub fn open(ereader_dir: &Path) -> Self {
+        let path = ereader_dir.join("stats.json");
+        Self {
+            data: load_json(&path),
+            path,
+            book: None,
+            last_turn: 0,
+        }
+    }
+
+    /// write the stats back to the sdcard
+    pub fn save(&self) -> Result<()> {
+        save_json(&self.path, &self.data)
+    }
+
+    /// all the stats
+    pub fn data(&self) -> &StatsData {
+        &self.data
+    }
+
+    /// a book was opened, or the device woke with it open
+    pub fn start_session(&mut self, book_path: &Path, title: &str, now: i64) {
+        let key = book_key(book_path);
+        let book = self.data.books.entry(key.clone()).or_default();
+        book.title = title.to_string();
+        book.sessions += 1;
+        if book.first_read == 0 {
+            book.first_read = now;
+        }
+        book.last_read = now;
+        self.book = Some(key);
+        self.last_turn = now;
+    }
+
+    // add the reading time since the last turn
+    fn add_time(&mut self, now: i64, pages: u32) {
+        let key = match &self.book {
+            Some(key) => key,
+            None => return,
+        };
+        let secs = (now - self.last_turn).clamp(0, MAX_TURN_SECS) as u64;
+        self.last_turn = now;
+        if let Some(book) = self.data.books.get_mut(key) {
+            book.seconds += secs;
+            book.pages += pages;
+            book.last_read = now;
+        }
+        let day = self
+            .data
+            .days
+            .entry(now.div_euclid(SECS_PER_DAY))
+            .or_default();
+        day.seconds += secs;
+        day.pages += pages;
+    }
+
+    /// pages were turned, 2 for a spread
+    pub fn page_turn(&mut self, now: i64, pages: u32) {
+        self.add_time(now, pages);
+    }
+
+    /// the book was closed or the device is going to sleep, saves the stats
+    pub fn end_session(&mut self, now: i64) -> Result<()> {
+        // reading the last page counts, it wasn't turned
+        self.add_time(now, 0);
+        self.book = None;
+        self.save()
+    }
+
+    /// the stats of a book
+    pub fn book(&self, book_path: &Path) -> Option<&BookStats> {
+        self.data.books.get(&book_key(book_path))
+    }
+
+    /// the reading speed for a book, all books if it has too few pages
+    pub fn pages_per_hour(&self, book_path: &Path) -> Option<f32> {
+        self.book(book_path)
+            .and_then(|b| b.pages_per_hour())
+            .or_else(|| {
+                let (pages, secs) = self
+                    .data
+                    .books
+                    .values()
+                    .fold((0, 0), |(p, s), b| (p + b.pages, s + b.seconds));
+                pages_per_hour(pages, secs)
+            })
+    }
+
+    /// estimated seconds to read `pages_left`
+    pub fn time_left(&self, book_path: &Path, pages_left: u32) -> Option<u64> {
+        self.pages_per_hour(book_path)
+            .map(|speed| (pages_left as f32 * 3600.0 / speed) as u64)
+    }
+
+    /// the current and longest runs of days with reading, the current run
+    /// still counts if today has no reading yet
+    pub fn streaks(&self, now: i64) -> (u32, u32) {
+        let today = now.div_euclid(SECS_PER_DAY);
+        let mut longest = 0;
+        let mut run = 0;
+        let mut prev = None;
+        for (day, stats) in &self.data.days {
+            if stats.seconds == 0 {
+                continue;
+            }
+            run = if prev == Some(day - 1) { run + 1 } else { 1 };
+            longest = longest.max(run);
+            prev = Some(*day);
+        }
+        let current = match prev {
+            Some(last) if last == today || last == today - 1 => run,
+            _ => 0,
+        };
+        (current, longest)
+    }
+
+    /// reading time today
+    pub fn today(&self, now: i64) -> DayStats {
+        self.data
+            .days
+            .get(&now.div_euclid(SECS_PER_DAY))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// write the stats to `<ereader_dir>/exports/stats.json`
+    pub fn export_json(&self, ereader_dir: &Path) -> Result<PathBuf> {
+        let path = export_path(ereader_dir, "stats.json")?;
+        fs::write(&path, serde_json::to_vec_pretty(&self.data)?)?;
+        info!("exported stats to {:?}", path);
+        Ok(path)
+    }
+
+    /// write the stats to `<ereader_dir>/exports/stats-books.csv` and `stats-days.csv`
+    pub fn export_csv(&self, ereader_dir: &Path) -> Result<Vec<PathBuf>> {
+        let mut books = String::from("title,seconds,pages,sessions,first_read,last_read\n");
+        for b in self.data.books.values() {
+            let _ = writeln!(
+                books,
+                "{},{},{},{},{},{}",
+                csv_field(&b.title),
+                b.seconds,
+                b.pages,
+                b.sessions,
+                b.first_read,
+                b.last_read
+            );
+        }
+        let mut days = String::from("date,seconds,pages\n");
+        for (day, d) in &self.data.days {
+            let _ = writeln!(days, "{},{},{}", date_string(*day), d.seconds, d.pages);
+        }
+        let books_path = export_path(ereader_dir, "stats-books.csv")?;
+        fs::write(&books_path, books)?;
+        let days_path = export_path(ereader_dir, "stats-days.csv")?;
+        fs::write(&days_path, days)?;
+        info!("exported stats to {:?} and {:?}", books_path, days_path);
+        Ok(vec![books_path, days_path])
+    }
+}
+
+fn export_path(ereader_dir: &Path, name: &str) -> Result<PathBuf> {
+    let dir = ereader_dir.join("exports");
+    fs::create_dir_all(&dir)?;
+    Ok(dir.join(name))
+}
+
+// quote a csv field when needed
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+/// the `YYYY-MM-DD` date of a day number since the epoch
+pub fn date_string(day: i64) -> String {
+    // civil from days, Howard Hinnant's algorithm
+    let z = day + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + i64::from(m <= 2);
+    format!("{:04}-{:02}-{:02}", y, m, d)
+}
+
+/// a duration as `3h 20m`
+pub fn format_duration(secs: u64) -> String {
+    let mins = secs / 60;
+    match (mins / 60, mins % 60) {
+        (0, 0) => "<1m".to_string(),
+        (0, m) => format!("{}m", m),
+        (h, m) => format!("{}h {}m", h, m),
+    }
+}
+
+/// The stats screen
+#[derive(Debug)]
+pub struct StatsView {
+    list: ListView,
+    lines: Vec<String>,
+}
+
+impl StatsView {
+    /// create the screen for the open book, with the pages left from the pagination
+    pub fn new(
+        width: u32,
+        height: u32,
+        stats: &ReadingStats,
+        book_path: &Path,
+        pages_left: (u32, u32),
+        now: i64,
+    ) -> Self {
+        let mut lines = Vec::new();
+        if let Some(book) = stats.book(book_path) {
+            lines.push(book.title.clone());
+            lines.push(format!(
+                "  {} over {} sessions, {} pages",
+                format_duration(book.seconds),
+                book.sessions,
+                book.pages
+            ));
+        }
+        match stats.pages_per_hour(book_path) {
+            Some(speed) => {
+                lines.push(format!("  {:.0} pages per hour", speed));
+                let (chapter, book) = pages_left;
+                let left = |pages| format_duration(stats.time_left(book_path, pages).unwrap_or(0));
+                lines.push(format!("  Chapter: {} left", left(chapter)));
+                lines.push(format!("  Book: {} left", left(book)));
+            }
+            None => lines.push("  Read a few more pages for estimates".to_string()),
+        }
+        let today = stats.today(now);
+        lines.push(format!(
+            "Today: {}, {} pages",
+            format_duration(today.seconds),
+            today.pages
+        ));
+        let (current, longest) = stats.streaks(now);
+        lines.push(format!(
+            "Streak: {} days, longest {} days",
+            current, longest
+        ));
+        let (secs, pages) = stats
+            .data()
+            .books
+            .values()
+            .fold((0, 0), |(s, p), b| (s + b.seconds, p + b.pages));
+        lines.push(format!(
+            "All books: {}, {} pages, {} books",
+            format_duration(secs),
+            pages,
+            stats.data().books.len()
+        ));
+        Self {
+            list: ListView::new("Reading stats", width, height),
+            lines,
+        }
+    }
+
+    /// draw the stats
+    pub fn draw<D>(&self, display: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget,
+        D::Color: GrayColor,
+    {
+        let rows: Vec<ListRow> = self.lines.iter().map(|l| ListRow::new(l)).collect();
+        self.list.draw(display, &rows)
+    }
+
+    /// handle a touch event, the title bar closes the screen
+    pub fn touch(&mut self, evt: &TouchEvent) -> ListAction {
+        match self.list.touch(evt, self.lines.len()) {
+            ListAction::Select(_) => ListAction::None,
+            action => action,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    // noon on a day since the epoch
+    fn noon(day: i64) -> i64 {
+        day * SECS_PER_DAY + SECS_PER_DAY / 2
+    }
+
+    #[test]
+    fn time_between_turns_is_reading() {
+        let root = temp_dir("stats-time");
+        let book = Path::new("/books/Book.epub");
+        let mut stats = ReadingStats::open(&root);
+        let start = noon(100);
+        stats.start_session(book, "Book", start);
+        stats.page_turn(start + 30, 1);
+        stats.page_turn(start + 90, 2);
+        // a long break counts only up to the limit
+        stats.page_turn(start + 90 + 3600, 1);
+        stats.end_session(start + 90 + 3600 + 20).unwrap();
+        let b = stats.book(book).unwrap().clone();
+        assert_eq!(b.seconds, 30 + 60 + MAX_TURN_SECS as u64 + 20);
+        assert_eq!((b.pages, b.sessions), (4, 1));
+        assert_eq!((b.first_read, b.last_read), (start, start + 3710));
+        assert_eq!(stats.today(start).pages, 4);
+        // turns after the session ended are not counted
+        stats.page_turn(start + 4000, 1);
+        assert_eq!(stats.book(book).unwrap().pages, 4);
+
+        // saved when the session ended
+        let stats = ReadingStats::open(&root);
+        assert_eq!(stats.book(book).unwrap().pages, 4);
+        assert_eq!(stats.today(start).seconds, b.seconds);
+    }
+
+    #[test]
+    fn days_roll_over_at_midnight() {
+        let root = temp_dir("stats-days");
+        let book = Path::new("/books/Book.epub");
+        let mut stats = ReadingStats::open(&root);
+        let midnight = 101 * SECS_PER_DAY;
+        stats.start_session(book, "Book", midnight - 120);
+        stats.page_turn(midnight - 60, 1);
+        stats.page_turn(midnight + 30, 1);
+        stats.page_turn(midnight + 60, 1);
+        stats.end_session(midnight + 60).unwrap();
+        // a turn's time goes to the day it was turned on
+        assert_eq!(
+            stats.today(midnight - 1),
+            DayStats {
+                seconds: 60,
+                pages: 1
+            }
+        );
+        assert_eq!(
+            stats.today(midnight),
+            DayStats {
+                seconds: 120,
+                pages: 2
+            }
+        );
+        assert_eq!(stats.today(midnight + SECS_PER_DAY), DayStats::default());
+        assert_eq!(stats.streaks(midnight), (2, 2));
+    }
+
+    #[test]
+    fn streaks_of_days() {
+        let mut stats = ReadingStats::open(&temp_dir("stats-streaks"));
+        let read = DayStats {
+            seconds: 60,
+            pages: 1,
+        };
+        for day in [10, 11, 12, 20, 21] {
+            stats.data.days.insert(day, read.clone());
+        }
+        // a day with no reading time breaks a run
+        stats.data.days.insert(22, DayStats::default());
+        assert_eq!(stats.streaks(noon(21)), (2, 3));
+        // today with no reading yet keeps yesterday's run
+        assert_eq!(stats.streaks(noon(22)), (2, 3));
+        assert_eq!(stats.streaks(noon(23)), (0, 3));
+        assert_eq!(
+            ReadingStats::open(&temp_dir("stats-none")).streaks(0),
+            (0, 0)
+        );
+    }
+
+    #[test]
+    fn speed_and_time_left() {
+        let mut stats = ReadingStats::open(&temp_dir("stats-speed"));
+        let (short, long) = (Path::new("/books/a.epub"), Path::new("/books/b.epub"));
+        stats.start_session(short, "A", 0);
+        stats.page_turn(60, 2);
+        assert_eq!(stats.pages_per_hour(short), None);
+        stats.start_session(long, "B", 1000);
+        for i in 1..=10 {
+            stats.page_turn(1000 + i * 120, 1);
+        }
+        assert_eq!(stats.pages_per_hour(long), Some(30.0));
+        // too few pages of its own, all books are used
+        let all = 12.0 * 3600.0 / 1260.0;
+        assert_eq!(stats.pages_per_hour(short), Some(all));
+        assert_eq!(stats.time_left(long, 15), Some(1800));
+    }
+
+    #[test]
+    fn dates_and_durations() {
+        assert_eq!(date_string(0), "1970-01-01");
+        assert_eq!(date_string(11_016), "2000-02-29");
+        assert_eq!(date_string(19_782), "2024-02-29");
+        assert_eq!(date_string(-1), "1969-12-31");
+        assert_eq!(format_duration(59), "<1m");
+        assert_eq!(format_duration(600), "10m");
+        assert_eq!(format_duration(3 * 3600 + 20 * 60), "3h 20m");
+    }
+
+    #[test]
+    fn csv_export() {
+        let root = temp_dir("stats-csv");
+        let mut stats = ReadingStats::open(&root);
+        stats.start_session(Path::new("/books/a.epub"), "Say \"hi\", then", noon(0));
+        stats.end_session(noon(0) + 60).unwrap();
+        let paths = stats.export_csv(&root).unwrap();
+        let books = fs::read_to_string(&paths[0]).unwrap();
+        assert!(
+            books.contains("\"Say \"\"hi\"\", then\",60,0,1,"),
+            "{}",
+            books
+        );
+        let days = fs::read_to_string(&paths[1]).unwrap();
+        assert_eq!(days, "date,seconds,pages\n1970-01-01,60,0\n");
+    }
+}
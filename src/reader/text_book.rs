@@ -0,0 +1,664 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::reader::content::BookContent;
+use crate::reader::item_cache::{escape_xml, source_stamp, CachedHeading, ItemReader, ItemWriter};
+use crate::reader::location::{book_data_dir, ContentLoc};
+use crate::reader::toc::TocEntry;
+use anyhow::{anyhow, Result};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::Read,
+    path::Path,
+};
+
+const CHUNK_SIZE: usize = 4096;
+// bump when the conversion changes, so cached books are converted again
+const CONVERT_VERSION: u32 = 1;
+
+/// The kinds of text book
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum TextFormat {
+    Plain,
+    Markdown,
+}
+
+/// the text format of a book file, from its extension
+pub fn text_format(path: &Path) -> Option<TextFormat> {
+    let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+    match ext.as_str() {
+        "txt" | "text" => Some(TextFormat::Plain),
+        "md" | "markdown" => Some(TextFormat::Markdown),
+        _ => None,
+    }
+}
+
+/// The encodings detected in text files
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum TextEncoding {
+    Utf8,
+    Latin1,
+    Cp1252,
+}
+
+// cp1252 characters for 0x80..0xa0, undefined bytes map to latin-1
+const CP1252: [char; 32] = [
+    '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8d}', 'Ž', '\u{8f}',
+    '\u{90}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{9d}', 'ž', 'Ÿ',
+];
+
+/// What a pass over the file found
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TextStats {
+    pub encoding: TextEncoding,
+    pub lines: u32,
+    pub blank_lines: u32,
+}
+
+/// detect the encoding of text, reading it a chunk at a time
+///
+/// Text that is valid UTF-8 is UTF-8, otherwise bytes in 0x80..0xa0,
+/// control codes in latin-1, mean the windows code page.
+pub fn detect_encoding<R: Read>(mut r: R) -> Result<TextStats> {
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut carry = Vec::new();
+    let mut utf8 = true;
+    let mut c1_controls = false;
+    let (mut lines, mut blank_lines) = (0, 0);
+    let mut line_empty = true;
+    loop {
+        let n = r.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        for b in &buf[..n] {
+            match *b {
+                b'\n' => {
+                    lines += 1;
+                    if line_empty {
+                        blank_lines += 1;
+                    }
+                    line_empty = true;
+                }
+                b' ' | b'\t' | b'\r' => (),
+                0x80..=0x9f => {
+                    c1_controls = true;
+                    line_empty = false;
+                }
+                _ => line_empty = false,
+            }
+        }
+        if utf8 {
+            carry.extend_from_slice(&buf[..n]);
+            match std::str::from_utf8(&carry) {
+                Ok(_) => carry.clear(),
+                // an incomplete sequence at the end is finished by the next chunk
+                Err(e) if e.error_len().is_none() => {
+                    carry.drain(..e.valid_up_to());
+                }
+                Err(_) => utf8 = false,
+            }
+        }
+    }
+    let encoding = if utf8 && carry.is_empty() {
+        TextEncoding::Utf8
+    } else if c1_controls {
+        TextEncoding::Cp1252
+    } else {
+        TextEncoding::Latin1
+    };
+    Ok(TextStats {
+        encoding,
+        lines,
+        blank_lines,
+    })
+}
+
+/// decode a chunk of text, an incomplete UTF-8 sequence at the end is left in `carry`
+pub fn decode_chunk(bytes: &[u8], encoding: TextEncoding, carry: &mut Vec<u8>, out: &mut String) {
+    match encoding {
+        TextEncoding::Utf8 => {
+            carry.extend_from_slice(bytes);
+            let valid = match std::str::from_utf8(carry) {
+                Ok(s) => s.len(),
+                Err(e) => e.valid_up_to(),
+            };
+            out.push_str(&String::from_utf8_lossy(&carry[..valid]));
+            carry.drain(..valid);
+        }
+        TextEncoding::Latin1 => out.extend(bytes.iter().map(|b| *b as char)),
+        TextEncoding::Cp1252 => out.extend(bytes.iter().map(|b| match b {
+            0x80..=0x9f => CP1252[(b - 0x80) as usize],
+            _ => *b as char,
+        })),
+    }
+}
+
+// is the character at a word edge, so `_` is emphasis and not part of a name
+fn word_edge(c: Option<char>) -> bool {
+    c.map(|c| !c.is_alphanumeric()).unwrap_or(true)
+}
+
+/// convert markdown inline markup to xhtml, emphasis, code spans and links
+pub fn markdown_inline(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::with_capacity(text.len() + 16);
+    let mut open: Vec<&str> = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let prev = if i > 0 { Some(chars[i - 1]) } else { None };
+        match c {
+            '\\' if next.map(|n| n.is_ascii_punctuation()).unwrap_or(false) => {
+                escape_xml(&next.unwrap_or_default().to_string(), &mut out);
+                i += 2;
+                continue;
+            }
+            '`' => {
+                if let Some(end) = chars[i + 1..].iter().position(|c| *c == '`') {
+                    let code: String = chars[i + 1..i + 1 + end].iter().collect();
+                    out.push_str("<code>");
+                    escape_xml(&code, &mut out);
+                    out.push_str("</code>");
+                    i += end + 2;
+                    continue;
+                }
+            }
+            '*' | '_' if next == Some(c) => {
+                toggle(&mut out, &mut open, "strong");
+                i += 2;
+                continue;
+            }
+            '*' | '_' => {
+                let closing = open.last() == Some(&"em");
+                let at_edge = if closing {
+                    word_edge(next)
+                } else {
+                    word_edge(prev) && !next.map(char::is_whitespace).unwrap_or(true)
+                };
+                if c == '*' || at_edge {
+                    toggle(&mut out, &mut open, "em");
+                    i += 1;
+                    continue;
+                }
+            }
+            '[' => {
+                if let Some((label, url, len)) = link_at(&chars[i..]) {
+                    out.push_str("<a href=\"");
+                    escape_xml(&url, &mut out);
+                    out.push_str("\">");
+                    out.push_str(&markdown_inline(&label));
+                    out.push_str("</a>");
+                    i += len;
+                    continue;
+                }
+            }
+            _ => (),
+        }
+        escape_xml(&c.to_string(), &mut out);
+        i += 1;
+    }
+    // close anything left open so the markup stays well formed
+    while let Some(tag) = open.pop() {
+        out.push_str(&format!("</{}>", tag));
+    }
+    out
+}
+
+fn toggle(out: &mut String, open: &mut Vec<&'static str>, tag: &'static str) {
+    if let Some(pos) = open.iter().rposition(|t| *t == tag) {
+        // close the tags inside it too
+        while open.len() > pos {
+            let t = open.pop().unwrap_or_default();
+            out.push_str(&format!("</{}>", t));
+        }
+    } else {
+        open.push(tag);
+        out.push_str(&format!("<{}>", tag));
+    }
+}
+
+// a `[label](url)` link at the start of `chars`, with its length
+fn link_at(chars: &[char]) -> Option<(String, String, usize)> {
+    let close = chars.iter().position(|c| *c == ']')?;
+    if chars.get(close + 1) != Some(&'(') {
+        return None;
+    }
+    let end = chars[close + 2..].iter().position(|c| *c == ')')? + close + 2;
+    let label = chars[1..close].iter().collect();
+    let url: String = chars[close + 2..end].iter().collect();
+    // drop a title, `(url "title")`
+    let url = url
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    Some((label, url, end + 1))
+}
+
+/// What the cache holds about a converted book
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct TextMeta {
+    version: u32,
+    source_len: u64,
+    source_modified: u64,
+    encoding: TextEncoding,
+    format: TextFormat,
+    /// title of each item, the heading it starts under
+    items: Vec<Option<String>>,
+    headings: Vec<CachedHeading>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Block {
+    None,
+    Para,
+    Quote,
+    List { ordered: bool },
+    Code,
+}
+
+// Writes the xhtml items of a book a line at a time
+struct Converter {
+    out: ItemWriter,
+    format: TextFormat,
+    // each line is a paragraph, for text without blank lines
+    line_paras: bool,
+    block: Block,
+    para: Vec<String>,
+}
+
+impl Converter {
+    fn new(dir: &Path, format: TextFormat, line_paras: bool) -> Result<Self> {
+        Ok(Self {
+            out: ItemWriter::new(dir)?,
+            format,
+            line_paras,
+            block: Block::None,
+            para: Vec::new(),
+        })
+    }
+
+    fn write(&mut self, html: &str) -> Result<()> {
+        self.out.write(html)
+    }
+
+    // close the open block, a new item may only start between blocks
+    fn end_block(&mut self) -> Result<()> {
+        let text = self.para.join(" ");
+        self.para.clear();
+        let html = match self.block {
+            Block::None => return Ok(()),
+            Block::Para => format!("<p>{}</p>\n", self.inline(&text)),
+            Block::Quote => format!("<blockquote><p>{}</p></blockquote>\n", self.inline(&text)),
+            Block::List { ordered: true } => "</ol>\n".to_string(),
+            Block::List { ordered: false } => "</ul>\n".to_string(),
+            Block::Code => "</pre>\n".to_string(),
+        };
+        self.block = Block::None;
+        self.write(&html)?;
+        self.out.block_end()
+    }
+
+    fn inline(&self, text: &str) -> String {
+        match self.format {
+            TextFormat::Markdown => markdown_inline(text),
+            TextFormat::Plain => {
+                let mut out = String::with_capacity(text.len());
+                escape_xml(text, &mut out);
+                out
+            }
+        }
+    }
+
+    fn heading(&mut self, level: u8, title: &str) -> Result<()> {
+        self.end_block()?;
+        let plain = title.replace(['*', '_', '`'], "");
+        self.out.heading(level, &plain, &markdown_inline(title))
+    }
+
+    fn line(&mut self, line: &str) -> Result<()> {
+        let line = line.trim_end_matches(['\r', '\n']);
+        match self.format {
+            TextFormat::Plain => self.plain_line(line),
+            TextFormat::Markdown => self.markdown_line(line),
+        }
+    }
+
+    fn plain_line(&mut self, line: &str) -> Result<()> {
+        if line.trim().is_empty() {
+            return self.end_block();
+        }
+        self.block = Block::Para;
+        self.para.push(line.trim().to_string());
+        if self.line_paras {
+            self.end_block()?;
+        }
+        Ok(())
+    }
+
+    fn markdown_line(&mut self, line: &str) -> Result<()> {
+        let trimmed = line.trim();
+        if self.block == Block::Code {
+            if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+                return self.end_block();
+            }
+            let mut text = String::new();
+            escape_xml(line, &mut text);
+            text.push('\n');
+            return self.write(&text);
+        }
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            self.end_block()?;
+            self.block = Block::Code;
+            return self.write("<pre>");
+        }
+        if trimmed.is_empty() {
+            return self.end_block();
+        }
+        // setext headings underline a one line paragraph
+        let rule =
+            trimmed.len() >= 3 && trimmed.chars().all(|c| c == trimmed.as_bytes()[0] as char);
+        if rule && matches!(trimmed.as_bytes()[0], b'=' | b'-') && self.block == Block::Para {
+            let title = self.para.join(" ");
+            self.para.clear();
+            self.block = Block::None;
+            let level = if trimmed.starts_with('=') { 1 } else { 2 };
+            return self.heading(level, &title);
+        }
+        if rule && matches!(trimmed.as_bytes()[0], b'-' | b'*' | b'_') {
+            self.end_block()?;
+            return self.write("<hr/>\n");
+        }
+        let hashes = trimmed.chars().take_while(|c| *c == '#').count();
+        if (1..=6).contains(&hashes) && trimmed[hashes..].starts_with(' ') {
+            let title = trimmed[hashes..].trim().trim_end_matches('#').trim();
+            return self.heading(hashes as u8, title);
+        }
+        if let Some(quote) = trimmed.strip_prefix('>') {
+            if self.block != Block::Quote {
+                self.end_block()?;
+                self.block = Block::Quote;
+            }
+            self.para.push(quote.trim().to_string());
+            return Ok(());
+        }
+        if let Some((ordered, item)) = list_item(trimmed) {
+            if self.block != (Block::List { ordered }) {
+                self.end_block()?;
+                self.write(if ordered { "<ol>\n" } else { "<ul>\n" })?;
+                self.block = Block::List { ordered };
+            }
+            let html = format!("<li>{}</li>\n", markdown_inline(item));
+            return self.write(&html);
+        }
+        if self.block != Block::Para {
+            self.end_block()?;
+            self.block = Block::Para;
+        }
+        self.para.push(trimmed.to_string());
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<(Vec<Option<String>>, Vec<CachedHeading>)> {
+        self.end_block()?;
+        self.out.finish()
+    }
+}
+
+// a list item line, `- item`, `* item`, `+ item` or `1. item`
+fn list_item(line: &str) -> Option<(bool, &str)> {
+    for bullet in ["- ", "* ", "+ "] {
+        if let Some(item) = line.strip_prefix(bullet) {
+            return Some((false, item));
+        }
+    }
+    let digits = line.chars().take_while(|c| c.is_ascii_digit()).count();
+    if digits > 0 && digits < 10 {
+        let rest = &line[digits..];
+        if let Some(item) = rest.strip_prefix(". ").or_else(|| rest.strip_prefix(") ")) {
+            return Some((true, item));
+        }
+    }
+    None
+}
+
+/// A plain text or markdown book
+///
+/// The file is converted once, a chunk at a time, to xhtml items in the
+/// book data directory, so text paginates like an EPUB with the same
+/// typography. The conversion is redone when the file changes.
+pub struct TextBook {
+    meta: TextMeta,
+    items: ItemReader,
+}
+
+impl TextBook {
+    /// open a text book, converting it if the cache is missing or stale
+    pub fn open(ereader_dir: &Path, book_path: &Path) -> Result<Self> {
+        let format =
+            text_format(book_path).ok_or_else(|| anyhow!("not a text book {:?}", book_path))?;
+        let dir = book_data_dir(ereader_dir, book_path).join("text");
+        let (len, modified) = source_stamp(book_path)?;
+        let meta_path = dir.join("meta.json");
+        let cached: Option<TextMeta> = fs::read(&meta_path)
+            .ok()
+            .and_then(|b| serde_json::from_slice(&b).ok());
+        let meta = match cached {
+            Some(meta)
+                if meta.version == CONVERT_VERSION
+                    && meta.source_len == len
+                    && meta.source_modified == modified =>
+            {
+                meta
+            }
+            _ => {
+                let meta = Self::convert(book_path, &dir, format, len, modified)?;
+                fs::write(&meta_path, serde_json::to_vec(&meta)?)?;
+                meta
+            }
+        };
+        Ok(Self {
+            meta,
+            items: ItemReader::new(&dir),
+        })
+    }
+
+    fn convert(
+        book_path: &Path,
+        dir: &Path,
+        format: TextFormat,
+        source_len: u64,
+        source_modified: u64,
+    ) -> Result<TextMeta> {
+        info!("converting {:?} as {:?}", book_path, format);
+        let stats = detect_encoding(File::open(book_path)?)?;
+        debug!("text stats {:?}", stats);
+        // hard wrapped text has blank lines between paragraphs, otherwise each line is one
+        let line_paras = stats.blank_lines == 0 || stats.blank_lines < stats.lines / 50;
+        let mut conv = Converter::new(dir, format, format == TextFormat::Plain && line_paras)?;
+        let mut file = File::open(book_path)?;
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        let mut carry = Vec::new();
+        let mut text = String::new();
+        let mut first = true;
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            decode_chunk(&buf[..n], stats.encoding, &mut carry, &mut text);
+            if first {
+                // a byte order mark isn't text
+                if let Some(rest) = text.strip_prefix('\u{feff}') {
+                    text = rest.to_string();
+                }
+                first = false;
+            }
+            // convert the complete lines, keep the partial one for the next chunk
+            while let Some(end) = text.find('\n') {
+                conv.line(&text[..end])?;
+                text.drain(..=end);
+            }
+        }
+        if !text.is_empty() {
+            conv.line(&text)?;
+        }
+        let (items, headings) = conv.finish()?;
+        Ok(TextMeta {
+            version: CONVERT_VERSION,
+            source_len,
+            source_modified,
+            encoding: stats.encoding,
+            format,
+            items,
+            headings,
+        })
+    }
+
+    /// the detected encoding of the file
+    pub fn encoding(&self) -> TextEncoding {
+        self.meta.encoding
+    }
+}
+
+impl BookContent for TextBook {
+    fn item_count(&self) -> u32 {
+        self.meta.items.len() as u32
+    }
+
+    fn item_title(&self, item: u32) -> Option<String> {
+        self.meta.items.get(item as usize).cloned().flatten()
+    }
+
+    fn read_item(&mut self, item: u32, offset: u32, buf: &mut [u8]) -> Result<usize> {
+        self.items.read(item, offset, buf)
+    }
+
+    fn toc(&mut self) -> Result<Vec<TocEntry>> {
+        Ok(self.meta.headings.iter().map(|h| h.toc_entry()).collect())
+    }
+
+    fn resolve_link(&mut self, href: &str, _from_item: u32) -> Option<ContentLoc> {
+        // markdown links to headings use their slugs, `#some-heading`
+        let id = href.strip_prefix('#')?;
+        self.meta
+            .headings
+            .iter()
+            .find(|h| heading_slug(&h.title) == id)
+            .map(|h| h.loc)
+    }
+}
+
+/// the anchor of a markdown heading, lowercase words joined by `-`
+pub fn heading_slug(title: &str) -> String {
+    title
+        .to_lowercase()
+        .chars()
+        .filter(|c| c.is_alphanumeric() || *c == ' ' || *c == '-' || *c == '_')
+        .map(|c| if c == ' ' { '-' } else { c })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    fn detect(bytes: &[u8]) -> TextEncoding {
+        detect_encoding(bytes).unwrap().encoding
+    }
+
+    // a book's first item, as text
+    fn first_item(book: &mut TextBook) -> String {
+        let mut buf = vec![0u8; 4096];
+        let n = book.read_item(0, 0, &mut buf).unwrap();
+        String::from_utf8(buf[..n].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn encodings_are_detected() {
+        assert_eq!(detect("café\n".as_bytes()), TextEncoding::Utf8);
+        assert_eq!(detect(b"caf\xe9\n"), TextEncoding::Latin1);
+        // curly quotes are c1 controls in latin-1
+        assert_eq!(detect(b"\x93caf\xe9\x94\n"), TextEncoding::Cp1252);
+        // a character split between chunks is still utf-8
+        let mut split = vec![b'a'; CHUNK_SIZE - 1];
+        split.extend_from_slice("é\n".as_bytes());
+        assert_eq!(detect(&split), TextEncoding::Utf8);
+        // cut short it isn't
+        assert_eq!(detect(&split[..CHUNK_SIZE]), TextEncoding::Latin1);
+
+        let stats = detect_encoding(&b"one\n\ntwo\n"[..]).unwrap();
+        assert_eq!((stats.lines, stats.blank_lines), (3, 1));
+    }
+
+    #[test]
+    fn chunks_decode() {
+        let mut out = String::new();
+        let mut carry = Vec::new();
+        let e = "é".as_bytes();
+        decode_chunk(&[b'a', e[0]], TextEncoding::Utf8, &mut carry, &mut out);
+        assert_eq!((out.as_str(), carry.len()), ("a", 1));
+        decode_chunk(&e[1..], TextEncoding::Utf8, &mut carry, &mut out);
+        assert_eq!((out.as_str(), carry.len()), ("aé", 0));
+
+        out.clear();
+        decode_chunk(b"\x93\xe9\x81", TextEncoding::Cp1252, &mut carry, &mut out);
+        assert_eq!(out, "“é\u{81}");
+        out.clear();
+        decode_chunk(b"\x93\xe9", TextEncoding::Latin1, &mut carry, &mut out);
+        assert_eq!(out, "\u{93}é");
+    }
+
+    #[test]
+    fn plain_text_books() {
+        let dir = temp_dir("text-plain");
+        let path = dir.join("b.txt");
+
+        // the byte order mark is dropped, blank lines end paragraphs
+        fs::write(&path, "\u{feff}Call me\nIshmael.\n\nSome years ago.\n").unwrap();
+        let mut book = TextBook::open(&dir.join("ereader"), &path).unwrap();
+        assert_eq!(book.encoding(), TextEncoding::Utf8);
+        let html = first_item(&mut book);
+        assert!(!html.contains('\u{feff}'));
+        assert!(html.contains("<p>Call me Ishmael.</p>\n<p>Some years ago.</p>"));
+
+        // latin-1 is read as such, without blank lines each line is a paragraph
+        fs::write(&path, b"caf\xe9 & cr\xe8me\nna\xefve\n").unwrap();
+        let mut book = TextBook::open(&dir.join("ereader"), &path).unwrap();
+        assert_eq!(book.encoding(), TextEncoding::Latin1);
+        let html = first_item(&mut book);
+        assert!(html.contains("<p>café &amp; crème</p>\n<p>naïve</p>"));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn markdown_books() {
+        let dir = temp_dir("text-markdown");
+        let path = dir.join("b.md");
+        let text = "# The *Whale*\n\nSome *em* and **strong**\ntext, snake_case.\n\n\
+                    Part\n----\n\n- one\n- [two](#the-whale)\n";
+        fs::write(&path, text).unwrap();
+        let mut book = TextBook::open(&dir.join("ereader"), &path).unwrap();
+        let html = first_item(&mut book);
+        assert!(html.contains("<h1>The <em>Whale</em></h1>\n"));
+        assert!(
+            html.contains("<p>Some <em>em</em> and <strong>strong</strong> text, snake_case.</p>")
+        );
+        assert!(html.contains(
+            "<h2>Part</h2>\n<ul>\n<li>one</li>\n<li><a href=\"#the-whale\">two</a></li>\n</ul>"
+        ));
+
+        let toc = book.toc().unwrap();
+        let titles: Vec<_> = toc.iter().map(|t| (t.title.as_str(), t.level)).collect();
+        assert_eq!(titles, [("The Whale", 0), ("Part", 1)]);
+        assert_eq!(book.item_title(0).as_deref(), Some("The Whale"));
+        let loc = book.resolve_link("#the-whale", 0).unwrap();
+        assert!(html[loc.offset as usize..].starts_with("<h1>"));
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,180 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::reader::location::ContentLoc;
+use crate::ui::list_view::{ListAction, ListRow, ListView};
+use crate::ui::touch::TouchEvent;
+use embedded_graphics::{pixelcolor::GrayColor, prelude::*};
+
+// deepest level indented, deeper levels are drawn at this level
+const MAX_INDENT: u8 = 4;
+
+/// An entry in the table of contents
+#[derive(Debug, Clone, PartialEq)]
+pub struct TocEntry {
+    pub title: String,
+    /// nesting depth, 0 for top level entries
+    pub level: u8,
+    pub loc: ContentLoc,
+}
+
+/// The table of contents of a book, entries in reading order
+#[derive(Debug, Default)]
+pub struct Toc {
+    entries: Vec<TocEntry>,
+}
+
+impl Toc {
+    /// create the toc from its entries
+    pub fn new(entries: Vec<TocEntry>) -> Self {
+        Self { entries }
+    }
+
+    /// the entries
+    pub fn entries(&self) -> &[TocEntry] {
+        &self.entries
+    }
+
+    /// index of the entry holding a location, the last entry starting at or before it
+    pub fn current(&self, loc: &ContentLoc) -> Option<usize> {
+        self.entries.iter().rposition(|e| e.loc <= *loc)
+    }
+}
+
+/// What the toc screen wants done after a touch event
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TocAction {
+    None,
+    Redraw,
+    Jump(ContentLoc),
+    Close,
+}
+
+/// The table of contents screen
+///
+/// Opens on the page holding the current chapter, which is marked
+#[derive(Debug)]
+pub struct TocView {
+    list: ListView,
+    current: Option<usize>,
+}
+
+impl TocView {
+    /// create the view for a display size, `loc` is the current reading location
+    pub fn new(toc: &Toc, loc: &ContentLoc, width: u32, height: u32) -> Self {
+        let mut list = ListView::new("Contents", width, height);
+        let current = toc.current(loc);
+        if let Some(c) = current {
+            list.show_row(c);
+        }
+        Self { list, current }
+    }
+
+    /// draw the current page of the toc
+    pub fn draw<D>(&self, display: &mut D, toc: &Toc) -> Result<(), D::Error>
+    where
+        D: DrawTarget,
+        D::Color: GrayColor,
+    {
+        self.list.draw(display, &self.rows(toc))
+    }
+
+    // the rows of the entries, deep levels share the last indent
+    fn rows<'a>(&self, toc: &'a Toc) -> Vec<ListRow<'a>> {
+        toc.entries()
+            .iter()
+            .enumerate()
+            .map(|(i, e)| ListRow {
+                text: &e.title,
+                indent: e.level.min(MAX_INDENT),
+                marked: Some(i) == self.current,
+            })
+            .collect()
+    }
+
+    /// handle a touch event
+    pub fn touch(&mut self, evt: &TouchEvent, toc: &Toc) -> TocAction {
+        match self.list.touch(evt, toc.entries().len()) {
+            ListAction::None => TocAction::None,
+            ListAction::Redraw => TocAction::Redraw,
+            ListAction::Select(i) => TocAction::Jump(toc.entries()[i].loc),
+            ListAction::Close => TocAction::Close,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ui::touch::TouchEventKind;
+
+    // three chapters, each with nine sections 100 bytes apart, nested a
+    // level deeper each time
+    fn toc() -> Toc {
+        let mut entries = Vec::new();
+        for chapter in 0..3 {
+            entries.push(TocEntry {
+                title: format!("Chapter {}", chapter),
+                level: 0,
+                loc: ContentLoc::new(chapter, 0),
+            });
+            for section in 1..10 {
+                entries.push(TocEntry {
+                    title: format!("Section {}.{}", chapter, section),
+                    level: section as u8,
+                    loc: ContentLoc::new(chapter, section * 100),
+                });
+            }
+        }
+        Toc::new(entries)
+    }
+
+    #[test]
+    fn current_entry() {
+        let toc = toc();
+        assert_eq!(toc.current(&ContentLoc::new(0, 0)), Some(0));
+        assert_eq!(toc.current(&ContentLoc::new(0, 150)), Some(1));
+        assert_eq!(toc.current(&ContentLoc::new(1, 0)), Some(10));
+        assert_eq!(toc.current(&ContentLoc::new(2, 5000)), Some(29));
+        // before the first entry
+        let late = Toc::new(toc.entries()[1..].to_vec());
+        assert_eq!(late.current(&ContentLoc::new(0, 50)), None);
+        assert_eq!(Toc::default().current(&ContentLoc::new(0, 0)), None);
+    }
+
+    #[test]
+    fn deep_levels_share_the_last_indent() {
+        let toc = toc();
+        let view = TocView::new(&toc, &ContentLoc::new(0, 320), 300, 400);
+        let rows = view.rows(&toc);
+        assert_eq!(rows.len(), 30);
+        let indents: Vec<u8> = rows[..10].iter().map(|r| r.indent).collect();
+        assert_eq!(indents, [0, 1, 2, 3, 4, 4, 4, 4, 4, 4]);
+        let marked: Vec<usize> = (0..30).filter(|i| rows[*i].marked).collect();
+        assert_eq!(marked, [3]);
+    }
+
+    #[test]
+    fn opens_at_the_current_entry() {
+        let toc = toc();
+        // 9 rows a page, entry 21 is on the third, which starts at 18
+        let mut view = TocView::new(&toc, &ContentLoc::new(2, 150), 300, 400);
+        assert_eq!(view.list.page(), 2);
+        let tap = |y| TouchEvent::with_position(TouchEventKind::Tap, 100, y);
+        assert_eq!(
+            view.touch(&tap(60), &toc),
+            TocAction::Jump(ContentLoc::new(1, 800))
+        );
+        assert_eq!(
+            view.touch(&tap(140), &toc),
+            TocAction::Jump(ContentLoc::new(2, 0))
+        );
+        let swipe = TouchEvent::new(TouchEventKind::SwipeRight);
+        assert_eq!(view.touch(&swipe, &toc), TocAction::Redraw);
+        assert_eq!(
+            view.touch(&tap(100), &toc),
+            TocAction::Jump(ContentLoc::new(1, 0))
+        );
+        assert_eq!(view.touch(&tap(10), &toc), TocAction::Close);
+    }
+}
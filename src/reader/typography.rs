@@ -0,0 +1,361 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::reader::location::book_data_dir;
+use crate::ui::settings_panel::{PanelAction, SettingsPanel};
+use crate::ui::touch::{PinchSteps, TouchEvent};
+use anyhow::Result;
+use embedded_graphics::{pixelcolor::GrayColor, prelude::*, primitives::Rectangle};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+// limits of the settings
+const FONT_SIZE_RANGE: (u8, u8) = (8, 32);
+const LINE_HEIGHT_RANGE: (u16, u16) = (80, 250);
+const LINE_HEIGHT_STEP: u16 = 10;
+const MARGIN_RANGE: (u16, u16) = (0, 120);
+const MARGIN_STEP: u16 = 5;
+const PARAGRAPH_RANGE: (u16, u16) = (0, 60);
+const PARAGRAPH_STEP: u16 = 2;
+/// pinch distance change needed for one font size step
+pub const PINCH_STEP: f32 = 20.0;
+
+/// The typography used to lay out pages
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Typography {
+    pub font_family: String,
+    /// font size in points
+    pub font_size: u8,
+    /// line height in percent of the font size
+    pub line_height: u16,
+    /// page margins in pixels
+    pub margin: u16,
+    /// space between paragraphs in pixels
+    pub paragraph_spacing: u16,
+    pub justify: bool,
+}
+
+impl Default for Typography {
+    fn default() -> Self {
+        Self {
+            font_family: "Default".to_string(),
+            font_size: 12,
+            line_height: 120,
+            margin: 20,
+            paragraph_spacing: 8,
+            justify: true,
+        }
+    }
+}
+
+impl Typography {
+    /// bring all the values into their allowed ranges
+    pub fn validate(&mut self) {
+        self.font_size = self.font_size.clamp(FONT_SIZE_RANGE.0, FONT_SIZE_RANGE.1);
+        self.line_height = self
+            .line_height
+            .clamp(LINE_HEIGHT_RANGE.0, LINE_HEIGHT_RANGE.1);
+        self.margin = self.margin.clamp(MARGIN_RANGE.0, MARGIN_RANGE.1);
+        self.paragraph_spacing = self
+            .paragraph_spacing
+            .clamp(PARAGRAPH_RANGE.0, PARAGRAPH_RANGE.1);
+        if self.font_family.is_empty() {
+            self.font_family = Typography::default().font_family;
+        }
+    }
+
+    /// change the font size with a pinch, `pinch` counts the steps of one
+    /// gesture, made with `PINCH_STEP`, returns true if the size changed
+    pub fn pinch(&mut self, pinch: &mut PinchSteps, evt: &TouchEvent) -> bool {
+        let steps = pinch.steps(evt);
+        let size = (self.font_size as i32 + steps)
+            .clamp(FONT_SIZE_RANGE.0 as i32, FONT_SIZE_RANGE.1 as i32) as u8;
+        let changed = size != self.font_size;
+        self.font_size = size;
+        changed
+    }
+}
+
+/// The fonts pages can be drawn with
+///
+/// Implemented by the renderer, so the panel only offers families it
+/// can load faces for.
+pub trait FontSource {
+    /// the names of the font families
+    fn families(&self) -> Vec<String>;
+}
+
+/// the font families to offer, the default family first
+pub fn font_families(source: &dyn FontSource) -> Vec<String> {
+    let default = Typography::default().font_family;
+    let mut families: Vec<String> = source
+        .families()
+        .into_iter()
+        .filter(|f| !f.is_empty() && *f != default)
+        .collect();
+    families.sort();
+    families.dedup();
+    families.insert(0, default);
+    families
+}
+
+/// Where typography is saved, globally and for each book
+///
+/// A book uses its own settings if it has them, the global ones otherwise
+#[derive(Debug)]
+pub struct TypographyStore {
+    global_path: PathBuf,
+    book_path: PathBuf,
+}
+
+impl TypographyStore {
+    /// create the store for a book
+    pub fn new(ereader_dir: &Path, book_path: &Path) -> Self {
+        Self {
+            global_path: ereader_dir.join("typography.json"),
+            book_path: book_data_dir(ereader_dir, book_path).join("typography.json"),
+        }
+    }
+
+    // read a settings file, defaults if missing or bad
+    fn read(path: &Path) -> Option<Typography> {
+        let data = fs::read(path).ok()?;
+        match serde_json::from_slice::<Typography>(&data) {
+            Ok(mut t) => {
+                t.validate();
+                Some(t)
+            }
+            Err(e) => {
+                warn!("bad typography settings {:?}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    // write a settings file
+    fn write(path: &Path, typography: &Typography) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_vec(typography)?)?;
+        Ok(())
+    }
+
+    /// the typography for the book
+    pub fn load(&self) -> Typography {
+        Self::read(&self.book_path)
+            .or_else(|| Self::read(&self.global_path))
+            .unwrap_or_default()
+    }
+
+    /// does the book have its own settings
+    pub fn has_book_settings(&self) -> bool {
+        self.book_path.exists()
+    }
+
+    /// save as the global settings, used by books without their own
+    pub fn save_global(&self, typography: &Typography) -> Result<()> {
+        Self::write(&self.global_path, typography)
+    }
+
+    /// save as the settings for this book only
+    pub fn save_book(&self, typography: &Typography) -> Result<()> {
+        Self::write(&self.book_path, typography)
+    }
+
+    /// remove the book settings, going back to the global ones
+    pub fn clear_book(&self) -> Result<()> {
+        if self.book_path.exists() {
+            fs::remove_file(&self.book_path)?;
+        }
+        Ok(())
+    }
+}
+
+/// What the typography panel wants done after a touch event
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TypographyAction {
+    None,
+    /// a setting changed, redraw the page as a preview
+    Changed,
+    /// only the panel changed
+    Redraw,
+    Close,
+}
+
+// the rows of the panel
+const ROWS: usize = 7;
+
+/// The typography settings panel
+///
+/// The page above the panel is drawn with the settings being edited,
+/// as a live preview. The last row picks where the settings are saved.
+#[derive(Debug)]
+pub struct TypographyPanel {
+    panel: SettingsPanel,
+    fonts: Vec<String>,
+    /// save for this book only, rather than globally
+    pub per_book: bool,
+}
+
+impl TypographyPanel {
+    /// create the panel for a display size, offering the fonts of the renderer
+    pub fn new(fonts: &dyn FontSource, per_book: bool, width: u32, height: u32) -> Self {
+        Self {
+            panel: SettingsPanel::new(ROWS, width, height),
+            fonts: font_families(fonts),
+            per_book,
+        }
+    }
+
+    /// area of the panel, for partial refresh
+    pub fn area(&self) -> Rectangle {
+        self.panel.area()
+    }
+
+    // step the font family through the available fonts
+    fn step_font(&self, t: &mut Typography, up: bool) {
+        if self.fonts.is_empty() {
+            return;
+        }
+        let n = self.fonts.len();
+        let i = self
+            .fonts
+            .iter()
+            .position(|f| *f == t.font_family)
+            .unwrap_or(0);
+        let i = if up { (i + 1) % n } else { (i + n - 1) % n };
+        t.font_family = self.fonts[i].clone();
+    }
+
+    /// handle a touch event, changing the settings
+    pub fn touch(&mut self, evt: &TouchEvent, t: &mut Typography) -> TypographyAction {
+        let (row, up) = match self.panel.touch(evt, ROWS) {
+            PanelAction::None => return TypographyAction::None,
+            PanelAction::Close => return TypographyAction::Close,
+            PanelAction::Decrease(row) => (row, false),
+            PanelAction::Increase(row) => (row, true),
+        };
+        let step = |v: u16, s: u16| {
+            if up {
+                v.saturating_add(s)
+            } else {
+                v.saturating_sub(s)
+            }
+        };
+        match row {
+            0 => self.step_font(t, up),
+            1 => {
+                t.font_size = if up {
+                    t.font_size.saturating_add(1)
+                } else {
+                    t.font_size.saturating_sub(1)
+                }
+            }
+            2 => t.line_height = step(t.line_height, LINE_HEIGHT_STEP),
+            3 => t.margin = step(t.margin, MARGIN_STEP),
+            4 => t.paragraph_spacing = step(t.paragraph_spacing, PARAGRAPH_STEP),
+            5 => t.justify = !t.justify,
+            _ => {
+                self.per_book = !self.per_book;
+                return TypographyAction::Redraw;
+            }
+        }
+        t.validate();
+        TypographyAction::Changed
+    }
+
+    /// draw the panel
+    pub fn draw<D>(&self, display: &mut D, t: &Typography) -> Result<(), D::Error>
+    where
+        D: DrawTarget,
+        D::Color: GrayColor,
+    {
+        let rows = [
+            ("Font", t.font_family.clone()),
+            ("Size", format!("{} pt", t.font_size)),
+            ("Line height", format!("{}%", t.line_height)),
+            ("Margins", format!("{} px", t.margin)),
+            ("Paragraph spacing", format!("{} px", t.paragraph_spacing)),
+            (
+                "Alignment",
+                if t.justify { "justified" } else { "left" }.to_string(),
+            ),
+            (
+                "Save for",
+                if self.per_book {
+                    "this book"
+                } else {
+                    "all books"
+                }
+                .to_string(),
+            ),
+        ];
+        self.panel.draw(display, &rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+    use crate::ui::touch::TouchEventKind;
+
+    struct Fonts(&'static [&'static str]);
+
+    impl FontSource for Fonts {
+        fn families(&self) -> Vec<String> {
+            self.0.iter().map(|f| f.to_string()).collect()
+        }
+    }
+
+    #[test]
+    fn families_start_with_the_default() {
+        let fonts = Fonts(&["Serif", "Sans", "Default", "Serif", ""]);
+        assert_eq!(font_families(&fonts), vec!["Default", "Sans", "Serif"]);
+        assert_eq!(font_families(&Fonts(&[])), vec!["Default"]);
+    }
+
+    #[test]
+    fn pinch_steps_the_size_by_whole_steps() {
+        let mut t = Typography::default();
+        let mut pinch = PinchSteps::new(PINCH_STEP);
+        let enlarge = TouchEvent::pinch(TouchEventKind::PinchEnlarge, 7.0);
+        assert!(!t.pinch(&mut pinch, &enlarge));
+        assert!(!t.pinch(&mut pinch, &enlarge));
+        assert!(t.pinch(&mut pinch, &enlarge));
+        assert_eq!(t.font_size, 13);
+        let reduce = TouchEvent::pinch(TouchEventKind::PinchReduce, 1000.0);
+        assert!(t.pinch(&mut pinch, &reduce));
+        assert_eq!(t.font_size, FONT_SIZE_RANGE.0);
+    }
+
+    #[test]
+    fn book_settings_before_global() {
+        let dir = temp_dir("typography");
+        let store = TypographyStore::new(&dir, Path::new("/sdcard/a.epub"));
+        assert_eq!(store.load(), Typography::default());
+        let global = Typography {
+            font_size: 14,
+            ..Default::default()
+        };
+        store.save_global(&global).unwrap();
+        assert_eq!(store.load(), global);
+        let book = Typography {
+            font_size: 99,
+            ..Default::default()
+        };
+        store.save_book(&book).unwrap();
+        assert!(store.has_book_settings());
+        // loaded settings are validated
+        assert_eq!(store.load().font_size, FONT_SIZE_RANGE.1);
+        store.clear_book().unwrap();
+        assert_eq!(store.load(), global);
+        let _ = fs::remove_dir_all(dir);
+    }
+}
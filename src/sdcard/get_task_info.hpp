@@ -1,5 +1,7 @@
 #pragma once
 
+#include "esp_err.h"
+
 #ifdef __cplusplus
 #define EXTERNC extern "C"
 #else
@@ -33,7 +33,7 @@ EXTERNC sdspi_device_config_t slot_config_default()
 }
 
 
-EXTERNC bool sdcard_setup()
+EXTERNC bool sdcard_setup(int miso, int mosi, int clk, int cs)
 {
   sdmmc_card_t* card;
   esp_err_t ret;
@@ -51,10 +51,11 @@ EXTERNC bool sdcard_setup()
     ESP_LOGI(TAG, "Setup SD card");
   }
 
-  static const gpio_num_t PIN_NUM_MISO = GPIO_NUM_12;
-  static const gpio_num_t PIN_NUM_MOSI = GPIO_NUM_13;
-  static const gpio_num_t PIN_NUM_CLK  = GPIO_NUM_14;
-  static const gpio_num_t PIN_NUM_CS   = GPIO_NUM_15;
+  // pins come from the settings, defaults are MISO 12, MOSI 13, CLK 14, CS 15
+  const gpio_num_t PIN_NUM_MISO = static_cast<gpio_num_t>(miso);
+  const gpio_num_t PIN_NUM_MOSI = static_cast<gpio_num_t>(mosi);
+  const gpio_num_t PIN_NUM_CLK  = static_cast<gpio_num_t>(clk);
+  const gpio_num_t PIN_NUM_CS   = static_cast<gpio_num_t>(cs);
 
   esp_vfs_fat_sdmmc_mount_config_t mount_config = {
     .format_if_mount_failed = false,
@@ -6,9 +6,11 @@
 #define EXTERNC
 #endif
 
+#include "get_task_info.hpp"
+
 //#include "driver/sdmmc_host.h"
 //#include "driver/sdspi_host.h"
 
 //EXTERNC sdmmc_host_t sdspi_host_default();
 //EXTERNC sdspi_slot_config_t slot_config_default();
-EXTERNC bool sdcard_setup();
+EXTERNC bool sdcard_setup(int miso, int mosi, int clk, int cs);
@@ -0,0 +1,191 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    net::TcpListener,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread::{self, JoinHandle},
+};
+
+static DIRS: AtomicUsize = AtomicUsize::new(0);
+
+/// an empty directory for a test, under the system temp dir
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "ereader-{}-{}-{}",
+        name,
+        std::process::id(),
+        DIRS.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// a bottom up 24 bit bmp, pixels are rows of (b, g, r) from the top
+pub fn bmp(width: i32, height: i32, pixels: &[[u8; 3]]) -> Vec<u8> {
+    let stride = ((24 * width as usize + 31) / 32) * 4;
+    let mut b = vec![0u8; 54];
+    b[0..2].copy_from_slice(b"BM");
+    b[10..14].copy_from_slice(&54u32.to_le_bytes());
+    b[14..18].copy_from_slice(&40u32.to_le_bytes());
+    b[18..22].copy_from_slice(&width.to_le_bytes());
+    b[22..26].copy_from_slice(&height.to_le_bytes());
+    b[26..28].copy_from_slice(&1u16.to_le_bytes());
+    b[28..30].copy_from_slice(&24u16.to_le_bytes());
+    for row in pixels.chunks(width.max(1) as usize).rev() {
+        let mut r: Vec<u8> = row.iter().flatten().copied().collect();
+        r.resize(stride, 0);
+        b.extend(r);
+    }
+    b
+}
+
+/// a png of rows of pixels, `color` says how many samples each has
+pub fn png(width: u32, height: u32, color: png::ColorType, pixels: &[u8]) -> Vec<u8> {
+    let mut b = Vec::new();
+    let mut encoder = png::Encoder::new(&mut b, width, height);
+    encoder.set_color(color);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().unwrap();
+    writer.write_image_data(pixels).unwrap();
+    writer.finish().unwrap();
+    b
+}
+
+/// a single frame gif, pixels index into the rgb palette
+pub fn gif(width: u16, height: u16, palette: &[u8], pixels: &[u8]) -> Vec<u8> {
+    let mut b = Vec::new();
+    {
+        let mut encoder = gif::Encoder::new(&mut b, width, height, palette).unwrap();
+        let frame = gif::Frame {
+            width,
+            height,
+            buffer: pixels.into(),
+            ..Default::default()
+        };
+        encoder.write_frame(&frame).unwrap();
+    }
+    b
+}
+
+/// write a zip of stored files
+pub fn zip_file(path: &Path, files: &[(&str, &[u8])]) {
+    let mut zip = zip::ZipWriter::new(fs::File::create(path).unwrap());
+    let options =
+        zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    for (name, data) in files {
+        zip.start_file(*name, options).unwrap();
+        zip.write_all(data).unwrap();
+    }
+    zip.finish().unwrap();
+}
+
+/// write an epub of (title, xhtml) chapters, with an ncx listing them
+pub fn epub(path: &Path, chapters: &[(String, String)]) {
+    let mut manifest = String::new();
+    let mut spine = String::new();
+    let mut nav = String::new();
+    for (i, (title, _)) in chapters.iter().enumerate() {
+        manifest.push_str(&format!(
+            r#"<item id="c{i}" href="c{i}.xhtml" media-type="application/xhtml+xml"/>"#
+        ));
+        spine.push_str(&format!(r#"<itemref idref="c{i}"/>"#));
+        nav.push_str(&format!(
+            r#"<navPoint><navLabel><text>{title}</text></navLabel><content src="c{i}.xhtml"/></navPoint>"#
+        ));
+    }
+    let container = r#"<container><rootfiles><rootfile full-path="OEBPS/content.opf"/></rootfiles></container>"#;
+    let opf = format!(
+        r#"<package><metadata/><manifest><item id="ncx" href="toc.ncx"/>{manifest}</manifest><spine toc="ncx">{spine}</spine></package>"#
+    );
+    let ncx = format!("<ncx><navMap>{nav}</navMap></ncx>");
+    let names: Vec<String> = (0..chapters.len())
+        .map(|i| format!("OEBPS/c{i}.xhtml"))
+        .collect();
+    let mut files: Vec<(&str, &[u8])> = vec![
+        ("META-INF/container.xml", container.as_bytes()),
+        ("OEBPS/content.opf", opf.as_bytes()),
+        ("OEBPS/toc.ncx", ncx.as_bytes()),
+    ];
+    for (name, (_, xhtml)) in names.iter().zip(chapters) {
+        files.push((name, xhtml.as_bytes()));
+    }
+    zip_file(path, &files);
+}
+
+/// A stand-in http server on a local port
+///
+/// Each connection gets the response `respond` makes for its request,
+/// the requests are kept for the test to check.
+pub struct TestServer {
+    /// `http://127.0.0.1:<port>`
+    pub url: String,
+    handle: JoinHandle<Vec<String>>,
+}
+
+impl TestServer {
+    /// serve `connections` requests, then stop
+    pub fn start<F>(connections: usize, mut respond: F) -> Self
+    where
+        F: FnMut(&str) -> Vec<u8> + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for _ in 0..connections {
+                let (mut stream, _) = listener.accept().unwrap();
+                let request = read_request(&mut BufReader::new(&mut stream));
+                stream.write_all(&respond(&request)).unwrap();
+                requests.push(request);
+            }
+            requests
+        });
+        Self { url, handle }
+    }
+
+    /// the requests served, head and body
+    pub fn requests(self) -> Vec<String> {
+        self.handle.join().unwrap()
+    }
+}
+
+// read a request head and its body
+fn read_request<R: BufRead>(reader: &mut R) -> String {
+    let mut request = String::new();
+    let mut length = 0;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+            request.push_str(&line);
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().unwrap();
+            }
+        }
+        request.push_str(&line);
+    }
+    let mut body = vec![0u8; length];
+    reader.read_exact(&mut body).unwrap();
+    request.push_str(&String::from_utf8_lossy(&body));
+    request
+}
+
+/// an http response with a body and extra header lines
+pub fn response(status: &str, headers: &[&str], body: &[u8]) -> Vec<u8> {
+    let mut head = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\n", status, body.len());
+    for header in headers {
+        head.push_str(header);
+        head.push_str("\r\n");
+    }
+    head.push_str("\r\n");
+    let mut bytes = head.into_bytes();
+    bytes.extend_from_slice(body);
+    bytes
+}
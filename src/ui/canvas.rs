@@ -0,0 +1,207 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use embedded_graphics::{pixelcolor::Gray8, prelude::*, primitives::Rectangle};
+use std::{
+    convert::Infallible,
+    io::{self, Write},
+};
+
+/// The frame the screens draw on, in 16 gray levels
+///
+/// Screens draw here rather than on the display, the board copies the
+/// area that changed to the display when it refreshes. Two pixels are
+/// kept in a byte, the display shows only 8 levels anyway. It is also
+/// what a screenshot saves.
+#[derive(Debug)]
+pub struct Canvas {
+    size: Size,
+    pixels: Vec<u8>,
+    // top left and bottom right of what changed, inclusive
+    dirty: Option<(Point, Point)>,
+}
+
+impl Canvas {
+    /// create a white canvas, in user coordinates
+    pub fn new(width: u32, height: u32) -> Self {
+        let mut canvas = Self {
+            size: Size::zero(),
+            pixels: Vec::new(),
+            dirty: None,
+        };
+        canvas.resize(width, height);
+        canvas
+    }
+
+    /// change the size after the display is rotated, the canvas is cleared
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.size = Size::new(width, height);
+        let len = (width as usize * height as usize + 1) / 2;
+        self.pixels.clear();
+        self.pixels.resize(len, 0xff);
+        self.invalidate();
+    }
+
+    /// the gray of a pixel, white outside the canvas
+    pub fn pixel(&self, p: Point) -> Gray8 {
+        match self.index(p) {
+            Some(i) => {
+                let byte = self.pixels[i / 2];
+                let level = if i % 2 == 0 { byte & 0x0f } else { byte >> 4 };
+                Gray8::new(level * 17)
+            }
+            None => Gray8::WHITE,
+        }
+    }
+
+    /// mark the whole canvas as changed, for a full refresh
+    pub fn invalidate(&mut self) {
+        self.dirty = self
+            .bounding_box()
+            .bottom_right()
+            .map(|br| (Point::zero(), br));
+    }
+
+    /// the area changed since the last call, for the board to refresh
+    pub fn take_dirty(&mut self) -> Option<Rectangle> {
+        self.dirty
+            .take()
+            .map(|(tl, br)| Rectangle::with_corners(tl, br))
+    }
+
+    /// the pixels of an area inside the canvas, row by row
+    pub fn pixels<'a>(&'a self, area: &Rectangle) -> impl Iterator<Item = Gray8> + 'a {
+        area.points().map(|p| self.pixel(p))
+    }
+
+    /// write the canvas as a binary pgm
+    pub fn write_pgm<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write!(w, "P5\n{} {}\n255\n", self.size.width, self.size.height)?;
+        let mut row = Vec::with_capacity(self.size.width as usize);
+        for y in 0..self.size.height as i32 {
+            row.clear();
+            row.extend((0..self.size.width as i32).map(|x| self.pixel(Point::new(x, y)).luma()));
+            w.write_all(&row)?;
+        }
+        Ok(())
+    }
+
+    // index of a pixel, none outside the canvas
+    fn index(&self, p: Point) -> Option<usize> {
+        let inside = p.x >= 0
+            && p.y >= 0
+            && (p.x as u32) < self.size.width
+            && (p.y as u32) < self.size.height;
+        inside.then(|| p.y as usize * self.size.width as usize + p.x as usize)
+    }
+
+    fn set(&mut self, i: usize, color: Gray8) {
+        let level = color.luma() >> 4;
+        let byte = &mut self.pixels[i / 2];
+        *byte = if i % 2 == 0 {
+            (*byte & 0xf0) | level
+        } else {
+            (*byte & 0x0f) | (level << 4)
+        };
+    }
+
+    fn mark(&mut self, tl: Point, br: Point) {
+        self.dirty = Some(match self.dirty {
+            Some((a, b)) => (a.component_min(tl), b.component_max(br)),
+            None => (tl, br),
+        });
+    }
+}
+
+impl OriginDimensions for Canvas {
+    fn size(&self) -> Size {
+        self.size
+    }
+}
+
+impl DrawTarget for Canvas {
+    type Color = Gray8;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(p, color) in pixels {
+            if let Some(i) = self.index(p) {
+                self.set(i, color);
+                self.mark(p, p);
+            }
+        }
+        Ok(())
+    }
+
+    fn fill_solid(&mut self, area: &Rectangle, color: Self::Color) -> Result<(), Self::Error> {
+        let area = area.intersection(&self.bounding_box());
+        if let Some(br) = area.bottom_right() {
+            for p in area.points() {
+                let i = p.y as usize * self.size.width as usize + p.x as usize;
+                self.set(i, color);
+            }
+            self.mark(area.top_left, br);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_graphics::primitives::PrimitiveStyle;
+
+    #[test]
+    fn draws_and_tracks_the_change() {
+        let mut canvas = Canvas::new(5, 3);
+        assert_eq!(
+            canvas.take_dirty(),
+            Some(Rectangle::new(Point::zero(), Size::new(5, 3)))
+        );
+        assert_eq!(canvas.take_dirty(), None);
+        Rectangle::new(Point::new(1, 1), Size::new(2, 2))
+            .into_styled(PrimitiveStyle::with_fill(Gray8::new(0x40)))
+            .draw(&mut canvas)
+            .unwrap();
+        Pixel(Point::new(4, 0), Gray8::BLACK)
+            .draw(&mut canvas)
+            .unwrap();
+        // off the canvas is ignored
+        Pixel(Point::new(5, 0), Gray8::BLACK)
+            .draw(&mut canvas)
+            .unwrap();
+        assert_eq!(
+            canvas.take_dirty(),
+            Some(Rectangle::with_corners(Point::new(1, 0), Point::new(4, 2)))
+        );
+        assert_eq!(canvas.pixel(Point::new(1, 1)), Gray8::new(0x44));
+        assert_eq!(canvas.pixel(Point::new(2, 2)), Gray8::new(0x44));
+        assert_eq!(canvas.pixel(Point::new(4, 0)), Gray8::BLACK);
+        assert_eq!(canvas.pixel(Point::new(0, 0)), Gray8::WHITE);
+        assert_eq!(canvas.pixel(Point::new(9, 9)), Gray8::WHITE);
+        let area = Rectangle::new(Point::new(2, 0), Size::new(3, 1));
+        let row: Vec<u8> = canvas.pixels(&area).map(|c| c.luma()).collect();
+        assert_eq!(row, vec![255, 255, 0]);
+    }
+
+    #[test]
+    fn pgm_screenshot() {
+        let mut canvas = Canvas::new(3, 2);
+        canvas.clear(Gray8::BLACK).unwrap();
+        Pixel(Point::new(2, 1), Gray8::WHITE)
+            .draw(&mut canvas)
+            .unwrap();
+        let mut pgm = Vec::new();
+        canvas.write_pgm(&mut pgm).unwrap();
+        let mut expected = b"P5\n3 2\n255\n".to_vec();
+        expected.extend([0, 0, 0, 0, 0, 255]);
+        assert_eq!(pgm, expected);
+        // a rotation clears the canvas
+        canvas.resize(2, 3);
+        assert_eq!(canvas.size(), Size::new(2, 3));
+        assert_eq!(canvas.pixel(Point::new(0, 0)), Gray8::WHITE);
+    }
+}
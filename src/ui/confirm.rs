@@ -0,0 +1,140 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::ui::popup::wrap_text;
+use crate::ui::touch::{TouchEvent, TouchEventKind};
+use embedded_graphics::{
+    mono_font::{iso_8859_1::FONT_10X20, MonoTextStyle},
+    pixelcolor::GrayColor,
+    prelude::*,
+    primitives::{PrimitiveStyle, PrimitiveStyleBuilder, Rectangle},
+    text::{Alignment, Baseline, Text, TextStyleBuilder},
+};
+
+// font cell size
+const CHAR_WIDTH: u32 = 10;
+const LINE_HEIGHT: u32 = 24;
+// space inside the border
+const PADDING: u32 = 16;
+// size of the buttons
+const BUTTON_WIDTH: u32 = 140;
+const BUTTON_HEIGHT: u32 = 50;
+
+/// What the dialog wants done after a touch event
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ConfirmAction {
+    None,
+    Yes,
+    No,
+}
+
+/// A question with yes and no buttons in the middle of the screen
+///
+/// Only its area needs a partial refresh, a tap outside it answers no.
+#[derive(Debug)]
+pub struct ConfirmDialog {
+    lines: Vec<String>,
+    yes: String,
+    no: String,
+    area: Rectangle,
+}
+
+impl ConfirmDialog {
+    /// create the dialog for a display size
+    pub fn new(question: &str, width: u32, height: u32) -> Self {
+        let box_width = width * 4 / 5;
+        let lines = wrap_text(question, ((box_width - 2 * PADDING) / CHAR_WIDTH) as usize);
+        let box_height = 3 * PADDING + lines.len() as u32 * LINE_HEIGHT + BUTTON_HEIGHT;
+        let area = Rectangle::new(
+            Point::new(
+                ((width - box_width) / 2) as i32,
+                (height.saturating_sub(box_height) / 2) as i32,
+            ),
+            Size::new(box_width, box_height),
+        );
+        Self {
+            lines,
+            yes: "Yes".to_string(),
+            no: "No".to_string(),
+            area,
+        }
+    }
+
+    /// use other labels for the buttons
+    pub fn labels(mut self, yes: &str, no: &str) -> Self {
+        self.yes = yes.to_string();
+        self.no = no.to_string();
+        self
+    }
+
+    /// the area to refresh
+    pub fn area(&self) -> Rectangle {
+        self.area
+    }
+
+    // the yes and no buttons
+    fn buttons(&self) -> (Rectangle, Rectangle) {
+        let bottom =
+            self.area.top_left.y + (self.area.size.height - PADDING - BUTTON_HEIGHT) as i32;
+        let center = self.area.center().x;
+        let size = Size::new(BUTTON_WIDTH, BUTTON_HEIGHT);
+        (
+            Rectangle::new(
+                Point::new(center - (BUTTON_WIDTH + PADDING) as i32, bottom),
+                size,
+            ),
+            Rectangle::new(Point::new(center + PADDING as i32, bottom), size),
+        )
+    }
+
+    /// handle a touch event
+    pub fn touch(&self, evt: &TouchEvent) -> ConfirmAction {
+        if evt.kind() != TouchEventKind::Tap {
+            return ConfirmAction::None;
+        }
+        let p = Point::new(evt.x() as i32, evt.y() as i32);
+        let (yes, no) = self.buttons();
+        if yes.contains(p) {
+            ConfirmAction::Yes
+        } else if no.contains(p) || !self.area.contains(p) {
+            ConfirmAction::No
+        } else {
+            ConfirmAction::None
+        }
+    }
+
+    /// draw the dialog
+    pub fn draw<D>(&self, display: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget,
+        D::Color: GrayColor,
+    {
+        let style = MonoTextStyle::new(&FONT_10X20, D::Color::BLACK);
+        let centered = TextStyleBuilder::new()
+            .alignment(Alignment::Center)
+            .baseline(Baseline::Middle)
+            .build();
+        self.area
+            .into_styled(
+                PrimitiveStyleBuilder::new()
+                    .stroke_color(D::Color::BLACK)
+                    .stroke_width(3)
+                    .fill_color(D::Color::WHITE)
+                    .build(),
+            )
+            .draw(display)?;
+        let left = self.area.top_left.x + PADDING as i32;
+        let mut y = self.area.top_left.y + PADDING as i32;
+        for line in &self.lines {
+            Text::with_baseline(line, Point::new(left, y), style, Baseline::Top).draw(display)?;
+            y += LINE_HEIGHT as i32;
+        }
+        let (yes, no) = self.buttons();
+        for (b, label) in [(yes, &self.yes), (no, &self.no)] {
+            b.into_styled(PrimitiveStyle::with_stroke(D::Color::BLACK, 2))
+                .draw(display)?;
+            Text::with_text_style(label, b.center(), style, centered).draw(display)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,613 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::ui::list_view::fit_text;
+use crate::ui::touch::{TouchEvent, TouchEventKind};
+use embedded_graphics::{
+    mono_font::{iso_8859_1::FONT_10X20, MonoTextStyle},
+    pixelcolor::GrayColor,
+    prelude::*,
+    primitives::{PrimitiveStyle, Rectangle},
+    text::{Alignment, Baseline, Text, TextStyleBuilder},
+};
+
+// height of the text field above the keys
+const FIELD_HEIGHT: u32 = 50;
+// height of a row of keys
+const KEY_HEIGHT: u32 = 70;
+// number of width units in the widest row, a normal key is 2 units
+const ROW_UNITS: u32 = 20;
+// gap between keys
+const KEY_GAP: u32 = 4;
+
+/// The keyboard layouts
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Layout {
+    Qwerty,
+    Numeric,
+    Symbols,
+}
+
+/// What a key does
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum KeyKind {
+    Char(char),
+    Shift,
+    Backspace,
+    Space,
+    Enter,
+    Cancel,
+    Layout(Layout),
+}
+
+// a key in a layout row, and its width in units
+#[derive(Debug, Copy, Clone)]
+struct KeySpec(KeyKind, u32);
+
+const fn c(ch: char) -> KeySpec {
+    KeySpec(KeyKind::Char(ch), 2)
+}
+
+const QWERTY: &[&[KeySpec]] = &[
+    &[
+        c('q'),
+        c('w'),
+        c('e'),
+        c('r'),
+        c('t'),
+        c('y'),
+        c('u'),
+        c('i'),
+        c('o'),
+        c('p'),
+    ],
+    &[
+        c('a'),
+        c('s'),
+        c('d'),
+        c('f'),
+        c('g'),
+        c('h'),
+        c('j'),
+        c('k'),
+        c('l'),
+    ],
+    &[
+        KeySpec(KeyKind::Shift, 3),
+        c('z'),
+        c('x'),
+        c('c'),
+        c('v'),
+        c('b'),
+        c('n'),
+        c('m'),
+        KeySpec(KeyKind::Backspace, 3),
+    ],
+    &[
+        KeySpec(KeyKind::Cancel, 3),
+        KeySpec(KeyKind::Layout(Layout::Numeric), 3),
+        c(','),
+        KeySpec(KeyKind::Space, 6),
+        c('.'),
+        KeySpec(KeyKind::Enter, 4),
+    ],
+];
+
+const NUMERIC: &[&[KeySpec]] = &[
+    &[
+        c('1'),
+        c('2'),
+        c('3'),
+        c('4'),
+        c('5'),
+        c('6'),
+        c('7'),
+        c('8'),
+        c('9'),
+        c('0'),
+    ],
+    &[
+        c('-'),
+        c('/'),
+        c(':'),
+        c(';'),
+        c('('),
+        c(')'),
+        c('$'),
+        c('&'),
+        c('@'),
+        c('"'),
+    ],
+    &[
+        KeySpec(KeyKind::Layout(Layout::Symbols), 3),
+        c('.'),
+        c(','),
+        c('?'),
+        c('!'),
+        c('\''),
+        c('+'),
+        KeySpec(KeyKind::Backspace, 3),
+    ],
+    &[
+        KeySpec(KeyKind::Cancel, 3),
+        KeySpec(KeyKind::Layout(Layout::Qwerty), 3),
+        KeySpec(KeyKind::Space, 10),
+        KeySpec(KeyKind::Enter, 4),
+    ],
+];
+
+const SYMBOLS: &[&[KeySpec]] = &[
+    &[
+        c('['),
+        c(']'),
+        c('{'),
+        c('}'),
+        c('#'),
+        c('%'),
+        c('^'),
+        c('*'),
+        c('+'),
+        c('='),
+    ],
+    &[
+        c('_'),
+        c('\\'),
+        c('|'),
+        c('~'),
+        c('<'),
+        c('>'),
+        c('£'),
+        c('¥'),
+        c('§'),
+    ],
+    &[
+        KeySpec(KeyKind::Layout(Layout::Numeric), 3),
+        c('.'),
+        c(','),
+        c('?'),
+        c('!'),
+        c('`'),
+        c('°'),
+        KeySpec(KeyKind::Backspace, 3),
+    ],
+    &[
+        KeySpec(KeyKind::Cancel, 3),
+        KeySpec(KeyKind::Layout(Layout::Qwerty), 3),
+        KeySpec(KeyKind::Space, 10),
+        KeySpec(KeyKind::Enter, 4),
+    ],
+];
+
+/// the accented variants of a character, offered when its key is held
+pub fn accents(ch: char) -> &'static [char] {
+    match ch {
+        'a' => &['à', 'á', 'â', 'ä', 'ã', 'å', 'æ'],
+        'c' => &['ç'],
+        'e' => &['è', 'é', 'ê', 'ë'],
+        'i' => &['ì', 'í', 'î', 'ï'],
+        'n' => &['ñ'],
+        'o' => &['ò', 'ó', 'ô', 'ö', 'õ', 'ø'],
+        's' => &['ß'],
+        'u' => &['ù', 'ú', 'û', 'ü'],
+        'y' => &['ý', 'ÿ'],
+        'A' => &['À', 'Á', 'Â', 'Ä', 'Ã', 'Å', 'Æ'],
+        'C' => &['Ç'],
+        'E' => &['È', 'É', 'Ê', 'Ë'],
+        'I' => &['Ì', 'Í', 'Î', 'Ï'],
+        'N' => &['Ñ'],
+        'O' => &['Ò', 'Ó', 'Ô', 'Ö', 'Õ', 'Ø'],
+        'U' => &['Ù', 'Ú', 'Û', 'Ü'],
+        'Y' => &['Ý'],
+        _ => &[],
+    }
+}
+
+/// A key placed on the screen
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Key {
+    pub kind: KeyKind,
+    pub rect: Rectangle,
+}
+
+/// place the keys of a layout, the keyboard is at the bottom of the screen
+pub fn layout_keys(layout: Layout, width: u32, height: u32) -> Vec<Key> {
+    let rows = match layout {
+        Layout::Qwerty => QWERTY,
+        Layout::Numeric => NUMERIC,
+        Layout::Symbols => SYMBOLS,
+    };
+    let unit = width / ROW_UNITS;
+    let top = height.saturating_sub(rows.len() as u32 * KEY_HEIGHT);
+    let mut keys = Vec::new();
+    for (r, row) in rows.iter().enumerate() {
+        // center the row
+        let units: u32 = row.iter().map(|k| k.1).sum();
+        let mut x = (width - units * unit) / 2;
+        let y = top + r as u32 * KEY_HEIGHT;
+        for spec in row.iter() {
+            let w = spec.1 * unit;
+            keys.push(Key {
+                kind: spec.0,
+                rect: Rectangle::new(
+                    Point::new((x + KEY_GAP / 2) as i32, (y + KEY_GAP / 2) as i32),
+                    Size::new(w - KEY_GAP, KEY_HEIGHT - KEY_GAP),
+                ),
+            });
+            x += w;
+        }
+    }
+    keys
+}
+
+/// the key at a user coordinate
+pub fn key_at(keys: &[Key], x: u32, y: u32) -> Option<Key> {
+    let p = Point::new(x as i32, y as i32);
+    keys.iter().find(|k| k.rect.contains(p)).copied()
+}
+
+/// What the keyboard wants done after a touch event
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyboardAction {
+    None,
+    Redraw,
+    Done(String),
+    Cancel,
+}
+
+/// An on-screen keyboard with a one line text field
+///
+/// Taps type keys, holding a letter shows its accented variants, which
+/// are then picked with a tap
+#[derive(Debug)]
+pub struct Keyboard {
+    prompt: String,
+    text: String,
+    max_len: usize,
+    masked: bool,
+    width: u32,
+    height: u32,
+    layout: Layout,
+    shift: bool,
+    keys: Vec<Key>,
+    accent_keys: Vec<Key>,
+}
+
+impl Keyboard {
+    /// create the keyboard for a display size
+    pub fn new(prompt: &str, width: u32, height: u32) -> Self {
+        Self {
+            prompt: prompt.to_string(),
+            text: String::new(),
+            max_len: 128,
+            masked: false,
+            width,
+            height,
+            layout: Layout::Qwerty,
+            shift: false,
+            keys: layout_keys(Layout::Qwerty, width, height),
+            accent_keys: Vec::new(),
+        }
+    }
+
+    /// start with some text, for renames and edits
+    pub fn with_text(mut self, text: &str) -> Self {
+        self.text = text.chars().take(self.max_len).collect();
+        self
+    }
+
+    /// limit the text length in characters
+    pub fn max_len(mut self, max_len: usize) -> Self {
+        self.max_len = max_len;
+        self
+    }
+
+    /// hide the typed text, for passwords
+    pub fn masked(mut self, masked: bool) -> Self {
+        self.masked = masked;
+        self
+    }
+
+    /// the text typed so far
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// the current layout
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    /// the keys of the current layout
+    pub fn keys(&self) -> &[Key] {
+        &self.keys
+    }
+
+    /// area of the screen used by the keyboard and text field, for partial refresh
+    pub fn area(&self) -> Rectangle {
+        let top = self
+            .keys
+            .first()
+            .map(|k| k.rect.top_left.y as u32)
+            .unwrap_or(self.height)
+            .saturating_sub(FIELD_HEIGHT + KEY_GAP);
+        Rectangle::new(
+            Point::new(0, top as i32),
+            Size::new(self.width, self.height - top),
+        )
+    }
+
+    /// switch to another layout
+    pub fn set_layout(&mut self, layout: Layout) {
+        self.layout = layout;
+        self.shift = false;
+        self.keys = layout_keys(layout, self.width, self.height);
+    }
+
+    // the character typed by a key, with shift applied
+    fn key_char(&self, ch: char) -> char {
+        if self.shift {
+            ch.to_uppercase().next().unwrap_or(ch)
+        } else {
+            ch
+        }
+    }
+
+    // add a character to the text
+    fn push(&mut self, ch: char) {
+        if self.text.chars().count() < self.max_len {
+            self.text.push(ch);
+        }
+        self.shift = false;
+    }
+
+    // place the accent keys above the held key
+    fn show_accents(&mut self, key: &Key, ch: char) {
+        let variants = accents(ch);
+        let w = key.rect.size.width + KEY_GAP;
+        let total = w * variants.len() as u32;
+        let left = (key.rect.top_left.x as u32).min(self.width.saturating_sub(total));
+        let top = key.rect.top_left.y - KEY_HEIGHT as i32;
+        self.accent_keys = variants
+            .iter()
+            .enumerate()
+            .map(|(i, v)| Key {
+                kind: KeyKind::Char(*v),
+                rect: Rectangle::new(Point::new((left + i as u32 * w) as i32, top), key.rect.size),
+            })
+            .collect();
+    }
+
+    /// handle a touch event
+    pub fn touch(&mut self, evt: &TouchEvent) -> KeyboardAction {
+        if !self.accent_keys.is_empty() {
+            // waiting for an accent to be picked, anything else closes the popup,
+            // the finger that held the key may still move or lift
+            if matches!(evt.kind(), TouchEventKind::Release | TouchEventKind::Drag) {
+                return KeyboardAction::None;
+            }
+            let picked = key_at(&self.accent_keys, evt.x(), evt.y());
+            self.accent_keys.clear();
+            if let (
+                TouchEventKind::Tap,
+                Some(Key {
+                    kind: KeyKind::Char(ch),
+                    ..
+                }),
+            ) = (evt.kind(), picked)
+            {
+                self.push(ch);
+            }
+            return KeyboardAction::Redraw;
+        }
+        let key = match key_at(&self.keys, evt.x(), evt.y()) {
+            Some(key) => key,
+            None => return KeyboardAction::None,
+        };
+        match (evt.kind(), key.kind) {
+            (TouchEventKind::Hold, KeyKind::Char(ch)) => {
+                let ch = self.key_char(ch);
+                if accents(ch).is_empty() {
+                    KeyboardAction::None
+                } else {
+                    self.show_accents(&key, ch);
+                    KeyboardAction::Redraw
+                }
+            }
+            (TouchEventKind::Tap, KeyKind::Char(ch)) => {
+                let ch = self.key_char(ch);
+                self.push(ch);
+                KeyboardAction::Redraw
+            }
+            (TouchEventKind::Tap, KeyKind::Space) => {
+                self.push(' ');
+                KeyboardAction::Redraw
+            }
+            (TouchEventKind::Tap, KeyKind::Backspace) => {
+                self.text.pop();
+                KeyboardAction::Redraw
+            }
+            (TouchEventKind::Tap, KeyKind::Shift) => {
+                self.shift = !self.shift;
+                KeyboardAction::Redraw
+            }
+            (TouchEventKind::Tap, KeyKind::Layout(layout)) => {
+                self.set_layout(layout);
+                KeyboardAction::Redraw
+            }
+            (TouchEventKind::Tap, KeyKind::Enter) => KeyboardAction::Done(self.text.clone()),
+            (TouchEventKind::Tap, KeyKind::Cancel) => KeyboardAction::Cancel,
+            _ => KeyboardAction::None,
+        }
+    }
+
+    // the label drawn on a key
+    fn label(&self, kind: KeyKind) -> String {
+        match kind {
+            KeyKind::Char(ch) => self.key_char(ch).to_string(),
+            KeyKind::Shift => if self.shift { "SHIFT" } else { "Shift" }.to_string(),
+            KeyKind::Backspace => "Del".to_string(),
+            KeyKind::Space => "space".to_string(),
+            KeyKind::Enter => "OK".to_string(),
+            KeyKind::Cancel => "Esc".to_string(),
+            KeyKind::Layout(Layout::Qwerty) => "ABC".to_string(),
+            KeyKind::Layout(Layout::Numeric) => "?123".to_string(),
+            KeyKind::Layout(Layout::Symbols) => "#+=".to_string(),
+        }
+    }
+
+    /// draw the text field and keys
+    pub fn draw<D>(&self, display: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget,
+        D::Color: GrayColor,
+    {
+        let black = MonoTextStyle::new(&FONT_10X20, D::Color::BLACK);
+        let white = MonoTextStyle::new(&FONT_10X20, D::Color::WHITE);
+        let centered = TextStyleBuilder::new()
+            .alignment(Alignment::Center)
+            .baseline(Baseline::Middle)
+            .build();
+        let area = self.area();
+        area.into_styled(PrimitiveStyle::with_fill(D::Color::WHITE))
+            .draw(display)?;
+
+        // the text field, showing the end of the text if it is long
+        let field = Rectangle::new(
+            area.top_left + Point::new(KEY_GAP as i32, 0),
+            Size::new(self.width - 2 * KEY_GAP, FIELD_HEIGHT),
+        );
+        field
+            .into_styled(PrimitiveStyle::with_stroke(D::Color::BLACK, 2))
+            .draw(display)?;
+        let shown = if self.masked {
+            "*".repeat(self.text.chars().count())
+        } else {
+            self.text.clone()
+        };
+        let max_chars = ((field.size.width - 20) / 10) as usize;
+        let skip =
+            (shown.chars().count() + self.prompt.chars().count() + 3).saturating_sub(max_chars);
+        let line = format!(
+            "{}: {}_",
+            self.prompt,
+            shown.chars().skip(skip).collect::<String>()
+        );
+        Text::with_baseline(
+            &fit_text(&line, field.size.width - 20),
+            field.top_left + Point::new(10, (FIELD_HEIGHT / 2) as i32),
+            black,
+            Baseline::Middle,
+        )
+        .draw(display)?;
+
+        for key in self.keys.iter().chain(self.accent_keys.iter()) {
+            let popup = self.accent_keys.contains(key);
+            let style = if popup || (key.kind == KeyKind::Shift && self.shift) {
+                PrimitiveStyle::with_fill(D::Color::BLACK)
+            } else {
+                PrimitiveStyle::with_stroke(D::Color::BLACK, 2)
+            };
+            key.rect.into_styled(style).draw(display)?;
+            let text_style = if style.fill_color.is_some() {
+                white
+            } else {
+                black
+            };
+            Text::with_text_style(
+                &self.label(key.kind),
+                key.rect.center(),
+                text_style,
+                centered,
+            )
+            .draw(display)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const W: u32 = 758;
+    const H: u32 = 1024;
+
+    fn find(keys: &[Key], kind: KeyKind) -> Key {
+        *keys.iter().find(|k| k.kind == kind).unwrap()
+    }
+
+    #[test]
+    fn keys_fit_the_screen_without_overlap() {
+        let screen = Rectangle::new(Point::zero(), Size::new(W, H));
+        for layout in [Layout::Qwerty, Layout::Numeric, Layout::Symbols] {
+            let keys = layout_keys(layout, W, H);
+            for (i, a) in keys.iter().enumerate() {
+                assert!(screen.contains(a.rect.bottom_right().unwrap()), "{:?}", a);
+                assert!(screen.contains(a.rect.top_left), "{:?}", a);
+                for b in &keys[i + 1..] {
+                    assert!(
+                        a.rect.intersection(&b.rect).is_zero_sized(),
+                        "{:?} {:?}",
+                        a,
+                        b
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn characters_are_in_the_font() {
+        // FONT_10X20 is iso 8859-1
+        for rows in [QWERTY, NUMERIC, SYMBOLS] {
+            for spec in rows.iter().flat_map(|r| r.iter()) {
+                if let KeyKind::Char(ch) = spec.0 {
+                    assert!((ch as u32) < 0x100, "{}", ch);
+                    for a in accents(ch)
+                        .iter()
+                        .chain(accents(ch.to_ascii_uppercase()).iter())
+                    {
+                        assert!((*a as u32) < 0x100, "{}", a);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn key_at_finds_keys_not_gaps() {
+        let keys = layout_keys(Layout::Qwerty, W, H);
+        let q = find(&keys, KeyKind::Char('q'));
+        let c = q.rect.center();
+        assert_eq!(key_at(&keys, c.x as u32, c.y as u32), Some(q));
+        // the gap between q and w
+        let gap = q.rect.top_left.x as u32 + q.rect.size.width;
+        assert_eq!(key_at(&keys, gap, c.y as u32), None);
+        assert_eq!(key_at(&keys, c.x as u32, 0), None);
+    }
+
+    #[test]
+    fn drag_keeps_the_accent_popup() {
+        let mut kb = Keyboard::new("text", W, H);
+        let e = kb.keys()[2];
+        assert_eq!(e.kind, KeyKind::Char('e'));
+        let c = e.rect.center();
+        let at = |kind, p: Point| TouchEvent::with_position(kind, p.x as u32, p.y as u32);
+        assert_eq!(
+            kb.touch(&at(TouchEventKind::Hold, c)),
+            KeyboardAction::Redraw
+        );
+        let accent = kb.accent_keys[1];
+        let a = accent.rect.center();
+        assert_eq!(kb.touch(&at(TouchEventKind::Drag, a)), KeyboardAction::None);
+        assert_eq!(
+            kb.touch(&at(TouchEventKind::Release, a)),
+            KeyboardAction::None
+        );
+        assert_eq!(
+            kb.touch(&at(TouchEventKind::Tap, a)),
+            KeyboardAction::Redraw
+        );
+        assert_eq!(kb.text(), "é");
+    }
+}
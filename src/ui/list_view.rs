@@ -0,0 +1,204 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::ui::touch::{TouchEvent, TouchEventKind};
+use embedded_graphics::{
+    mono_font::{iso_8859_1::FONT_10X20, MonoTextStyle},
+    pixelcolor::GrayColor,
+    prelude::*,
+    primitives::{Line, PrimitiveStyle, Rectangle},
+    text::{Baseline, Text},
+};
+
+// height of a row, and the title bar
+const ROW_HEIGHT: u32 = 40;
+// left margin, and indent per level
+const MARGIN: u32 = 10;
+const INDENT: u32 = 20;
+// width of a character in the font
+const CHAR_WIDTH: u32 = 10;
+
+/// A row to show in the list
+#[derive(Debug, Clone)]
+pub struct ListRow<'a> {
+    pub text: &'a str,
+    pub indent: u8,
+    pub marked: bool,
+}
+
+impl<'a> ListRow<'a> {
+    /// create a plain row
+    pub fn new(text: &'a str) -> Self {
+        Self {
+            text,
+            indent: 0,
+            marked: false,
+        }
+    }
+}
+
+/// What the list wants done after a touch event
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ListAction {
+    None,
+    Redraw,
+    Select(usize),
+    Close,
+}
+
+/// A full screen list, paged with swipes
+///
+/// A tap on the title bar closes the list, a tap on a row selects it
+#[derive(Debug)]
+pub struct ListView {
+    title: String,
+    width: u32,
+    height: u32,
+    page: usize,
+}
+
+impl ListView {
+    /// create the list for a display size
+    pub fn new(title: &str, width: u32, height: u32) -> Self {
+        Self {
+            title: title.to_string(),
+            width,
+            height,
+            page: 0,
+        }
+    }
+
+    /// number of rows shown on a page
+    pub fn rows_per_page(&self) -> usize {
+        ((self.height / ROW_HEIGHT).max(2) - 1) as usize
+    }
+
+    /// number of pages needed for `count` rows
+    pub fn page_count(&self, count: usize) -> usize {
+        ((count + self.rows_per_page() - 1) / self.rows_per_page()).max(1)
+    }
+
+    /// the current page
+    pub fn page(&self) -> usize {
+        self.page
+    }
+
+    /// show the page holding row `index`
+    pub fn show_row(&mut self, index: usize) {
+        self.page = index / self.rows_per_page();
+    }
+
+    /// row index at user coordinate y, if any
+    pub fn row_at(&self, y: u32, count: usize) -> Option<usize> {
+        if y < ROW_HEIGHT {
+            return None;
+        }
+        let index = self.page * self.rows_per_page() + ((y - ROW_HEIGHT) / ROW_HEIGHT) as usize;
+        if index < count && (y - ROW_HEIGHT) / ROW_HEIGHT < self.rows_per_page() as u32 {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    /// handle a touch event, `count` is the number of rows
+    pub fn touch(&mut self, evt: &TouchEvent, count: usize) -> ListAction {
+        match evt.kind() {
+            TouchEventKind::SwipeLeft => {
+                if self.page + 1 < self.page_count(count) {
+                    self.page += 1;
+                    ListAction::Redraw
+                } else {
+                    ListAction::None
+                }
+            }
+            TouchEventKind::SwipeRight => {
+                if self.page > 0 {
+                    self.page -= 1;
+                    ListAction::Redraw
+                } else {
+                    ListAction::None
+                }
+            }
+            TouchEventKind::Tap => {
+                if evt.y() < ROW_HEIGHT {
+                    ListAction::Close
+                } else {
+                    match self.row_at(evt.y(), count) {
+                        Some(index) => ListAction::Select(index),
+                        None => ListAction::None,
+                    }
+                }
+            }
+            _ => ListAction::None,
+        }
+    }
+
+    /// draw the current page of rows
+    pub fn draw<D>(&self, display: &mut D, rows: &[ListRow]) -> Result<(), D::Error>
+    where
+        D: DrawTarget,
+        D::Color: GrayColor,
+    {
+        let style = MonoTextStyle::new(&FONT_10X20, D::Color::BLACK);
+        let line_style = PrimitiveStyle::with_stroke(D::Color::BLACK, 1);
+        display.clear(D::Color::WHITE)?;
+
+        // title bar, with the page number
+        let title = format!(
+            "{}  ({}/{})",
+            self.title,
+            self.page + 1,
+            self.page_count(rows.len())
+        );
+        Text::with_baseline(
+            &fit_text(&title, self.width - 2 * MARGIN),
+            Point::new(MARGIN as i32, (ROW_HEIGHT / 2) as i32),
+            style,
+            Baseline::Middle,
+        )
+        .draw(display)?;
+        Line::new(
+            Point::new(0, ROW_HEIGHT as i32 - 1),
+            Point::new(self.width as i32 - 1, ROW_HEIGHT as i32 - 1),
+        )
+        .into_styled(line_style)
+        .draw(display)?;
+
+        let first = self.page * self.rows_per_page();
+        for (i, row) in rows
+            .iter()
+            .skip(first)
+            .take(self.rows_per_page())
+            .enumerate()
+        {
+            let top = ((i as u32 + 1) * ROW_HEIGHT) as i32;
+            let left = MARGIN + row.indent as u32 * INDENT;
+            if row.marked {
+                Rectangle::new(Point::new(0, top + 4), Size::new(4, ROW_HEIGHT - 8))
+                    .into_styled(PrimitiveStyle::with_fill(D::Color::BLACK))
+                    .draw(display)?;
+            }
+            Text::with_baseline(
+                &fit_text(row.text, self.width.saturating_sub(left + MARGIN)),
+                Point::new(left as i32, top + (ROW_HEIGHT / 2) as i32),
+                style,
+                Baseline::Middle,
+            )
+            .draw(display)?;
+        }
+        Ok(())
+    }
+}
+
+/// truncate text to fit in `width` pixels
+pub fn fit_text(text: &str, width: u32) -> String {
+    let max_chars = (width / CHAR_WIDTH) as usize;
+    if text.chars().count() <= max_chars {
+        text.to_string()
+    } else {
+        let mut s: String = text.chars().take(max_chars.saturating_sub(3)).collect();
+        s.push_str("...");
+        s
+    }
+}
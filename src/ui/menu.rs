@@ -0,0 +1,88 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::ui::touch::{TouchEvent, TouchEventKind};
+use embedded_graphics::{
+    mono_font::{iso_8859_1::FONT_10X20, MonoTextStyle},
+    pixelcolor::GrayColor,
+    prelude::*,
+    primitives::{PrimitiveStyle, Rectangle},
+    text::{Alignment, Baseline, Text, TextStyleBuilder},
+};
+
+// height of the menu bar
+const BAR_HEIGHT: u32 = 60;
+
+/// What the menu wants done after a touch event
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MenuAction<T> {
+    None,
+    Select(T),
+    Close,
+}
+
+/// A bar of buttons across the top of the screen
+#[derive(Debug)]
+pub struct Menu<T> {
+    items: Vec<(String, T)>,
+    width: u32,
+}
+
+impl<T: Copy> Menu<T> {
+    /// create the menu for a display width
+    pub fn new(items: &[(&str, T)], width: u32) -> Self {
+        Self {
+            items: items.iter().map(|(l, t)| (l.to_string(), *t)).collect(),
+            width,
+        }
+    }
+
+    /// area of the menu bar, for partial refresh
+    pub fn area(&self) -> Rectangle {
+        Rectangle::new(Point::zero(), Size::new(self.width, BAR_HEIGHT))
+    }
+
+    // area of button `i`
+    fn button(&self, i: usize) -> Rectangle {
+        let w = self.width / self.items.len().max(1) as u32;
+        Rectangle::new(
+            Point::new((i as u32 * w) as i32, 0),
+            Size::new(w, BAR_HEIGHT),
+        )
+    }
+
+    /// handle a touch event, a tap below the bar closes the menu
+    pub fn touch(&self, evt: &TouchEvent) -> MenuAction<T> {
+        if evt.kind() != TouchEventKind::Tap {
+            return MenuAction::None;
+        }
+        let p = Point::new(evt.x() as i32, evt.y() as i32);
+        match (0..self.items.len()).find(|i| self.button(*i).contains(p)) {
+            Some(i) => MenuAction::Select(self.items[i].1),
+            None => MenuAction::Close,
+        }
+    }
+
+    /// draw the menu bar
+    pub fn draw<D>(&self, display: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget,
+        D::Color: GrayColor,
+    {
+        let style = MonoTextStyle::new(&FONT_10X20, D::Color::BLACK);
+        let centered = TextStyleBuilder::new()
+            .alignment(Alignment::Center)
+            .baseline(Baseline::Middle)
+            .build();
+        self.area()
+            .into_styled(PrimitiveStyle::with_fill(D::Color::WHITE))
+            .draw(display)?;
+        for (i, (label, _)) in self.items.iter().enumerate() {
+            let b = self.button(i);
+            b.into_styled(PrimitiveStyle::with_stroke(D::Color::BLACK, 2))
+                .draw(display)?;
+            Text::with_text_style(label, b.center(), style, centered).draw(display)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,268 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::config::settings::OpdsCatalog;
+use crate::net::opds::{OpdsEntry, OpdsFeed};
+use crate::ui::keyboard::{Keyboard, KeyboardAction};
+use crate::ui::list_view::{ListAction, ListRow, ListView};
+use crate::ui::popup::{Popup, PopupAction};
+use crate::ui::touch::{TouchEvent, TouchEventKind};
+use embedded_graphics::{pixelcolor::GrayColor, prelude::*};
+
+// part of the screen the book details cover
+const DETAILS_PERCENT: u32 = 50;
+
+/// What the catalog browser wants done, the network work is left to the caller
+#[derive(Debug, Clone, PartialEq)]
+pub enum OpdsAction {
+    None,
+    Redraw,
+    /// fetch a feed and `push_feed` it, the catalog index is for its login
+    Open(usize, String),
+    /// search the current feed and `push_feed` the results
+    Search(String),
+    /// download an entry of the current feed
+    Download(OpdsEntry),
+    Close,
+}
+
+// a row of the list
+#[derive(Debug, Clone, PartialEq)]
+enum Row {
+    Catalog(usize),
+    Search,
+    Entry(usize),
+    More,
+}
+
+/// Browses OPDS catalogs, from the list of catalogs down to the books
+///
+/// A tap on the title bar goes back a feed, a tap on a book downloads it
+/// and holding a book shows its details.
+#[derive(Debug)]
+pub struct OpdsBrowser {
+    catalogs: Vec<OpdsCatalog>,
+    // the catalog being browsed
+    catalog: usize,
+    // feeds visited, with the list page they were left on
+    stack: Vec<(OpdsFeed, usize)>,
+    list: ListView,
+    rows: Vec<Row>,
+    labels: Vec<String>,
+    keyboard: Option<Keyboard>,
+    details: Option<Popup>,
+    width: u32,
+    height: u32,
+}
+
+impl OpdsBrowser {
+    /// create the browser for the catalogs in the settings
+    pub fn new(catalogs: &[OpdsCatalog], width: u32, height: u32) -> Self {
+        let mut browser = Self {
+            catalogs: catalogs.to_vec(),
+            catalog: 0,
+            stack: Vec::new(),
+            list: ListView::new("Catalogs", width, height),
+            rows: Vec::new(),
+            labels: Vec::new(),
+            keyboard: None,
+            details: None,
+            width,
+            height,
+        };
+        browser.update_rows();
+        browser
+    }
+
+    /// the catalog being browsed, for its login
+    pub fn catalog(&self) -> Option<&OpdsCatalog> {
+        self.catalogs.get(self.catalog)
+    }
+
+    /// the feed shown
+    pub fn feed(&self) -> Option<&OpdsFeed> {
+        self.stack.last().map(|(f, _)| f)
+    }
+
+    /// show a feed fetched for an `Open` or a `Search`
+    pub fn push_feed(&mut self, feed: OpdsFeed) {
+        if let Some((_, page)) = self.stack.last_mut() {
+            *page = self.list.page();
+        }
+        self.stack.push((feed, 0));
+        self.update_rows();
+    }
+
+    // go back a feed, false at the catalog list
+    fn back(&mut self) -> bool {
+        if self.stack.pop().is_none() {
+            return false;
+        }
+        self.update_rows();
+        if let Some(&(_, page)) = self.stack.last() {
+            self.list.show_row(page * self.list.rows_per_page());
+        }
+        true
+    }
+
+    // fill the rows for the feed shown
+    fn update_rows(&mut self) {
+        self.rows.clear();
+        self.labels.clear();
+        match self.stack.last() {
+            None => {
+                self.list = ListView::new("Catalogs", self.width, self.height);
+                for (i, c) in self.catalogs.iter().enumerate() {
+                    self.rows.push(Row::Catalog(i));
+                    self.labels.push(c.name.clone());
+                }
+            }
+            Some((feed, _)) => {
+                self.list = ListView::new(&feed.title, self.width, self.height);
+                if feed.search().is_some() {
+                    self.rows.push(Row::Search);
+                    self.labels.push("Search...".to_string());
+                }
+                for (i, e) in feed.entries.iter().enumerate() {
+                    self.rows.push(Row::Entry(i));
+                    self.labels.push(entry_label(e));
+                }
+                if feed.next().is_some() {
+                    self.rows.push(Row::More);
+                    self.labels.push("More...".to_string());
+                }
+            }
+        }
+    }
+
+    /// handle a touch event
+    pub fn touch(&mut self, evt: &TouchEvent) -> OpdsAction {
+        if let Some(keyboard) = &mut self.keyboard {
+            return match keyboard.touch(evt) {
+                KeyboardAction::None => OpdsAction::None,
+                KeyboardAction::Redraw => OpdsAction::Redraw,
+                KeyboardAction::Done(terms) => {
+                    self.keyboard = None;
+                    if terms.trim().is_empty() {
+                        OpdsAction::Redraw
+                    } else {
+                        OpdsAction::Search(terms.trim().to_string())
+                    }
+                }
+                KeyboardAction::Cancel => {
+                    self.keyboard = None;
+                    OpdsAction::Redraw
+                }
+            };
+        }
+        if let Some(details) = &mut self.details {
+            return match details.touch(evt) {
+                PopupAction::None => OpdsAction::None,
+                PopupAction::Redraw => OpdsAction::Redraw,
+                PopupAction::Close => {
+                    self.details = None;
+                    OpdsAction::Redraw
+                }
+            };
+        }
+        if evt.kind() == TouchEventKind::Hold {
+            let row = self.list.row_at(evt.y(), self.rows.len());
+            if let (Some(Row::Entry(i)), Some(feed)) = (row.map(|r| &self.rows[r]), self.feed()) {
+                let entry = &feed.entries[*i];
+                let text = format!("{}\n\n{}", entry.authors.join(", "), entry.summary);
+                self.details = Some(Popup::new(
+                    &entry.title,
+                    &text,
+                    self.width,
+                    self.height,
+                    DETAILS_PERCENT,
+                ));
+                return OpdsAction::Redraw;
+            }
+            return OpdsAction::None;
+        }
+        match self.list.touch(evt, self.rows.len()) {
+            ListAction::None => OpdsAction::None,
+            ListAction::Redraw => OpdsAction::Redraw,
+            ListAction::Close => {
+                if self.back() {
+                    OpdsAction::Redraw
+                } else {
+                    OpdsAction::Close
+                }
+            }
+            ListAction::Select(i) => self.select(i),
+        }
+    }
+
+    // act on a selected row
+    fn select(&mut self, index: usize) -> OpdsAction {
+        match (self.rows[index].clone(), self.feed()) {
+            (Row::Catalog(c), _) => {
+                self.catalog = c;
+                OpdsAction::Open(c, self.catalogs[c].url.clone())
+            }
+            (Row::Search, _) => {
+                self.keyboard = Some(Keyboard::new("Search", self.width, self.height));
+                OpdsAction::Redraw
+            }
+            (Row::More, Some(feed)) => match feed.next() {
+                Some(url) => OpdsAction::Open(self.catalog, url.to_string()),
+                None => OpdsAction::None,
+            },
+            (Row::Entry(e), Some(feed)) => {
+                let entry = &feed.entries[e];
+                if let Some(link) = entry.navigation() {
+                    OpdsAction::Open(self.catalog, link.href.clone())
+                } else if entry.acquisition().is_some() {
+                    OpdsAction::Download(entry.clone())
+                } else {
+                    OpdsAction::None
+                }
+            }
+            _ => OpdsAction::None,
+        }
+    }
+
+    /// draw the list, the search keyboard or the book details
+    pub fn draw<D>(&self, display: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget,
+        D::Color: GrayColor,
+    {
+        if let Some(keyboard) = &self.keyboard {
+            display.clear(D::Color::WHITE)?;
+            return keyboard.draw(display);
+        }
+        let rows: Vec<ListRow> = self
+            .labels
+            .iter()
+            .zip(self.rows.iter())
+            .map(|(l, r)| ListRow {
+                text: l,
+                indent: 0,
+                marked: matches!(r, Row::Entry(i) if self.is_book(*i)),
+            })
+            .collect();
+        self.list.draw(display, &rows)?;
+        if let Some(details) = &self.details {
+            details.draw(display)?;
+        }
+        Ok(())
+    }
+
+    // can the entry be downloaded
+    fn is_book(&self, index: usize) -> bool {
+        self.feed()
+            .map(|f| f.entries[index].acquisition().is_some())
+            .unwrap_or(false)
+    }
+}
+
+// the list label of an entry, the title and first author
+fn entry_label(entry: &OpdsEntry) -> String {
+    match entry.authors.first() {
+        Some(author) => format!("{} - {}", entry.title, author),
+        None => entry.title.clone(),
+    }
+}
@@ -0,0 +1,160 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::ui::list_view::fit_text;
+use crate::ui::touch::{TouchEvent, TouchEventKind};
+use embedded_graphics::{
+    mono_font::{iso_8859_1::FONT_10X20, MonoTextStyle},
+    pixelcolor::GrayColor,
+    prelude::*,
+    primitives::{Line, PrimitiveStyle, Rectangle},
+    text::{Baseline, Text},
+};
+
+// font cell size
+const CHAR_WIDTH: u32 = 10;
+const LINE_HEIGHT: u32 = 24;
+// space inside the border
+const PADDING: u32 = 12;
+
+/// What the popup wants done after a touch event
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PopupAction {
+    None,
+    Redraw,
+    Close,
+}
+
+/// A text overlay on the bottom part of the page
+///
+/// Only its area needs a partial refresh. A tap on it shows the next part
+/// of a long text, a tap outside or on the last part closes it.
+#[derive(Debug)]
+pub struct Popup {
+    title: String,
+    lines: Vec<String>,
+    area: Rectangle,
+    page: usize,
+}
+
+impl Popup {
+    /// create a popup covering the bottom `percent` of the display
+    pub fn new(title: &str, text: &str, width: u32, height: u32, percent: u32) -> Self {
+        let h = height * percent.min(100) / 100;
+        let area = Rectangle::new(Point::new(0, (height - h) as i32), Size::new(width, h));
+        let max_chars = ((width - 2 * PADDING) / CHAR_WIDTH) as usize;
+        Self {
+            title: title.to_string(),
+            lines: wrap_text(text, max_chars),
+            area,
+            page: 0,
+        }
+    }
+
+    /// the area to refresh
+    pub fn area(&self) -> Rectangle {
+        self.area
+    }
+
+    // lines of text that fit below the title
+    fn lines_per_page(&self) -> usize {
+        ((self.area.size.height.saturating_sub(2 * PADDING) / LINE_HEIGHT).max(2) - 1) as usize
+    }
+
+    /// handle a touch event
+    pub fn touch(&mut self, evt: &TouchEvent) -> PopupAction {
+        match evt.kind() {
+            TouchEventKind::Tap => {
+                let inside = self
+                    .area
+                    .contains(Point::new(evt.x() as i32, evt.y() as i32));
+                if inside && (self.page + 1) * self.lines_per_page() < self.lines.len() {
+                    self.page += 1;
+                    PopupAction::Redraw
+                } else {
+                    PopupAction::Close
+                }
+            }
+            _ => PopupAction::None,
+        }
+    }
+
+    /// draw the popup
+    pub fn draw<D>(&self, display: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget,
+        D::Color: GrayColor,
+    {
+        let style = MonoTextStyle::new(&FONT_10X20, D::Color::BLACK);
+        self.area
+            .into_styled(PrimitiveStyle::with_fill(D::Color::WHITE))
+            .draw(display)?;
+        let top = self.area.top_left.y;
+        let right = self.area.top_left.x + self.area.size.width as i32 - 1;
+        Line::new(
+            Point::new(self.area.top_left.x, top),
+            Point::new(right, top),
+        )
+        .into_styled(PrimitiveStyle::with_stroke(D::Color::BLACK, 3))
+        .draw(display)?;
+
+        let left = self.area.top_left.x + PADDING as i32;
+        let width = self.area.size.width - 2 * PADDING;
+        let pages = (self.lines.len() + self.lines_per_page() - 1) / self.lines_per_page();
+        let title = if pages > 1 {
+            format!("{}  ({}/{})", self.title, self.page + 1, pages)
+        } else {
+            self.title.clone()
+        };
+        let mut y = top + PADDING as i32;
+        Text::with_baseline(
+            &fit_text(&title, width),
+            Point::new(left, y),
+            style,
+            Baseline::Top,
+        )
+        .draw(display)?;
+        for line in self
+            .lines
+            .iter()
+            .skip(self.page * self.lines_per_page())
+            .take(self.lines_per_page())
+        {
+            y += LINE_HEIGHT as i32;
+            Text::with_baseline(line, Point::new(left, y), style, Baseline::Top).draw(display)?;
+        }
+        Ok(())
+    }
+}
+
+/// wrap text into lines of at most `max_chars` characters, on word boundaries
+pub fn wrap_text(text: &str, max_chars: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    for para in text.lines() {
+        let mut line = String::new();
+        let mut len = 0;
+        for word in para.split_whitespace() {
+            let wlen = word.chars().count();
+            if len > 0 && len + 1 + wlen > max_chars {
+                lines.push(std::mem::take(&mut line));
+                len = 0;
+            }
+            if len > 0 {
+                line.push(' ');
+                len += 1;
+            }
+            // words longer than a line are split
+            let mut chars = word.chars().peekable();
+            while chars.peek().is_some() {
+                if len == max_chars {
+                    lines.push(std::mem::take(&mut line));
+                    len = 0;
+                }
+                line.push(chars.next().unwrap());
+                len += 1;
+            }
+        }
+        lines.push(line);
+    }
+    lines
+}
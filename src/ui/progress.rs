@@ -0,0 +1,115 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::ui::list_view::fit_text;
+use embedded_graphics::{
+    mono_font::{iso_8859_1::FONT_10X20, MonoTextStyle},
+    pixelcolor::GrayColor,
+    prelude::*,
+    primitives::{PrimitiveStyle, PrimitiveStyleBuilder, Rectangle},
+    text::{Baseline, Text},
+};
+
+// height of the progress box
+const BOX_HEIGHT: u32 = 100;
+// space inside the box
+const PADDING: u32 = 12;
+// height of the bar
+const BAR_HEIGHT: u32 = 24;
+// progress between redraws, eink refreshes are slow
+const STEP_PERCENT: u32 = 5;
+const STEP_BYTES: u64 = 256 * 1024;
+
+/// A progress box in the middle of the screen
+///
+/// Only its area needs a partial refresh, and `update` says when the
+/// progress moved enough to be worth a refresh.
+#[derive(Debug)]
+pub struct ProgressBar {
+    title: String,
+    area: Rectangle,
+    done: u64,
+    total: Option<u64>,
+    // progress when last drawn
+    drawn: Option<(u64, u32)>,
+}
+
+impl ProgressBar {
+    /// create the box for a display size
+    pub fn new(title: &str, width: u32, height: u32) -> Self {
+        let top = height.saturating_sub(BOX_HEIGHT) / 2;
+        Self {
+            title: title.to_string(),
+            area: Rectangle::new(Point::new(0, top as i32), Size::new(width, BOX_HEIGHT)),
+            done: 0,
+            total: None,
+            drawn: None,
+        }
+    }
+
+    /// the area to refresh
+    pub fn area(&self) -> Rectangle {
+        self.area
+    }
+
+    /// percent done, none if the total isn't known
+    pub fn percent(&self) -> Option<u32> {
+        self.total
+            .filter(|t| *t > 0)
+            .map(|t| (self.done.min(t) * 100 / t) as u32)
+    }
+
+    /// set the progress, returns true if it should be drawn again
+    pub fn update(&mut self, done: u64, total: Option<u64>) -> bool {
+        self.done = done;
+        self.total = total;
+        match (self.drawn, self.percent()) {
+            (None, _) => true,
+            (Some((_, drawn)), Some(p)) => p >= drawn + STEP_PERCENT || (p == 100 && drawn < 100),
+            (Some((drawn, _)), None) => done >= drawn + STEP_BYTES,
+        }
+    }
+
+    /// draw the box
+    pub fn draw<D>(&mut self, display: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget,
+        D::Color: GrayColor,
+    {
+        let style = MonoTextStyle::new(&FONT_10X20, D::Color::BLACK);
+        self.area
+            .into_styled(
+                PrimitiveStyleBuilder::new()
+                    .stroke_color(D::Color::BLACK)
+                    .stroke_width(2)
+                    .fill_color(D::Color::WHITE)
+                    .build(),
+            )
+            .draw(display)?;
+        let left = self.area.top_left.x + PADDING as i32;
+        let width = self.area.size.width - 2 * PADDING;
+        let top = self.area.top_left.y + PADDING as i32;
+        let label = match self.percent() {
+            Some(p) => format!("{}  {}%", self.title, p),
+            None => format!("{}  {} KB", self.title, self.done / 1024),
+        };
+        Text::with_baseline(
+            &fit_text(&label, width),
+            Point::new(left, top),
+            style,
+            Baseline::Top,
+        )
+        .draw(display)?;
+
+        let bar = Rectangle::new(Point::new(left, top + 36), Size::new(width, BAR_HEIGHT));
+        bar.into_styled(PrimitiveStyle::with_stroke(D::Color::BLACK, 1))
+            .draw(display)?;
+        if let Some(p) = self.percent() {
+            Rectangle::new(bar.top_left, Size::new(width * p / 100, BAR_HEIGHT))
+                .into_styled(PrimitiveStyle::with_fill(D::Color::BLACK))
+                .draw(display)?;
+        }
+        self.drawn = Some((self.done, self.percent().unwrap_or(0)));
+        Ok(())
+    }
+}
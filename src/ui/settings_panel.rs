@@ -0,0 +1,128 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::ui::list_view::fit_text;
+use crate::ui::touch::{TouchEvent, TouchEventKind};
+use embedded_graphics::{
+    mono_font::{iso_8859_1::FONT_10X20, MonoTextStyle},
+    pixelcolor::GrayColor,
+    prelude::*,
+    primitives::{Line, PrimitiveStyle, Rectangle},
+    text::{Alignment, Baseline, Text, TextStyleBuilder},
+};
+
+// height of a row
+const ROW_HEIGHT: u32 = 60;
+// width of the - and + buttons
+const BUTTON_WIDTH: u32 = 70;
+const MARGIN: u32 = 10;
+
+/// What the panel wants done after a touch event
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PanelAction {
+    None,
+    Decrease(usize),
+    Increase(usize),
+    Close,
+}
+
+/// A panel of settings at the bottom of the screen
+///
+/// Each row shows a label and value, with buttons to step the value down
+/// or up. The page above stays visible as a preview. A tap above the
+/// panel closes it.
+#[derive(Debug)]
+pub struct SettingsPanel {
+    area: Rectangle,
+}
+
+impl SettingsPanel {
+    /// create the panel for `rows` rows on a display
+    pub fn new(rows: usize, width: u32, height: u32) -> Self {
+        let h = (rows as u32 * ROW_HEIGHT).min(height);
+        Self {
+            area: Rectangle::new(Point::new(0, (height - h) as i32), Size::new(width, h)),
+        }
+    }
+
+    /// area of the panel, for partial refresh
+    pub fn area(&self) -> Rectangle {
+        self.area
+    }
+
+    // the row, - button and + button areas
+    fn row(&self, i: usize) -> (Rectangle, Rectangle, Rectangle) {
+        let top = self.area.top_left.y + (i as u32 * ROW_HEIGHT) as i32;
+        let width = self.area.size.width;
+        let size = Size::new(BUTTON_WIDTH, ROW_HEIGHT - 10);
+        (
+            Rectangle::new(Point::new(0, top), Size::new(width, ROW_HEIGHT)),
+            Rectangle::new(
+                Point::new((width - 2 * (BUTTON_WIDTH + MARGIN)) as i32, top + 5),
+                size,
+            ),
+            Rectangle::new(
+                Point::new((width - BUTTON_WIDTH - MARGIN) as i32, top + 5),
+                size,
+            ),
+        )
+    }
+
+    /// handle a touch event, `rows` is the number of rows
+    pub fn touch(&self, evt: &TouchEvent, rows: usize) -> PanelAction {
+        if evt.kind() != TouchEventKind::Tap {
+            return PanelAction::None;
+        }
+        let p = Point::new(evt.x() as i32, evt.y() as i32);
+        if !self.area.contains(p) {
+            return PanelAction::Close;
+        }
+        for i in 0..rows {
+            let (_, minus, plus) = self.row(i);
+            if minus.contains(p) {
+                return PanelAction::Decrease(i);
+            }
+            if plus.contains(p) {
+                return PanelAction::Increase(i);
+            }
+        }
+        PanelAction::None
+    }
+
+    /// draw the rows, each a label and its current value
+    pub fn draw<D>(&self, display: &mut D, rows: &[(&str, String)]) -> Result<(), D::Error>
+    where
+        D: DrawTarget,
+        D::Color: GrayColor,
+    {
+        let style = MonoTextStyle::new(&FONT_10X20, D::Color::BLACK);
+        let centered = TextStyleBuilder::new()
+            .alignment(Alignment::Center)
+            .baseline(Baseline::Middle)
+            .build();
+        let stroke = PrimitiveStyle::with_stroke(D::Color::BLACK, 2);
+        self.area
+            .into_styled(PrimitiveStyle::with_fill(D::Color::WHITE))
+            .draw(display)?;
+        let right = self.area.size.width as i32 - 1;
+        Line::new(self.area.top_left, Point::new(right, self.area.top_left.y))
+            .into_styled(PrimitiveStyle::with_stroke(D::Color::BLACK, 3))
+            .draw(display)?;
+        let text_width = self.area.size.width - 2 * (BUTTON_WIDTH + MARGIN) - 2 * MARGIN;
+        for (i, (label, value)) in rows.iter().enumerate() {
+            let (row, minus, plus) = self.row(i);
+            Text::with_baseline(
+                &fit_text(&format!("{}: {}", label, value), text_width),
+                Point::new(MARGIN as i32, row.center().y),
+                style,
+                Baseline::Middle,
+            )
+            .draw(display)?;
+            minus.into_styled(stroke).draw(display)?;
+            Text::with_text_style("-", minus.center(), style, centered).draw(display)?;
+            plus.into_styled(stroke).draw(display)?;
+            Text::with_text_style("+", plus.center(), style, centered).draw(display)?;
+        }
+        Ok(())
+    }
+}
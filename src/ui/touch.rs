@@ -0,0 +1,139 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+/// Event kind
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TouchEventKind {
+    None,
+    Tap,
+    Hold,
+    Drag,
+    SwipeLeft,
+    SwipeRight,
+    PinchEnlarge,
+    PinchReduce,
+    Release,
+}
+
+/// The touch event
+///
+/// Made by the touch thread from the sensor, in user coordinates, or
+/// injected from the serial console.
+#[derive(Debug, Copy, Clone)]
+pub struct TouchEvent {
+    kind: TouchEventKind,
+    x: u32,
+    y: u32,
+    dist: f32,
+}
+
+impl TouchEvent {
+    /// create a new touch event
+    pub fn new(kind: TouchEventKind) -> Self {
+        Self {
+            kind,
+            x: 0,
+            y: 0,
+            dist: 0.0,
+        }
+    }
+
+    /// create a touch event at a position
+    pub fn with_position(kind: TouchEventKind, x: u32, y: u32) -> Self {
+        Self {
+            kind,
+            x,
+            y,
+            dist: 0.0,
+        }
+    }
+
+    /// create a pinch event, `dist` is the change in finger distance
+    pub fn pinch(kind: TouchEventKind, dist: f32) -> Self {
+        Self {
+            kind,
+            x: 0,
+            y: 0,
+            dist,
+        }
+    }
+
+    /// the kind of event
+    pub fn kind(&self) -> TouchEventKind {
+        self.kind
+    }
+
+    /// x position of the event in user coordinates
+    pub fn x(&self) -> u32 {
+        self.x
+    }
+
+    /// y position of the event in user coordinates
+    pub fn y(&self) -> u32 {
+        self.y
+    }
+
+    /// distance change of a pinch event
+    pub fn dist(&self) -> f32 {
+        self.dist
+    }
+}
+
+/// Turns pinch events into whole steps
+///
+/// The touch thread sends a pinch event for every small change in finger
+/// distance, so the distances are added up and a step is only taken for
+/// each whole `step` of it, the rest carries to the next event. A release
+/// ends the pinch.
+#[derive(Debug, Copy, Clone)]
+pub struct PinchSteps {
+    step: f32,
+    // distance not used yet, enlarging is positive
+    carried: f32,
+}
+
+impl PinchSteps {
+    /// create the counter, `step` is the distance change for one step
+    pub fn new(step: f32) -> Self {
+        Self { step, carried: 0.0 }
+    }
+
+    /// the steps for an event, positive to enlarge, 0 for other events
+    pub fn steps(&mut self, evt: &TouchEvent) -> i32 {
+        match evt.kind() {
+            TouchEventKind::PinchEnlarge => self.carried += evt.dist(),
+            TouchEventKind::PinchReduce => self.carried -= evt.dist(),
+            TouchEventKind::Release => {
+                self.carried = 0.0;
+                return 0;
+            }
+            _ => return 0,
+        }
+        let steps = (self.carried / self.step).trunc();
+        self.carried -= steps * self.step;
+        steps as i32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pinch_steps_add_up() {
+        let mut pinch = PinchSteps::new(20.0);
+        let enlarge = |d| TouchEvent::pinch(TouchEventKind::PinchEnlarge, d);
+        let reduce = |d| TouchEvent::pinch(TouchEventKind::PinchReduce, d);
+        // small moves don't step until they make a whole step
+        assert_eq!(pinch.steps(&enlarge(8.0)), 0);
+        assert_eq!(pinch.steps(&enlarge(8.0)), 0);
+        assert_eq!(pinch.steps(&enlarge(8.0)), 1);
+        assert_eq!(pinch.steps(&enlarge(45.0)), 2);
+        // 9 carried, reducing goes back through it first
+        assert_eq!(pinch.steps(&reduce(25.0)), 0);
+        assert_eq!(pinch.steps(&reduce(10.0)), -1);
+        assert_eq!(pinch.steps(&TouchEvent::new(TouchEventKind::Release)), 0);
+        assert_eq!(pinch.steps(&reduce(19.0)), 0);
+        assert_eq!(pinch.steps(&TouchEvent::new(TouchEventKind::Tap)), 0);
+    }
+}
@@ -0,0 +1,172 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::net::wifi::{KnownNetworks, NetworkInfo, WifiCredentials};
+use crate::ui::keyboard::{Keyboard, KeyboardAction};
+use crate::ui::list_view::{ListAction, ListRow, ListView};
+use crate::ui::touch::{TouchEvent, TouchEventKind};
+use embedded_graphics::{pixelcolor::GrayColor, prelude::*};
+
+// wpa passphrases are at most 63 characters
+const MAX_PASSWORD: usize = 63;
+
+/// What the wifi setup screen wants done
+#[derive(Debug, Clone, PartialEq)]
+pub enum WifiSetupAction {
+    None,
+    Redraw,
+    /// connect, and remember the network if it works
+    Connect(WifiCredentials),
+    /// forget a saved network
+    Forget(String),
+    Close,
+}
+
+/// Picks a network from a scan and asks for its password
+///
+/// Saved networks connect with their stored password, holding one
+/// forgets it. When a saved password stops working the password is asked
+/// for again, see `connect_failed`.
+#[derive(Debug)]
+pub struct WifiSetupView {
+    list: ListView,
+    networks: Vec<NetworkInfo>,
+    labels: Vec<String>,
+    known: Vec<bool>,
+    keyboard: Option<(String, Keyboard)>,
+    width: u32,
+    height: u32,
+}
+
+impl WifiSetupView {
+    /// create the screen for the networks found by a scan
+    pub fn new(width: u32, height: u32, networks: Vec<NetworkInfo>, known: &KnownNetworks) -> Self {
+        let mut view = Self {
+            list: ListView::new("WiFi networks", width, height),
+            networks,
+            labels: Vec::new(),
+            known: Vec::new(),
+            keyboard: None,
+            width,
+            height,
+        };
+        view.set_known(known);
+        view
+    }
+
+    /// mark the saved networks again, after one is remembered or forgotten
+    pub fn set_known(&mut self, known: &KnownNetworks) {
+        self.known = self
+            .networks
+            .iter()
+            .map(|n| known.find(&n.ssid).is_some())
+            .collect();
+        self.labels = self
+            .networks
+            .iter()
+            .zip(self.known.iter())
+            .map(|(n, k)| {
+                let bars = "|".repeat(n.bars() as usize);
+                let lock = if n.secured { " *" } else { "" };
+                let saved = if *k { "  (saved)" } else { "" };
+                format!("{:<4} {}{}{}", bars, n.ssid, lock, saved)
+            })
+            .collect();
+    }
+
+    /// a connection failed, ask for the password of a secured network again
+    pub fn connect_failed(&mut self, ssid: &str) {
+        if self.networks.iter().any(|n| n.ssid == ssid && n.secured) {
+            let prompt = format!("Can't connect, password for {}", ssid);
+            self.ask_password(ssid, &prompt);
+        }
+    }
+
+    /// is the password keyboard shown
+    pub fn asking_password(&self) -> bool {
+        self.keyboard.is_some()
+    }
+
+    fn ask_password(&mut self, ssid: &str, prompt: &str) {
+        let keyboard = Keyboard::new(prompt, self.width, self.height)
+            .max_len(MAX_PASSWORD)
+            .masked(true);
+        self.keyboard = Some((ssid.to_string(), keyboard));
+    }
+
+    /// handle a touch event
+    pub fn touch(&mut self, evt: &TouchEvent, known: &KnownNetworks) -> WifiSetupAction {
+        if let Some((ssid, keyboard)) = &mut self.keyboard {
+            return match keyboard.touch(evt) {
+                KeyboardAction::None => WifiSetupAction::None,
+                KeyboardAction::Redraw => WifiSetupAction::Redraw,
+                KeyboardAction::Done(password) => {
+                    let creds = WifiCredentials {
+                        ssid: ssid.clone(),
+                        password,
+                    };
+                    self.keyboard = None;
+                    WifiSetupAction::Connect(creds)
+                }
+                KeyboardAction::Cancel => {
+                    self.keyboard = None;
+                    WifiSetupAction::Redraw
+                }
+            };
+        }
+        if evt.kind() == TouchEventKind::Hold {
+            // hold a saved network to forget it
+            return match self.list.row_at(evt.y(), self.networks.len()) {
+                Some(i) if self.known[i] => WifiSetupAction::Forget(self.networks[i].ssid.clone()),
+                _ => WifiSetupAction::None,
+            };
+        }
+        match self.list.touch(evt, self.networks.len()) {
+            ListAction::None => WifiSetupAction::None,
+            ListAction::Redraw => WifiSetupAction::Redraw,
+            ListAction::Close => WifiSetupAction::Close,
+            ListAction::Select(i) => {
+                let network = &self.networks[i];
+                if let Some(creds) = known.find(&network.ssid) {
+                    WifiSetupAction::Connect(creds.clone())
+                } else if !network.secured {
+                    WifiSetupAction::Connect(WifiCredentials {
+                        ssid: network.ssid.clone(),
+                        password: String::new(),
+                    })
+                } else {
+                    let ssid = network.ssid.clone();
+                    self.ask_password(&ssid, &format!("Password for {}", ssid));
+                    WifiSetupAction::Redraw
+                }
+            }
+        }
+    }
+
+    /// draw the network list, or the password keyboard
+    pub fn draw<D>(&self, display: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget,
+        D::Color: GrayColor,
+    {
+        match &self.keyboard {
+            Some((_, keyboard)) => {
+                display.clear(D::Color::WHITE)?;
+                keyboard.draw(display)
+            }
+            None => {
+                let rows: Vec<ListRow> = self
+                    .labels
+                    .iter()
+                    .zip(self.known.iter())
+                    .map(|(l, k)| ListRow {
+                        text: l,
+                        indent: 0,
+                        marked: *k,
+                    })
+                    .collect();
+                self.list.draw(display, &rows)
+            }
+        }
+    }
+}
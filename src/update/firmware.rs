@@ -0,0 +1,447 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+use std::{cmp::Ordering, io::Read};
+
+/// the update image looked for on the sdcard, in the ereader dir
+pub const FIRMWARE_FILE: &str = "firmware.bin";
+/// an optional sha-256 of the whole image, as hex, next to it
+pub const FIRMWARE_SHA_FILE: &str = "firmware.bin.sha256";
+/// the image is renamed to this once installed, so it isn't installed again
+pub const INSTALLED_FILE: &str = "firmware.installed";
+/// or to this when it fails its checks
+pub const FAILED_FILE: &str = "firmware.failed";
+
+// the first byte of an esp32 app image
+const IMAGE_MAGIC: u8 = 0xe9;
+// the first word of the app description
+const APP_DESC_MAGIC: u32 = 0xabcd_5432;
+// chip id in the image header of an esp32
+const CHIP_ID_ESP32: u16 = 0;
+// image header, then each segment has a header
+const HEADER_LEN: usize = 24;
+const SEGMENT_HEADER_LEN: usize = 8;
+// the app description starts the first segment
+const APP_DESC_LEN: usize = 256;
+// bytes needed to check the header
+const HEAD_LEN: usize = HEADER_LEN + SEGMENT_HEADER_LEN + APP_DESC_LEN;
+// limits a real image stays well under
+const MAX_SEGMENTS: u8 = 16;
+const MAX_SEGMENT_LEN: u32 = 16 * 1024 * 1024;
+const SHA256_LEN: usize = 32;
+// size of the reads copied
+const CHUNK_SIZE: usize = 4096;
+
+/// The app description in an image
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AppInfo {
+    pub project_name: String,
+    pub version: String,
+    pub idf_version: String,
+    /// build date and time
+    pub date: String,
+    pub time: String,
+}
+
+// a nul terminated string field of the app description
+fn c_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).to_string()
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+/// check the start of an image, the segment count, if a sha-256 is
+/// appended and the app description
+pub fn parse_header(bytes: &[u8]) -> Result<(u8, bool, AppInfo)> {
+    if bytes.len() < HEAD_LEN {
+        return Err(anyhow!("firmware image is too short"));
+    }
+    if bytes[0] != IMAGE_MAGIC {
+        return Err(anyhow!("not a firmware image"));
+    }
+    let segments = bytes[1];
+    if segments == 0 || segments > MAX_SEGMENTS {
+        return Err(anyhow!("bad firmware segment count {}", segments));
+    }
+    let chip_id = u16_at(bytes, 12);
+    if chip_id != CHIP_ID_ESP32 {
+        return Err(anyhow!("firmware is for another chip, id {}", chip_id));
+    }
+    let hash_appended = bytes[23] == 1;
+    let desc = &bytes[HEADER_LEN + SEGMENT_HEADER_LEN..];
+    if u32_at(desc, 0) != APP_DESC_MAGIC {
+        return Err(anyhow!("firmware has no app description"));
+    }
+    let info = AppInfo {
+        version: c_string(&desc[16..48]),
+        project_name: c_string(&desc[48..80]),
+        time: c_string(&desc[80..96]),
+        date: c_string(&desc[96..112]),
+        idf_version: c_string(&desc[112..144]),
+    };
+    Ok((segments, hash_appended, info))
+}
+
+/// parse a sha-256 written as hex, like `sha256sum` prints it
+pub fn parse_sha256(text: &str) -> Result<[u8; SHA256_LEN]> {
+    let hex = text.split_whitespace().next().unwrap_or("");
+    if hex.len() != 2 * SHA256_LEN || !hex.is_ascii() {
+        return Err(anyhow!("bad sha-256 {:?}", hex));
+    }
+    let mut hash = [0u8; SHA256_LEN];
+    for (i, b) in hash.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)
+            .map_err(|_| anyhow!("bad sha-256 {:?}", hex))?;
+    }
+    Ok(hash)
+}
+
+/// compare dotted versions like `0.2.10`, a leading `v` is skipped
+///
+/// parts that aren't numbers compare as text
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let parts = |v: &str| -> Vec<String> {
+        v.trim()
+            .trim_start_matches('v')
+            .split(['.', '-', '+'])
+            .map(|p| p.to_string())
+            .collect()
+    };
+    let (a, b) = (parts(a), parts(b));
+    for (x, y) in a.iter().zip(b.iter()) {
+        let order = match (x.parse::<u64>(), y.parse::<u64>()) {
+            (Ok(x), Ok(y)) => x.cmp(&y),
+            _ => x.cmp(y),
+        };
+        if order != Ordering::Equal {
+            return order;
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
+/// Checks a firmware image as it is streamed into the update slot
+///
+/// The header is checked as soon as it arrives, so a wrong file fails
+/// before much is written. The sha-256 esp-idf appends to an image and
+/// the expected sha-256 of the whole file, if given, are checked by
+/// `finish`, at least one of them must be there.
+#[derive(Debug)]
+pub struct ImageVerifier {
+    project_name: String,
+    expected: Option<[u8; SHA256_LEN]>,
+    // bytes seen
+    pos: u64,
+    // the start of the image until the header is checked
+    head: Vec<u8>,
+    info: Option<AppInfo>,
+    hash_appended: bool,
+    // segment headers left, where the next one starts and its bytes so far
+    segments_left: u8,
+    segment_at: u64,
+    segment_header: Vec<u8>,
+    // the length of the image without the appended sha, once known
+    image_len: Option<u64>,
+    appended: Vec<u8>,
+    image_hasher: Sha256,
+    file_hasher: Sha256,
+}
+
+impl ImageVerifier {
+    /// a verifier for an image of the named project, the one running
+    pub fn new(project_name: &str, expected: Option<[u8; SHA256_LEN]>) -> Self {
+        Self {
+            project_name: project_name.to_string(),
+            expected,
+            pos: 0,
+            head: Vec::with_capacity(HEAD_LEN),
+            info: None,
+            hash_appended: false,
+            segments_left: 0,
+            segment_at: HEADER_LEN as u64,
+            segment_header: Vec::with_capacity(SEGMENT_HEADER_LEN),
+            image_len: None,
+            appended: Vec::with_capacity(SHA256_LEN),
+            image_hasher: Sha256::new(),
+            file_hasher: Sha256::new(),
+        }
+    }
+
+    /// the app description, once the header has been checked
+    pub fn info(&self) -> Option<&AppInfo> {
+        self.info.as_ref()
+    }
+
+    /// check the next part of the image, an error means it is not usable
+    pub fn update(&mut self, data: &[u8]) -> Result<()> {
+        self.file_hasher.update(data);
+        // where the bytes the segment walk hasn't seen start
+        let mut from = 0;
+        if self.info.is_none() {
+            let want = HEAD_LEN - self.head.len();
+            from = want.min(data.len());
+            self.head.extend_from_slice(&data[..from]);
+            if self.head.len() == HEAD_LEN {
+                let (segments, hash_appended, info) = parse_header(&self.head)?;
+                if info.project_name != self.project_name {
+                    return Err(anyhow!(
+                        "firmware is for {}, not {}",
+                        info.project_name,
+                        self.project_name
+                    ));
+                }
+                self.segments_left = segments;
+                self.hash_appended = hash_appended;
+                self.info = Some(info);
+                // walk the segments seen so far
+                let head = std::mem::take(&mut self.head);
+                self.walk_segments(&head, 0);
+            }
+        }
+        if self.info.is_some() {
+            self.walk_segments(&data[from..], self.pos + from as u64);
+        }
+        // the image hash covers everything before the appended sha
+        let end = self.image_len.unwrap_or(u64::MAX);
+        let hashed = end.saturating_sub(self.pos).min(data.len() as u64) as usize;
+        self.image_hasher.update(&data[..hashed]);
+        let want = SHA256_LEN - self.appended.len();
+        let rest = &data[hashed..];
+        self.appended
+            .extend_from_slice(&rest[..want.min(rest.len())]);
+        self.pos += data.len() as u64;
+        if self.segment_header.len() == SEGMENT_HEADER_LEN {
+            return Err(anyhow!("bad firmware segment length"));
+        }
+        Ok(())
+    }
+
+    // follow the segment headers in data, which starts at offset `start`
+    fn walk_segments(&mut self, data: &[u8], start: u64) {
+        let end = start + data.len() as u64;
+        while self.segments_left > 0 && self.segment_at < end {
+            let at = self.segment_at + self.segment_header.len() as u64;
+            if at >= end {
+                break;
+            }
+            let from = (at - start) as usize;
+            let want = SEGMENT_HEADER_LEN - self.segment_header.len();
+            let part = &data[from..(from + want).min(data.len())];
+            self.segment_header.extend_from_slice(part);
+            if self.segment_header.len() < SEGMENT_HEADER_LEN {
+                break;
+            }
+            let len = u32_at(&self.segment_header, 4);
+            if len > MAX_SEGMENT_LEN {
+                // left full, `update` reports it
+                return;
+            }
+            self.segment_header.clear();
+            self.segment_at += (SEGMENT_HEADER_LEN as u64) + len as u64;
+            self.segments_left -= 1;
+            if self.segments_left == 0 {
+                // a checksum byte ends the image, padded to 16 bytes
+                self.image_len = Some((self.segment_at + 1 + 15) & !15);
+            }
+        }
+    }
+
+    /// check the hashes once the whole image is in, the app description
+    /// of a good image
+    pub fn finish(self) -> Result<AppInfo> {
+        let info = self
+            .info
+            .ok_or_else(|| anyhow!("firmware image is too short"))?;
+        let image_len = self
+            .image_len
+            .filter(|len| *len <= self.pos)
+            .ok_or_else(|| anyhow!("firmware image is cut short"))?;
+        let mut checked = false;
+        if self.hash_appended {
+            if self.appended.len() < SHA256_LEN
+                || self.image_hasher.finalize().as_slice() != self.appended.as_slice()
+            {
+                return Err(anyhow!(
+                    "firmware sha-256 does not match, {} bytes",
+                    image_len
+                ));
+            }
+            checked = true;
+        }
+        if let Some(expected) = self.expected {
+            if self.file_hasher.finalize().as_slice() != expected {
+                return Err(anyhow!("firmware file sha-256 does not match"));
+            }
+            checked = true;
+        }
+        if !checked {
+            return Err(anyhow!("firmware has no sha-256 to check"));
+        }
+        Ok(info)
+    }
+}
+
+/// copy an image to `write`, checking it on the way
+///
+/// `progress` gets the bytes done and the total, if known. The app
+/// description of a good image is returned once it is all written.
+pub fn copy_image(
+    reader: &mut dyn Read,
+    total: Option<u64>,
+    mut verifier: ImageVerifier,
+    write: &mut dyn FnMut(&[u8]) -> Result<()>,
+    progress: &mut dyn FnMut(u64, Option<u64>),
+) -> Result<AppInfo> {
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut done = 0u64;
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        verifier.update(&buf[..n])?;
+        write(&buf[..n])?;
+        done += n as u64;
+        progress(done, total);
+    }
+    if let Some(total) = total.filter(|t| done < *t) {
+        return Err(anyhow!("firmware stopped at {} of {} bytes", done, total));
+    }
+    verifier.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // an esp32 image of the project with a second segment of `data`,
+    // with the sha-256 appended if `hashed`
+    fn image(project: &str, version: &str, data: &[u8], hashed: bool) -> Vec<u8> {
+        let mut image = vec![0u8; HEADER_LEN];
+        image[0] = IMAGE_MAGIC;
+        image[1] = 2;
+        image[23] = hashed as u8;
+        let mut desc = vec![0u8; APP_DESC_LEN];
+        desc[..4].copy_from_slice(&APP_DESC_MAGIC.to_le_bytes());
+        desc[16..16 + version.len()].copy_from_slice(version.as_bytes());
+        desc[48..48 + project.len()].copy_from_slice(project.as_bytes());
+        for segment in [&desc[..], data] {
+            image.extend_from_slice(&0x3f40_0020u32.to_le_bytes());
+            image.extend_from_slice(&(segment.len() as u32).to_le_bytes());
+            image.extend_from_slice(segment);
+        }
+        // the checksum byte and padding to 16 bytes
+        image.push(0xef);
+        image.resize((image.len() + 15) & !15, 0);
+        if hashed {
+            let hash = Sha256::digest(&image);
+            image.extend_from_slice(&hash);
+        }
+        image
+    }
+
+    // check an image a few bytes at a time
+    fn verify(image: &[u8], expected: Option<[u8; 32]>) -> Result<AppInfo> {
+        let mut verifier = ImageVerifier::new("ereader", expected);
+        for chunk in image.chunks(7) {
+            verifier.update(chunk)?;
+        }
+        verifier.finish()
+    }
+
+    #[test]
+    fn versions_compare_by_number() {
+        assert_eq!(compare_versions("0.2.10", "0.2.9"), Ordering::Greater);
+        assert_eq!(compare_versions("v1.0", "1.0"), Ordering::Equal);
+        assert_eq!(compare_versions("1.0", "1.0.1"), Ordering::Less);
+        assert_eq!(compare_versions("1.0-rc2", "1.0-rc1"), Ordering::Greater);
+        assert_eq!(compare_versions(" 2 ", "10"), Ordering::Less);
+    }
+
+    #[test]
+    fn sha256_hex() {
+        let hash = parse_sha256(
+            "00ff0102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e  firmware.bin",
+        );
+        assert_eq!(hash.unwrap()[..3], [0x00, 0xff, 0x01]);
+        assert!(parse_sha256("00ff").is_err());
+        assert!(parse_sha256(&"zz".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn good_image_is_accepted() {
+        let data = [7u8; 1000];
+        let info = verify(&image("ereader", "0.3.0", &data, true), None).unwrap();
+        assert_eq!(
+            (info.project_name.as_str(), info.version.as_str()),
+            ("ereader", "0.3.0")
+        );
+        // without an appended sha the file sha is needed
+        let plain = image("ereader", "0.3.0", &data, false);
+        assert!(verify(&plain, None).is_err());
+        let sha: [u8; 32] = Sha256::digest(&plain).into();
+        assert!(verify(&plain, Some(sha)).is_ok());
+    }
+
+    #[test]
+    fn bad_images_are_refused() {
+        let good = image("ereader", "0.3.0", &[7u8; 1000], true);
+        let cut = &good[..good.len() - 40];
+        assert!(verify(cut, None).is_err());
+        assert!(verify(&good[..100], None).is_err());
+
+        let mut tampered = good.clone();
+        tampered[600] ^= 1;
+        assert!(verify(&tampered, None).is_err());
+
+        // the appended sha is fine, the file isn't the one expected
+        assert!(verify(&good, Some([0u8; 32])).is_err());
+
+        let other = image("other", "0.3.0", &[7u8; 1000], true);
+        let e = verify(&other, None).unwrap_err();
+        assert_eq!(e.to_string(), "firmware is for other, not ereader");
+
+        let mut huge = good.clone();
+        let at = HEAD_LEN + 4;
+        huge[at..at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(verify(&huge, None).is_err());
+        assert!(verify(b"PK\x03\x04 not an image", None).is_err());
+    }
+
+    #[test]
+    fn copy_checks_the_length() {
+        let good = image("ereader", "0.3.0", &[7u8; 100], true);
+        let mut written = Vec::new();
+        let mut last = 0;
+        let info = copy_image(
+            &mut &good[..],
+            Some(good.len() as u64),
+            ImageVerifier::new("ereader", None),
+            &mut |d| {
+                written.extend_from_slice(d);
+                Ok(())
+            },
+            &mut |done, _| last = done,
+        );
+        assert_eq!(info.unwrap().version, "0.3.0");
+        assert_eq!((written, last), (good.clone(), good.len() as u64));
+
+        let short = copy_image(
+            &mut &good[..],
+            Some(good.len() as u64 + 1),
+            ImageVerifier::new("ereader", None),
+            &mut |_| Ok(()),
+            &mut |_, _| (),
+        );
+        assert!(short.is_err());
+    }
+}
@@ -0,0 +1,201 @@
+// Copyright (C) 2024 Greg Green <ggreen@bit-builder.com>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::net::http::{resolve_url, HttpClient};
+use crate::ui::confirm::ConfirmDialog;
+use crate::update::firmware::{self, compare_versions};
+use anyhow::{anyhow, Result};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{cmp::Ordering, io::Read};
+
+// release notes longer than this are cut, the dialog has to fit the screen
+const MAX_NOTES: usize = 400;
+
+/// The update manifest, a small json file published with each release
+///
+/// ```json
+/// { "version": "0.3.0", "url": "inkplate-ereader2-0.3.0.bin",
+///   "sha256": "<hex>", "min_battery": 3.7, "notes": "..." }
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: String,
+    /// the image, made absolute against the manifest url
+    pub url: String,
+    /// sha-256 of the image, as hex
+    pub sha256: String,
+    /// lowest battery voltage to start the update at, 0 for the setting
+    #[serde(default)]
+    pub min_battery: f64,
+    /// what changed, shown when the update is offered
+    #[serde(default)]
+    pub notes: String,
+}
+
+impl Manifest {
+    /// the sha-256 of the image
+    pub fn sha256(&self) -> Result<[u8; 32]> {
+        firmware::parse_sha256(&self.sha256)
+    }
+
+    /// is the release newer than the running version
+    pub fn is_newer(&self, running_version: &str) -> bool {
+        compare_versions(&self.version, running_version) == Ordering::Greater
+    }
+}
+
+/// parse a manifest fetched from `base_url`
+pub fn parse_manifest(bytes: &[u8], base_url: &str) -> Result<Manifest> {
+    let mut manifest: Manifest = serde_json::from_slice(bytes)?;
+    if manifest.version.trim().is_empty() || manifest.url.trim().is_empty() {
+        return Err(anyhow!("update manifest needs a version and a url"));
+    }
+    manifest.sha256()?;
+    manifest.url = resolve_url(base_url, manifest.url.trim());
+    Ok(manifest)
+}
+
+/// Opens a url for reading, plain http or https
+pub trait Fetcher {
+    /// the body of a url and its length, if known
+    fn open(&self, url: &str) -> Result<(Box<dyn Read>, Option<u64>)>;
+}
+
+impl Fetcher for HttpClient {
+    fn open(&self, url: &str) -> Result<(Box<dyn Read>, Option<u64>)> {
+        let resp = self.get(url, 0)?;
+        if resp.status != 200 {
+            return Err(anyhow!("http status {} for {}", resp.status, url));
+        }
+        let len = resp.content_length();
+        Ok((resp.body, len))
+    }
+}
+
+/// fetch the manifest, a release newer than the running version if any
+pub fn check(
+    fetcher: &dyn Fetcher,
+    manifest_url: &str,
+    running_version: &str,
+) -> Result<Option<Manifest>> {
+    let (mut body, _) = fetcher.open(manifest_url)?;
+    let mut bytes = Vec::new();
+    body.read_to_end(&mut bytes)?;
+    let manifest = parse_manifest(&bytes, manifest_url)?;
+    info!(
+        "firmware {} is available, running {}",
+        manifest.version, running_version
+    );
+    Ok(Some(manifest).filter(|m| m.is_newer(running_version)))
+}
+
+/// refuse to start with the battery below the manifest's minimum, or
+/// the one from the settings when it is higher
+pub fn check_battery(level: f64, setting: f64, manifest: &Manifest) -> Result<()> {
+    let min = setting.max(manifest.min_battery);
+    if level < min {
+        return Err(anyhow!(
+            "battery is at {:.2} V, charge to {:.2} V to update",
+            level,
+            min
+        ));
+    }
+    Ok(())
+}
+
+/// the question asked before an update
+pub fn update_prompt(
+    manifest: &Manifest,
+    running_version: &str,
+    width: u32,
+    height: u32,
+) -> ConfirmDialog {
+    let mut question = format!(
+        "Update the firmware from {} to {}?",
+        running_version, manifest.version
+    );
+    let notes = manifest.notes.trim();
+    if !notes.is_empty() {
+        question.push_str("\n\n");
+        question.extend(notes.chars().take(MAX_NOTES));
+    }
+    ConfirmDialog::new(&question, width, height).labels("Update", "Later")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, io::Cursor};
+
+    const SHA: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    // serves fixed bodies, noting the urls asked for
+    struct Bodies(Vec<(&'static str, String)>, RefCell<Vec<String>>);
+
+    impl Fetcher for Bodies {
+        fn open(&self, url: &str) -> Result<(Box<dyn Read>, Option<u64>)> {
+            self.1.borrow_mut().push(url.to_string());
+            let (_, body) = self
+                .0
+                .iter()
+                .find(|(u, _)| *u == url)
+                .ok_or_else(|| anyhow!("not found {}", url))?;
+            Ok((Box::new(Cursor::new(body.clone().into_bytes())), None))
+        }
+    }
+
+    fn manifest_json(version: &str) -> String {
+        format!(
+            r#"{{"version":"{}","url":"fw/{}.bin","sha256":"{}","min_battery":3.6,"notes":" fixes "}}"#,
+            version, version, SHA
+        )
+    }
+
+    #[test]
+    fn manifest_urls_are_made_absolute() {
+        let base = "https://example.org/ereader/latest.json";
+        let manifest = parse_manifest(manifest_json("0.3.0").as_bytes(), base).unwrap();
+        assert_eq!(manifest.url, "https://example.org/ereader/fw/0.3.0.bin");
+        assert_eq!(manifest.sha256().unwrap()[0], 0x9f);
+        assert_eq!(manifest.min_battery, 3.6);
+        let minimal = format!(r#"{{"version":"1","url":"/a.bin","sha256":"{}"}}"#, SHA);
+        let manifest = parse_manifest(minimal.as_bytes(), base).unwrap();
+        assert_eq!(manifest.url, "https://example.org/a.bin");
+        assert_eq!((manifest.min_battery, manifest.notes.as_str()), (0.0, ""));
+
+        let no_url = format!(r#"{{"version":"1","url":" ","sha256":"{}"}}"#, SHA);
+        assert!(parse_manifest(no_url.as_bytes(), base).is_err());
+        let bad_sha = r#"{"version":"1","url":"a.bin","sha256":"abc"}"#;
+        assert!(parse_manifest(bad_sha.as_bytes(), base).is_err());
+        assert!(parse_manifest(b"<html>", base).is_err());
+    }
+
+    #[test]
+    fn only_newer_releases_are_offered() {
+        let url = "https://example.org/latest.json";
+        let fetcher = Bodies(vec![(url, manifest_json("0.3.0"))], RefCell::default());
+        assert_eq!(
+            check(&fetcher, url, "0.2.9").unwrap().unwrap().version,
+            "0.3.0"
+        );
+        assert!(check(&fetcher, url, "0.3.0").unwrap().is_none());
+        assert!(check(&fetcher, url, "v0.10.0").unwrap().is_none());
+        assert!(check(&fetcher, "https://example.org/other.json", "0.1.0").is_err());
+        assert_eq!(fetcher.1.borrow().len(), 4);
+    }
+
+    #[test]
+    fn low_battery_is_refused() {
+        let manifest = parse_manifest(manifest_json("1.0").as_bytes(), "http://a/").unwrap();
+        assert!(check_battery(3.7, 3.5, &manifest).is_ok());
+        // the manifest asks for more than the setting
+        let e = check_battery(3.55, 3.5, &manifest).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "battery is at 3.55 V, charge to 3.60 V to update"
+        );
+        // and the setting for more than the manifest
+        assert!(check_battery(3.7, 3.8, &manifest).is_err());
+    }
+}